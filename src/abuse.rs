@@ -0,0 +1,351 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::shared_bitmap::CHUNK_BITS;
+use crate::unix_now;
+
+// How many past detections are kept around for the admin API
+const RECENT_DETECTIONS: usize = 100;
+// Writes further apart than this (in bits) don't continue a sweep
+const MAX_SWEEP_STRIDE: u64 = 64;
+
+#[derive(Debug, Clone)]
+pub struct AbuseConfig {
+    /// Period over which per-chunk write counts are accumulated
+    pub window: Duration,
+    /// Writes to a single chunk within one window before a client is flagged, 0 to disable
+    pub max_chunk_writes: u32,
+    /// Consecutive small steps in one direction before a client is flagged as sweeping the
+    /// board, 0 to disable
+    pub sweep_length: u32,
+    /// How long a flagged client's writes are refused for
+    pub throttle_for: Duration,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DetectionKind {
+    HotChunk { chunk: u64, writes: u32 },
+    Sweep { length: u32 },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Detection {
+    pub ip: IpAddr,
+    #[serde(flatten)]
+    pub kind: DetectionKind,
+    /// Unix time of the detection
+    pub at: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Throttle {
+    pub ip: IpAddr,
+    pub remaining_secs: u64,
+}
+
+struct ClientActivity {
+    window_start: Instant,
+    chunk_writes: HashMap<u64, u32>,
+    last_index: Option<u64>,
+    sweep_direction: bool,
+    sweep_length: u32,
+    throttled_until: Option<Instant>,
+}
+
+impl ClientActivity {
+    fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            chunk_writes: HashMap::new(),
+            last_index: None,
+            sweep_direction: true,
+            sweep_length: 0,
+            throttled_until: None,
+        }
+    }
+}
+
+struct Clients {
+    activity: HashMap<IpAddr, ClientActivity>,
+    last_prune: Instant,
+}
+
+/// Watches the pattern of writes from each client, and temporarily refuses writes from clients
+/// which look like bots hammering one spot or sweeping the board
+pub struct AbuseDetector {
+    config: AbuseConfig,
+    clients: Mutex<Clients>,
+    recent: Mutex<VecDeque<Detection>>,
+    total_detections: AtomicU64,
+}
+
+impl AbuseDetector {
+    pub fn new(config: AbuseConfig) -> Self {
+        Self {
+            config,
+            clients: Mutex::new(Clients {
+                activity: HashMap::new(),
+                last_prune: Instant::now(),
+            }),
+            recent: Mutex::new(VecDeque::with_capacity(RECENT_DETECTIONS)),
+            total_detections: AtomicU64::new(0),
+        }
+    }
+
+    /// Records a write by `ip` to checkbox `bit_index`. Returns how much longer the client is
+    /// throttled for if the write should be refused.
+    pub fn check(&self, ip: IpAddr, bit_index: u64) -> Result<(), Duration> {
+        let ip = ip.to_canonical();
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        if now.duration_since(clients.last_prune) > self.config.window {
+            clients.last_prune = now;
+            let window = self.config.window;
+            clients.activity.retain(|_, client| {
+                client.throttled_until.is_some_and(|until| until > now)
+                    || now.duration_since(client.window_start) <= window
+            });
+        }
+        let client = clients
+            .activity
+            .entry(ip)
+            .or_insert_with(|| ClientActivity::new(now));
+
+        if let Some(until) = client.throttled_until {
+            if until > now {
+                return Err(until - now);
+            }
+            client.throttled_until = None;
+        }
+
+        if now.duration_since(client.window_start) > self.config.window {
+            client.window_start = now;
+            client.chunk_writes.clear();
+        }
+        let chunk = bit_index / CHUNK_BITS as u64;
+        let chunk_writes = client.chunk_writes.entry(chunk).or_default();
+        *chunk_writes += 1;
+        let chunk_writes = *chunk_writes;
+
+        if let Some(last_index) = client.last_index {
+            let direction = bit_index > last_index;
+            let stride = bit_index.abs_diff(last_index);
+            if stride != 0 && stride <= MAX_SWEEP_STRIDE && direction == client.sweep_direction {
+                client.sweep_length += 1;
+            } else {
+                client.sweep_direction = direction;
+                client.sweep_length = u32::from(stride != 0 && stride <= MAX_SWEEP_STRIDE);
+            }
+        }
+        client.last_index = Some(bit_index);
+
+        let detection = if self.config.max_chunk_writes != 0
+            && chunk_writes > self.config.max_chunk_writes
+        {
+            DetectionKind::HotChunk {
+                chunk,
+                writes: chunk_writes,
+            }
+        } else if self.config.sweep_length != 0 && client.sweep_length >= self.config.sweep_length {
+            DetectionKind::Sweep {
+                length: client.sweep_length,
+            }
+        } else {
+            return Ok(());
+        };
+
+        client.throttled_until = Some(now + self.config.throttle_for);
+        client.chunk_writes.clear();
+        client.sweep_length = 0;
+        drop(clients);
+
+        warn!(%ip, ?detection, "throttling client for suspected abuse");
+        self.total_detections.fetch_add(1, Ordering::Relaxed);
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT_DETECTIONS {
+            recent.pop_front();
+        }
+        recent.push_back(Detection {
+            ip,
+            kind: detection,
+            at: unix_now(),
+        });
+        Err(self.config.throttle_for)
+    }
+
+    /// Lifts any throttle on `ip`, returning if it was throttled
+    pub fn unthrottle(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        clients
+            .activity
+            .get_mut(&ip.to_canonical())
+            .and_then(|client| client.throttled_until.take())
+            .is_some_and(|until| until > now)
+    }
+
+    pub fn throttles(&self) -> Vec<Throttle> {
+        let now = Instant::now();
+        let clients = self.clients.lock().unwrap();
+        clients
+            .activity
+            .iter()
+            .filter_map(|(&ip, client)| {
+                let until = client.throttled_until.filter(|&until| until > now)?;
+                Some(Throttle {
+                    ip,
+                    remaining_secs: (until - now).as_secs(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn recent_detections(&self) -> Vec<Detection> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn total_detections(&self) -> u64 {
+        self.total_detections.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(all(test, not(sliders_loom)))]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const WINDOW: Duration = Duration::from_secs(10);
+    const THROTTLE_FOR: Duration = Duration::from_secs(60);
+
+    fn detector(max_chunk_writes: u32, sweep_length: u32) -> AbuseDetector {
+        AbuseDetector::new(AbuseConfig {
+            window: WINDOW,
+            max_chunk_writes,
+            sweep_length,
+            throttle_for: THROTTLE_FOR,
+        })
+    }
+
+    /// Writes far enough apart not to look like a sweep, all in chunk `chunk`
+    fn hammer(detector: &AbuseDetector, chunk: u64, writes: u64) -> Result<(), Duration> {
+        for i in 0..writes {
+            let bit = chunk * CHUNK_BITS as u64 + (i % 2) * (MAX_SWEEP_STRIDE + 1);
+            detector.check(IP, bit)?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hot_chunk_throttled() {
+        let detector = detector(3, 0);
+        assert_eq!(hammer(&detector, 5, 3), Ok(()));
+        // Other chunks are counted separately
+        assert_eq!(hammer(&detector, 6, 3), Ok(()));
+        assert_eq!(hammer(&detector, 5, 1), Err(THROTTLE_FOR));
+        assert_eq!(detector.total_detections(), 1);
+        let detections = detector.recent_detections();
+        assert!(matches!(
+            detections[..],
+            [Detection {
+                ip: IP,
+                kind: DetectionKind::HotChunk {
+                    chunk: 5,
+                    writes: 4
+                },
+                ..
+            }]
+        ));
+        tokio::time::advance(Duration::from_secs(15)).await;
+        assert_eq!(
+            detector.check(IP, 0),
+            Err(THROTTLE_FOR - Duration::from_secs(15))
+        );
+        // Refused writes aren't detected again
+        assert_eq!(detector.total_detections(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn window_resets_chunk_counts() {
+        let detector = detector(3, 0);
+        assert_eq!(hammer(&detector, 5, 3), Ok(()));
+        tokio::time::advance(WINDOW + Duration::from_millis(1)).await;
+        assert_eq!(hammer(&detector, 5, 3), Ok(()));
+        assert_eq!(hammer(&detector, 5, 1), Err(THROTTLE_FOR));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sweep_throttled() {
+        let detector = detector(0, 4);
+        for bit in [0, 10, 20, 30] {
+            assert_eq!(detector.check(IP, bit), Ok(()));
+        }
+        assert_eq!(detector.check(IP, 40), Err(THROTTLE_FOR));
+        assert!(matches!(
+            detector.recent_detections()[..],
+            [Detection {
+                kind: DetectionKind::Sweep { length: 4 },
+                ..
+            }]
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sweep_broken_by_turn_or_long_stride() {
+        let turning = detector(0, 3);
+        // Turning back starts a new sweep in the other direction, one step long
+        for bit in [100, 110, 120, 115, 105] {
+            assert_eq!(turning.check(IP, bit), Ok(()));
+        }
+        assert_eq!(turning.check(IP, 95), Err(THROTTLE_FOR));
+
+        let restarting = detector(0, 3);
+        // A stride too long to continue a sweep, or none at all, starts over from nothing
+        for bit in [
+            100,
+            110,
+            120,
+            120 + MAX_SWEEP_STRIDE + 1,
+            200,
+            210,
+            210,
+            220,
+            230,
+        ] {
+            assert_eq!(restarting.check(IP, bit), Ok(()));
+        }
+        assert_eq!(restarting.check(IP, 240), Err(THROTTLE_FOR));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttle_expires() {
+        let detector = detector(1, 0);
+        assert_eq!(hammer(&detector, 0, 2), Err(THROTTLE_FOR));
+        assert_eq!(detector.throttles().len(), 1);
+        tokio::time::advance(THROTTLE_FOR).await;
+        assert!(detector.throttles().is_empty());
+        // The writes which got it throttled don't count any more
+        assert_eq!(hammer(&detector, 0, 1), Ok(()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unthrottle_lifts_throttle() {
+        let detector = detector(1, 0);
+        assert!(!detector.unthrottle(IP));
+        assert_eq!(hammer(&detector, 0, 2), Err(THROTTLE_FOR));
+        // Given as the same address IPv4-mapped
+        assert!(detector.unthrottle(IpAddr::V6(Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped())));
+        assert!(detector.throttles().is_empty());
+        assert_eq!(hammer(&detector, 0, 1), Ok(()));
+        assert!(!detector.unthrottle(IP));
+    }
+}
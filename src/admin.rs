@@ -0,0 +1,317 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Query, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post, put};
+use axum::{Json, Router};
+use tracing::info;
+
+use crate::abuse::{Detection, Throttle};
+use crate::announcement::{Announcement, Severity, MAX_ANNOUNCEMENT_LEN};
+use crate::audit::{self, AuditEntry};
+use crate::bans::{Ban, Cidr};
+use crate::cdn;
+use crate::cluster::Writes;
+use crate::picture::{self, BOARD_HEIGHT, BOARD_WIDTH, MAX_PICTURE_BYTES, MAX_PICTURE_DIMENSION};
+use crate::reporting;
+use crate::shared_bitmap::{CHUNK_BITS, CHUNK_BYTES, NUM_CHUNKS};
+use crate::{unix_now, SharedState};
+
+/// The admin API, mounted under `/admin`. Every route requires the configured admin token, and
+/// without one the whole API is disabled. Every change made through it is recorded in the audit
+/// log. `/seed_image` is left out unless `images` is set.
+pub fn router(state: SharedState, images: bool) -> Router<SharedState> {
+    let router = Router::new()
+        .route("/bans", get(list_bans).post(add_ban).delete(remove_ban))
+        .route("/abuse", get(abuse_report).delete(lift_throttle))
+        .route("/audit", get(audit_log))
+        .route("/chunk_stats", get(chunk_stats))
+        .route(
+            "/announcement",
+            put(set_announcement).delete(clear_announcement),
+        )
+        .route("/cdn/purge", post(purge_cdn));
+    let router = if images {
+        router.route(
+            "/seed_image",
+            post(seed_image).layer(DefaultBodyLimit::max(MAX_PICTURE_BYTES)),
+        )
+    } else {
+        router
+    };
+    router
+        .route_layer(middleware::from_fn_with_state(state.clone(), audit::record))
+        .route_layer(middleware::from_fn_with_state(state, require_admin))
+}
+
+/// Middleware rejecting requests without the admin token, or every request if there isn't one
+pub async fn require_admin(State(state): State<SharedState>, req: Request, next: Next) -> Response {
+    let Some(token) = state.admin_token.as_deref() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let provided = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !provided.is_some_and(|provided| constant_time_eq(provided.as_bytes(), token.as_bytes())) {
+        return (StatusCode::UNAUTHORIZED, "Missing or invalid admin token").into_response();
+    }
+    next.run(req).await
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn list_bans(State(state): State<SharedState>) -> Json<Vec<Ban>> {
+    Json(state.bans.list())
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct NewBan {
+    cidr: Cidr,
+    /// How long the ban lasts, forever if not given
+    duration_secs: Option<u64>,
+    reason: Option<String>,
+}
+
+#[tracing::instrument(skip(state))]
+async fn add_ban(
+    State(state): State<SharedState>,
+    Json(new_ban): Json<NewBan>,
+) -> axum::response::Result<(StatusCode, Json<Ban>)> {
+    let ban = Ban {
+        cidr: new_ban.cidr,
+        expires_at: new_ban.duration_secs.map(|secs| unix_now() + secs),
+        reason: new_ban.reason,
+    };
+    state
+        .bans
+        .add(ban.clone())
+        .map_err(|e| reporting::internal_error("Failed to save ban list", e))?;
+    info!(cidr = %ban.cidr, "added ban");
+    Ok((StatusCode::CREATED, Json(ban)))
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct BanTarget {
+    cidr: Cidr,
+}
+
+#[tracing::instrument(skip(state))]
+async fn remove_ban(
+    State(state): State<SharedState>,
+    Query(target): Query<BanTarget>,
+) -> axum::response::Result<StatusCode> {
+    let removed = state
+        .bans
+        .remove(target.cidr)
+        .map_err(|e| reporting::internal_error("Failed to save ban list", e))?;
+    if !removed {
+        return Err((StatusCode::NOT_FOUND, "No ban for that block").into());
+    }
+    info!(cidr = %target.cidr, "removed ban");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct NewAnnouncement {
+    text: String,
+    #[serde(default)]
+    severity: Severity,
+    /// How long the announcement lasts, until taken down if not given
+    duration_secs: Option<u64>,
+}
+
+#[tracing::instrument(skip(state))]
+async fn set_announcement(
+    State(state): State<SharedState>,
+    Json(new): Json<NewAnnouncement>,
+) -> axum::response::Result<Json<Announcement>> {
+    if new.text.is_empty() || new.text.len() > MAX_ANNOUNCEMENT_LEN {
+        return Err((StatusCode::BAD_REQUEST, "Invalid announcement length").into());
+    }
+    let now = unix_now();
+    let announcement = Announcement {
+        text: new.text,
+        severity: new.severity,
+        set_at: now,
+        expires_at: new.duration_secs.map(|secs| now + secs),
+    };
+    state.announcements.set(announcement.clone());
+    info!(severity = ?announcement.severity, "set announcement");
+    Ok(Json(announcement))
+}
+
+#[tracing::instrument(skip(state))]
+async fn clear_announcement(State(state): State<SharedState>) -> StatusCode {
+    if !state.announcements.clear() {
+        return StatusCode::NOT_FOUND;
+    }
+    info!("cleared announcement");
+    StatusCode::NO_CONTENT
+}
+
+#[derive(serde::Serialize)]
+struct AbuseReport {
+    throttled: Vec<Throttle>,
+    recent_detections: Vec<Detection>,
+}
+
+async fn abuse_report(State(state): State<SharedState>) -> Json<AbuseReport> {
+    Json(AbuseReport {
+        throttled: state.abuse.throttles(),
+        recent_detections: state.abuse.recent_detections(),
+    })
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct ChunkStatsParams {
+    /// Only the most written to chunks, busiest first, rather than every chunk in order
+    top: Option<usize>,
+}
+
+#[derive(serde::Serialize)]
+struct ChunkStats {
+    chunk: usize,
+    /// Index of the chunk's first checkbox
+    offset: u64,
+    /// Writes since the server started
+    mutations: u64,
+    /// Unix time the chunk last changed, or null if it hasn't since the server started
+    last_modified: Option<u64>,
+    /// Checked checkboxes
+    count: u32,
+    /// Sum of the chunk's sliders
+    sum: u32,
+}
+
+/// Activity and totals for each chunk, for finding hot spots
+async fn chunk_stats(
+    State(state): State<SharedState>,
+    Query(params): Query<ChunkStatsParams>,
+) -> Json<Vec<ChunkStats>> {
+    let bitmap = &state.bitmap;
+    let mut bytes = [0; CHUNK_BYTES];
+    let mut stats: Vec<_> = (0..NUM_CHUNKS)
+        .map(|chunk| {
+            bitmap.load_bytes(chunk * CHUNK_BYTES, &mut bytes);
+            ChunkStats {
+                chunk,
+                offset: (chunk * CHUNK_BITS) as u64,
+                mutations: bitmap.mutations(chunk),
+                last_modified: bitmap.last_modified(chunk),
+                count: bytes.iter().map(|byte| byte.count_ones()).sum(),
+                sum: bytes.iter().map(|&byte| u32::from(byte)).sum(),
+            }
+        })
+        .collect();
+    if let Some(top) = params.top {
+        stats.sort_by_key(|chunk| std::cmp::Reverse(chunk.mutations));
+        stats.truncate(top);
+    }
+    Json(stats)
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct ThrottleTarget {
+    ip: IpAddr,
+}
+
+#[tracing::instrument(skip(state))]
+async fn lift_throttle(
+    State(state): State<SharedState>,
+    Query(target): Query<ThrottleTarget>,
+) -> axum::response::Result<StatusCode> {
+    if !state.abuse.unthrottle(target.ip) {
+        return Err((StatusCode::NOT_FOUND, "That address isn't throttled").into());
+    }
+    info!(ip = %target.ip, "lifted throttle");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct AuditParams {
+    /// Most recent entries to return
+    #[serde(default = "default_audit_limit")]
+    limit: usize,
+}
+
+fn default_audit_limit() -> usize {
+    100
+}
+
+async fn audit_log(
+    State(state): State<SharedState>,
+    Query(params): Query<AuditParams>,
+) -> axum::response::Result<Json<Vec<AuditEntry>>> {
+    let audit = Arc::clone(&state.audit);
+    let entries = tokio::task::spawn_blocking(move || audit.recent(params.limit))
+        .await
+        .map_err(|e| reporting::internal_error("Failed to read audit log", e))?
+        .map_err(|e| reporting::internal_error("Failed to read audit log", e))?;
+    Ok(Json(entries))
+}
+
+/// Overwrites the whole board with an uploaded PNG or JPEG, scaled to fit and converted to
+/// grayscale
+#[tracing::instrument(skip_all, fields(len = body.len()))]
+async fn seed_image(
+    State(state): State<SharedState>,
+    body: Bytes,
+) -> axum::response::Result<StatusCode> {
+    let pixels = state
+        .images
+        .run(move || {
+            let image = picture::decode_gray(&body, MAX_PICTURE_DIMENSION)?;
+            let image = image::imageops::resize(
+                &image,
+                BOARD_WIDTH,
+                BOARD_HEIGHT,
+                image::imageops::FilterType::Triangle,
+            );
+            Ok::<_, (StatusCode, String)>(image.into_raw())
+        })
+        .await
+        .map_err(|e| e.response("Failed to decode image"))??;
+    let mut writes = Writes::new(&state);
+    writes.store_bytes(0, &pixels);
+    writes.finish().await?;
+    info!("seeded board from image");
+    cdn::purge_board(&state).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+struct PurgeRequest {
+    /// Surrogate keys to purge, everything if empty
+    #[serde(default)]
+    keys: Vec<String>,
+}
+
+/// Purges the CDN of everything tagged with any of the given keys, or of everything
+async fn purge_cdn(
+    State(state): State<SharedState>,
+    request: Option<Json<PurgeRequest>>,
+) -> axum::response::Result<StatusCode> {
+    let Some(cdn) = state.cdn.as_ref().filter(|cdn| cdn.can_purge()) else {
+        return Err((StatusCode::NOT_FOUND, "CDN purging isn't configured").into());
+    };
+    let mut keys = request
+        .map(|Json(request)| request.keys)
+        .unwrap_or_default();
+    if keys.is_empty() {
+        keys.push(cdn::BOARD_KEY.to_owned());
+    }
+    cdn.purge(&keys).await.map_err(|e| {
+        reporting::report("Failed to purge the CDN", &e);
+        (StatusCode::BAD_GATEWAY, "The CDN didn't accept the purge")
+    })?;
+    info!(?keys, "purged the cdn");
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,65 @@
+//! Choice of global allocator, `mimalloc` or `jemalloc` by cargo feature or the system allocator
+//! otherwise, and what it can tell us about memory use
+
+use serde::Serialize;
+
+#[cfg(all(feature = "mimalloc", feature = "jemalloc"))]
+compile_error!("the mimalloc and jemalloc features can't be enabled together");
+
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[derive(Debug, Serialize)]
+pub struct AllocatorStats {
+    pub name: &'static str,
+    /// Bytes currently allocated by the program, if the allocator tracks it
+    pub allocated_bytes: Option<u64>,
+    /// Bytes of physical memory held by the process
+    pub resident_bytes: Option<u64>,
+}
+
+#[cfg(feature = "jemalloc")]
+pub fn stats() -> AllocatorStats {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    // jemalloc caches its statistics, advancing the epoch refreshes them
+    let fresh = epoch::advance().is_ok();
+    AllocatorStats {
+        name: "jemalloc",
+        allocated_bytes: stats::allocated::read()
+            .ok()
+            .filter(|_| fresh)
+            .map(|bytes| bytes as u64),
+        resident_bytes: stats::resident::read()
+            .ok()
+            .filter(|_| fresh)
+            .map(|bytes| bytes as u64),
+    }
+}
+
+#[cfg(not(feature = "jemalloc"))]
+pub fn stats() -> AllocatorStats {
+    AllocatorStats {
+        name: if cfg!(feature = "mimalloc") {
+            "mimalloc"
+        } else {
+            "system"
+        },
+        allocated_bytes: None,
+        resident_bytes: process_resident_bytes(),
+    }
+}
+
+/// Resident set size according to the kernel
+#[cfg(not(feature = "jemalloc"))]
+fn process_resident_bytes() -> Option<u64> {
+    // The second field of statm is resident pages
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * 4096)
+}
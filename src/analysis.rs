@@ -0,0 +1,139 @@
+//! Whole-board statistics for the frontend's progress widgets. Cheap ones come straight from the
+//! bitmap's running counters, the rest from a periodic background scan.
+
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::Json;
+use serde::Serialize;
+use tokio::time::MissedTickBehavior;
+use tracing::warn;
+
+use crate::shared_bitmap::SharedBitmap;
+use crate::{unix_now, SharedState, NUM_CHECKBOXES, NUM_SLIDERS};
+
+const SCAN_INTERVAL: Duration = Duration::from_secs(10);
+// Bytes copied out of the bitmap at a time while scanning
+const SCAN_PIECE_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Scan {
+    /// Shannon entropy of the slider values, in bits per slider (0 to 8)
+    pub entropy: f64,
+    /// Longest stretch of consecutive set checkboxes
+    pub longest_set_run: u64,
+    /// Longest stretch of consecutive clear checkboxes
+    pub longest_clear_run: u64,
+    /// Sliders at their maximum value
+    pub saturated_sliders: u64,
+    /// Unix time the scan finished
+    pub scanned_at: u64,
+}
+
+#[derive(Default)]
+struct Runs {
+    current_set: bool,
+    current: u64,
+    longest_set: u64,
+    longest_clear: u64,
+}
+
+impl Runs {
+    fn extend(&mut self, set: bool, len: u64) {
+        if set != self.current_set {
+            self.current_set = set;
+            self.current = 0;
+        }
+        self.current += len;
+        let longest = if set {
+            &mut self.longest_set
+        } else {
+            &mut self.longest_clear
+        };
+        *longest = (*longest).max(self.current);
+    }
+}
+
+fn scan(bitmap: &SharedBitmap) -> Scan {
+    let mut histogram = [0u64; 256];
+    let mut runs = Runs::default();
+    let mut piece = vec![0; SCAN_PIECE_BYTES];
+    for offset in (0..NUM_SLIDERS).step_by(SCAN_PIECE_BYTES) {
+        let piece = &mut piece[..SCAN_PIECE_BYTES.min(NUM_SLIDERS - offset)];
+        bitmap.load_bytes(offset, piece);
+        for &byte in piece.iter() {
+            histogram[usize::from(byte)] += 1;
+            match byte {
+                0 => runs.extend(false, 8),
+                u8::MAX => runs.extend(true, 8),
+                _ => {
+                    for bit in 0..8 {
+                        runs.extend(byte & (1 << bit) != 0, 1);
+                    }
+                }
+            }
+        }
+    }
+
+    let entropy = histogram
+        .iter()
+        .filter(|&&count| count != 0)
+        .map(|&count| {
+            let p = count as f64 / NUM_SLIDERS as f64;
+            p * p.recip().log2()
+        })
+        .sum();
+    Scan {
+        entropy,
+        longest_set_run: runs.longest_set,
+        longest_clear_run: runs.longest_clear,
+        saturated_sliders: histogram[usize::from(u8::MAX)],
+        scanned_at: unix_now(),
+    }
+}
+
+/// The result of the most recent background scan
+#[derive(Default)]
+pub struct Analysis {
+    latest: RwLock<Option<Scan>>,
+}
+
+impl Analysis {
+    /// Rescans the board every `SCAN_INTERVAL` forever
+    pub async fn run(self: Arc<Self>, bitmap: Arc<SharedBitmap>) {
+        let mut interval = tokio::time::interval(SCAN_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let bitmap = Arc::clone(&bitmap);
+            match tokio::task::spawn_blocking(move || scan(&bitmap)).await {
+                Ok(scan) => *self.latest.write().unwrap() = Some(scan),
+                Err(e) => warn!(error = %e, "board scan panicked"),
+            }
+        }
+    }
+
+    pub fn latest(&self) -> Option<Scan> {
+        self.latest.read().unwrap().clone()
+    }
+}
+
+#[derive(Serialize)]
+pub struct AnalysisReport {
+    bits_set: u64,
+    /// Share of checkboxes which are set, from 0 to 100
+    percent_complete: f64,
+    /// Absent until the first scan finishes
+    #[serde(flatten)]
+    scan: Option<Scan>,
+}
+
+pub async fn analysis(State(state): State<SharedState>) -> Json<AnalysisReport> {
+    let bits_set = state.bitmap.count();
+    Json(AnalysisReport {
+        bits_set,
+        percent_complete: bits_set as f64 * 100.0 / NUM_CHECKBOXES as f64,
+        scan: state.analysis.latest(),
+    })
+}
@@ -0,0 +1,90 @@
+//! A message from whoever runs the server to everyone using it, like notice of a coming reset or
+//! an ongoing incident. It's set through the admin API, readable at `/announcement`, and pushed
+//! down every update stream as an `announce` event whenever it changes.
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures::{stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio_stream::wrappers::WatchStream;
+
+use crate::{unix_now, SharedState};
+
+/// Longest announcement text accepted, in bytes
+pub const MAX_ANNOUNCEMENT_LEN: usize = 1024;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Announcement {
+    pub text: String,
+    pub severity: Severity,
+    /// Unix time it was made
+    pub set_at: u64,
+    /// Unix time it lapses, or never if `None`
+    pub expires_at: Option<u64>,
+}
+
+impl Announcement {
+    pub fn expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+pub struct Announcements {
+    current: watch::Sender<Option<Announcement>>,
+}
+
+impl Announcements {
+    pub fn new() -> Self {
+        Self {
+            current: watch::Sender::new(None),
+        }
+    }
+
+    /// The announcement, unless there isn't one or it has expired
+    pub fn current(&self) -> Option<Announcement> {
+        let now = unix_now();
+        self.current
+            .borrow()
+            .as_ref()
+            .filter(|announcement| !announcement.expired(now))
+            .cloned()
+    }
+
+    /// Replaces any earlier announcement
+    pub fn set(&self, announcement: Announcement) {
+        self.current.send_replace(Some(announcement));
+    }
+
+    /// Takes down the announcement, returning if there was one
+    pub fn clear(&self) -> bool {
+        self.current.send_replace(None).is_some()
+    }
+
+    /// The announcement if there is one, then every change to it, `None` meaning it was taken
+    /// down. An announcement expiring isn't a change, clients are expected to hide it themselves.
+    pub fn subscribe(&self) -> impl Stream<Item = Option<Announcement>> {
+        // Subscribed before reading, so a change in between is seen as a change
+        let changes = WatchStream::from_changes(self.current.subscribe());
+        stream::iter(self.current().map(Some)).chain(changes)
+    }
+}
+
+/// The current announcement, or no content if there isn't one
+pub async fn announcement(State(state): State<SharedState>) -> Response {
+    match state.announcements.current() {
+        Some(announcement) => Json(announcement).into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
+}
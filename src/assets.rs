@@ -0,0 +1,256 @@
+//! The frontend in `www/`, either embedded in the binary (with the `embed-frontend` feature) or
+//! served from the source tree.
+//!
+//! Embedded assets are also served at paths with a hash of their contents added to the file name,
+//! like `js/main.0123456789abcdef.js`, which never change and so are cached for good. HTML pages
+//! have their references to other assets rewritten to the hashed paths, so a page only ever loads
+//! the scripts it was deployed with, and `/asset-manifest.json` lists the hashed path of every
+//! asset along with a version which changes whenever any of them do.
+
+use std::collections::BTreeMap;
+
+use axum::routing::get;
+use axum::Router;
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+struct Manifest {
+    /// Changes whenever any asset does, `None` when serving from the source tree
+    version: Option<String>,
+    /// Every asset's path, to the hashed path it's also served at
+    files: BTreeMap<String, String>,
+}
+
+#[cfg(feature = "embed-frontend")]
+pub fn router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    Router::new()
+        .route("/asset-manifest.json", get(embedded::manifest))
+        .fallback(embedded::serve)
+}
+
+#[cfg(not(feature = "embed-frontend"))]
+pub fn router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    use axum::http::header;
+    use axum::Json;
+
+    // Relative to the crate rather than the working directory, so `cargo run` works from anywhere
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/www");
+    // The files can change under us, so there are no hashed paths to give out
+    let manifest = || async {
+        (
+            [(header::CACHE_CONTROL, "no-cache")],
+            Json(Manifest {
+                version: None,
+                files: BTreeMap::new(),
+            }),
+        )
+    };
+    Router::new()
+        .route("/asset-manifest.json", get(manifest))
+        .fallback_service(tower_http::services::ServeDir::new(dir))
+}
+
+#[cfg(feature = "embed-frontend")]
+mod embedded {
+    use std::borrow::Cow;
+    use std::collections::{BTreeMap, HashMap};
+    use std::fmt::Write;
+    use std::hash::{DefaultHasher, Hash, Hasher};
+    use std::sync::OnceLock;
+
+    use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri};
+    use axum::response::{IntoResponse, Response};
+    use axum::Json;
+    use rust_embed::RustEmbed;
+
+    use super::Manifest;
+
+    #[derive(RustEmbed)]
+    #[folder = "www/"]
+    struct Assets;
+
+    /// Hex digits of an asset's hash put in its hashed path
+    const HASH_LEN: usize = 16;
+
+    /// What's worked out from the embedded assets the first time they're asked for
+    struct Versioned {
+        manifest: Manifest,
+        /// Hashed path to the asset's path
+        by_hashed: HashMap<String, String>,
+        /// HTML pages with their references rewritten, by path
+        pages: HashMap<String, Page>,
+    }
+
+    struct Page {
+        data: Vec<u8>,
+        etag: HeaderValue,
+    }
+
+    fn versioned() -> &'static Versioned {
+        static VERSIONED: OnceLock<Versioned> = OnceLock::new();
+        VERSIONED.get_or_init(|| {
+            let mut files = BTreeMap::new();
+            let mut version = DefaultHasher::new();
+            for path in Assets::iter() {
+                let hash = Assets::get(&path).unwrap().metadata.sha256_hash();
+                hash.hash(&mut version);
+                let hex = hex(&hash[..HASH_LEN / 2]);
+                files.insert(path.clone().into_owned(), hashed_path(&path, &hex));
+            }
+            let version = format!("{:016x}", version.finish());
+
+            let by_hashed = files
+                .iter()
+                .map(|(path, hashed)| (hashed.clone(), path.clone()))
+                .collect();
+            let pages = files
+                .keys()
+                .filter(|path| path.ends_with(".html"))
+                .map(|path| {
+                    let file = Assets::get(path).unwrap();
+                    let data = rewrite_references(path, &file.data, &files);
+                    // Depends on the other assets as well as the page itself
+                    let etag = format!("\"{}-{version}\"", hex(&file.metadata.sha256_hash()));
+                    let etag = HeaderValue::try_from(etag).unwrap();
+                    (path.clone(), Page { data, etag })
+                })
+                .collect();
+            Versioned {
+                manifest: Manifest {
+                    version: Some(version),
+                    files,
+                },
+                by_hashed,
+                pages,
+            }
+        })
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        let mut hex = String::with_capacity(bytes.len() * 2);
+        for byte in bytes {
+            write!(hex, "{byte:02x}").unwrap();
+        }
+        hex
+    }
+
+    /// `js/main.js` with hash `abc` becomes `js/main.abc.js`
+    fn hashed_path(path: &str, hash: &str) -> String {
+        let name_start = path.rfind('/').map_or(0, |i| i + 1);
+        match path[name_start..].rfind('.').filter(|&i| i > 0) {
+            Some(i) => {
+                let (stem, extension) = path.split_at(name_start + i);
+                format!("{stem}.{hash}{extension}")
+            }
+            None => format!("{path}.{hash}"),
+        }
+    }
+
+    /// Points the `src` and `href` attributes of the page at `path` which refer to other assets at
+    /// their hashed paths instead
+    fn rewrite_references(path: &str, page: &[u8], files: &BTreeMap<String, String>) -> Vec<u8> {
+        let Ok(page) = std::str::from_utf8(page) else {
+            return page.to_vec();
+        };
+        let dir = &path[..path.rfind('/').map_or(0, |i| i + 1)];
+        let mut out = String::with_capacity(page.len());
+        let mut rest = page;
+        while let Some(start) = ["src=\"", "href=\""]
+            .iter()
+            .filter_map(|attr| rest.find(attr).map(|i| i + attr.len()))
+            .min()
+        {
+            let Some(len) = rest[start..].find('"') else {
+                break;
+            };
+            let (before, after) = rest.split_at(start);
+            out.push_str(before);
+            let reference = &after[..len];
+            let target = match reference.strip_prefix('/') {
+                Some(absolute) => absolute.to_owned(),
+                None => format!("{dir}{reference}"),
+            };
+            match files.get(&target) {
+                // Only the file name changes, so the reference stays relative if it was
+                Some(hashed) => {
+                    let name_start = reference.rfind('/').map_or(0, |i| i + 1);
+                    let hashed_name_start = hashed.rfind('/').map_or(0, |i| i + 1);
+                    out.push_str(&reference[..name_start]);
+                    out.push_str(&hashed[hashed_name_start..]);
+                }
+                None => out.push_str(reference),
+            }
+            rest = &after[len..];
+        }
+        out.push_str(rest);
+        out.into_bytes()
+    }
+
+    pub async fn manifest() -> impl IntoResponse {
+        (
+            [(header::CACHE_CONTROL, "no-cache")],
+            Json(&versioned().manifest),
+        )
+    }
+
+    pub async fn serve(method: Method, uri: Uri, headers: HeaderMap) -> Response {
+        if !matches!(method, Method::GET | Method::HEAD) {
+            return (
+                StatusCode::METHOD_NOT_ALLOWED,
+                [(header::ALLOW, "GET,HEAD")],
+            )
+                .into_response();
+        }
+        let mut path = uri.path().trim_start_matches('/').to_owned();
+        if path.is_empty() || path.ends_with('/') {
+            path.push_str("index.html");
+        }
+        let versioned = versioned();
+        let (path, immutable) = match versioned.by_hashed.get(&path) {
+            Some(path) => (path.as_str(), true),
+            None => (path.as_str(), false),
+        };
+        let Some(file) = Assets::get(path) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+
+        let (data, etag) = match versioned.pages.get(path) {
+            Some(page) => (Cow::Borrowed(page.data.as_slice()), page.etag.clone()),
+            None => {
+                let etag = format!("\"{}\"", hex(&file.metadata.sha256_hash()));
+                (file.data, HeaderValue::try_from(etag).unwrap())
+            }
+        };
+        // A hashed path always has the same contents, while anything else can only change with a
+        // new binary, but the browser has to ask to find out
+        let cache_control = if immutable {
+            HeaderValue::from_static("public, max-age=31536000, immutable")
+        } else {
+            HeaderValue::from_static("no-cache")
+        };
+        let cache_headers = [
+            (header::ETAG, etag.clone()),
+            (header::CACHE_CONTROL, cache_control),
+        ];
+        if matches_etag(&headers, &etag) {
+            return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+        }
+
+        let content_type = HeaderValue::from_str(file.metadata.mimetype())
+            .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+        (cache_headers, [(header::CONTENT_TYPE, content_type)], data).into_response()
+    }
+
+    fn matches_etag(headers: &HeaderMap, etag: &HeaderValue) -> bool {
+        let Some(if_none_match) = headers
+            .get(header::IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return false;
+        };
+        if_none_match.split(',').any(|candidate| {
+            let candidate = candidate.trim();
+            candidate == "*" || candidate.trim_start_matches("W/").as_bytes() == etag.as_bytes()
+        })
+    }
+}
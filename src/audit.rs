@@ -0,0 +1,169 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use axum::body::{self, Body};
+use axum::extract::{ConnectInfo, OriginalUri, Request, State};
+use axum::http::{header, HeaderMap, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::{unix_now, SharedState};
+
+// JSON bodies up to this size are recorded, anything else only by its length
+const MAX_RECORDED_BODY: usize = 64 * 1024;
+
+/// Most of the log read back for `/admin/audit`, older entries stay in the file but aren't served
+const MAX_TAIL_BYTES: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Unix time the operation completed
+    pub at: u64,
+    /// Address the operation came from
+    pub actor: IpAddr,
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    /// The request body, if it was JSON and small enough to record
+    pub body: Option<serde_json::Value>,
+    /// Length of the request body, if the client gave it
+    #[serde(default)]
+    pub body_len: Option<u64>,
+    pub status: u16,
+}
+
+/// Append-only record of every operation performed through the admin API, one JSON object per
+/// line
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends `entry` and syncs it to disk, blocking until it has
+    pub fn record(&self, entry: &AuditEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        // A single write per entry, so entries can't interleave
+        file.write_all(&line)?;
+        file.sync_data()
+    }
+
+    /// The last `limit` entries, oldest first, from at most the last [`MAX_TAIL_BYTES`] of the
+    /// log. Lines that don't parse are skipped
+    pub fn recent(&self, limit: usize) -> io::Result<Vec<AuditEntry>> {
+        // Hold the lock so we don't read a half-written entry
+        let guard = self.file.lock().unwrap();
+        let mut file = File::open(&self.path)?;
+        let start = file.metadata()?.len().saturating_sub(MAX_TAIL_BYTES);
+        file.seek(SeekFrom::Start(start))?;
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail)?;
+        drop(guard);
+
+        let mut lines = tail.split(|&b| b == b'\n').filter(|line| !line.is_empty());
+        if start > 0 {
+            // Most likely the end of an entry cut off by where we started reading
+            lines.next();
+        }
+        let mut entries: Vec<AuditEntry> = lines
+            .rev()
+            .filter_map(|line| match serde_json::from_slice(line) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    warn!(error = %e, "skipping unreadable audit log entry");
+                    None
+                }
+            })
+            .take(limit)
+            .collect();
+        entries.reverse();
+        Ok(entries)
+    }
+}
+
+/// Middleware recording every state-changing admin request, along with its outcome, in the
+/// audit log
+pub async fn record(
+    State(state): State<SharedState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    OriginalUri(uri): OriginalUri,
+    req: Request,
+    next: Next,
+) -> Response {
+    if matches!(*req.method(), Method::GET | Method::HEAD) {
+        return next.run(req).await;
+    }
+    let body_len = content_length(req.headers());
+    let mut entry = AuditEntry {
+        at: 0,
+        actor: addr.ip().to_canonical(),
+        method: req.method().to_string(),
+        path: uri.path().to_owned(),
+        query: uri.query().map(str::to_owned),
+        body: None,
+        body_len,
+        status: 0,
+    };
+
+    // Anything else, like an uploaded picture, is passed on as it streams in
+    let recordable =
+        is_json(req.headers()) && body_len.is_some_and(|len| len <= MAX_RECORDED_BODY as u64);
+    let req = if recordable {
+        let (parts, req_body) = req.into_parts();
+        let Ok(bytes) = body::to_bytes(req_body, MAX_RECORDED_BODY).await else {
+            return (StatusCode::BAD_REQUEST, "Failed to read request body").into_response();
+        };
+        entry.body = serde_json::from_slice(&bytes).ok();
+        Request::from_parts(parts, Body::from(bytes))
+    } else {
+        req
+    };
+
+    let response = next.run(req).await;
+
+    entry.at = unix_now();
+    entry.status = response.status().as_u16();
+    // Each entry is synced to disk, which can take a while
+    let audit = Arc::clone(&state.audit);
+    let written = tokio::task::spawn_blocking(move || {
+        if let Err(e) = audit.record(&entry) {
+            error!(error = %e, ?entry, "failed to write audit log");
+        }
+    });
+    if let Err(e) = written.await {
+        error!(error = %e, "audit log write panicked");
+    }
+    response
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/json"))
+}
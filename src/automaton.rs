@@ -0,0 +1,141 @@
+//! Optional background rules which keep changing the board on their own, so a quiet board still
+//! has something going on. Changes go through the same mutation methods as client writes, so
+//! subscribers see them like any other update.
+
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::{debug, info, warn};
+
+use crate::picture::{BOARD_HEIGHT, BOARD_WIDTH};
+use crate::rng::Rng;
+use crate::shared_bitmap::SharedBitmap;
+use crate::{NUM_CHECKBOXES, NUM_SLIDERS};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rule {
+    Off,
+    /// Conway's game of life over the checkboxes, laid out as rows of `BOARD_WIDTH * 8`
+    Life,
+    /// Toggles randomly chosen checkboxes
+    Noise,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "life" => Ok(Self::Life),
+            "noise" => Ok(Self::Noise),
+            _ => Err(format!("unknown automaton rule {s:?}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AutomatonConfig {
+    pub rule: Rule,
+    /// Time between ticks
+    pub interval: Duration,
+    /// Checkboxes toggled per tick by the noise rule
+    pub noise_toggles: u32,
+    /// How often every slider is lowered by one, independent of the rule. Zero disables decay.
+    pub decay_every: Duration,
+}
+
+/// Ticks the board with the configured rule forever, or returns immediately if it's off
+pub async fn run(bitmap: Arc<SharedBitmap>, config: AutomatonConfig) {
+    if config.rule == Rule::Off {
+        return;
+    }
+    info!(rule = ?config.rule, interval = ?config.interval, "starting automaton");
+    let mut interval = tokio::time::interval(config.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut rng = Rng::seeded(0);
+    loop {
+        interval.tick().await;
+        match config.rule {
+            Rule::Off => unreachable!(),
+            Rule::Life => {
+                let bitmap = Arc::clone(&bitmap);
+                match tokio::task::spawn_blocking(move || life_tick(&bitmap)).await {
+                    Ok(changed) => debug!(changed, "ticked life"),
+                    Err(e) => warn!(error = %e, "life tick panicked"),
+                }
+            }
+            Rule::Noise => {
+                for _ in 0..config.noise_toggles {
+                    bitmap.toggle(rng.below(NUM_CHECKBOXES as u64) as usize);
+                }
+            }
+        }
+    }
+}
+
+/// Lowers every slider by one each `decay_every` forever, or returns immediately if decay is off,
+/// so the board slowly fades unless people keep it up
+pub async fn run_decay(bitmap: Arc<SharedBitmap>, decay_every: Duration) {
+    if decay_every.is_zero() {
+        return;
+    }
+    info!(?decay_every, "starting decay");
+    let mut interval = tokio::time::interval_at(Instant::now() + decay_every, decay_every);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let bitmap = Arc::clone(&bitmap);
+        match tokio::task::spawn_blocking(move || bitmap.decay()).await {
+            Ok(changed) => debug!(changed, "decayed sliders"),
+            Err(e) => warn!(error = %e, "decay panicked"),
+        }
+    }
+}
+
+/// Advances the game of life by one generation, returning the number of bytes changed. Cells
+/// past the edges of the board are always dead.
+fn life_tick(bitmap: &SharedBitmap) -> usize {
+    let width = BOARD_WIDTH as usize * 8;
+    let height = BOARD_HEIGHT as usize;
+    // Unpacked to a byte per cell, with a border of dead cells so neighbors never go out of bounds
+    let padded_width = width + 2;
+    let mut cells = vec![0u8; padded_width * (height + 2)];
+    let mut current = vec![0u8; NUM_SLIDERS];
+    bitmap.load_bytes(0, &mut current);
+    for (y, row) in current.chunks_exact(BOARD_WIDTH as usize).enumerate() {
+        let out = &mut cells[(y + 1) * padded_width + 1..][..width];
+        for (x, cell) in out.iter_mut().enumerate() {
+            *cell = (row[x / 8] >> (x % 8)) & 1;
+        }
+    }
+
+    let mut changed = 0;
+    for y in 0..height {
+        let above = &cells[y * padded_width..][..padded_width];
+        let here = &cells[(y + 1) * padded_width..][..padded_width];
+        let below = &cells[(y + 2) * padded_width..][..padded_width];
+        for byte_x in 0..BOARD_WIDTH as usize {
+            let mut next = 0u8;
+            for bit in 0..8 {
+                let x = byte_x * 8 + bit + 1;
+                let neighbors: u8 = above[x - 1..=x + 1].iter().sum::<u8>()
+                    + here[x - 1]
+                    + here[x + 1]
+                    + below[x - 1..=x + 1].iter().sum::<u8>();
+                let alive = matches!((here[x], neighbors), (1, 2) | (_, 3));
+                next |= u8::from(alive) << bit;
+            }
+            let index = y * BOARD_WIDTH as usize + byte_x;
+            if next != current[index] {
+                bitmap.set_byte(index, next);
+                changed += 1;
+            }
+        }
+    }
+    changed
+}
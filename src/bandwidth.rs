@@ -0,0 +1,198 @@
+//! Accounting of the bytes sent down long-lived streams, per connection and per client address,
+//! with optional budgets for each. A stream which would go over either budget gets a last
+//! `quota_exceeded` event, in whatever form its format has for one, and is closed.
+//!
+//! An address's usage is the total across its streams since it last had none open, so closing a
+//! stream doesn't give back its share while the client keeps others open.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use axum::body::{Body, Bytes};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::header;
+use axum::middleware::Next;
+use axum::response::Response;
+use futures::{stream, StreamExt};
+
+use crate::{SharedState, NDJSON_CONTENT_TYPE};
+
+/// Clients listed in `/stats`, the heaviest first
+const TOP_CLIENTS: usize = 10;
+
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthConfig {
+    /// Bytes one stream may be sent before it's closed, unlimited if `None`
+    pub per_connection: Option<u64>,
+    /// Bytes all of an address's open streams may be sent together, unlimited if `None`
+    pub per_ip: Option<u64>,
+}
+
+#[derive(Default)]
+struct IpUsage {
+    /// Open streams, only changed with the map of addresses locked
+    connections: usize,
+    sent: u64,
+}
+
+pub struct Bandwidth {
+    config: BandwidthConfig,
+    /// Bytes sent down every stream since startup
+    total: AtomicU64,
+    per_ip: Mutex<HashMap<IpAddr, Arc<Mutex<IpUsage>>>>,
+}
+
+#[derive(serde::Serialize)]
+pub struct ClientUsage {
+    ip: IpAddr,
+    connections: usize,
+    bytes_sent: u64,
+}
+
+#[derive(serde::Serialize)]
+pub struct BandwidthStats {
+    /// Bytes sent down every stream since startup
+    bytes_sent: u64,
+    /// The addresses whose open streams have been sent the most
+    top_clients: Vec<ClientUsage>,
+}
+
+impl Bandwidth {
+    pub fn new(config: BandwidthConfig) -> Self {
+        Self {
+            config,
+            total: AtomicU64::new(0),
+            per_ip: Mutex::default(),
+        }
+    }
+
+    /// Starts accounting for a stream to `ip`, until the returned meter is dropped
+    pub fn open(self: &Arc<Self>, ip: IpAddr) -> Meter {
+        let ip = ip.to_canonical();
+        let usage = Arc::clone(self.per_ip.lock().unwrap().entry(ip).or_default());
+        usage.lock().unwrap().connections += 1;
+        Meter {
+            bandwidth: Arc::clone(self),
+            ip,
+            usage,
+            sent: 0,
+        }
+    }
+
+    pub fn stats(&self) -> BandwidthStats {
+        let mut top_clients: Vec<_> = self
+            .per_ip
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&ip, usage)| {
+                let usage = usage.lock().unwrap();
+                ClientUsage {
+                    ip,
+                    connections: usage.connections,
+                    bytes_sent: usage.sent,
+                }
+            })
+            .collect();
+        top_clients.sort_by_key(|client| std::cmp::Reverse(client.bytes_sent));
+        top_clients.truncate(TOP_CLIENTS);
+        BandwidthStats {
+            bytes_sent: self.total.load(Ordering::Relaxed),
+            top_clients,
+        }
+    }
+}
+
+/// The bytes sent down one stream
+pub struct Meter {
+    bandwidth: Arc<Bandwidth>,
+    ip: IpAddr,
+    usage: Arc<Mutex<IpUsage>>,
+    sent: u64,
+}
+
+impl Meter {
+    /// Counts `len` more bytes sent, unless that would go over a budget, in which case nothing is
+    /// counted and `false` is returned
+    pub fn send(&mut self, len: usize) -> bool {
+        let len = len as u64;
+        let config = &self.bandwidth.config;
+        if config
+            .per_connection
+            .is_some_and(|budget| self.sent + len > budget)
+        {
+            return false;
+        }
+        let mut usage = self.usage.lock().unwrap();
+        if config
+            .per_ip
+            .is_some_and(|budget| usage.sent + len > budget)
+        {
+            return false;
+        }
+        usage.sent += len;
+        self.sent += len;
+        self.bandwidth.total.fetch_add(len, Ordering::Relaxed);
+        true
+    }
+}
+
+impl Drop for Meter {
+    fn drop(&mut self) {
+        let mut per_ip = self.bandwidth.per_ip.lock().unwrap();
+        let mut usage = self.usage.lock().unwrap();
+        usage.connections -= 1;
+        if usage.connections == 0 {
+            per_ip.remove(&self.ip);
+        }
+    }
+}
+
+/// The last thing sent down a stream of the given content type when it's closed for going over
+/// budget, if the format has a way to say so
+fn quota_exceeded(content_type: &str) -> Option<Bytes> {
+    match content_type {
+        "text/event-stream" => Some(Bytes::from_static(b"event: quota_exceeded\ndata: \n\n")),
+        NDJSON_CONTENT_TYPE => Some(Bytes::from_static(b"{\"event\":\"quota_exceeded\"}\n")),
+        _ => None,
+    }
+}
+
+/// Middleware metering a streaming route's response body
+pub async fn meter(
+    State(state): State<SharedState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let response = next.run(req).await;
+    if !response.status().is_success() {
+        return response;
+    }
+    let meter = state.bandwidth.open(addr.ip());
+    let quota_exceeded = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| quota_exceeded(value.split(';').next().unwrap_or_default().trim()));
+    let (parts, body) = response.into_parts();
+    let data = body.into_data_stream();
+    let metered = stream::unfold(Some((data, meter, quota_exceeded)), |state| async move {
+        let (mut data, mut meter, quota_exceeded) = state?;
+        match data.next().await? {
+            Ok(bytes) if meter.send(bytes.len()) => {
+                Some((Ok(bytes), Some((data, meter, quota_exceeded))))
+            }
+            Ok(_) => {
+                tracing::info!(ip = %meter.ip, sent = meter.sent, "stream went over budget");
+                // Dropping the rest of the stream here closes it, and frees its subscription
+                drop(data);
+                quota_exceeded.map(|bytes| (Ok(bytes), None))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    Response::from_parts(parts, Body::from_stream(metered))
+}
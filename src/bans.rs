@@ -0,0 +1,186 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+
+use crate::{unix_now, SharedState};
+
+/// An address block like `203.0.113.0/24` or `2001:db8::/32`. A bare address is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        ip.is_ipv4() == self.addr.is_ipv4() && network(ip, self.prefix) == self.addr
+    }
+}
+
+/// Clears all but the first `prefix` bits of `addr`
+fn network(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V4((u32::from(addr) & mask).into())
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            IpAddr::V6((u128::from(addr) & mask).into())
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid address {addr:?}"))?
+            .to_canonical();
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|&prefix| prefix <= max_prefix)
+                .ok_or_else(|| format!("invalid prefix length {prefix:?}"))?,
+            None => max_prefix,
+        };
+        Ok(Self {
+            // Normalize away any host bits, so equivalent blocks compare equal
+            addr: network(addr, prefix),
+            prefix,
+        })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub cidr: Cidr,
+    /// Unix time the ban lapses at, or never if `None`
+    pub expires_at: Option<u64>,
+    pub reason: Option<String>,
+}
+
+impl Ban {
+    fn expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Address blocks which may not write to the board, persisted as JSON so they survive restarts
+pub struct BanList {
+    path: PathBuf,
+    bans: RwLock<Vec<Ban>>,
+}
+
+impl BanList {
+    pub fn load_or_create(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let bans = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            bans: RwLock::new(bans),
+        })
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let now = unix_now();
+        let bans = self.bans.read().unwrap();
+        bans.iter()
+            .any(|ban| !ban.expired(now) && ban.cidr.contains(ip))
+    }
+
+    /// All bans which haven't expired yet
+    pub fn list(&self) -> Vec<Ban> {
+        let now = unix_now();
+        let bans = self.bans.read().unwrap();
+        bans.iter()
+            .filter(|ban| !ban.expired(now))
+            .cloned()
+            .collect()
+    }
+
+    /// Adds a ban, replacing any existing ban for the same block
+    pub fn add(&self, ban: Ban) -> io::Result<()> {
+        let mut bans = self.bans.write().unwrap();
+        let now = unix_now();
+        bans.retain(|existing| existing.cidr != ban.cidr && !existing.expired(now));
+        bans.push(ban);
+        self.save(&bans)
+    }
+
+    /// Removes the ban for exactly `cidr`, returning if there was one
+    pub fn remove(&self, cidr: Cidr) -> io::Result<bool> {
+        let mut bans = self.bans.write().unwrap();
+        let len = bans.len();
+        bans.retain(|existing| existing.cidr != cidr);
+        if bans.len() == len {
+            return Ok(false);
+        }
+        self.save(&bans)?;
+        Ok(true)
+    }
+
+    fn save(&self, bans: &[Ban]) -> io::Result<()> {
+        // Write then rename, so a crash mid-write can't leave a truncated list behind
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(bans)?)?;
+        fs::rename(tmp_path, &self.path)
+    }
+}
+
+/// Middleware rejecting requests from banned addresses
+pub async fn reject_banned(
+    State(state): State<SharedState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if state.bans.is_banned(addr.ip()) {
+        return (StatusCode::FORBIDDEN, "This address is banned").into_response();
+    }
+    next.run(req).await
+}
@@ -0,0 +1,125 @@
+//! Single byte writes in a range as they happen, old value and new, for visualizations like
+//! ripples which need every write rather than the coalesced chunk states sent by `/updates`.
+//! Each stream is limited to [`MAX_EVENTS_PER_SEC`], writes past that are counted and the count is
+//! sent before the next event which is allowed through.
+
+use std::convert::Infallible;
+use std::time::{Duration, Instant};
+
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::{sse, Sse};
+use futures::{stream, Stream, StreamExt};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+
+use crate::proxy::Origin;
+use crate::shared_bitmap::ByteWrite;
+use crate::{chunk, Range, SharedState, MAX_SUBSCRIPTION_BITS, NUM_CHECKBOXES};
+
+/// Most byte events sent down one stream each second
+pub const MAX_EVENTS_PER_SEC: u32 = 100;
+
+#[derive(serde::Serialize)]
+struct Skipped {
+    /// Writes in the range which weren't sent since the last event
+    count: u64,
+}
+
+/// Counts events sent in the current second
+struct EventBudget {
+    window_start: Instant,
+    sent: u32,
+}
+
+impl EventBudget {
+    fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.sent = 0;
+        }
+        if self.sent >= MAX_EVENTS_PER_SEC {
+            return false;
+        }
+        self.sent += 1;
+        true
+    }
+}
+
+/// Server-sent `byte` events holding the `index`, `old` and `new` value of each write to a byte in
+/// the range, and `skipped` events counting writes which weren't sent
+#[tracing::instrument(skip(state, range), fields(start=range.start, end=range.end))]
+pub async fn byte_changes(
+    State(state): State<SharedState>,
+    origin: Origin,
+    Query(range): Query<Range>,
+) -> axum::response::Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>> {
+    if range.start > range.end {
+        return Err((StatusCode::BAD_REQUEST, "start must be less than end").into());
+    }
+    if range.end > NUM_CHECKBOXES as u64 {
+        return Err((StatusCode::BAD_REQUEST, "end too large").into());
+    }
+    if range.end - range.start > MAX_SUBSCRIPTION_BITS as u64 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Cannot listen to such a large range",
+        )
+            .into());
+    }
+    let chunks = chunk::overlapping(range.start, range.end).len();
+    let subscription = state
+        .subscriptions
+        .try_acquire(origin, chunks)
+        .map_err(|message| (StatusCode::TOO_MANY_REQUESTS, message))?;
+
+    let bytes = (range.start / 8) as usize..range.end.div_ceil(8) as usize;
+    let mut budget = EventBudget {
+        window_start: Instant::now(),
+        sent: 0,
+    };
+    let mut skipped = 0;
+    let writes = BroadcastStream::new(state.bitmap.subscribe_byte_writes());
+    let events = writes.map(move |write| -> [Option<sse::Event>; 2] {
+        // Held until the stream is dropped
+        let _subscription = &subscription;
+        let write = match write {
+            Ok(write) if bytes.contains(&write.index) => write,
+            Ok(_) => return [None, None],
+            // Missed writes can't be told apart by range, so all of them are counted
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                skipped += missed;
+                return [None, None];
+            }
+        };
+        if !budget.try_take() {
+            skipped += 1;
+            return [None, None];
+        }
+        let skipped_event = (skipped > 0).then(|| {
+            let count = std::mem::take(&mut skipped);
+            sse::Event::default()
+                .json_data(Skipped { count })
+                .expect("serializing an update can't fail")
+                .event("skipped")
+        });
+        [skipped_event, Some(byte_event(write))]
+    });
+    let events = events
+        .flat_map(|events| stream::iter(events.into_iter().flatten()))
+        .take_until(state.shutdown.wait())
+        .chain(stream::once(async {
+            sse::Event::default().data("").event("end")
+        }))
+        .map(Ok);
+
+    Ok(Sse::new(events).keep_alive(sse::KeepAlive::new()))
+}
+
+fn byte_event(write: ByteWrite) -> sse::Event {
+    sse::Event::default()
+        .json_data(write)
+        .expect("serializing an update can't fail")
+        .event("byte")
+}
@@ -0,0 +1,84 @@
+//! `/canvas.ws`, a WebSocket for clients which draw the board as an image rather than as widgets.
+//!
+//! Every message is binary: the index of a chunk's first slider as a little endian `u32`, followed
+//! by the chunk's 128 bytes, each a grayscale pixel ready to be copied straight into the image.
+//! Each chunk in the range is sent once as soon as the socket opens, then again whenever it
+//! changes. The last chunk runs past the end of the board, and its extra bytes are always zero.
+//! Messages from the client are ignored. A socket which goes over its bandwidth budget is closed
+//! with a policy violation and the reason `quota_exceeded`.
+
+use std::pin::pin;
+
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::response::Response;
+use futures::{SinkExt, Stream, StreamExt};
+use tracing::field::Empty;
+
+use crate::bandwidth::Meter;
+use crate::proxy::Origin;
+use crate::shared_bitmap::CHUNK_BYTES;
+use crate::{pause, subscribe_updates, Range, SharedState, Update};
+
+#[tracing::instrument(
+    skip(state, range, ws),
+    fields(start=range.start, end=range.end, chunks=Empty, subscribers=Empty)
+)]
+pub async fn canvas_ws(
+    State(state): State<SharedState>,
+    origin: Origin,
+    Query(range): Query<Range>,
+    ws: WebSocketUpgrade,
+) -> axum::response::Result<Response> {
+    // Subscribing before the upgrade lets a bad range or too many subscriptions fail the request
+    // with a proper status
+    let meter = state.bandwidth.open(origin.client);
+    let updates = subscribe_updates(state, origin, range, pause::never())?;
+    Ok(ws.on_upgrade(move |socket| send_updates(socket, updates, meter)))
+}
+
+async fn send_updates(socket: WebSocket, updates: impl Stream<Item = Update>, mut meter: Meter) {
+    let (mut tx, mut rx) = socket.split();
+    let mut updates = pin!(updates);
+    loop {
+        tokio::select! {
+            update = updates.next() => match update {
+                Some(Update::Chunk(i, chunk)) => {
+                    let mut msg = Vec::with_capacity(4 + CHUNK_BYTES);
+                    msg.extend_from_slice(&((i * CHUNK_BYTES) as u32).to_le_bytes());
+                    msg.extend_from_slice(&chunk.bytes);
+                    if !meter.send(msg.len()) {
+                        let _ = tx
+                            .send(Message::Close(Some(CloseFrame {
+                                code: close_code::POLICY,
+                                reason: "quota_exceeded".into(),
+                            })))
+                            .await;
+                        return;
+                    }
+                    if tx.send(Message::Binary(msg)).await.is_err() {
+                        return;
+                    }
+                }
+                Some(
+                    Update::Sum(_)
+                    | Update::Count(_)
+                    | Update::Milestone(_)
+                    | Update::Viewers(_)
+                    | Update::Announce(_)
+                    | Update::Shutdown(_),
+                ) => {}
+                Some(Update::End) | None => {
+                    let _ = tx.send(Message::Close(None)).await;
+                    return;
+                }
+            },
+            // Reading is still needed to answer pings and to notice the client going away
+            msg = rx.next() => {
+                if !matches!(msg, Some(Ok(_))) {
+                    return;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Support for putting the heavy read endpoints behind a CDN: surrogate keys on their responses
+//! naming the chunks each one covers, and purging the CDN when the board is rewritten wholesale.
+//!
+//! Purges are `POST`ed as `{"keys": [...]}` to a plain `http://` endpoint, like a cache inside
+//! the same network or a small relay in front of the CDN's own API.
+
+use std::ops::Range;
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use serde::Serialize;
+use tracing::info;
+
+use crate::http_client::{Connection, Target};
+use crate::shared_bitmap::NUM_CHUNKS;
+use crate::{reporting, SharedState};
+
+/// Key carried by every tagged response, so purging it purges everything
+pub const BOARD_KEY: &str = "board";
+
+#[derive(Debug, Clone)]
+pub struct CdnConfig {
+    /// Headers to send the keys in, like `Surrogate-Key` or `Cache-Tag`
+    pub key_headers: Vec<HeaderName>,
+    /// Chunks covered by each range key
+    pub key_chunks: usize,
+    pub purge_url: Option<Target>,
+    /// Sent as a bearer token with purges
+    pub purge_token: Option<String>,
+}
+
+pub struct Cdn {
+    config: CdnConfig,
+}
+
+#[derive(Serialize)]
+struct Purge<'a> {
+    keys: &'a [String],
+}
+
+impl Cdn {
+    pub fn new(config: CdnConfig) -> Self {
+        Self { config }
+    }
+
+    /// Keys for a response covering the chunks in `chunks`
+    fn chunk_keys(&self, chunks: Range<usize>) -> impl Iterator<Item = String> + '_ {
+        let groups =
+            chunks.start / self.config.key_chunks..chunks.end.div_ceil(self.config.key_chunks);
+        groups.map(|group| {
+            let first = group * self.config.key_chunks;
+            let last = (first + self.config.key_chunks).min(NUM_CHUNKS) - 1;
+            format!("chunks-{first}-{last}")
+        })
+    }
+
+    /// Adds `keys` to a response, along with the board key
+    fn insert_keys(&self, headers: &mut HeaderMap, keys: impl Iterator<Item = String>) {
+        let keys: Vec<String> = std::iter::once(BOARD_KEY.to_owned()).chain(keys).collect();
+        for name in &self.config.key_headers {
+            // Cloudflare's `Cache-Tag` separates keys with commas, everything else with spaces
+            let separator = if name.as_str() == "cache-tag" {
+                ","
+            } else {
+                " "
+            };
+            let value = HeaderValue::try_from(keys.join(separator))
+                .expect("keys are always valid header values");
+            headers.insert(name, value);
+        }
+    }
+
+    pub fn can_purge(&self) -> bool {
+        self.config.purge_url.is_some()
+    }
+
+    /// Asks the CDN to drop everything tagged with any of `keys`
+    pub async fn purge(&self, keys: &[String]) -> Result<(), String> {
+        let Some(target) = &self.config.purge_url else {
+            return Err("no purge url is configured".into());
+        };
+        let body = serde_json::to_vec(&Purge { keys }).map_err(|e| e.to_string())?;
+        let authorization = self
+            .config
+            .purge_token
+            .as_ref()
+            .map(|token| format!("Bearer {token}"));
+        let mut headers = vec![("Content-Type", "application/json")];
+        if let Some(authorization) = &authorization {
+            headers.push(("Authorization", authorization));
+        }
+        let mut conn = Connection::connect(target)
+            .await
+            .map_err(|e| e.to_string())?;
+        conn.send(target, "POST", "", &headers, &body)
+            .await
+            .map_err(|e| e.to_string())?;
+        let head = conn.read_head().await.map_err(|e| e.to_string())?;
+        if !(200..300).contains(&head.status) {
+            return Err(format!("purge endpoint responded with {}", head.status));
+        }
+        Ok(())
+    }
+}
+
+/// Keys a response covering the chunks in `chunks`, if there's a CDN to key it for
+pub fn tag_chunks(state: &SharedState, headers: &mut HeaderMap, chunks: Range<usize>) {
+    if let Some(cdn) = &state.cdn {
+        cdn.insert_keys(headers, cdn.chunk_keys(chunks));
+    }
+}
+
+/// Keys a response with `keys` and the board key, if there's a CDN to key it for
+pub fn tag(state: &SharedState, headers: &mut HeaderMap, keys: &[&str]) {
+    if let Some(cdn) = &state.cdn {
+        cdn.insert_keys(headers, keys.iter().map(|&key| key.to_owned()));
+    }
+}
+
+/// Purges everything from the CDN after the board was rewritten wholesale, if purging is
+/// configured. Failures are only reported, the rewrite already happened either way.
+pub async fn purge_board(state: &SharedState) {
+    let Some(cdn) = state.cdn.as_ref().filter(|cdn| cdn.can_purge()) else {
+        return;
+    };
+    match cdn.purge(&[BOARD_KEY.to_owned()]).await {
+        Ok(()) => info!("purged the cdn"),
+        Err(e) => reporting::report("Failed to purge the CDN", e),
+    }
+}
@@ -0,0 +1,193 @@
+//! A chunk of the board, the unit it's stored, watched and sent in, and the math for finding the
+//! chunks, bytes and bits an index falls in
+
+use std::ops::Range;
+
+#[cfg(sliders_loom)]
+use loom::sync::atomic::AtomicU8;
+#[cfg(not(sliders_loom))]
+use std::sync::atomic::AtomicU8;
+
+pub const CHUNK_BYTES: usize = 128;
+pub const CHUNK_BITS: usize = CHUNK_BYTES * 8;
+
+/// The chunks holding any of the bits in `start..end`
+pub fn overlapping(start: u64, end: u64) -> Range<usize> {
+    let start_chunk = (start / CHUNK_BITS as u64) as usize;
+    let end_chunk = end.div_ceil(CHUNK_BITS as u64) as usize;
+    start_chunk..end_chunk
+}
+
+#[repr(transparent)]
+pub struct Chunk([AtomicU8; CHUNK_BYTES]);
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Chunk {
+    #[cfg(not(sliders_loom))]
+    pub const fn new() -> Self {
+        Self([const { AtomicU8::new(0) }; CHUNK_BYTES])
+    }
+
+    // loom atomics can't be constructed in a const context
+    #[cfg(sliders_loom)]
+    pub fn new() -> Self {
+        Self(std::array::from_fn(|_| AtomicU8::new(0)))
+    }
+
+    // Returns if the byte was added, otherwise, it was removed
+    pub fn toggle(&self, index: u16) -> bool {
+        let (byte_index, mask) = Self::index_mask(index);
+        let byte = &self.0[byte_index];
+        let orig = byte.fetch_xor(mask, std::sync::atomic::Ordering::Relaxed);
+        (orig & mask) != 0
+    }
+
+    /// Toggles the bit if it's currently `expected`, returning whether it did
+    pub fn toggle_if(&self, index: u16, expected: bool) -> bool {
+        let (byte_index, mask) = Self::index_mask(index);
+        self.0[byte_index]
+            .fetch_update(
+                std::sync::atomic::Ordering::Relaxed,
+                std::sync::atomic::Ordering::Relaxed,
+                |byte| ((byte & mask != 0) == expected).then_some(byte ^ mask),
+            )
+            .is_ok()
+    }
+
+    pub fn set_byte(&self, index: usize, byte: u8) -> u8 {
+        self.0[index].swap(byte, std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Lowers the byte by one unless it's already zero, returning the previous value
+    pub fn decrement(&self, index: usize) -> u8 {
+        let result = self.0[index].fetch_update(
+            std::sync::atomic::Ordering::Relaxed,
+            std::sync::atomic::Ordering::Relaxed,
+            |byte| byte.checked_sub(1),
+        );
+        result.unwrap_or_else(|byte| byte)
+    }
+
+    // Byte loads to match the byte writes above, reading the same memory through wider atomics
+    // while they happen would be a mixed-size race
+    pub fn load(&self, dst: &mut [u8; CHUNK_BYTES]) {
+        for (out, byte) in dst.iter_mut().zip(&self.0) {
+            *out = byte.load(std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    /// The chunk's bytes, for reads and writes not covered above
+    pub fn bytes(&self) -> &[AtomicU8; CHUNK_BYTES] {
+        &self.0
+    }
+
+    #[inline]
+    pub const fn index_mask(index: u16) -> (usize, u8) {
+        debug_assert!(index < CHUNK_BITS as u16);
+        let index = index % CHUNK_BITS as u16;
+        let byte_index = index / 8;
+        let bit_index = index % 8;
+        (byte_index as usize, 1 << bit_index)
+    }
+}
+
+// loom's atomics only work inside a loom model, these run as plain tests
+#[cfg(all(test, not(sliders_loom)))]
+mod tests {
+    use super::*;
+
+    fn contents(chunk: &Chunk) -> [u8; CHUNK_BYTES] {
+        let mut bytes = [0; CHUNK_BYTES];
+        chunk.load(&mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn index_mask_covers_every_bit_once() {
+        let mut seen = [0u8; CHUNK_BYTES];
+        for index in 0..CHUNK_BITS as u16 {
+            let (byte_index, mask) = Chunk::index_mask(index);
+            assert_eq!(mask.count_ones(), 1);
+            assert_eq!(seen[byte_index] & mask, 0, "bit {index} overlaps another");
+            seen[byte_index] |= mask;
+        }
+        assert_eq!(seen, [0xFF; CHUNK_BYTES]);
+    }
+
+    #[test]
+    fn toggle_twice_is_identity() {
+        let chunk = Chunk::new();
+        for (i, byte) in (0..CHUNK_BYTES).zip((0..=u8::MAX).step_by(3)) {
+            chunk.set_byte(i, byte);
+        }
+        let before = contents(&chunk);
+        for index in 0..CHUNK_BITS as u16 {
+            let was_set = chunk.toggle(index);
+            assert_eq!(chunk.toggle(index), !was_set);
+            assert_eq!(contents(&chunk), before, "bit {index} didn't toggle back");
+        }
+    }
+
+    #[test]
+    fn toggle_reports_previous_bit() {
+        let chunk = Chunk::new();
+        for index in 0..CHUNK_BITS as u16 {
+            assert!(!chunk.toggle(index));
+        }
+        assert_eq!(contents(&chunk), [0xFF; CHUNK_BYTES]);
+        for index in 0..CHUNK_BITS as u16 {
+            assert!(chunk.toggle(index));
+        }
+        assert_eq!(contents(&chunk), [0; CHUNK_BYTES]);
+    }
+
+    #[test]
+    fn toggle_if_only_toggles_expected() {
+        let chunk = Chunk::new();
+        assert!(!chunk.toggle_if(9, true));
+        assert_eq!(contents(&chunk)[1], 0);
+        assert!(chunk.toggle_if(9, false));
+        assert_eq!(contents(&chunk)[1], 0b10);
+        assert!(!chunk.toggle_if(9, false));
+        assert!(chunk.toggle_if(9, true));
+        assert_eq!(contents(&chunk)[1], 0);
+    }
+
+    #[test]
+    fn decrement_stops_at_zero() {
+        let chunk = Chunk::new();
+        chunk.set_byte(5, 2);
+        assert_eq!(chunk.decrement(5), 2);
+        assert_eq!(chunk.decrement(5), 1);
+        assert_eq!(chunk.decrement(5), 0);
+        assert_eq!(contents(&chunk)[5], 0);
+    }
+
+    #[test]
+    fn overlapping_rounds_out_to_whole_chunks() {
+        let bits = CHUNK_BITS as u64;
+        assert_eq!(overlapping(0, 0), 0..0);
+        assert_eq!(overlapping(0, 1), 0..1);
+        assert_eq!(overlapping(0, bits), 0..1);
+        assert_eq!(overlapping(bits - 1, bits + 1), 0..2);
+        assert_eq!(overlapping(bits, 2 * bits), 1..2);
+        for start in (0..4 * bits).step_by(97) {
+            for end in (start..4 * bits).step_by(89) {
+                let chunks = overlapping(start, end);
+                for bit in start..end {
+                    assert!(chunks.contains(&((bit / bits) as usize)));
+                }
+                if start < end {
+                    assert!((chunks.start as u64 * bits) <= start);
+                    assert!((chunks.end as u64 * bits) >= end);
+                    assert!((chunks.end as u64 - 1) * bits < end);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,344 @@
+//! Content-addressed chunks, for mirrors which keep their own copy of the board. The manifest at
+//! `/chunks/manifest` lists the SHA-256 of every chunk's current contents (or, given
+//! `since_version`, only of chunks updated after that version), and `/chunks/:hash` serves the
+//! contents of a chunk with that hash. A body fetched by hash can never change, so it's cached for
+//! good, and a mirror only fetches the hashes it hasn't seen before.
+//!
+//! Only contents some chunk currently holds can be fetched. Chunks are hashed as they were last
+//! sent to their watchers, so the manifest agrees with the versions in update streams, and in the
+//! background, so the manifest can be a moment behind them.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use sha2::{Digest, Sha256};
+use tokio::time::MissedTickBehavior;
+use tracing::warn;
+
+use crate::shared_bitmap::{SharedBitmap, CHUNK_BITS, CHUNK_BYTES, NUM_CHUNKS};
+use crate::{snapshot, SharedState};
+
+// However often the board changes, chunks are only hashed again this often
+const HASH_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy)]
+struct Hashed {
+    version: u64,
+    hash: [u8; 32],
+}
+
+struct Contents {
+    bytes: [u8; CHUNK_BYTES],
+    /// How many chunks currently have them
+    chunks: usize,
+}
+
+struct Hashes {
+    /// [`SharedBitmap::version`] as of the last pass, every chunk update up to which is hashed
+    version: u64,
+    /// `None` until first hashed
+    chunks: Vec<Option<Hashed>>,
+    /// The contents of every hash some chunk currently has
+    by_hash: HashMap<[u8; 32], Contents>,
+}
+
+impl Hashes {
+    fn update(&mut self, version: u64, updates: Vec<(usize, Hashed, [u8; CHUNK_BYTES])>) {
+        for (i, hashed, bytes) in updates {
+            if let Some(old) = self.chunks[i].replace(hashed) {
+                if let Entry::Occupied(mut contents) = self.by_hash.entry(old.hash) {
+                    contents.get_mut().chunks -= 1;
+                    if contents.get().chunks == 0 {
+                        contents.remove();
+                    }
+                }
+            }
+            self.by_hash
+                .entry(hashed.hash)
+                .or_insert(Contents { bytes, chunks: 0 })
+                .chunks += 1;
+        }
+        self.version = version;
+    }
+}
+
+/// Every chunk's hash, worked out again in the background only for chunks whose version has
+/// changed, so requests only ever look them up
+pub struct ChunkHashes {
+    bitmap: Arc<SharedBitmap>,
+    hashes: Mutex<Hashes>,
+}
+
+impl ChunkHashes {
+    /// Hashes the whole board up front, so it's only for startup
+    pub fn new(bitmap: Arc<SharedBitmap>) -> Self {
+        let hashes = Self {
+            bitmap,
+            hashes: Mutex::new(Hashes {
+                version: 0,
+                chunks: vec![None; NUM_CHUNKS],
+                by_hash: HashMap::new(),
+            }),
+        };
+        hashes.rehash();
+        hashes
+    }
+
+    /// Hashes the chunks updated since, every `HASH_INTERVAL` the board has changed, forever
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(HASH_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if self.bitmap.version() == self.hashes.lock().unwrap().version {
+                continue;
+            }
+            let hashes = Arc::clone(&self);
+            if let Err(e) = tokio::task::spawn_blocking(move || hashes.rehash()).await {
+                warn!(error = %e, "hashing chunks panicked");
+            }
+        }
+    }
+
+    /// Hashes every chunk which changed since it was last hashed. Only one pass may run at a
+    /// time. The lock is only held to apply the results, so lookups don't wait on the hashing.
+    fn rehash(&self) {
+        // Read first, so every chunk update up to it is hashed by the end
+        let version = self.bitmap.version();
+        let known: Vec<Option<u64>> = self
+            .hashes
+            .lock()
+            .unwrap()
+            .chunks
+            .iter()
+            .map(|hashed| hashed.map(|hashed| hashed.version))
+            .collect();
+        let updates = known
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, known)| {
+                let chunk = self.bitmap.current(i);
+                (known != Some(chunk.version)).then(|| {
+                    let hash = Sha256::digest(chunk.bytes).into();
+                    (
+                        i,
+                        Hashed {
+                            version: chunk.version,
+                            hash,
+                        },
+                        chunk.bytes,
+                    )
+                })
+            })
+            .collect();
+        self.hashes.lock().unwrap().update(version, updates);
+    }
+
+    /// As of [`Hashes::version`], each chunk's version and hash
+    fn list(&self) -> (u64, Vec<Option<Hashed>>) {
+        let hashes = self.hashes.lock().unwrap();
+        (hashes.version, hashes.chunks.clone())
+    }
+
+    /// The contents of a chunk with `hash`, if any chunk currently has it
+    fn find(&self, hash: &[u8; 32]) -> Option<[u8; CHUNK_BYTES]> {
+        let hashes = self.hashes.lock().unwrap();
+        hashes.by_hash.get(hash).map(|contents| contents.bytes)
+    }
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct ManifestParams {
+    /// Only list chunks updated after this version
+    since_version: Option<u64>,
+}
+
+#[derive(serde::Serialize)]
+struct ManifestEntry {
+    /// Index of the chunk's first checkbox
+    offset: u64,
+    version: u64,
+    /// SHA-256 of the chunk's contents, hex encoded
+    hash: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct Manifest {
+    /// [`SharedBitmap::version`] as of the manifest, to pass as `since_version` next time
+    version: u64,
+    chunks: Vec<ManifestEntry>,
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn manifest(
+    State(state): State<SharedState>,
+    Query(params): Query<ManifestParams>,
+) -> Response {
+    // Every chunk updated after this version is listed now or next time
+    let (version, hashed) = state.chunk_hashes.list();
+    let since_version = params.since_version.unwrap_or(0);
+    let chunks = hashed
+        .iter()
+        .enumerate()
+        .filter_map(|(i, hashed)| {
+            let hashed = hashed.as_ref()?;
+            (params.since_version.is_none() || hashed.version > since_version).then(|| {
+                ManifestEntry {
+                    offset: (i * CHUNK_BITS) as u64,
+                    version: hashed.version,
+                    hash: hex(&hashed.hash),
+                }
+            })
+        })
+        .collect();
+    (
+        [(header::CACHE_CONTROL, "no-cache")],
+        Json(Manifest { version, chunks }),
+    )
+        .into_response()
+}
+
+/// The contents of a chunk with the given hash, if any chunk currently has it
+#[tracing::instrument(skip(state, headers))]
+pub async fn chunk(
+    State(state): State<SharedState>,
+    Path(hash): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(wanted) = parse_hex(&hash) else {
+        return (StatusCode::BAD_REQUEST, "Expected a SHA-256 hash in hex").into_response();
+    };
+    let etag = HeaderValue::try_from(format!("\"{}\"", hex(&wanted))).unwrap();
+    let cache_headers = [
+        (header::ETAG, etag.clone()),
+        (
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=31536000, immutable"),
+        ),
+    ];
+    // A copy the client holds for this hash is the right contents, whether or not any chunk still
+    // has them
+    if headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|value| snapshot::etag_matches(value, &etag))
+    {
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+    let Some(bytes) = state.chunk_hashes.find(&wanted) else {
+        return (StatusCode::NOT_FOUND, "No chunk currently has that hash").into_response();
+    };
+    (
+        cache_headers,
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        )],
+        bytes.to_vec(),
+    )
+        .into_response()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    use std::fmt::Write;
+
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(hex, "{byte:02x}").unwrap();
+    }
+    hex
+}
+
+fn parse_hex(s: &str) -> Option<[u8; 32]> {
+    if s.len() != 64 || !s.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; 32];
+    for (byte, pair) in bytes.iter_mut().zip(s.as_bytes().chunks_exact(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(all(test, not(sliders_loom)))]
+mod tests {
+    use super::*;
+
+    fn hashes(name: &str) -> (ChunkHashes, std::path::PathBuf) {
+        let path =
+            std::env::temp_dir().join(format!("sliders-hashes-{name}-{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let bitmap = Arc::new(SharedBitmap::load_or_create(&path).unwrap());
+        (ChunkHashes::new(bitmap), path)
+    }
+
+    fn hash_of(bytes: &[u8; CHUNK_BYTES]) -> [u8; 32] {
+        Sha256::digest(bytes).into()
+    }
+
+    #[test]
+    fn changed_chunks_found_by_new_hash() {
+        let (hashes, path) = hashes("changed");
+        let empty = [0; CHUNK_BYTES];
+        assert_eq!(hashes.find(&hash_of(&empty)), Some(empty));
+
+        hashes.bitmap.set_byte(3 * CHUNK_BYTES + 1, 7);
+        hashes.bitmap.refresh(3);
+        let mut changed = empty;
+        changed[1] = 7;
+        // Not until the next pass
+        assert_eq!(hashes.find(&hash_of(&changed)), None);
+        hashes.rehash();
+        assert_eq!(hashes.find(&hash_of(&changed)), Some(changed));
+        let (version, listed) = hashes.list();
+        assert_eq!(version, hashes.bitmap.version());
+        let chunk = listed[3].unwrap();
+        assert_eq!(chunk.hash, hash_of(&changed));
+        assert_eq!(chunk.version, hashes.bitmap.current(3).version);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn hash_forgotten_once_no_chunk_has_it() {
+        let (hashes, path) = hashes("forgotten");
+        let mut first = [0; CHUNK_BYTES];
+        first[0] = 1;
+        let mut second = [0; CHUNK_BYTES];
+        second[0] = 2;
+
+        for chunk in [0, 1] {
+            hashes.bitmap.set_byte(chunk * CHUNK_BYTES, 1);
+            hashes.bitmap.refresh(chunk);
+        }
+        hashes.rehash();
+        assert_eq!(hashes.find(&hash_of(&first)), Some(first));
+
+        // Still held by chunk 1
+        hashes.bitmap.set_byte(0, 2);
+        hashes.bitmap.refresh(0);
+        hashes.rehash();
+        assert_eq!(hashes.find(&hash_of(&first)), Some(first));
+        assert_eq!(hashes.find(&hash_of(&second)), Some(second));
+
+        hashes.bitmap.set_byte(CHUNK_BYTES, 2);
+        hashes.bitmap.refresh(1);
+        hashes.rehash();
+        assert_eq!(hashes.find(&hash_of(&first)), None);
+        assert_eq!(
+            hashes.hashes.lock().unwrap().by_hash[&hash_of(&second)].chunks,
+            2
+        );
+        // The rest of the board is still empty
+        let empty = hash_of(&[0; CHUNK_BYTES]);
+        assert_eq!(
+            hashes.hashes.lock().unwrap().by_hash[&empty].chunks,
+            NUM_CHUNKS - 2
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+//! `/config.json`, describing the board and how to talk to this server, so clients don't have to
+//! hard-code any of it
+
+use axum::extract::State;
+use axum::Json;
+use serde::Serialize;
+
+use crate::automaton::Rule;
+use crate::byte_changes;
+use crate::config::Config;
+use crate::global_events;
+use crate::overview::{OVERVIEW_HEIGHT, OVERVIEW_WIDTH};
+use crate::picture::{BOARD_HEIGHT, BOARD_WIDTH};
+use crate::rate_limit::{Schedule, Tier};
+use crate::shared_bitmap::{CHUNK_BITS, CHUNK_BYTES};
+use crate::{SharedState, MAX_SUBSCRIPTION_BITS, NUM_CHECKBOXES, NUM_SLIDERS};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientConfig {
+    board: Board,
+    /// Updates are sent a whole chunk at a time, starting at a multiple of the chunk size
+    chunk_bits: usize,
+    chunk_bytes: usize,
+    /// Widest range, after rounding out to whole chunks, a single subscription may cover
+    max_subscription_bits: usize,
+    /// Most events a single `byte_changes` stream sends each second
+    max_byte_events_per_sec: u32,
+    /// `milestone` events are sent each time the count climbs past another multiple of this
+    milestone_every: u64,
+    /// Path the server is mounted under, like `/sliders`, empty at the root. Endpoints are given
+    /// relative to it.
+    base_path: String,
+    endpoints: Endpoints,
+    features: Features,
+    rate_limits: RateLimits,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Board {
+    sliders: usize,
+    checkboxes: usize,
+    /// Sliders per row when the board is drawn as a picture
+    width: u32,
+    height: u32,
+    /// Cells per row and rows of the downsampled overview
+    overview_width: u32,
+    overview_height: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Endpoints {
+    /// Server-sent events, taking `start` and `end` in bits, and optionally `only` (`bits` or
+    /// `bytes`) and `min_change` to skip small changes
+    updates: &'static str,
+    /// The same updates as newline delimited JSON
+    updates_ndjson: &'static str,
+    /// POST with the id from the first `stream` event of `updates` or `updates_ndjson` to stop
+    /// board updates while keeping the stream open, and to start them again
+    pause: &'static str,
+    resume: &'static str,
+    /// The same updates in the binary frame format, without checkbox counts
+    updates_bin: &'static str,
+    /// The whole board and then updates, in the binary frame format
+    bootstrap: &'static str,
+    /// Server-sent events for each single byte write in the range, with its old and new value,
+    /// limited to `max_byte_events_per_sec`
+    byte_changes: &'static str,
+    /// WebSocket sending chunks as grayscale pixels, taking `start` and `end` in bits
+    canvas: &'static str,
+    /// WebSocket carrying several labeled subscriptions, each with its own range and throttle,
+    /// subscribed to and unsubscribed from with JSON messages
+    mux: &'static str,
+    /// Server-sent events holding a downsampled view of the whole board
+    overview_updates: &'static str,
+    /// The latest overview, as raw bytes or as JSON
+    overview_bin: &'static str,
+    overview_json: &'static str,
+    /// `POST` with the bit index in place of `{index}`
+    toggle: &'static str,
+    /// `POST` with the bit index in place of `{index}` and `?expected=0|1`, toggles only from that
+    /// state
+    toggle_if: &'static str,
+    /// `POST` with the slider index and new value in place of `{index}` and `{value}`
+    set_byte: &'static str,
+    stamp: &'static str,
+    /// `POST` changes recorded while offline
+    merge: &'static str,
+    /// `POST` a JSON list of `set`, `toggle`, and `fill` operations
+    r#macro: &'static str,
+    /// JSON snapshot of the chunks overlapping `start` to `end`, with each chunk's version
+    range_snapshot: &'static str,
+    snapshot: &'static str,
+    board: &'static str,
+    /// Chunks changed since a version, taking `start`, `end`, and `since_seq`
+    delta: &'static str,
+    /// SHA-256 of every chunk, or with `since_version` of those updated since, and the contents
+    /// of a chunk by its hash, which never change
+    chunk_manifest: &'static str,
+    chunk: &'static str,
+    /// PNG of `{width}` × `{height}` sliders, from `start` on
+    image: &'static str,
+    /// The current sum of all sliders and count of checked checkboxes, as JSON
+    sum: &'static str,
+    count: &'static str,
+    /// The frontend's assets with their content-hashed paths, and a version to notice deploys by
+    asset_manifest: &'static str,
+    /// The current announcement, if any, which update streams also send as `announce` events
+    announcement: &'static str,
+}
+
+/// Limits on writes, a `per_sec` of 0 meaning unlimited
+#[derive(Debug, Clone, Serialize)]
+struct RateLimits {
+    anonymous: Tier,
+    /// For clients sending an API key
+    keyed: Tier,
+    /// Windows of the day, in UTC, during which every limit is multiplied by `factor`, the first
+    /// listed winning where they overlap. Writes are refused altogether while a window with a
+    /// `factor` of 0 is open.
+    schedule: Schedule,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Features {
+    /// Rule changing the board by itself, if any
+    automaton: Option<Rule>,
+    /// Whether sliders slowly decay towards zero
+    decay: bool,
+    /// Whether the board is bridged to an MQTT broker
+    mqtt: bool,
+}
+
+impl ClientConfig {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            board: Board {
+                sliders: NUM_SLIDERS,
+                checkboxes: NUM_CHECKBOXES,
+                width: BOARD_WIDTH,
+                height: BOARD_HEIGHT,
+                overview_width: OVERVIEW_WIDTH,
+                overview_height: OVERVIEW_HEIGHT,
+            },
+            chunk_bits: CHUNK_BITS,
+            chunk_bytes: CHUNK_BYTES,
+            max_subscription_bits: MAX_SUBSCRIPTION_BITS,
+            max_byte_events_per_sec: byte_changes::MAX_EVENTS_PER_SEC,
+            milestone_every: global_events::MILESTONE_EVERY,
+            base_path: config.proxy.base_path.clone(),
+            endpoints: Endpoints {
+                updates: "/updates",
+                updates_ndjson: "/updates.ndjson",
+                pause: "/updates/{id}/pause",
+                resume: "/updates/{id}/resume",
+                updates_bin: "/updates.bin",
+                bootstrap: "/bootstrap",
+                byte_changes: "/updates/bytes",
+                canvas: "/canvas.ws",
+                mux: "/mux.ws",
+                overview_updates: "/overview/updates",
+                overview_bin: "/overview.bin",
+                overview_json: "/overview.json",
+                toggle: "/toggle/{index}",
+                toggle_if: "/toggle_if/{index}",
+                set_byte: "/set_byte/{index}/{value}",
+                stamp: "/stamp",
+                merge: "/merge",
+                r#macro: "/macro",
+                range_snapshot: "/snapshot",
+                snapshot: "/snapshot/full",
+                board: "/board.bin",
+                delta: "/delta",
+                chunk_manifest: "/chunks/manifest",
+                chunk: "/chunks/{hash}",
+                image: "/image/{width}/{height}.png",
+                sum: "/sum",
+                count: "/count",
+                asset_manifest: "/asset-manifest.json",
+                announcement: "/announcement",
+            },
+            features: Features {
+                automaton: Some(config.automaton.rule).filter(|&rule| rule != Rule::Off),
+                decay: !config.automaton.decay_every.is_zero(),
+                mqtt: cfg!(feature = "mqtt") && config.mqtt.is_some(),
+            },
+            rate_limits: RateLimits {
+                anonymous: config.rate_limit.anonymous,
+                keyed: config.rate_limit.keyed,
+                schedule: config.rate_limit.schedule.clone(),
+            },
+        }
+    }
+}
+
+pub async fn client_config(State(state): State<SharedState>) -> Json<ClientConfig> {
+    Json(ClientConfig::clone(&state.client_config))
+}
@@ -0,0 +1,484 @@
+//! Clustering: several instances share the board, each owning a slice of the chunks picked by
+//! hashing the chunk index. Writes are applied by the owner of the chunk they land in, forwarded
+//! there by whichever instance the client talked to. Every instance keeps a full copy of the
+//! board, kept up to date by streaming each peer's owned chunks from it, so subscriptions and
+//! snapshots are served locally just like on a single instance.
+//!
+//! Instances talk to each other through `/cluster`, authenticated with a shared secret.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::http::{HeaderName, HeaderValue, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use base64::prelude::BASE64_STANDARD_NO_PAD;
+use base64::Engine;
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::admin::constant_time_eq;
+use crate::disk;
+use crate::http_client::{Connection, Target};
+use crate::shared_bitmap::{SharedBitmap, CHUNK_BITS, CHUNK_BYTES, NUM_CHUNKS};
+use crate::{SharedState, Shutdown, NUM_CHECKBOXES, NUM_SLIDERS};
+
+static X_CLUSTER_SECRET: HeaderName = HeaderName::from_static("x-cluster-secret");
+
+// A chunk's index, little endian, followed by its bytes
+const FRAME_BYTES: usize = 4 + CHUNK_BYTES;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    /// Base URL of every instance, like `http://10.0.0.2:8000`, in the same order on each
+    pub peers: Vec<Target>,
+    /// This instance's position in `peers`
+    pub index: usize,
+    /// Secret the instances authenticate to each other with
+    pub secret: String,
+}
+
+pub struct Cluster {
+    config: ClusterConfig,
+    /// Idle keep-alive connections to each peer, for forwarding writes
+    idle: Vec<Mutex<Vec<Connection>>>,
+}
+
+impl Cluster {
+    pub fn new(config: ClusterConfig) -> Self {
+        let idle = config.peers.iter().map(|_| Mutex::default()).collect();
+        Self { config, idle }
+    }
+
+    /// The instance owning chunk `chunk`
+    pub fn owner(&self, chunk: usize) -> usize {
+        // Fibonacci hashing spreads neighbouring chunks, and so busy parts of the board, across
+        // the instances
+        let hash = (chunk as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        ((u128::from(hash) * self.config.peers.len() as u128) >> 64) as usize
+    }
+
+    pub fn is_owned(&self, chunk: usize) -> bool {
+        self.owner(chunk) == self.config.index
+    }
+
+    async fn forward(&self, peer: usize, changes: &Changes) -> Result<(), String> {
+        self.post(peer, "/cluster/apply", changes).await?;
+        Ok(())
+    }
+
+    /// Toggles a bit on the instance owning it if it's currently `expected`, returning whether
+    /// it was toggled
+    async fn forward_toggle_if(
+        &self,
+        peer: usize,
+        bit_index: usize,
+        expected: bool,
+    ) -> Result<bool, String> {
+        let request = ToggleIf {
+            index: bit_index as u64,
+            expected,
+        };
+        let response = self.post(peer, "/cluster/toggle_if", &request).await?;
+        let response: ToggleIfResponse =
+            serde_json::from_slice(&response).map_err(|e| e.to_string())?;
+        Ok(response.toggled)
+    }
+
+    /// Posts `request` as JSON to `path` on `peer`, returning the response body
+    async fn post(
+        &self,
+        peer: usize,
+        path: &str,
+        request: &impl Serialize,
+    ) -> Result<Vec<u8>, String> {
+        let body = serde_json::to_vec(request).map_err(|e| e.to_string())?;
+        let conn = match self.pooled(peer) {
+            Some(conn) => conn,
+            None => self.connect(peer).await?,
+        };
+        // Never retried once sent: the peer may have applied it with only the response lost, and
+        // a forwarded toggle applied twice undoes itself
+        let (status, response) = self.post_on(peer, conn, path, &body).await?;
+        if !(200..300).contains(&status) {
+            return Err(format!("peer responded with {status}"));
+        }
+        Ok(response)
+    }
+
+    /// An idle connection to `peer` that it hasn't closed in the meantime, if there is one
+    fn pooled(&self, peer: usize) -> Option<Connection> {
+        let mut idle = self.idle[peer].lock().unwrap();
+        std::iter::from_fn(|| idle.pop()).find(|conn| !conn.is_stale())
+    }
+
+    async fn connect(&self, peer: usize) -> Result<Connection, String> {
+        Connection::connect(&self.config.peers[peer])
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn post_on(
+        &self,
+        peer: usize,
+        mut conn: Connection,
+        path: &str,
+        body: &[u8],
+    ) -> Result<(u16, Vec<u8>), String> {
+        let target = &self.config.peers[peer];
+        let headers = [
+            (X_CLUSTER_SECRET.as_str(), self.config.secret.as_str()),
+            ("Content-Type", "application/json"),
+        ];
+        conn.send(target, "POST", path, &headers, body)
+            .await
+            .map_err(|e| e.to_string())?;
+        let head = conn.read_head().await.map_err(|e| e.to_string())?;
+        let response = conn.read_body(&head).await.map_err(|e| e.to_string())?;
+        self.idle[peer].lock().unwrap().push(conn);
+        Ok((head.status, response))
+    }
+}
+
+/// Changes to chunks owned by one instance
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Changes {
+    toggles: Vec<u64>,
+    stores: Vec<Store>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Store {
+    offset: u64,
+    /// The bytes, base64 encoded
+    bytes: String,
+}
+
+impl Changes {
+    fn is_empty(&self) -> bool {
+        self.toggles.is_empty() && self.stores.is_empty()
+    }
+}
+
+/// Writes made on behalf of a request. Writes to chunks this instance owns (or all of them, if
+/// it isn't clustered) are applied right away, the rest are forwarded to their owners by
+/// [`Writes::finish`].
+pub struct Writes<'a> {
+    state: &'a SharedState,
+    /// Changes for chunks owned by other instances, by instance
+    remote: Vec<Changes>,
+}
+
+impl<'a> Writes<'a> {
+    pub fn new(state: &'a SharedState) -> Self {
+        let peers = state
+            .cluster
+            .as_ref()
+            .map_or(0, |cluster| cluster.config.peers.len());
+        Self {
+            state,
+            remote: vec![Changes::default(); peers],
+        }
+    }
+
+    /// The instance to forward changes to chunk `chunk` to, if it's not this one
+    fn remote_owner(&self, chunk: usize) -> Option<usize> {
+        let cluster = self.state.cluster.as_ref()?;
+        (!cluster.is_owned(chunk)).then(|| cluster.owner(chunk))
+    }
+
+    pub fn toggle(&mut self, bit_index: usize) {
+        match self.remote_owner(bit_index / CHUNK_BITS) {
+            Some(peer) => self.remote[peer].toggles.push(bit_index as u64),
+            None => self.state.bitmap.toggle(bit_index),
+        }
+    }
+
+    /// Toggles bit `bit_index` if it's currently `expected`, returning whether it did. Unlike
+    /// other writes this is never deferred to [`Writes::finish`], as only the chunk's owner can
+    /// tell.
+    pub async fn toggle_if(
+        &mut self,
+        bit_index: usize,
+        expected: bool,
+    ) -> Result<bool, (StatusCode, &'static str)> {
+        let (Some(cluster), Some(peer)) = (
+            self.state.cluster.as_deref(),
+            self.remote_owner(bit_index / CHUNK_BITS),
+        ) else {
+            return Ok(self.state.bitmap.toggle_if(bit_index, expected));
+        };
+        cluster
+            .forward_toggle_if(peer, bit_index, expected)
+            .await
+            .map_err(|e| {
+                warn!(
+                    peer = %cluster.config.peers[peer],
+                    error = e,
+                    "failed to forward conditional toggle"
+                );
+                (
+                    StatusCode::BAD_GATEWAY,
+                    "Failed to reach the instance owning part of the board",
+                )
+            })
+    }
+
+    pub fn set_byte(&mut self, index: usize, value: u8) {
+        match self.remote_owner(index / CHUNK_BYTES) {
+            Some(peer) => self.remote[peer].stores.push(Store {
+                offset: index as u64,
+                bytes: BASE64_STANDARD_NO_PAD.encode([value]),
+            }),
+            None => self.state.bitmap.set_byte(index, value),
+        }
+    }
+
+    /// Overwrites the bytes starting at byte `offset` with `src`
+    pub fn store_bytes(&mut self, offset: usize, src: &[u8]) {
+        if self.state.cluster.is_none() {
+            self.state.bitmap.store_bytes(offset, src);
+            return;
+        }
+        let end = offset + src.len();
+        let mut index = offset;
+        while index < end {
+            let chunk = index / CHUNK_BYTES;
+            let chunk_end = ((chunk + 1) * CHUNK_BYTES).min(end);
+            let part = &src[index - offset..chunk_end - offset];
+            match self.remote_owner(chunk) {
+                Some(peer) => self.remote[peer].stores.push(Store {
+                    offset: index as u64,
+                    bytes: BASE64_STANDARD_NO_PAD.encode(part),
+                }),
+                None => self.state.bitmap.store_bytes(index, part),
+            }
+            index = chunk_end;
+        }
+    }
+
+    /// Toggles `bits` and overwrites the runs of bytes in `stores`, both indexes within chunk
+    /// `chunk`, see [`SharedBitmap::write_chunk`]. Forwarded, the toggles and stores are applied
+    /// separately, so they shouldn't overlap.
+    pub fn write_chunk(&mut self, chunk: usize, bits: &[u16], stores: &[(usize, &[u8])]) {
+        let Some(peer) = self.remote_owner(chunk) else {
+            self.state.bitmap.write_chunk(chunk, bits, stores);
+            return;
+        };
+        let remote = &mut self.remote[peer];
+        remote.toggles.extend(
+            bits.iter()
+                .map(|&bit| (chunk * CHUNK_BITS + usize::from(bit)) as u64),
+        );
+        remote
+            .stores
+            .extend(stores.iter().map(|&(offset, bytes)| Store {
+                offset: (chunk * CHUNK_BYTES + offset) as u64,
+                bytes: BASE64_STANDARD_NO_PAD.encode(bytes),
+            }));
+    }
+
+    /// Forwards the changes to chunks owned by other instances
+    pub async fn finish(self) -> Result<(), (StatusCode, &'static str)> {
+        let Some(cluster) = self.state.cluster.as_deref() else {
+            return Ok(());
+        };
+        let forwards = self
+            .remote
+            .iter()
+            .enumerate()
+            .filter(|(_, changes)| !changes.is_empty())
+            .map(|(peer, changes)| async move {
+                cluster.forward(peer, changes).await.map_err(|e| {
+                    warn!(
+                        peer = %cluster.config.peers[peer],
+                        error = e,
+                        "failed to forward writes"
+                    );
+                })
+            });
+        let results = futures::future::join_all(forwards).await;
+        if results.iter().any(Result::is_err) {
+            return Err((
+                StatusCode::BAD_GATEWAY,
+                "Failed to reach the instance owning part of the board",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Routes other instances use to talk to this one, mounted under `/cluster`
+pub fn router(state: SharedState) -> Router<SharedState> {
+    let writes = Router::new()
+        .route("/apply", post(apply))
+        .route("/toggle_if", post(apply_toggle_if))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            disk::reject_when_read_only,
+        ));
+    Router::new()
+        .merge(writes)
+        .route("/updates", get(owned_updates))
+        .route_layer(middleware::from_fn_with_state(state, require_peer))
+}
+
+/// Middleware rejecting requests without the cluster secret, or every request if this instance
+/// isn't clustered
+async fn require_peer(State(state): State<SharedState>, req: Request, next: Next) -> Response {
+    let Some(cluster) = state.cluster.as_deref() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let provided = req
+        .headers()
+        .get(&X_CLUSTER_SECRET)
+        .map(HeaderValue::as_bytes);
+    if !provided
+        .is_some_and(|provided| constant_time_eq(provided, cluster.config.secret.as_bytes()))
+    {
+        return (
+            StatusCode::UNAUTHORIZED,
+            "Missing or invalid cluster secret",
+        )
+            .into_response();
+    }
+    next.run(req).await
+}
+
+/// Applies changes forwarded by another instance
+async fn apply(
+    State(state): State<SharedState>,
+    Json(changes): Json<Changes>,
+) -> axum::response::Result<StatusCode> {
+    let mut stores = Vec::with_capacity(changes.stores.len());
+    for store in &changes.stores {
+        let bytes = BASE64_STANDARD_NO_PAD
+            .decode(&store.bytes)
+            .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid base64"))?;
+        if store.offset as usize + bytes.len() > NUM_SLIDERS {
+            return Err((StatusCode::BAD_REQUEST, "Index too large").into());
+        }
+        stores.push((store.offset as usize, bytes));
+    }
+    if changes
+        .toggles
+        .iter()
+        .any(|&idx| idx >= NUM_CHECKBOXES as u64)
+    {
+        return Err((StatusCode::BAD_REQUEST, "Index too large").into());
+    }
+    for &idx in &changes.toggles {
+        state.bitmap.toggle(idx as usize);
+    }
+    for (offset, bytes) in stores {
+        state.bitmap.store_bytes(offset, &bytes);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ToggleIf {
+    index: u64,
+    expected: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ToggleIfResponse {
+    toggled: bool,
+}
+
+/// Applies a conditional toggle forwarded by another instance
+async fn apply_toggle_if(
+    State(state): State<SharedState>,
+    Json(request): Json<ToggleIf>,
+) -> axum::response::Result<Json<ToggleIfResponse>> {
+    if request.index >= NUM_CHECKBOXES as u64 {
+        return Err((StatusCode::BAD_REQUEST, "Index too large").into());
+    }
+    let toggled = state
+        .bitmap
+        .toggle_if(request.index as usize, request.expected);
+    Ok(Json(ToggleIfResponse { toggled }))
+}
+
+/// Streams this instance's chunks as they change, starting with their current contents, for
+/// other instances to mirror
+async fn owned_updates(State(state): State<SharedState>) -> Response {
+    let Some(cluster) = state.cluster.as_deref() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let watches = (0..NUM_CHUNKS)
+        .filter(|&i| cluster.is_owned(i))
+        .map(|i| {
+            tokio_stream::wrappers::WatchStream::new(state.bitmap.watch(i)).map(move |chunk| {
+                let mut frame = Vec::with_capacity(FRAME_BYTES);
+                frame.extend_from_slice(&(i as u32).to_le_bytes());
+                frame.extend_from_slice(&chunk.bytes);
+                Ok::<_, std::convert::Infallible>(frame)
+            })
+        })
+        .collect::<Vec<_>>();
+    let frames = stream::select_all(watches).take_until(state.shutdown.clone().wait());
+    Body::from_stream(frames).into_response()
+}
+
+/// Keeps this instance's copy of every other instance's chunks up to date, reconnecting to any
+/// instance that goes away
+pub async fn run_mirror(cluster: Arc<Cluster>, bitmap: Arc<SharedBitmap>, shutdown: Shutdown) {
+    let peers = (0..cluster.config.peers.len()).filter(|&peer| peer != cluster.config.index);
+    let mirrors = peers.map(|peer| {
+        let cluster = Arc::clone(&cluster);
+        let bitmap = Arc::clone(&bitmap);
+        async move {
+            loop {
+                match mirror_peer(&cluster, &bitmap, peer).await {
+                    Ok(()) => info!(peer = %cluster.config.peers[peer], "peer ended its updates"),
+                    Err(e) => warn!(peer = %cluster.config.peers[peer], error = e, "lost peer"),
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    });
+    tokio::select! {
+        _ = futures::future::join_all(mirrors) => {}
+        () = shutdown.wait() => {}
+    }
+}
+
+async fn mirror_peer(cluster: &Cluster, bitmap: &SharedBitmap, peer: usize) -> Result<(), String> {
+    let target = &cluster.config.peers[peer];
+    let mut conn = cluster.connect(peer).await?;
+    let headers = [(X_CLUSTER_SECRET.as_str(), cluster.config.secret.as_str())];
+    conn.send(target, "GET", "/cluster/updates", &headers, &[])
+        .await
+        .map_err(|e| e.to_string())?;
+    let head = conn.read_head().await.map_err(|e| e.to_string())?;
+    if !(200..300).contains(&head.status) {
+        return Err(format!("peer responded with {}", head.status));
+    }
+    if !head.chunked {
+        return Err("peer's updates aren't streamed".into());
+    }
+    info!(peer = %target, "mirroring peer");
+    let mut buf = Vec::new();
+    while let Some(data) = conn.read_chunk().await.map_err(|e| e.to_string())? {
+        buf.extend_from_slice(&data);
+        let whole = buf.len() - buf.len() % FRAME_BYTES;
+        for frame in buf[..whole].chunks_exact(FRAME_BYTES) {
+            let (index, bytes) = frame.split_at(4);
+            let index = u32::from_le_bytes(index.try_into().unwrap()) as usize;
+            // Only the owner's word counts for its chunks
+            if index < NUM_CHUNKS && cluster.owner(index) == peer {
+                bitmap.mirror_chunk(index, bytes.try_into().unwrap());
+            }
+        }
+        buf.drain(..whole);
+    }
+    Ok(())
+}
@@ -0,0 +1,208 @@
+//! The binary update format served by `/updates.bin`, a compact alternative to the base64 text of
+//! `/updates` for clients able to parse it.
+//!
+//! The response body is a sequence of frames, all integers little endian:
+//!
+//! | bytes | field                                                   |
+//! |-------|---------------------------------------------------------|
+//! | 2     | magic, `SD`                                             |
+//! | 1     | format version, currently 1                             |
+//! | 1     | kind, 0 for a chunk diff, 1 for a sum, or 2 for the end |
+//! | 8     | the board's sequence number when the frame was produced |
+//!
+//! followed for a chunk diff by
+//!
+//! | bytes | field                                                          |
+//! |-------|----------------------------------------------------------------|
+//! | 4     | index of the chunk's first checkbox                            |
+//! | 16    | bitmask of changed bytes, bit `i` of byte `i / 8` for byte `i` |
+//! | n     | the new value of each changed byte, in order                   |
+//!
+//! or for a sum by
+//!
+//! | bytes | field                  |
+//! |-------|------------------------|
+//! | 8     | the sum of all sliders |
+//!
+//! An end frame has nothing after the header. It's sent when the server shuts down, after a diff
+//! for every chunk bringing it up to date, and is the last frame of the response.
+//!
+//! There's no frame for the count of checked checkboxes which `/updates` also sends.
+//!
+//! The first diff for each chunk has every byte marked as changed, later ones only the bytes
+//! which differ from the previous diff for that chunk.
+//!
+//! `/bootstrap` sends the same frames, starting with a diff for every chunk of the board in order,
+//! all as of the single sequence number in their headers, before the live updates for the range.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::extract::{Query, State};
+use axum::http::header;
+use axum::response::IntoResponse;
+use futures::stream;
+use tokio_stream::StreamExt;
+use tracing::field::Empty;
+
+use crate::proxy::Origin;
+use crate::shared_bitmap::{Snapshot, CHUNK_BITS, CHUNK_BYTES, NUM_CHUNKS};
+use crate::{pause, subscribe_updates, Range, SharedState, Update};
+
+pub const CONTENT_TYPE: &str = "application/x-sliders-diff";
+
+const MAGIC: [u8; 2] = *b"SD";
+const VERSION: u8 = 1;
+const KIND_CHUNK: u8 = 0;
+const KIND_SUM: u8 = 1;
+const KIND_END: u8 = 2;
+const MASK_BYTES: usize = CHUNK_BYTES / 8;
+
+fn write_header(out: &mut Vec<u8>, kind: u8, seq: u64) {
+    out.extend_from_slice(&MAGIC);
+    out.push(VERSION);
+    out.push(kind);
+    out.extend_from_slice(&seq.to_le_bytes());
+}
+
+/// Appends a frame holding the bytes of `chunk` which differ from `prev`, or all of them if
+/// there's no previous version
+pub fn encode_chunk_diff(
+    out: &mut Vec<u8>,
+    seq: u64,
+    offset: u32,
+    prev: Option<&[u8; CHUNK_BYTES]>,
+    chunk: &[u8; CHUNK_BYTES],
+) {
+    write_header(out, KIND_CHUNK, seq);
+    out.extend_from_slice(&offset.to_le_bytes());
+    let mut mask = [0u8; MASK_BYTES];
+    for (i, &byte) in chunk.iter().enumerate() {
+        if prev.is_none_or(|prev| prev[i] != byte) {
+            mask[i / 8] |= 1 << (i % 8);
+        }
+    }
+    out.extend_from_slice(&mask);
+    for (i, &byte) in chunk.iter().enumerate() {
+        if mask[i / 8] & (1 << (i % 8)) != 0 {
+            out.push(byte);
+        }
+    }
+}
+
+/// Appends a frame holding the sum of all sliders
+pub fn encode_sum(out: &mut Vec<u8>, seq: u64, sum: u64) {
+    write_header(out, KIND_SUM, seq);
+    out.extend_from_slice(&sum.to_le_bytes());
+}
+
+/// Appends a frame marking the end of the stream
+pub fn encode_end(out: &mut Vec<u8>, seq: u64) {
+    write_header(out, KIND_END, seq);
+}
+
+/// The same events as `/updates`, as binary frames
+#[tracing::instrument(
+    skip(state, range),
+    fields(start=range.start, end=range.end, chunks=Empty, subscribers=Empty)
+)]
+pub async fn updates_bin(
+    State(state): State<SharedState>,
+    origin: Origin,
+    Query(range): Query<Range>,
+) -> axum::response::Result<impl IntoResponse> {
+    let bitmap = Arc::clone(&state.bitmap);
+    let updates = subscribe_updates(state, origin, range, pause::never())?;
+
+    let mut sent = HashMap::new();
+    let frames = updates.filter_map(move |update| {
+        let seq = bitmap.sequence();
+        let mut frame = Vec::new();
+        match update {
+            Update::Chunk(i, chunk) => {
+                let offset = (i * CHUNK_BITS) as u32;
+                encode_chunk_diff(&mut frame, seq, offset, sent.get(&i), &chunk.bytes);
+                sent.insert(i, chunk.bytes);
+            }
+            Update::Sum(sum) => encode_sum(&mut frame, seq, sum),
+            // Version 1 readers can't skip a kind they don't know, so these aren't sent
+            Update::Count(_)
+            | Update::Milestone(_)
+            | Update::Viewers(_)
+            | Update::Announce(_)
+            | Update::Shutdown(_) => return None,
+            Update::End => encode_end(&mut frame, seq),
+        }
+        Some(Ok::<_, Infallible>(frame))
+    });
+    Ok((
+        [(header::CONTENT_TYPE, CONTENT_TYPE)],
+        Body::from_stream(frames),
+    ))
+}
+
+/// The whole board followed by live updates for the range, so a new client can't miss a change
+/// made between fetching a snapshot and subscribing
+#[tracing::instrument(
+    skip(state, range),
+    fields(start=range.start, end=range.end, chunks=Empty, subscribers=Empty)
+)]
+pub async fn bootstrap(
+    State(state): State<SharedState>,
+    origin: Origin,
+    Query(range): Query<Range>,
+) -> axum::response::Result<impl IntoResponse> {
+    let bitmap = Arc::clone(&state.bitmap);
+    // Subscribing before taking the snapshot means no change can fall between the two
+    let updates = subscribe_updates(state, origin, range, pause::never())?;
+    // The last chunk runs past the last slider
+    let snapshot = Arc::new(bitmap.snapshot(0..NUM_CHUNKS * CHUNK_BYTES));
+    let snapshot_chunk = |snapshot: &Snapshot, i: usize| -> [u8; CHUNK_BYTES] {
+        snapshot.bytes[i * CHUNK_BYTES..][..CHUNK_BYTES]
+            .try_into()
+            .expect("the snapshot is a whole number of chunks")
+    };
+
+    let board = stream::iter(0..NUM_CHUNKS).map({
+        let snapshot = Arc::clone(&snapshot);
+        move |i| {
+            let mut frame = Vec::new();
+            let offset = (i * CHUNK_BITS) as u32;
+            let bytes = snapshot_chunk(&snapshot, i);
+            encode_chunk_diff(&mut frame, snapshot.sequence, offset, None, &bytes);
+            Ok::<_, Infallible>(frame)
+        }
+    });
+    // Diffs carry on from the board as sent above
+    let mut sent = HashMap::new();
+    let live = updates.filter_map(move |update| {
+        let seq = bitmap.sequence();
+        let mut frame = Vec::new();
+        match update {
+            // Anything up to the snapshot's version is already in it
+            Update::Chunk(_, chunk) if chunk.version <= snapshot.version => return None,
+            Update::Chunk(i, chunk) => {
+                let offset = (i * CHUNK_BITS) as u32;
+                let prev = *sent
+                    .entry(i)
+                    .or_insert_with(|| snapshot_chunk(&snapshot, i));
+                encode_chunk_diff(&mut frame, seq, offset, Some(&prev), &chunk.bytes);
+                sent.insert(i, chunk.bytes);
+            }
+            Update::Sum(sum) => encode_sum(&mut frame, seq, sum),
+            Update::Count(_)
+            | Update::Milestone(_)
+            | Update::Viewers(_)
+            | Update::Announce(_)
+            | Update::Shutdown(_) => return None,
+            Update::End => encode_end(&mut frame, seq),
+        }
+        Some(Ok::<_, Infallible>(frame))
+    });
+    Ok((
+        [(header::CONTENT_TYPE, CONTENT_TYPE)],
+        Body::from_stream(board.chain(live)),
+    ))
+}
@@ -0,0 +1,317 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use axum::http::HeaderName;
+
+use crate::abuse::AbuseConfig;
+use crate::automaton::{AutomatonConfig, Rule};
+use crate::bandwidth::BandwidthConfig;
+use crate::cdn::CdnConfig;
+use crate::cluster::ClusterConfig;
+use crate::disk::DiskConfig;
+use crate::http_client::Target;
+use crate::proxy::{self, ProxyConfig};
+use crate::rate_limit::{RateLimitConfig, Schedule, Tier};
+
+/// Server tunables, read from `SLIDERS_*` environment variables
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// How often dirty regions of `bitmap.bin` are handed to the kernel for writeback
+    /// (`SLIDERS_FLUSH_INTERVAL_MS`)
+    pub flush_interval: Duration,
+    /// Longest a busy or heavily watched chunk's updates may be held back to coalesce changes
+    /// (`SLIDERS_MAX_NOTIFY_INTERVAL_MS`)
+    pub max_notify_interval: Duration,
+    /// How often the sum, count and number of open streams sent down update streams are read,
+    /// stretched as more streams are open (`SLIDERS_TOTALS_INTERVAL_MS`)
+    pub totals_interval: Duration,
+    /// Requests allowed in flight at once across `/toggle` and `/set_byte`, beyond which requests
+    /// are shed with a 503 (`SLIDERS_MAX_CONCURRENT_WRITES`)
+    pub max_concurrent_writes: usize,
+    /// Toggles allowed to wait to be applied (`SLIDERS_TOGGLE_QUEUE_CAPACITY`), and how long a
+    /// `/toggle` waits for space before it's refused with a 503 (`SLIDERS_TOGGLE_QUEUE_WAIT_MS`)
+    pub toggle_queue_capacity: usize,
+    pub toggle_queue_wait: Duration,
+    /// Threads decoding uploaded pictures (`SLIDERS_IMAGE_THREADS`), and pictures allowed to wait
+    /// for one before uploads are refused with a 503 (`SLIDERS_IMAGE_QUEUE`)
+    pub image_threads: usize,
+    pub image_queue: usize,
+    /// `/updates` subscriptions allowed to be setting up at once (`SLIDERS_MAX_CONCURRENT_SUBSCRIBES`)
+    pub max_concurrent_subscribes: usize,
+    /// Longest a non-streaming request may run before it's failed with a 408
+    /// (`SLIDERS_REQUEST_TIMEOUT_MS`)
+    pub request_timeout: Duration,
+    /// Requests slower than this are logged, none if unset (`SLIDERS_SLOW_REQUEST_MS`)
+    pub slow_request_threshold: Option<Duration>,
+    /// Open `/updates` subscriptions allowed across all clients (`SLIDERS_MAX_SUBSCRIPTIONS`)
+    pub max_subscriptions: usize,
+    /// Open `/updates` subscriptions allowed from a single address
+    /// (`SLIDERS_MAX_SUBSCRIPTIONS_PER_IP`)
+    pub max_subscriptions_per_ip: usize,
+    /// Chunks which may be watched by all the subscriptions on one connection together
+    /// (`SLIDERS_MAX_SUBSCRIBED_CHUNKS_PER_CONNECTION`), and by all of one address's
+    /// (`SLIDERS_MAX_SUBSCRIBED_CHUNKS_PER_IP`)
+    pub max_subscribed_chunks_per_connection: usize,
+    pub max_subscribed_chunks_per_ip: usize,
+    /// Bytes a single stream may be sent (`SLIDERS_STREAM_BYTE_BUDGET`), and all of one address's
+    /// open streams together (`SLIDERS_STREAM_IP_BYTE_BUDGET`), before they're closed, both
+    /// unlimited by default
+    pub bandwidth: BandwidthConfig,
+    /// Bearer token required by the `/admin` API, which is disabled if unset
+    /// (`SLIDERS_ADMIN_TOKEN`)
+    pub admin_token: Option<String>,
+    /// Thresholds for automatically throttling abusive clients (`SLIDERS_ABUSE_WINDOW_SECS`,
+    /// `SLIDERS_ABUSE_MAX_CHUNK_WRITES`, `SLIDERS_ABUSE_SWEEP_LENGTH`,
+    /// `SLIDERS_ABUSE_THROTTLE_SECS`)
+    pub abuse: AbuseConfig,
+    /// Write rate limits for anonymous clients and API key holders, unlimited by default
+    /// (`SLIDERS_RATE_LIMIT_PER_SEC`, `SLIDERS_RATE_LIMIT_BURST`, `SLIDERS_KEYED_RATE_LIMIT_PER_SEC`,
+    /// `SLIDERS_KEYED_RATE_LIMIT_BURST`), the keys, comma separated (`SLIDERS_API_KEYS`), windows
+    /// of the day scaling them, like `22:00-06:00=0.5,03:00-03:30=0` (`SLIDERS_RATE_LIMIT_SCHEDULE`),
+    /// and where to share the limits between instances, which needs the `redis` feature
+    /// (`SLIDERS_REDIS_URL`)
+    pub rate_limit: RateLimitConfig,
+    /// Background rule which keeps changing the board, off by default (`SLIDERS_AUTOMATON`, one of
+    /// `off`, `life`, or `noise`, `SLIDERS_AUTOMATON_INTERVAL_MS`,
+    /// `SLIDERS_AUTOMATON_NOISE_TOGGLES`), and how often sliders decay, never by default
+    /// (`SLIDERS_DECAY_INTERVAL_SECS`)
+    pub automaton: AutomatonConfig,
+    /// How long a background pass checking the board's bookkeeping takes, 0 to never check
+    /// (`SLIDERS_VERIFY_PASS_SECS`)
+    pub verify_pass: Duration,
+    /// MQTT broker to bridge the board to, which needs the `mqtt` feature (`SLIDERS_MQTT_HOST`,
+    /// `SLIDERS_MQTT_PORT`, `SLIDERS_MQTT_TOPIC_PREFIX`)
+    pub mqtt: Option<MqttConfig>,
+    /// Where to report internal errors and panics, which needs the `sentry` feature
+    /// (`SLIDERS_SENTRY_DSN`)
+    pub sentry_dsn: Option<String>,
+    /// Instances to share the board with, none by default (`SLIDERS_CLUSTER_PEERS`, the base URL
+    /// of every instance including this one, comma separated, `SLIDERS_CLUSTER_INDEX`, this
+    /// instance's position in the list, and `SLIDERS_CLUSTER_SECRET`)
+    pub cluster: Option<ClusterConfig>,
+    /// Free space the volume holding the board must keep, below which writes are refused, in
+    /// megabytes (`SLIDERS_MIN_FREE_DISK_MB`), how often it's checked
+    /// (`SLIDERS_DISK_CHECK_INTERVAL_SECS`), how long the board can go unflushed before writes
+    /// are refused too (`SLIDERS_MAX_FLUSH_AGE_SECS`, a minute by default), and where to post
+    /// alerts (`SLIDERS_DISK_ALERT_URL`)
+    pub disk: DiskConfig,
+    /// Surrogate keys for caching reads in a CDN, none by default (`SLIDERS_CDN_KEY_HEADERS`, the
+    /// headers to send them in, like `Surrogate-Key` or `Cache-Tag`, comma separated, and
+    /// `SLIDERS_CDN_KEY_CHUNKS`, chunks per key), and where to purge it when the board is
+    /// rewritten (`SLIDERS_CDN_PURGE_URL`, `SLIDERS_CDN_PURGE_TOKEN`)
+    pub cdn: Option<CdnConfig>,
+    /// Path the service is mounted under behind a reverse proxy, like `/sliders`, the root by
+    /// default (`SLIDERS_BASE_PATH`), and the proxies trusted to name the client in
+    /// `X-Forwarded-For` and `X-Forwarded-Proto`, addresses or networks like `10.0.0.0/8`, comma
+    /// separated, none by default (`SLIDERS_TRUSTED_PROXIES`)
+    pub proxy: ProxyConfig,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    /// Prepended to the bridge's topics, `<prefix>/chunk/<n>` and `<prefix>/commands`
+    pub topic_prefix: String,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, String> {
+        let config = Self {
+            flush_interval: Duration::from_millis(env_or("SLIDERS_FLUSH_INTERVAL_MS", 5_000)?),
+            max_notify_interval: Duration::from_millis(env_or(
+                "SLIDERS_MAX_NOTIFY_INTERVAL_MS",
+                1_000,
+            )?),
+            totals_interval: Duration::from_millis(env_or("SLIDERS_TOTALS_INTERVAL_MS", 250)?),
+            max_concurrent_writes: env_or("SLIDERS_MAX_CONCURRENT_WRITES", 1024)?,
+            toggle_queue_capacity: env_or("SLIDERS_TOGGLE_QUEUE_CAPACITY", 65_536)?,
+            toggle_queue_wait: Duration::from_millis(env_or("SLIDERS_TOGGLE_QUEUE_WAIT_MS", 100)?),
+            image_threads: env_or("SLIDERS_IMAGE_THREADS", 2)?,
+            image_queue: env_or("SLIDERS_IMAGE_QUEUE", 16)?,
+            max_concurrent_subscribes: env_or("SLIDERS_MAX_CONCURRENT_SUBSCRIBES", 128)?,
+            request_timeout: Duration::from_millis(env_or("SLIDERS_REQUEST_TIMEOUT_MS", 10_000)?),
+            slow_request_threshold: match env_or("SLIDERS_SLOW_REQUEST_MS", 0)? {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            max_subscriptions: env_or("SLIDERS_MAX_SUBSCRIPTIONS", 10_000)?,
+            max_subscriptions_per_ip: env_or("SLIDERS_MAX_SUBSCRIPTIONS_PER_IP", 16)?,
+            max_subscribed_chunks_per_connection: env_or(
+                "SLIDERS_MAX_SUBSCRIBED_CHUNKS_PER_CONNECTION",
+                256,
+            )?,
+            max_subscribed_chunks_per_ip: env_or("SLIDERS_MAX_SUBSCRIBED_CHUNKS_PER_IP", 512)?,
+            bandwidth: BandwidthConfig {
+                per_connection: Some(env_or("SLIDERS_STREAM_BYTE_BUDGET", 0)?)
+                    .filter(|&budget| budget != 0),
+                per_ip: Some(env_or("SLIDERS_STREAM_IP_BYTE_BUDGET", 0)?)
+                    .filter(|&budget| budget != 0),
+            },
+            admin_token: std::env::var("SLIDERS_ADMIN_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            abuse: AbuseConfig {
+                window: Duration::from_secs(env_or("SLIDERS_ABUSE_WINDOW_SECS", 10)?),
+                max_chunk_writes: env_or("SLIDERS_ABUSE_MAX_CHUNK_WRITES", 500)?,
+                sweep_length: env_or("SLIDERS_ABUSE_SWEEP_LENGTH", 1_000)?,
+                throttle_for: Duration::from_secs(env_or("SLIDERS_ABUSE_THROTTLE_SECS", 60)?),
+            },
+            rate_limit: RateLimitConfig {
+                anonymous: Tier {
+                    per_sec: env_or("SLIDERS_RATE_LIMIT_PER_SEC", 0)?,
+                    burst: env_or("SLIDERS_RATE_LIMIT_BURST", 20)?,
+                },
+                keyed: Tier {
+                    per_sec: env_or("SLIDERS_KEYED_RATE_LIMIT_PER_SEC", 0)?,
+                    burst: env_or("SLIDERS_KEYED_RATE_LIMIT_BURST", 200)?,
+                },
+                api_keys: std::env::var("SLIDERS_API_KEYS")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|key| !key.is_empty())
+                    .map(str::to_owned)
+                    .collect(),
+                redis_url: std::env::var("SLIDERS_REDIS_URL")
+                    .ok()
+                    .filter(|url| !url.is_empty()),
+                schedule: env_or("SLIDERS_RATE_LIMIT_SCHEDULE", Schedule::default())?,
+            },
+            automaton: AutomatonConfig {
+                rule: env_or("SLIDERS_AUTOMATON", Rule::Off)?,
+                interval: Duration::from_millis(env_or("SLIDERS_AUTOMATON_INTERVAL_MS", 5_000)?),
+                noise_toggles: env_or("SLIDERS_AUTOMATON_NOISE_TOGGLES", 100)?,
+                decay_every: Duration::from_secs(env_or("SLIDERS_DECAY_INTERVAL_SECS", 0)?),
+            },
+            verify_pass: Duration::from_secs(env_or("SLIDERS_VERIFY_PASS_SECS", 600)?),
+            mqtt: match std::env::var("SLIDERS_MQTT_HOST") {
+                Ok(host) if !host.is_empty() => Some(MqttConfig {
+                    host,
+                    port: env_or("SLIDERS_MQTT_PORT", 1883)?,
+                    topic_prefix: env_or("SLIDERS_MQTT_TOPIC_PREFIX", "sliders".to_owned())?,
+                }),
+                _ => None,
+            },
+            sentry_dsn: std::env::var("SLIDERS_SENTRY_DSN")
+                .ok()
+                .filter(|dsn| !dsn.is_empty()),
+            cluster: cluster_from_env()?,
+            disk: DiskConfig {
+                min_free_bytes: env_or::<u64>("SLIDERS_MIN_FREE_DISK_MB", 256)? * 1024 * 1024,
+                check_interval: Duration::from_secs(env_or(
+                    "SLIDERS_DISK_CHECK_INTERVAL_SECS",
+                    10,
+                )?),
+                max_flush_age: Duration::from_secs(env_or("SLIDERS_MAX_FLUSH_AGE_SECS", 60)?),
+                webhook: match std::env::var("SLIDERS_DISK_ALERT_URL") {
+                    Ok(url) if !url.is_empty() => {
+                        Some(Target::parse(&url).map_err(|e| {
+                            format!("invalid value for SLIDERS_DISK_ALERT_URL: {e}")
+                        })?)
+                    }
+                    _ => None,
+                },
+            },
+            cdn: cdn_from_env()?,
+            proxy: proxy_from_env()?,
+        };
+        if config.flush_interval >= config.disk.max_flush_age {
+            // Otherwise the board would look stalled between every two flushes
+            return Err(
+                "SLIDERS_FLUSH_INTERVAL_MS must be shorter than SLIDERS_MAX_FLUSH_AGE_SECS".into(),
+            );
+        }
+        Ok(config)
+    }
+}
+
+fn cluster_from_env() -> Result<Option<ClusterConfig>, String> {
+    let peers = std::env::var("SLIDERS_CLUSTER_PEERS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|peer| !peer.is_empty())
+        .map(Target::parse)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid value for SLIDERS_CLUSTER_PEERS: {e}"))?;
+    if peers.is_empty() {
+        return Ok(None);
+    }
+    let index = env_or("SLIDERS_CLUSTER_INDEX", usize::MAX)?;
+    if index >= peers.len() {
+        return Err(
+            "SLIDERS_CLUSTER_INDEX must give this instance's position in SLIDERS_CLUSTER_PEERS"
+                .into(),
+        );
+    }
+    let secret = std::env::var("SLIDERS_CLUSTER_SECRET").unwrap_or_default();
+    if secret.is_empty() {
+        return Err("SLIDERS_CLUSTER_SECRET is required with SLIDERS_CLUSTER_PEERS".into());
+    }
+    Ok(Some(ClusterConfig {
+        peers,
+        index,
+        secret,
+    }))
+}
+
+fn cdn_from_env() -> Result<Option<CdnConfig>, String> {
+    let key_headers = std::env::var("SLIDERS_CDN_KEY_HEADERS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            HeaderName::try_from(name)
+                .map_err(|_| format!("invalid value for SLIDERS_CDN_KEY_HEADERS: {name:?}"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let purge_url = match std::env::var("SLIDERS_CDN_PURGE_URL") {
+        Ok(url) if !url.is_empty() => Some(
+            Target::parse(&url)
+                .map_err(|e| format!("invalid value for SLIDERS_CDN_PURGE_URL: {e}"))?,
+        ),
+        _ => None,
+    };
+    if key_headers.is_empty() && purge_url.is_none() {
+        return Ok(None);
+    }
+    let key_chunks = env_or("SLIDERS_CDN_KEY_CHUNKS", 64)?;
+    if key_chunks == 0 {
+        return Err("SLIDERS_CDN_KEY_CHUNKS must be at least 1".into());
+    }
+    Ok(Some(CdnConfig {
+        key_headers,
+        key_chunks,
+        purge_url,
+        purge_token: std::env::var("SLIDERS_CDN_PURGE_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty()),
+    }))
+}
+
+fn proxy_from_env() -> Result<ProxyConfig, String> {
+    let base_path = proxy::parse_base_path(&std::env::var("SLIDERS_BASE_PATH").unwrap_or_default())
+        .map_err(|e| format!("invalid value for SLIDERS_BASE_PATH: {e}"))?;
+    let trusted = std::env::var("SLIDERS_TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|proxy| !proxy.is_empty())
+        .map(str::parse)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid value for SLIDERS_TRUSTED_PROXIES: {e}"))?;
+    Ok(ProxyConfig { base_path, trusted })
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> Result<T, String> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| format!("invalid value for {name}: {value:?}")),
+        Err(std::env::VarError::NotPresent) => Ok(default),
+        Err(e) => Err(format!("invalid value for {name}: {e}")),
+    }
+}
@@ -0,0 +1,284 @@
+//! A watchdog on the free space of the volume holding `bitmap.bin` and the other state files.
+//! Writes to the mapped board only reach the disk later, and if the file's blocks can't be
+//! allocated then, the process is killed rather than given an error. So well before the volume
+//! fills up, the server goes read-only, refusing writes with a 503 until space is freed.
+//!
+//! The same goes for the task flushing the board: if it hasn't got through a flush for
+//! `max_flush_age`, because its flushes keep failing or it has died, writes are refused until it
+//! does, rather than piling up in memory with nothing saving them. A flush only schedules the
+//! dirty pages to be written back, without waiting for them, so a slow disk doesn't count as a
+//! stall.
+//!
+//! Going read-only and back is logged, exported in `/metrics`, and optionally posted to a webhook
+//! as `{"event":"disk_low"|"disk_ok","free_bytes":..,"min_free_bytes":..}` or
+//! `{"event":"flush_stalled"|"flush_ok","since_flush_ms":..}`.
+
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::{Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use tokio::time::MissedTickBehavior;
+use tracing::{info, warn};
+
+use crate::http_client::{Connection, Target};
+use crate::shared_bitmap::SharedBitmap;
+use crate::{reporting, SharedState};
+
+#[derive(Debug, Clone)]
+pub struct DiskConfig {
+    /// Free space below which the server goes read-only
+    pub min_free_bytes: u64,
+    pub check_interval: Duration,
+    /// How long the board can go without a flush before the server goes read-only
+    pub max_flush_age: Duration,
+    /// Where to post changes to and from read-only, if anywhere
+    pub webhook: Option<Target>,
+}
+
+pub struct DiskWatchdog {
+    config: DiskConfig,
+    /// For lack of free space
+    read_only: AtomicBool,
+    flush_stalled: AtomicBool,
+    /// As of the last check, `u64::MAX` before the first
+    free_bytes: AtomicU64,
+}
+
+#[derive(serde::Serialize)]
+struct Alert {
+    event: &'static str,
+    free_bytes: u64,
+    min_free_bytes: u64,
+}
+
+#[derive(serde::Serialize)]
+struct FlushAlert {
+    event: &'static str,
+    since_flush_ms: u64,
+}
+
+impl DiskWatchdog {
+    pub fn new(config: DiskConfig) -> Self {
+        Self {
+            config,
+            read_only: AtomicBool::new(false),
+            flush_stalled: AtomicBool::new(false),
+            free_bytes: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed) || self.flush_stalled.load(Ordering::Relaxed)
+    }
+
+    /// Checks the free space in the working directory and how long since `bitmap` was flushed
+    /// every `check_interval`
+    pub async fn run(self: Arc<Self>, bitmap: Arc<SharedBitmap>) {
+        let mut interval = tokio::time::interval(self.config.check_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.check_flush(&bitmap).await;
+            let free_bytes = match free_bytes(Path::new(".")) {
+                Ok(free_bytes) => free_bytes,
+                Err(e) => {
+                    warn!(error = %e, "failed to check free disk space");
+                    continue;
+                }
+            };
+            self.free_bytes.store(free_bytes, Ordering::Relaxed);
+            let min_free_bytes = self.config.min_free_bytes;
+            // A little headroom before leaving read-only, so it doesn't flap around the limit
+            let read_only = if self.read_only.load(Ordering::Relaxed) {
+                free_bytes < min_free_bytes + min_free_bytes / 10
+            } else {
+                free_bytes < min_free_bytes
+            };
+            if read_only == self.read_only.swap(read_only, Ordering::Relaxed) {
+                continue;
+            }
+            let event = if read_only {
+                warn!(
+                    free_bytes,
+                    min_free_bytes, "disk space is low, refusing writes"
+                );
+                "disk_low"
+            } else {
+                info!(
+                    free_bytes,
+                    min_free_bytes, "disk space recovered, accepting writes"
+                );
+                "disk_ok"
+            };
+            if let Some(webhook) = &self.config.webhook {
+                let alert = Alert {
+                    event,
+                    free_bytes,
+                    min_free_bytes,
+                };
+                if let Err(e) = post(webhook, &alert).await {
+                    reporting::report("Failed to post disk space alert", e);
+                }
+            }
+        }
+    }
+
+    async fn check_flush(&self, bitmap: &SharedBitmap) {
+        let since_flush = bitmap.since_flush();
+        let stalled = since_flush > self.config.max_flush_age;
+        if stalled == self.flush_stalled.swap(stalled, Ordering::Relaxed) {
+            return;
+        }
+        let since_flush_ms = since_flush.as_millis() as u64;
+        let event = if stalled {
+            reporting::report(
+                "Board hasn't been flushed, refusing writes",
+                format!("last flushed {since_flush_ms}ms ago"),
+            );
+            "flush_stalled"
+        } else {
+            info!(since_flush_ms, "board flushed again, accepting writes");
+            "flush_ok"
+        };
+        if let Some(webhook) = &self.config.webhook {
+            let alert = FlushAlert {
+                event,
+                since_flush_ms,
+            };
+            if let Err(e) = post(webhook, &alert).await {
+                reporting::report("Failed to post flush alert", e);
+            }
+        }
+    }
+
+    /// Free space and whether writes are refused, in the Prometheus text format
+    pub fn prometheus(&self) -> String {
+        let mut out = String::new();
+        let free_bytes = self.free_bytes.load(Ordering::Relaxed);
+        if free_bytes != u64::MAX {
+            out.push_str(
+                "# HELP sliders_disk_free_bytes Free space on the volume holding the board\n",
+            );
+            out.push_str("# TYPE sliders_disk_free_bytes gauge\n");
+            writeln!(out, "sliders_disk_free_bytes {free_bytes}").unwrap();
+        }
+        out.push_str(
+            "# HELP sliders_read_only Whether writes are refused, for lack of disk space or a stalled flush\n",
+        );
+        out.push_str("# TYPE sliders_read_only gauge\n");
+        writeln!(out, "sliders_read_only {}", u8::from(self.is_read_only())).unwrap();
+        out.push_str(
+            "# HELP sliders_flush_stalled Whether writes are refused because the board isn't being flushed\n",
+        );
+        out.push_str("# TYPE sliders_flush_stalled gauge\n");
+        writeln!(
+            out,
+            "sliders_flush_stalled {}",
+            u8::from(self.flush_stalled.load(Ordering::Relaxed))
+        )
+        .unwrap();
+        out
+    }
+}
+
+async fn post(target: &Target, alert: &impl serde::Serialize) -> Result<(), String> {
+    let body = serde_json::to_vec(alert).map_err(|e| e.to_string())?;
+    let mut conn = Connection::connect(target)
+        .await
+        .map_err(|e| e.to_string())?;
+    conn.send(
+        target,
+        "POST",
+        "",
+        &[("Content-Type", "application/json")],
+        &body,
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+    let head = conn.read_head().await.map_err(|e| e.to_string())?;
+    if !(200..300).contains(&head.status) {
+        return Err(format!("webhook responded with {}", head.status));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn free_bytes(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: the path is a valid C string and `stat` is only read once the call succeeds
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    // Blocks available to unprivileged users, which the server should be running as. The fields
+    // are narrower on some platforms.
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_bytes(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "free space is only checked on unix",
+    ))
+}
+
+/// Middleware refusing writes while disk space is low or the board isn't being flushed
+pub async fn reject_when_read_only(
+    State(state): State<SharedState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if state.disk.is_read_only() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, "60")],
+            "The server is read-only while it can't save the board",
+        )
+            .into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(all(test, not(sliders_loom)))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn read_only_while_flush_stalled() {
+        let path = std::env::temp_dir().join(format!("sliders-disk-{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let bitmap = Arc::new(SharedBitmap::load_or_create(&path).unwrap());
+        let watchdog = DiskWatchdog::new(DiskConfig {
+            min_free_bytes: 0,
+            check_interval: Duration::from_secs(1),
+            max_flush_age: Duration::from_millis(50),
+            webhook: None,
+        });
+
+        // Nothing is flushing the board
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        watchdog.check_flush(&bitmap).await;
+        assert!(watchdog.is_read_only());
+
+        let tasks = bitmap.spawn_tasks(Duration::from_millis(10), Duration::from_secs(1));
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        watchdog.check_flush(&bitmap).await;
+        assert!(!watchdog.is_read_only());
+
+        drop(tasks);
+        let _ = std::fs::remove_file(path);
+    }
+}
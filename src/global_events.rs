@@ -0,0 +1,169 @@
+//! Events about the whole board rather than any one chunk: the sum and count, milestones in the
+//! count, the number of open streams, and the announcement. A single task works them out and
+//! broadcasts them to every update stream, rather than each stream polling for them itself.
+//!
+//! The board is read on an interval which stretches as more streams are open, since each change is
+//! sent down every one of them.
+
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::{stream, Stream, StreamExt};
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+use tokio_stream::wrappers::BroadcastStream;
+
+use crate::announcement::{Announcement, Announcements};
+use crate::shared_bitmap::SharedBitmap;
+use crate::subscriptions::SubscriptionLimits;
+use crate::unix_now;
+
+// Each this many open subscriptions stretch the interval by another base interval
+const SUBSCRIPTIONS_PER_STEP: usize = 1_000;
+// The interval is never stretched past this many base intervals
+const MAX_STEPS: usize = 8;
+// Events held for a stream which isn't keeping up, before it's sent the current values instead
+const CAPACITY: usize = 64;
+/// A milestone is reached each time the count climbs past another multiple of this
+pub const MILESTONE_EVERY: u64 = 10_000;
+
+#[derive(Debug, Clone)]
+pub enum GlobalEvent {
+    /// The new sum of all sliders
+    Sum(u64),
+    /// The new number of checked checkboxes
+    Count(u64),
+    /// The count climbed to this multiple of [`MILESTONE_EVERY`] for the first time since startup
+    Milestone(u64),
+    /// The new number of open update streams
+    Viewers(usize),
+    /// The announcement changed, or was taken down if `None`
+    Announce(Option<Announcement>),
+}
+
+/// The values last broadcast, for streams which join later or fall behind
+struct Latest {
+    sum: u64,
+    count: u64,
+    viewers: usize,
+    announcement: Option<Announcement>,
+    /// Highest milestone reached
+    milestone: u64,
+}
+
+pub struct GlobalEvents {
+    sender: broadcast::Sender<GlobalEvent>,
+    latest: Mutex<Latest>,
+    /// Time between reads of the board with few streams open
+    interval: Duration,
+}
+
+impl GlobalEvents {
+    pub fn new(bitmap: &SharedBitmap, interval: Duration) -> Self {
+        let count = bitmap.count();
+        Self {
+            sender: broadcast::Sender::new(CAPACITY),
+            latest: Mutex::new(Latest {
+                sum: bitmap.sum(),
+                count,
+                viewers: 0,
+                announcement: None,
+                // Only milestones reached while running count
+                milestone: count / MILESTONE_EVERY * MILESTONE_EVERY,
+            }),
+            interval,
+        }
+    }
+
+    /// The current sum, count and number of streams, and the announcement if there is one, then
+    /// every event as it happens. A stream which falls too far behind is sent the current values
+    /// again in place of the events it missed.
+    pub fn subscribe(self: &Arc<Self>) -> impl Stream<Item = GlobalEvent> {
+        // Subscribed before reading, so an event in between is seen, if twice
+        let events = BroadcastStream::new(self.sender.subscribe());
+        let current = self.current();
+        let this = Arc::clone(self);
+        let events = events.flat_map(move |event| {
+            stream::iter(match event {
+                Ok(event) => vec![event],
+                Err(_lagged) => this.current(),
+            })
+        });
+        stream::iter(current).chain(events)
+    }
+
+    fn current(&self) -> Vec<GlobalEvent> {
+        let latest = self.latest.lock().unwrap();
+        let now = unix_now();
+        let mut events = vec![
+            GlobalEvent::Sum(latest.sum),
+            GlobalEvent::Count(latest.count),
+            GlobalEvent::Viewers(latest.viewers),
+        ];
+        if let Some(announcement) = latest
+            .announcement
+            .as_ref()
+            .filter(|announcement| !announcement.expired(now))
+        {
+            events.push(GlobalEvent::Announce(Some(announcement.clone())));
+        }
+        events
+    }
+
+    /// Time between reads of the board with `open` subscriptions
+    fn interval_for(&self, open: usize) -> Duration {
+        let steps = (1 + open / SUBSCRIPTIONS_PER_STEP).min(MAX_STEPS);
+        self.interval * steps as u32
+    }
+
+    /// Broadcasts whatever changed since the last read
+    fn read(&self, bitmap: &SharedBitmap, subscriptions: &SubscriptionLimits) {
+        let (sum, count, viewers) = (bitmap.sum(), bitmap.count(), subscriptions.open());
+        let mut latest = self.latest.lock().unwrap();
+        // An error only means no stream is open
+        if sum != latest.sum {
+            latest.sum = sum;
+            let _ = self.sender.send(GlobalEvent::Sum(sum));
+        }
+        if count != latest.count {
+            latest.count = count;
+            let _ = self.sender.send(GlobalEvent::Count(count));
+            let milestone = count / MILESTONE_EVERY * MILESTONE_EVERY;
+            if milestone > latest.milestone {
+                latest.milestone = milestone;
+                let _ = self.sender.send(GlobalEvent::Milestone(milestone));
+            }
+        }
+        if viewers != latest.viewers {
+            latest.viewers = viewers;
+            let _ = self.sender.send(GlobalEvent::Viewers(viewers));
+        }
+    }
+
+    fn announce(&self, announcement: Option<Announcement>) {
+        let mut latest = self.latest.lock().unwrap();
+        latest.announcement.clone_from(&announcement);
+        let _ = self.sender.send(GlobalEvent::Announce(announcement));
+    }
+
+    /// Reads the board every interval, and passes on every change to the announcement
+    pub async fn run(
+        self: Arc<Self>,
+        bitmap: Arc<SharedBitmap>,
+        subscriptions: Arc<SubscriptionLimits>,
+        announcements: Arc<Announcements>,
+    ) {
+        let mut announcements = pin!(announcements.subscribe());
+        let mut next_read = Instant::now();
+        loop {
+            tokio::select! {
+                () = tokio::time::sleep_until(next_read) => {
+                    self.read(&bitmap, &subscriptions);
+                    next_read = Instant::now() + self.interval_for(subscriptions.open());
+                }
+                Some(announcement) = announcements.next() => self.announce(announcement),
+            }
+        }
+    }
+}
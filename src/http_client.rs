@@ -0,0 +1,237 @@
+//! A minimal keep-alive HTTP/1.1 client, just enough to talk to our own server, for the load
+//! generator and for instances of a cluster talking to each other
+
+use std::{fmt, io};
+
+use axum::http::Uri;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// A server to talk to, split out of an `http://host[:port][/prefix]` url
+#[derive(Debug, Clone)]
+pub struct Target {
+    authority: String,
+    prefix: String,
+}
+
+impl Target {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let uri: Uri = url
+            .parse()
+            .map_err(|e| format!("invalid url {url:?}: {e}"))?;
+        if uri.scheme_str() != Some("http") {
+            return Err(format!("only http:// urls are supported, got {url:?}"));
+        }
+        let authority = uri
+            .authority()
+            .filter(|authority| !authority.host().is_empty())
+            .ok_or_else(|| format!("missing host in {url:?}"))?;
+        Ok(Self {
+            // An IPv6 host keeps its brackets, as connecting needs them too
+            authority: format!(
+                "{}:{}",
+                authority.host(),
+                authority.port_u16().unwrap_or(80)
+            ),
+            prefix: uri.path().trim_end_matches('/').to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}{}", self.authority, self.prefix)
+    }
+}
+
+/// The parts of a response head we care about
+pub struct Head {
+    pub status: u16,
+    pub content_length: Option<usize>,
+    /// Whether the body uses chunked transfer encoding
+    pub chunked: bool,
+}
+
+pub struct Connection {
+    stream: TcpStream,
+    /// Bytes received but not yet consumed
+    pub buf: Vec<u8>,
+}
+
+impl Connection {
+    pub async fn connect(target: &Target) -> io::Result<Self> {
+        let stream = TcpStream::connect(&target.authority).await?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream,
+            buf: Vec::with_capacity(4096),
+        })
+    }
+
+    /// Whether the server closed the connection, or sent something we didn't ask for, while it
+    /// sat idle, so it's no use for another request
+    pub fn is_stale(&self) -> bool {
+        let mut byte = [0; 1];
+        !matches!(
+            self.stream.try_read(&mut byte),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock
+        )
+    }
+
+    pub async fn send(
+        &mut self,
+        target: &Target,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<()> {
+        let path = format!("{}{path}", target.prefix);
+        let mut req = format!(
+            "{method} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n",
+            if path.is_empty() { "/" } else { &path },
+            target.authority,
+            body.len()
+        );
+        for (name, value) in headers {
+            req.push_str(&format!("{name}: {value}\r\n"));
+        }
+        req.push_str("\r\n");
+        let mut req = req.into_bytes();
+        req.extend_from_slice(body);
+        self.stream.write_all(&req).await
+    }
+
+    /// Reads more of the response into `buf`
+    pub async fn fill(&mut self) -> io::Result<()> {
+        let n = self.stream.read_buf(&mut self.buf).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    pub async fn read_head(&mut self) -> io::Result<Head> {
+        loop {
+            let mut headers = [httparse::EMPTY_HEADER; 32];
+            let mut res = httparse::Response::new(&mut headers);
+            let status = res
+                .parse(&self.buf)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if let httparse::Status::Complete(head_len) = status {
+                let header = |name: &str| {
+                    res.headers
+                        .iter()
+                        .find(|h| h.name.eq_ignore_ascii_case(name))
+                        .and_then(|h| std::str::from_utf8(h.value).ok())
+                        .map(str::trim)
+                };
+                let head = Head {
+                    status: res.code.unwrap_or_default(),
+                    content_length: header("content-length").and_then(|len| len.parse().ok()),
+                    chunked: header("transfer-encoding")
+                        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked")),
+                };
+                self.buf.drain(..head_len);
+                return Ok(head);
+            }
+            self.fill().await?;
+        }
+    }
+
+    pub async fn skip_body(&mut self, len: usize) -> io::Result<()> {
+        while self.buf.len() < len {
+            self.fill().await?;
+        }
+        self.buf.drain(..len);
+        Ok(())
+    }
+
+    /// Reads the whole body of a response with the given head
+    pub async fn read_body(&mut self, head: &Head) -> io::Result<Vec<u8>> {
+        if head.chunked {
+            let mut body = Vec::new();
+            while let Some(data) = self.read_chunk().await? {
+                body.extend_from_slice(&data);
+            }
+            return Ok(body);
+        }
+        let len = head.content_length.unwrap_or(0);
+        while self.buf.len() < len {
+            self.fill().await?;
+        }
+        Ok(self.buf.drain(..len).collect())
+    }
+
+    /// Reads the next piece of a chunked body, or `None` at the end of the body
+    pub async fn read_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let size_end = loop {
+            if let Some(pos) = self.buf.windows(2).position(|w| w == b"\r\n") {
+                break pos;
+            }
+            self.fill().await?;
+        };
+        let size_line = std::str::from_utf8(&self.buf[..size_end])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // Chunk extensions follow a `;`, and we have no use for them
+        let size = size_line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let total = size_end + 2 + size + 2;
+        while self.buf.len() < total {
+            self.fill().await?;
+        }
+        let data = self.buf[size_end + 2..size_end + 2 + size].to_vec();
+        self.buf.drain(..total);
+        // The last chunk is empty, and we don't send trailers
+        Ok((size != 0).then_some(data))
+    }
+}
+
+#[cfg(all(test, not(sliders_loom)))]
+mod tests {
+    use super::*;
+
+    fn parsed(url: &str) -> (String, String) {
+        let target = Target::parse(url).unwrap();
+        (target.authority, target.prefix)
+    }
+
+    #[test]
+    fn parse_defaults_port() {
+        assert_eq!(
+            parsed("http://example.com"),
+            ("example.com:80".into(), "".into())
+        );
+        assert_eq!(
+            parsed("http://example.com:8000/"),
+            ("example.com:8000".into(), "".into())
+        );
+        assert_eq!(
+            parsed("http://10.0.0.2/sliders/"),
+            ("10.0.0.2:80".into(), "/sliders".into())
+        );
+    }
+
+    #[test]
+    fn parse_bracketed_ipv6() {
+        assert_eq!(parsed("http://[::1]/"), ("[::1]:80".into(), "".into()));
+        assert_eq!(
+            parsed("http://[::1]:8000/a/b"),
+            ("[::1]:8000".into(), "/a/b".into())
+        );
+        assert_eq!(
+            parsed("http://[fe80::1]"),
+            ("[fe80::1]:80".into(), "".into())
+        );
+    }
+
+    #[test]
+    fn parse_rejects_other_urls() {
+        assert!(Target::parse("https://example.com").is_err());
+        assert!(Target::parse("example.com:8000").is_err());
+        assert!(Target::parse("http://").is_err());
+        assert!(Target::parse("http://[::1/").is_err());
+    }
+}
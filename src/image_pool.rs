@@ -0,0 +1,138 @@
+//! A small pool of threads of its own for decoding and resizing uploaded pictures, so a burst of
+//! uploads queues up here rather than tying up tokio's blocking threads, which file IO and the
+//! other background work share. Jobs beyond the queue's capacity are refused straight away.
+
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+use axum::http::StatusCode;
+use tokio::sync::oneshot;
+
+use crate::reporting;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Debug)]
+pub enum PoolError {
+    /// Every thread is busy and the queue is full
+    Saturated,
+    /// The job panicked
+    Panicked,
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::Saturated => f.write_str("image pool is saturated"),
+            PoolError::Panicked => f.write_str("image job panicked"),
+        }
+    }
+}
+
+impl PoolError {
+    /// The response to a request whose job couldn't run, `what` describing the job if it failed
+    pub fn response(self, what: &'static str) -> (StatusCode, &'static str) {
+        match self {
+            PoolError::Saturated => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many pictures are being processed, try again later",
+            ),
+            PoolError::Panicked => reporting::internal_error(what, self),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    queued: AtomicUsize,
+    running: AtomicUsize,
+    rejected: AtomicU64,
+}
+
+pub struct ImagePool {
+    tx: SyncSender<Job>,
+    threads: usize,
+    counters: Arc<Counters>,
+}
+
+#[derive(serde::Serialize)]
+pub struct ImagePoolStats {
+    threads: usize,
+    /// Jobs waiting for a thread
+    queued: usize,
+    running: usize,
+    /// Jobs refused since startup because the queue was full
+    rejected: u64,
+}
+
+impl ImagePool {
+    /// Starts `threads` threads, with room for `queue` jobs waiting on them
+    pub fn new(threads: usize, queue: usize) -> Self {
+        let threads = threads.max(1);
+        let (tx, rx) = mpsc::sync_channel::<Job>(queue);
+        let rx = Arc::new(Mutex::new(rx));
+        let counters = Arc::new(Counters::default());
+        for i in 0..threads {
+            let rx = Arc::clone(&rx);
+            let counters = Arc::clone(&counters);
+            std::thread::Builder::new()
+                .name(format!("image-{i}"))
+                .spawn(move || loop {
+                    // The lock is only held while waiting, not while running the job
+                    let Ok(job) = rx.lock().unwrap().recv() else {
+                        return;
+                    };
+                    counters.queued.fetch_sub(1, Ordering::Relaxed);
+                    counters.running.fetch_add(1, Ordering::Relaxed);
+                    job();
+                    counters.running.fetch_sub(1, Ordering::Relaxed);
+                })
+                .expect("failed to start an image thread");
+        }
+        Self {
+            tx,
+            threads,
+            counters,
+        }
+    }
+
+    /// Runs `job` on one of the pool's threads, unless the pool is saturated
+    pub async fn run<T: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<T, PoolError> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            // The caller gives up on the result if it has gone away, and a panic shouldn't take
+            // the thread with it
+            let _ = result_tx.send(catch_unwind(AssertUnwindSafe(job)));
+        });
+        self.counters.queued.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = self.tx.try_send(job) {
+            self.counters.queued.fetch_sub(1, Ordering::Relaxed);
+            match e {
+                TrySendError::Full(_) => {
+                    self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+                    return Err(PoolError::Saturated);
+                }
+                TrySendError::Disconnected(_) => unreachable!("the pool's threads never exit"),
+            }
+        }
+        match result_rx.await {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(_)) | Err(_) => Err(PoolError::Panicked),
+        }
+    }
+
+    pub fn stats(&self) -> ImagePoolStats {
+        ImagePoolStats {
+            threads: self.threads,
+            queued: self.counters.queued.load(Ordering::Relaxed),
+            running: self.counters.running.load(Ordering::Relaxed),
+            rejected: self.counters.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! Per-route latency histograms, for watching percentiles without scraping trace logs, and
+//! histograms of how long board changes take to reach watchers
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use axum::extract::{MatchedPath, Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Serialize;
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::SharedState;
+
+// Each power of two is split into this many buckets, so percentiles are within 12.5%
+const SUB_BUCKETS: u64 = 8;
+// Enough to cover a couple of minutes in microseconds
+const NUM_BUCKETS: usize = 200;
+// Requests that didn't match a route, mostly static files
+const OTHER_ROUTE: &str = "other";
+
+/// Latencies in microseconds, bucketed by magnitude
+struct Histogram {
+    buckets: [AtomicU64; NUM_BUCKETS],
+    count: AtomicU64,
+    total_micros: AtomicU64,
+}
+
+fn bucket_index(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let shift = micros.ilog2() - SUB_BUCKETS.ilog2();
+    let sub_bucket = (micros >> shift) - SUB_BUCKETS;
+    ((u64::from(shift) + 1) * SUB_BUCKETS + sub_bucket).min(NUM_BUCKETS as u64 - 1) as usize
+}
+
+/// The largest latency which lands in bucket `index`
+fn bucket_max(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let shift = index / SUB_BUCKETS - 1;
+    let sub_bucket = index % SUB_BUCKETS;
+    ((SUB_BUCKETS + sub_bucket + 1) << shift) - 1
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; NUM_BUCKETS],
+            count: AtomicU64::new(0),
+            total_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, latency: Duration) {
+        let micros = latency.as_micros().try_into().unwrap_or(u64::MAX);
+        self.buckets[bucket_index(micros)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn summary(&self) -> RouteLatency {
+        let buckets: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        let count: u64 = buckets.iter().sum();
+        let quantile = |q: f64| {
+            let rank = (count as f64 * q).ceil().max(1.0) as u64;
+            let mut seen = 0;
+            for (i, &in_bucket) in buckets.iter().enumerate() {
+                seen += in_bucket;
+                if seen >= rank {
+                    return bucket_max(i);
+                }
+            }
+            0
+        };
+        RouteLatency {
+            count,
+            total_micros: self.total_micros.load(Ordering::Relaxed),
+            p50_micros: quantile(0.5),
+            p95_micros: quantile(0.95),
+            p99_micros: quantile(0.99),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteLatency {
+    pub count: u64,
+    pub total_micros: u64,
+    pub p50_micros: u64,
+    pub p95_micros: u64,
+    pub p99_micros: u64,
+}
+
+/// Time to response head of every request, grouped by route. For streaming routes, that's only
+/// how long it took to set the stream up.
+pub struct LatencyStats {
+    routes: RwLock<HashMap<String, Arc<Histogram>>>,
+    slow_threshold: Option<Duration>,
+}
+
+impl LatencyStats {
+    /// Requests taking longer than `slow_threshold` are logged, if given
+    pub fn new(slow_threshold: Option<Duration>) -> Self {
+        Self {
+            routes: RwLock::default(),
+            slow_threshold,
+        }
+    }
+
+    fn histogram(&self, route: &str) -> Arc<Histogram> {
+        if let Some(histogram) = self.routes.read().unwrap().get(route) {
+            return Arc::clone(histogram);
+        }
+        let mut routes = self.routes.write().unwrap();
+        Arc::clone(
+            routes
+                .entry(route.to_owned())
+                .or_insert_with(|| Arc::new(Histogram::new())),
+        )
+    }
+
+    pub fn summaries(&self) -> BTreeMap<String, RouteLatency> {
+        let routes = self.routes.read().unwrap();
+        routes
+            .iter()
+            .map(|(route, histogram)| (route.clone(), histogram.summary()))
+            .collect()
+    }
+
+    /// The summaries in the Prometheus text exposition format
+    pub fn prometheus(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP sliders_request_duration_seconds Time to response head by route\n");
+        out.push_str("# TYPE sliders_request_duration_seconds summary\n");
+        for (route, latency) in self.summaries() {
+            let route = route.replace('\\', "\\\\").replace('"', "\\\"");
+            write_summary(
+                &mut out,
+                "sliders_request_duration_seconds",
+                &format!("route=\"{route}\""),
+                &latency,
+            );
+        }
+        out
+    }
+}
+
+/// Writes one labeled Prometheus summary, in seconds
+fn write_summary(out: &mut String, name: &str, labels: &str, latency: &RouteLatency) {
+    for (quantile, micros) in [
+        ("0.5", latency.p50_micros),
+        ("0.95", latency.p95_micros),
+        ("0.99", latency.p99_micros),
+    ] {
+        let seconds = micros as f64 / 1e6;
+        writeln!(out, "{name}{{{labels},quantile=\"{quantile}\"}} {seconds}").unwrap();
+    }
+    let total_seconds = latency.total_micros as f64 / 1e6;
+    writeln!(out, "{name}_sum{{{labels}}} {total_seconds}").unwrap();
+    writeln!(out, "{name}_count{{{labels}}} {}", latency.count).unwrap();
+}
+
+/// How far a change to the board has got, timed from the first write to a chunk since its
+/// watchers were last sent its contents
+#[derive(Debug, Clone, Copy)]
+pub enum Stage {
+    /// The chunk's task woke up to send the change
+    Woken,
+    /// The change was sent to the chunk's watchers, after being coalesced with later writes
+    Sent,
+    /// A stream took the change to write it out to its client
+    Delivered,
+}
+
+impl Stage {
+    const ALL: [Stage; 3] = [Stage::Woken, Stage::Sent, Stage::Delivered];
+
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Woken => "woken",
+            Stage::Sent => "sent",
+            Stage::Delivered => "delivered",
+        }
+    }
+}
+
+/// Time for board changes to reach each [`Stage`], for tuning how long chunk updates are coalesced
+pub struct Propagation {
+    stages: [Histogram; 3],
+}
+
+impl Propagation {
+    pub fn new() -> Self {
+        Self {
+            stages: [Histogram::new(), Histogram::new(), Histogram::new()],
+        }
+    }
+
+    pub fn record(&self, stage: Stage, latency: Duration) {
+        self.stages[stage as usize].record(latency);
+    }
+
+    /// The summaries in the Prometheus text exposition format
+    pub fn prometheus(&self) -> String {
+        let mut out = String::new();
+        out.push_str(
+            "# HELP sliders_update_propagation_seconds Time from a write to each stage of sending it\n",
+        );
+        out.push_str("# TYPE sliders_update_propagation_seconds summary\n");
+        for stage in Stage::ALL {
+            write_summary(
+                &mut out,
+                "sliders_update_propagation_seconds",
+                &format!("stage=\"{}\"", stage.name()),
+                &self.stages[stage as usize].summary(),
+            );
+        }
+        out
+    }
+}
+
+/// Middleware recording the latency of every request
+pub async fn record(State(state): State<SharedState>, req: Request, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(OTHER_ROUTE, MatchedPath::as_str)
+        .to_owned();
+    let method = req.method().clone();
+    let start = Instant::now();
+    let response = next.run(req).await;
+    let elapsed = start.elapsed();
+
+    state.latency.histogram(&route).record(elapsed);
+    if state
+        .latency
+        .slow_threshold
+        .is_some_and(|threshold| elapsed > threshold)
+    {
+        warn!(%method, route, status = response.status().as_u16(), ?elapsed, "slow request");
+    }
+    response
+}
@@ -0,0 +1,1431 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{io, mem};
+
+use axum::body::Body;
+use axum::error_handling::HandleErrorLayer;
+use axum::extract::{ConnectInfo, DefaultBodyLimit, Path, Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::{sse, IntoResponse, Response, Sse};
+use axum::routing::{get, post, MethodRouter};
+use axum::{middleware, BoxError, Json, Router};
+use base64::prelude::BASE64_STANDARD_NO_PAD;
+use base64::Engine;
+use futures::{stream, Stream};
+use tokio::net::TcpListener;
+use tokio::sync::{watch, Semaphore};
+use tokio_stream::StreamExt;
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower::load_shed::LoadShedLayer;
+use tower::timeout::TimeoutLayer;
+use tower::ServiceBuilder;
+use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::compression::predicate::{DefaultPredicate, NotForContentType, Predicate};
+use tower_http::trace::{DefaultOnResponse, TraceLayer};
+use tower_http::LatencyUnit;
+use tracing::field::Empty;
+use tracing::{debug, error, info, Span};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+use crate::abuse::AbuseDetector;
+use crate::allocator::AllocatorStats;
+use crate::analysis::Analysis;
+use crate::announcement::{Announcement, Announcements};
+use crate::audit::AuditLog;
+use crate::bandwidth::{Bandwidth, BandwidthStats};
+use crate::bans::BanList;
+use crate::cdn::Cdn;
+use crate::chunk_hashes::ChunkHashes;
+use crate::client_config::ClientConfig;
+use crate::cluster::{Cluster, Writes};
+use crate::disk::DiskWatchdog;
+use crate::global_events::{GlobalEvent, GlobalEvents};
+use crate::image_pool::{ImagePool, ImagePoolStats};
+use crate::latency::{LatencyStats, RouteLatency, Stage};
+use crate::lifetime::{Lifetime, LifetimeTotals};
+use crate::overview::Overview;
+use crate::pause::Pauses;
+use crate::proxy::Origin;
+use crate::rate_limit::RateLimiter;
+use crate::shared_bitmap::{
+    SharedBitmap, SharedBitmapRunningTasks, VersionedChunk, CHUNK_BITS, CHUNK_BYTES,
+};
+use crate::snapshot::PrecompressedBoard;
+use crate::subscriptions::SubscriptionLimits;
+use crate::toggle_queue::{QueueFull, ToggleQueue};
+
+pub use crate::config::Config;
+
+mod abuse;
+mod admin;
+mod allocator;
+mod analysis;
+mod announcement;
+mod assets;
+mod audit;
+mod automaton;
+mod bandwidth;
+mod bans;
+mod byte_changes;
+mod canvas;
+mod cdn;
+mod chunk;
+mod chunk_hashes;
+mod client_config;
+mod cluster;
+mod comm;
+mod config;
+mod disk;
+mod global_events;
+mod http_client;
+mod image_pool;
+mod latency;
+mod lifetime;
+mod loadgen;
+mod merge;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod mux;
+mod overview;
+mod pause;
+mod picture;
+#[cfg(feature = "pprof")]
+mod profiling;
+mod proxy;
+mod rate_limit;
+mod reporting;
+mod request_id;
+mod rng;
+mod roaring;
+mod script;
+mod shared_bitmap;
+mod snapshot;
+mod subscriptions;
+mod toggle_queue;
+mod verify;
+
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+// One byte per slider
+const NUM_SLIDERS: usize = 1_000_000;
+const NUM_CHECKBOXES: usize = NUM_SLIDERS * 8;
+
+/// Widest range of bits, rounded out to whole chunks, one subscription may watch
+const MAX_SUBSCRIPTION_BITS: usize = 90_000;
+
+/// Everything the routes share, from [`start`]
+#[derive(Clone)]
+pub struct SharedState {
+    bitmap: Arc<SharedBitmap>,
+    subscriptions: Arc<SubscriptionLimits>,
+    lifetime: Arc<Lifetime>,
+    global_events: Arc<GlobalEvents>,
+    announcements: Arc<Announcements>,
+    chunk_hashes: Arc<ChunkHashes>,
+    pauses: Arc<Pauses>,
+    bandwidth: Arc<Bandwidth>,
+    bans: Arc<BanList>,
+    abuse: Arc<AbuseDetector>,
+    rate_limiter: Arc<RateLimiter>,
+    toggles: Arc<ToggleQueue>,
+    disk: Arc<DiskWatchdog>,
+    /// Threads for decoding uploaded pictures
+    images: Arc<ImagePool>,
+    /// The other instances sharing the board, if any
+    cluster: Option<Arc<Cluster>>,
+    /// The CDN in front of the read endpoints, if any
+    cdn: Option<Arc<Cdn>>,
+    analysis: Arc<Analysis>,
+    overview: Arc<Overview>,
+    precompressed: Arc<PrecompressedBoard>,
+    latency: Arc<LatencyStats>,
+    admin_token: Option<Arc<str>>,
+    audit: Arc<AuditLog>,
+    client_config: Arc<ClientConfig>,
+    /// Unix time the server started, to tell apart versions from before and after a restart
+    started_at: u64,
+    shutdown: Shutdown,
+    _tasks: Arc<SharedBitmapRunningTasks>,
+}
+
+impl SharedState {
+    fn new(config: &Config, shutdown: Shutdown) -> io::Result<Self> {
+        let bitmap = Arc::new(SharedBitmap::load_or_create("bitmap.bin")?);
+        let tasks = Arc::new(bitmap.spawn_tasks(config.flush_interval, config.max_notify_interval));
+
+        let subscriptions = Arc::new(SubscriptionLimits::new(
+            config.max_subscriptions,
+            config.max_subscriptions_per_ip,
+            config.max_subscribed_chunks_per_connection,
+            config.max_subscribed_chunks_per_ip,
+        ));
+
+        let lifetime = Arc::new(Lifetime::load_or_create(
+            "lifetime.json",
+            Arc::clone(&bitmap),
+            Arc::clone(&subscriptions),
+        )?);
+        let global_events = Arc::new(GlobalEvents::new(&bitmap, config.totals_interval));
+        let announcements = Arc::new(Announcements::new());
+        let chunk_hashes = Arc::new(ChunkHashes::new(Arc::clone(&bitmap)));
+        let pauses = Arc::new(Pauses::new());
+        let bandwidth = Arc::new(Bandwidth::new(config.bandwidth.clone()));
+        let bans = Arc::new(BanList::load_or_create("bans.json")?);
+        let admin_token = config.admin_token.as_deref().map(Arc::from);
+        let audit = Arc::new(AuditLog::open("audit.log")?);
+        let abuse = Arc::new(AbuseDetector::new(config.abuse.clone()));
+        let rate_limiter = Arc::new(RateLimiter::new(config.rate_limit.clone()));
+        let toggles = Arc::new(ToggleQueue::new(
+            Arc::clone(&bitmap),
+            config.toggle_queue_capacity,
+            config.toggle_queue_wait,
+        ));
+        let disk = Arc::new(DiskWatchdog::new(config.disk.clone()));
+        let images = Arc::new(ImagePool::new(config.image_threads, config.image_queue));
+        let cluster = config.cluster.clone().map(|c| Arc::new(Cluster::new(c)));
+        let cdn = config.cdn.clone().map(|c| Arc::new(Cdn::new(c)));
+        let analysis = Arc::new(Analysis::default());
+        let overview = Arc::new(Overview::new(&bitmap));
+        let precompressed = Arc::new(PrecompressedBoard::default());
+        let latency = Arc::new(LatencyStats::new(config.slow_request_threshold));
+        let client_config = Arc::new(ClientConfig::new(config));
+        let started_at = unix_now();
+
+        Ok(Self {
+            bitmap,
+            subscriptions,
+            lifetime,
+            global_events,
+            announcements,
+            chunk_hashes,
+            pauses,
+            bandwidth,
+            bans,
+            abuse,
+            rate_limiter,
+            toggles,
+            disk,
+            images,
+            cluster,
+            cdn,
+            analysis,
+            overview,
+            precompressed,
+            latency,
+            admin_token,
+            audit,
+            client_config,
+            started_at,
+            shutdown,
+            _tasks: tasks,
+        })
+    }
+}
+
+/// Resolves once the server starts shutting down, so long-lived streams can end instead of
+/// holding graceful shutdown open forever
+#[derive(Clone)]
+struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    async fn wait(mut self) {
+        // An error means the sender is gone, which only happens when we're exiting anyway
+        let _ = self.0.wait_for(|&shutting_down| shutting_down).await;
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Runs `loadgen` with the arguments after it, or otherwise serves the board in the working
+/// directory on the port given, 8000 by default, until interrupted
+pub async fn run() {
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer())
+        .with(EnvFilter::from_default_env())
+        .init();
+
+    let mut args = std::env::args().skip(1).peekable();
+    if args.next_if_eq("loadgen").is_some() {
+        match loadgen::LoadgenArgs::parse(args) {
+            Ok(args) => loadgen::run(args).await,
+            Err(e) => {
+                eprintln!("loadgen: {e}");
+                eprintln!(
+                    "usage: loadgen [URL] [--duration SECS] [--concurrency N] [--toggles WEIGHT] \
+                     [--set-bytes WEIGHT] [--subscriptions N] [--subscription-bits BITS]"
+                );
+                std::process::exit(2);
+            }
+        }
+        return;
+    }
+
+    let config = Config::from_env().unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(2);
+    });
+    let _reporter = reporting::init(config.sentry_dsn.as_deref());
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let state = start(&config, shutdown_rx).unwrap();
+    let app = router(state.clone(), &config);
+
+    let port: u16 = args
+        .next()
+        .and_then(|port_str| port_str.parse().ok())
+        .unwrap_or(8000);
+    let listener = TcpListener::bind((Ipv6Addr::UNSPECIFIED, port))
+        .await
+        .unwrap();
+
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        shutdown_tx.send_replace(true);
+    })
+    .await
+    .unwrap();
+
+    state.save();
+}
+
+/// Loads the board and the rest of the state kept in the working directory, and spawns the tasks
+/// which run alongside the routes. Long-lived streams end once `shutdown` is set.
+pub fn start(config: &Config, shutdown: watch::Receiver<bool>) -> io::Result<SharedState> {
+    let state = SharedState::new(config, Shutdown(shutdown))?;
+    let bitmap = Arc::clone(&state.bitmap);
+    tokio::spawn(Arc::clone(&state.lifetime).run(config.flush_interval));
+    tokio::spawn(Arc::clone(&state.global_events).run(
+        Arc::clone(&bitmap),
+        Arc::clone(&state.subscriptions),
+        Arc::clone(&state.announcements),
+    ));
+    tokio::spawn(Arc::clone(&state.analysis).run(Arc::clone(&bitmap)));
+    tokio::spawn(Arc::clone(&state.overview).run(Arc::clone(&bitmap)));
+    tokio::spawn(Arc::clone(&state.chunk_hashes).run());
+    tokio::spawn(verify::run(Arc::clone(&bitmap), config.verify_pass));
+    tokio::spawn(Arc::clone(&state.disk).run(Arc::clone(&bitmap)));
+    if let Some(cluster) = &state.cluster {
+        tokio::spawn(cluster::run_mirror(
+            Arc::clone(cluster),
+            Arc::clone(&bitmap),
+            state.shutdown.clone(),
+        ));
+    }
+    // These write to the whole board directly, which only works when this instance owns it all
+    let standalone = state.cluster.is_none();
+    if !standalone
+        && (config.automaton.rule != automaton::Rule::Off
+            || !config.automaton.decay_every.is_zero()
+            || config.mqtt.is_some())
+    {
+        tracing::warn!("the automaton, decay and mqtt bridge aren't supported in cluster mode");
+    }
+    if standalone {
+        tokio::spawn(automaton::run(
+            Arc::clone(&bitmap),
+            config.automaton.clone(),
+        ));
+        tokio::spawn(automaton::run_decay(
+            Arc::clone(&bitmap),
+            config.automaton.decay_every,
+        ));
+    }
+    if let Some(mqtt) = config.mqtt.clone().filter(|_| standalone) {
+        #[cfg(feature = "mqtt")]
+        tokio::spawn(mqtt::run(Arc::clone(&bitmap), mqtt));
+        #[cfg(not(feature = "mqtt"))]
+        tracing::warn!(
+            host = mqtt.host,
+            "SLIDERS_MQTT_HOST is set, but the server was built without the mqtt feature"
+        );
+    }
+    Ok(state)
+}
+
+/// Every route and its middleware, to serve as is or nest in another app. It must be served with
+/// `ConnectInfo<SocketAddr>`.
+pub fn router(state: SharedState, config: &Config) -> Router {
+    app(state, config).build()
+}
+
+/// Builds the app's routes like [`router`], but with groups of them left out, for embedding the
+/// board in an app which doesn't want them. Every group is included unless turned off.
+pub struct AppBuilder<'a> {
+    state: SharedState,
+    config: &'a Config,
+    images: bool,
+    admin: bool,
+}
+
+/// Starts building the app's routes, see [`AppBuilder`]
+pub fn app(state: SharedState, config: &Config) -> AppBuilder<'_> {
+    AppBuilder {
+        state,
+        config,
+        images: true,
+        admin: true,
+    }
+}
+
+impl AppBuilder<'_> {
+    /// Whether to serve the routes which render or decode pictures: `/image`, `/stamp`, and
+    /// `/admin/seed_image`
+    pub fn images(mut self, enabled: bool) -> Self {
+        self.images = enabled;
+        self
+    }
+
+    /// Whether to serve `/admin` and the other routes only the admin can use
+    pub fn admin(mut self, enabled: bool) -> Self {
+        self.admin = enabled;
+        self
+    }
+
+    /// The routes and their middleware, served with `ConnectInfo<SocketAddr>` like [`router`]'s
+    pub fn build(self) -> Router {
+        routes(self)
+    }
+}
+
+fn routes(
+    AppBuilder {
+        state,
+        config,
+        images,
+        admin,
+    }: AppBuilder,
+) -> Router {
+    let write_budget = Arc::new(Semaphore::new(config.max_concurrent_writes));
+    let subscribe_budget = Arc::new(Semaphore::new(config.max_concurrent_subscribes));
+    let timeout = config.request_timeout;
+
+    let writes = Router::new()
+        .route(
+            "/toggle/:idx",
+            with_timeout(with_budget(post(toggle), &write_budget), timeout),
+        )
+        .route(
+            "/toggle_if/:idx",
+            with_timeout(with_budget(post(toggle_if), &write_budget), timeout),
+        )
+        .route(
+            "/set_byte/:idx/:value",
+            with_timeout(with_budget(post(set_byte), &write_budget), timeout),
+        )
+        .route(
+            "/merge",
+            with_timeout(with_budget(post(merge::merge), &write_budget), timeout),
+        )
+        .route(
+            "/macro",
+            with_timeout(with_budget(post(script::run), &write_budget), timeout),
+        );
+    let writes = if images {
+        writes.route(
+            "/stamp",
+            with_timeout(
+                with_budget(post(picture::stamp), &write_budget)
+                    .layer(DefaultBodyLimit::max(picture::MAX_STAMP_BYTES)),
+                timeout,
+            ),
+        )
+    } else {
+        writes
+    };
+    let writes = writes
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::enforce,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            disk::reject_when_read_only,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            bans::reject_banned,
+        ));
+
+    let app = Router::new()
+        .route(
+            "/updates",
+            with_metering(with_budget(get(range_updates), &subscribe_budget), &state),
+        )
+        .route(
+            "/updates.ndjson",
+            with_metering(with_budget(get(updates_ndjson), &subscribe_budget), &state),
+        )
+        .route(
+            "/updates.bin",
+            with_metering(
+                with_budget(get(comm::updates_bin), &subscribe_budget),
+                &state,
+            ),
+        )
+        .route(
+            "/bootstrap",
+            with_metering(with_budget(get(comm::bootstrap), &subscribe_budget), &state),
+        )
+        .route(
+            "/canvas.ws",
+            with_budget(get(canvas::canvas_ws), &subscribe_budget),
+        )
+        .route("/mux.ws", with_budget(get(mux::mux_ws), &subscribe_budget))
+        .route(
+            "/updates/bytes",
+            with_metering(
+                with_budget(get(byte_changes::byte_changes), &subscribe_budget),
+                &state,
+            ),
+        )
+        .route("/updates/:id/pause", post(pause::pause))
+        .route("/updates/:id/resume", post(pause::resume))
+        .route(
+            "/overview/updates",
+            with_metering(
+                with_budget(get(overview::overview_updates), &subscribe_budget),
+                &state,
+            ),
+        )
+        .route(
+            "/snapshot",
+            with_budget(
+                get(snapshot::range_snapshot).head(snapshot::range_snapshot_head),
+                &subscribe_budget,
+            ),
+        )
+        .route(
+            "/snapshot/full",
+            get(snapshot::full_snapshot).head(snapshot::full_snapshot_head),
+        )
+        .route(
+            "/board.bin",
+            get(snapshot::board_bin).head(snapshot::board_bin_head),
+        )
+        .route("/bits.roaring", get(roaring::bits_roaring))
+        .route("/chunks/manifest", get(chunk_hashes::manifest))
+        .route("/chunks/:hash", get(chunk_hashes::chunk))
+        .route("/config.json", get(client_config::client_config))
+        .route("/sum", get(sum))
+        .route("/count", get(count))
+        .route("/announcement", get(announcement::announcement))
+        .route("/stats", get(stats))
+        .route("/metrics", get(metrics))
+        .route("/analysis", get(analysis::analysis))
+        .route("/overview.bin", get(overview::overview_bin))
+        .route("/overview.json", get(overview::overview_json))
+        .route("/last_modified", get(last_modified))
+        .route("/delta", get(delta));
+    let app = if images {
+        app.route(
+            "/image/:width/:height",
+            get(picture::render).head(picture::render_head),
+        )
+    } else {
+        app
+    };
+    let app = if admin {
+        #[cfg(feature = "pprof")]
+        let app = app.route(
+            "/debug/pprof",
+            get(profiling::pprof).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                admin::require_admin,
+            )),
+        );
+        app.nest("/admin", admin::router(state.clone(), images))
+    } else {
+        app
+    };
+    let app = app
+        .merge(writes)
+        .nest("/cluster", cluster::router(state.clone()))
+        .merge(assets::router())
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            latency::record,
+        ))
+        .layer(CatchPanicLayer::custom(reporting::panic_response))
+        .layer(middleware::from_fn(request_id::assign))
+        .layer(middleware::from_fn_with_state(
+            Arc::from(config.proxy.trusted.as_slice()),
+            proxy::resolve_client,
+        ))
+        .layer(
+            ServiceBuilder::new()
+                .layer(
+                    TraceLayer::new_for_http()
+                        .make_span_with(request_id::make_span)
+                        .on_response(DefaultOnResponse::new().latency_unit(LatencyUnit::Micros)),
+                )
+                .layer(tower_http::cors::CorsLayer::new().allow_origin(tower_http::cors::Any))
+                .layer(
+                    tower_http::compression::CompressionLayer::new()
+                        .gzip(true)
+                        .br(true)
+                        // The encoder buffers output, which would hold streamed updates back
+                        .compress_when(
+                            DefaultPredicate::new()
+                                .and(NotForContentType::const_new(NDJSON_CONTENT_TYPE))
+                                .and(NotForContentType::const_new(comm::CONTENT_TYPE)),
+                        ),
+                ),
+        );
+    let app = app.with_state(state);
+    // Behind a proxy which passes the prefix on rather than stripping it
+    match config.proxy.base_path.as_str() {
+        "" => app,
+        base_path => Router::new()
+            .fallback_service(app)
+            .layer(middleware::from_fn_with_state(
+                Arc::from(base_path),
+                proxy::strip_base_path,
+            )),
+    }
+}
+
+impl SharedState {
+    /// Writes the board and lifetime totals out to disk, for just before exiting
+    pub fn save(&self) {
+        info!("flushing bitmap before exit");
+        if let Err(e) = self.bitmap.flush() {
+            error!(error = %e, "failed to flush bitmap");
+        }
+        if let Err(e) = self.lifetime.save() {
+            error!(error = %e, "failed to save lifetime totals");
+        }
+    }
+}
+
+/// Caps concurrent requests across every route sharing `budget`, shedding the excess with a 503
+/// instead of letting it pile up on the runtime
+fn with_budget(
+    route: MethodRouter<SharedState>,
+    budget: &Arc<Semaphore>,
+) -> MethodRouter<SharedState> {
+    route.layer(
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(|_: BoxError| async {
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Server is overloaded, try again later",
+                )
+            }))
+            .layer(LoadShedLayer::new())
+            .layer(GlobalConcurrencyLimitLayer::with_semaphore(Arc::clone(
+                budget,
+            ))),
+    )
+}
+
+/// Counts the bytes sent down a streaming route's responses, closing any which go over budget
+fn with_metering(
+    route: MethodRouter<SharedState>,
+    state: &SharedState,
+) -> MethodRouter<SharedState> {
+    route.layer(middleware::from_fn_with_state(
+        state.clone(),
+        bandwidth::meter,
+    ))
+}
+
+/// Fails requests which haven't completed within `timeout`, only for routes which should finish
+/// quickly (i.e. not streams)
+fn with_timeout(route: MethodRouter<SharedState>, timeout: Duration) -> MethodRouter<SharedState> {
+    route.layer(
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(move |_: BoxError| async move {
+                (
+                    StatusCode::REQUEST_TIMEOUT,
+                    format!("Request timed out after {}ms", timeout.as_millis()),
+                )
+            }))
+            .layer(TimeoutLayer::new(timeout)),
+    )
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = tokio::signal::ctrl_c();
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<Option<()>>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("shutting down");
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug)]
+struct Range {
+    start: u64,
+    end: u64,
+}
+
+#[derive(serde::Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum UpdateFormat {
+    /// Event data is the bare base64 chunk, with its offset in the event id
+    #[default]
+    Base64,
+    /// Event data is a JSON object holding the offset alongside the chunk
+    Json,
+}
+
+/// How a chunk's change is measured, for subscribers only interested in large changes
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ChangeMeasure {
+    /// Checkboxes set or cleared on balance, the change in the chunk's popcount
+    Bits,
+    /// Sliders with a different value
+    Bytes,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct UpdatesParams {
+    start: u64,
+    end: u64,
+    #[serde(default)]
+    format: UpdateFormat,
+    /// Only send a chunk once it has changed by at least `min_change` by this measure since it
+    /// was last sent
+    only: Option<ChangeMeasure>,
+    #[serde(default = "default_min_change")]
+    min_change: u32,
+}
+
+fn default_min_change() -> u32 {
+    1
+}
+
+/// Drops chunk updates which changed the chunk too little since it was last sent. The first
+/// update for each chunk always passes, as the client has nothing to compare it to.
+struct ChangeFilter {
+    measure: ChangeMeasure,
+    min_change: u32,
+    sent: HashMap<usize, [u8; CHUNK_BYTES]>,
+}
+
+impl ChangeFilter {
+    fn passes(&mut self, i: usize, chunk: &[u8; CHUNK_BYTES]) -> bool {
+        let Some(prev) = self.sent.get(&i) else {
+            self.sent.insert(i, *chunk);
+            return true;
+        };
+        let change = match self.measure {
+            ChangeMeasure::Bits => {
+                let popcount = |bytes: &[u8]| bytes.iter().map(|b| b.count_ones()).sum::<u32>();
+                popcount(chunk).abs_diff(popcount(prev))
+            }
+            ChangeMeasure::Bytes => prev.iter().zip(chunk).filter(|(a, b)| a != b).count() as u32,
+        };
+        if change < self.min_change {
+            return false;
+        }
+        self.sent.insert(i, *chunk);
+        true
+    }
+}
+
+#[derive(serde::Serialize)]
+struct ChunkUpdate<'a> {
+    /// Index of the chunk's first checkbox
+    offset: u64,
+    /// The chunk's bytes, base64 encoded
+    bits: &'a str,
+    /// The chunk's version, larger for newer contents
+    version: u64,
+    /// The board's sequence number when the update was sent
+    seq: u64,
+}
+
+#[derive(serde::Serialize)]
+struct SumUpdate {
+    sum: u64,
+    seq: u64,
+}
+
+#[derive(serde::Serialize)]
+struct CountUpdate {
+    count: u64,
+    seq: u64,
+}
+
+#[derive(serde::Serialize)]
+struct MilestoneUpdate {
+    count: u64,
+    seq: u64,
+}
+
+#[derive(serde::Serialize)]
+struct ViewersUpdate {
+    viewers: usize,
+}
+
+#[derive(serde::Serialize)]
+struct AnnounceUpdate {
+    /// `None` if the announcement was taken down
+    announcement: Option<Announcement>,
+}
+
+/// The first event of a stream which can be paused, see [`pause`]
+#[derive(serde::Serialize)]
+struct StreamUpdate {
+    /// Passed to `/updates/:id/pause` and `/updates/:id/resume`
+    id: String,
+}
+
+#[derive(serde::Serialize)]
+struct ShutdownUpdate {
+    seq: u64,
+    /// How long to wait before reconnecting, spread out so clients don't all come back at once
+    reconnect_after_ms: u64,
+}
+
+#[derive(serde::Serialize)]
+struct EndUpdate {
+    seq: u64,
+}
+
+/// One event on an update stream
+enum Update {
+    /// The current contents of the chunk with the given index
+    Chunk(usize, VersionedChunk),
+    /// The new sum of all sliders
+    Sum(u64),
+    /// The new number of checked checkboxes
+    Count(u64),
+    /// The count climbed past another milestone, see [`global_events::MILESTONE_EVERY`]
+    Milestone(u64),
+    /// The new number of open update streams
+    Viewers(usize),
+    /// The announcement changed, or was taken down if `None`
+    Announce(Option<Announcement>),
+    /// The server has started shutting down, and the stream will end after a last update of each
+    /// chunk. Carries the suggested delay before reconnecting.
+    Shutdown(Duration),
+    /// The server is shutting down, and this is the last event
+    End,
+}
+
+// Clients are told to reconnect after a shutdown somewhere in this range, long enough for a
+// restarted server to be back up
+const RECONNECT_AFTER_MIN: Duration = Duration::from_secs(2);
+const RECONNECT_AFTER_SPREAD: Duration = Duration::from_secs(8);
+
+/// Subscribes to changes to every chunk overlapping the range, along with the board-wide
+/// [`GlobalEvent`]s, holding one of the client's subscription slots until the stream is dropped. When the server
+/// shuts down, the stream finishes with an `Update::Shutdown`, then the latest contents of every
+/// chunk, even ones which changed too recently to have been sent, and then an `Update::End`.
+/// Board updates are held back while `paused` is true, see [`pause`].
+fn subscribe_updates(
+    state: SharedState,
+    origin: Origin,
+    range: Range,
+    paused: watch::Receiver<bool>,
+) -> Result<impl Stream<Item = Update>, (StatusCode, &'static str)> {
+    if range.start > range.end {
+        return Err((StatusCode::BAD_REQUEST, "start must be less than end"));
+    }
+    if range.end > NUM_CHECKBOXES as u64 {
+        return Err((StatusCode::BAD_REQUEST, "end too large"));
+    }
+    let std::ops::Range {
+        start: start_chunk,
+        end: end_chunk,
+    } = chunk::overlapping(range.start, range.end);
+    if (end_chunk - start_chunk) * CHUNK_BITS > MAX_SUBSCRIPTION_BITS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Cannot listen to such a large range",
+        ));
+    }
+
+    let subscription = state
+        .subscriptions
+        .try_acquire(origin, end_chunk - start_chunk)
+        .map_err(|message| (StatusCode::TOO_MANY_REQUESTS, message))?;
+
+    // Recorded on the handler's span, for handlers which declare these fields
+    let span = Span::current();
+    span.record("chunks", end_chunk - start_chunk);
+    span.record("subscribers", state.subscriptions.open());
+    let chunk_updates = Arc::new(AtomicU64::new(0));
+    let watches = (start_chunk..end_chunk).map(|i| {
+        let span = span.clone();
+        let chunk_updates = Arc::clone(&chunk_updates);
+        let bitmap = Arc::clone(&state.bitmap);
+        // The first is the contents as of subscribing rather than a change as it's made
+        let mut initial = true;
+        tokio_stream::wrappers::WatchStream::new(state.bitmap.watch(i)).map(move |chunk| {
+            debug!(parent: &span, i, "going to send a chunk update");
+            chunk_updates.fetch_add(1, Ordering::Relaxed);
+            let was_initial = mem::take(&mut initial);
+            if let Some(changed_at) = chunk.changed_at.filter(|_| !was_initial) {
+                bitmap
+                    .propagation()
+                    .record(Stage::Delivered, changed_at.elapsed());
+            }
+            Update::Chunk(i, chunk)
+        })
+    });
+    let stream = stream::select_all(watches);
+
+    /// Logs a summary of the subscription once it ends
+    struct LogOnDisconnect {
+        span: Span,
+        subscribed_at: Instant,
+        chunk_updates: Arc<AtomicU64>,
+    }
+    impl Drop for LogOnDisconnect {
+        fn drop(&mut self) {
+            debug!(
+                parent: &self.span,
+                duration_ms = self.subscribed_at.elapsed().as_millis() as u64,
+                chunk_updates = self.chunk_updates.load(Ordering::Relaxed),
+                "client disconnected",
+            );
+        }
+    }
+    let log_on_disconnect = LogOnDisconnect {
+        span: span.clone(),
+        subscribed_at: Instant::now(),
+        chunk_updates,
+    };
+    let global = state.global_events.subscribe().map(move |event| {
+        // Move the logger and subscription slot into the closure to ensure they're dropped
+        // when the stream ends
+        let _log_on_disconnect = &log_on_disconnect;
+        let _subscription = &subscription;
+        debug!(parent: &span, ?event, "going to send a global event");
+        match event {
+            GlobalEvent::Sum(sum) => Update::Sum(sum),
+            GlobalEvent::Count(count) => Update::Count(count),
+            GlobalEvent::Milestone(count) => Update::Milestone(count),
+            GlobalEvent::Viewers(viewers) => Update::Viewers(viewers),
+            GlobalEvent::Announce(announcement) => Update::Announce(announcement),
+        }
+    });
+    let stream = pause::gate(stream::select(global, stream), paused);
+    let stream = futures::StreamExt::take_until(stream, state.shutdown.wait());
+    let reconnect_after = RECONNECT_AFTER_MIN
+        + Duration::from_millis(
+            rng::Rng::seeded(u64::from(origin.connection.port()))
+                .below(RECONNECT_AFTER_SPREAD.as_millis() as u64),
+        );
+    // Only runs once the stream above has ended, so reads the chunks as of shutdown
+    let final_chunks = stream::iter(start_chunk..end_chunk)
+        .map(move |i| Update::Chunk(i, state.bitmap.refresh(i)));
+    Ok(stream
+        .chain(stream::once(
+            async move { Update::Shutdown(reconnect_after) },
+        ))
+        .chain(final_chunks)
+        .chain(stream::once(async { Update::End })))
+}
+
+type Base64ChunkBuffer = [u8; CHUNK_BYTES * 4 / 3 + 4];
+
+fn encode_chunk<'a>(chunk: &[u8; CHUNK_BYTES], buf: &'a mut Base64ChunkBuffer) -> &'a str {
+    let len = BASE64_STANDARD_NO_PAD
+        .encode_slice(chunk, buf)
+        .expect("a chunk is guaranteed to fit in the available space");
+    // SAFETY: base64 encoding is guaranteed to be valid UTF-8
+    unsafe { std::str::from_utf8_unchecked(&buf[..len]) }
+}
+
+#[tracing::instrument(
+    skip(state, params),
+    fields(start=params.start, end=params.end, chunks=Empty, subscribers=Empty)
+)]
+async fn range_updates(
+    State(state): State<SharedState>,
+    origin: Origin,
+    Query(params): Query<UpdatesParams>,
+) -> axum::response::Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>> {
+    let range = Range {
+        start: params.start,
+        end: params.end,
+    };
+    let format = params.format;
+    let mut filter = params.only.map(|measure| ChangeFilter {
+        measure,
+        min_change: params.min_change,
+        sent: HashMap::new(),
+    });
+    let bitmap = Arc::clone(&state.bitmap);
+    let pause_handle = state.pauses.register(origin.client);
+    let stream_id = pause_handle.id();
+    let updates = subscribe_updates(state, origin, range, pause_handle.paused())?.filter(
+        move |update| match (update, &mut filter) {
+            (Update::Chunk(i, chunk), Some(filter)) => filter.passes(*i, &chunk.bytes),
+            _ => true,
+        },
+    );
+
+    let mut b64_chunk = [0; CHUNK_BYTES * 4 / 3 + 4];
+    let mut int_buffer = itoa::Buffer::new();
+    let mut id = String::new();
+    let stream = updates.map(move |update| {
+        // Keeps the stream pausable for as long as it's open
+        let _pause_handle = &pause_handle;
+        let event = match update {
+            Update::Chunk(i, chunk) => {
+                let b64_chunk = encode_chunk(&chunk.bytes, &mut b64_chunk);
+                let offset = i as u64 * CHUNK_BITS as u64;
+                let event = match format {
+                    UpdateFormat::Base64 => sse::Event::default().data(b64_chunk),
+                    UpdateFormat::Json => sse::Event::default()
+                        .json_data(ChunkUpdate {
+                            offset,
+                            bits: b64_chunk,
+                            version: chunk.version,
+                            seq: bitmap.sequence(),
+                        })
+                        .expect("serializing an update can't fail"),
+                };
+                // Clients parsing the id as a number still get the offset
+                id.clear();
+                id.push_str(int_buffer.format(offset));
+                id.push(':');
+                id.push_str(int_buffer.format(chunk.version));
+                event.id(&*id).event("update")
+            }
+            Update::Sum(sum) => {
+                let event = match format {
+                    UpdateFormat::Base64 => sse::Event::default().data(int_buffer.format(sum)),
+                    UpdateFormat::Json => sse::Event::default()
+                        .json_data(SumUpdate {
+                            sum,
+                            seq: bitmap.sequence(),
+                        })
+                        .expect("serializing an update can't fail"),
+                };
+                event.event("sum")
+            }
+            Update::Count(count) => {
+                let event = match format {
+                    UpdateFormat::Base64 => sse::Event::default().data(int_buffer.format(count)),
+                    UpdateFormat::Json => sse::Event::default()
+                        .json_data(CountUpdate {
+                            count,
+                            seq: bitmap.sequence(),
+                        })
+                        .expect("serializing an update can't fail"),
+                };
+                event.event("count")
+            }
+            Update::Milestone(count) => sse::Event::default()
+                .json_data(MilestoneUpdate {
+                    count,
+                    seq: bitmap.sequence(),
+                })
+                .expect("serializing an update can't fail")
+                .event("milestone"),
+            Update::Viewers(viewers) => {
+                let event = match format {
+                    UpdateFormat::Base64 => sse::Event::default().data(int_buffer.format(viewers)),
+                    UpdateFormat::Json => sse::Event::default()
+                        .json_data(ViewersUpdate { viewers })
+                        .expect("serializing an update can't fail"),
+                };
+                event.event("viewers")
+            }
+            Update::Announce(announcement) => sse::Event::default()
+                .json_data(AnnounceUpdate { announcement })
+                .expect("serializing an update can't fail")
+                .event("announce"),
+            Update::Shutdown(reconnect_after) => sse::Event::default()
+                .json_data(ShutdownUpdate {
+                    seq: bitmap.sequence(),
+                    reconnect_after_ms: reconnect_after.as_millis() as u64,
+                })
+                .expect("serializing an update can't fail")
+                // Browsers' `EventSource` waits this long before reconnecting by itself
+                .retry(reconnect_after)
+                .event("server_shutdown"),
+            Update::End => {
+                let event = match format {
+                    UpdateFormat::Base64 => sse::Event::default().data(""),
+                    UpdateFormat::Json => sse::Event::default()
+                        .json_data(EndUpdate {
+                            seq: bitmap.sequence(),
+                        })
+                        .expect("serializing an update can't fail"),
+                };
+                event.event("end")
+            }
+        };
+        Ok(event)
+    });
+    let stream_event = match format {
+        UpdateFormat::Base64 => sse::Event::default().data(&stream_id),
+        UpdateFormat::Json => sse::Event::default()
+            .json_data(StreamUpdate { id: stream_id })
+            .expect("serializing an update can't fail"),
+    };
+    let stream = stream::once(async { Ok(stream_event.event("stream")) }).chain(stream);
+
+    Ok(Sse::new(stream).keep_alive(sse::KeepAlive::new()))
+}
+
+/// A line of `/updates.ndjson`
+#[derive(serde::Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum NdjsonUpdate<'a> {
+    Stream(StreamUpdate),
+    Update(ChunkUpdate<'a>),
+    Sum(SumUpdate),
+    Count(CountUpdate),
+    Milestone(MilestoneUpdate),
+    Viewers(ViewersUpdate),
+    Announce(AnnounceUpdate),
+    #[serde(rename = "server_shutdown")]
+    ServerShutdown(ShutdownUpdate),
+    End(EndUpdate),
+}
+
+fn ndjson_line(update: &NdjsonUpdate) -> Vec<u8> {
+    let mut line = serde_json::to_vec(update).expect("serializing an update can't fail");
+    line.push(b'\n');
+    line
+}
+
+/// The same events as `/updates`, as newline delimited JSON for clients without an SSE parser
+#[tracing::instrument(
+    skip(state, range),
+    fields(start=range.start, end=range.end, chunks=Empty, subscribers=Empty)
+)]
+async fn updates_ndjson(
+    State(state): State<SharedState>,
+    origin: Origin,
+    Query(range): Query<Range>,
+) -> axum::response::Result<impl IntoResponse> {
+    let bitmap = Arc::clone(&state.bitmap);
+    let pause_handle = state.pauses.register(origin.client);
+    let stream_id = pause_handle.id();
+    let updates = subscribe_updates(state, origin, range, pause_handle.paused())?;
+
+    let mut b64_chunk = [0; CHUNK_BYTES * 4 / 3 + 4];
+    let lines = updates.map(move |update| {
+        // Keeps the stream pausable for as long as it's open
+        let _pause_handle = &pause_handle;
+        let seq = bitmap.sequence();
+        let line = match update {
+            Update::Chunk(i, chunk) => NdjsonUpdate::Update(ChunkUpdate {
+                offset: i as u64 * CHUNK_BITS as u64,
+                bits: encode_chunk(&chunk.bytes, &mut b64_chunk),
+                version: chunk.version,
+                seq,
+            }),
+            Update::Sum(sum) => NdjsonUpdate::Sum(SumUpdate { sum, seq }),
+            Update::Count(count) => NdjsonUpdate::Count(CountUpdate { count, seq }),
+            Update::Milestone(count) => NdjsonUpdate::Milestone(MilestoneUpdate { count, seq }),
+            Update::Viewers(viewers) => NdjsonUpdate::Viewers(ViewersUpdate { viewers }),
+            Update::Announce(announcement) => {
+                NdjsonUpdate::Announce(AnnounceUpdate { announcement })
+            }
+            Update::Shutdown(reconnect_after) => NdjsonUpdate::ServerShutdown(ShutdownUpdate {
+                seq,
+                reconnect_after_ms: reconnect_after.as_millis() as u64,
+            }),
+            Update::End => NdjsonUpdate::End(EndUpdate { seq }),
+        };
+        Ok::<_, Infallible>(ndjson_line(&line))
+    });
+    let stream_line = ndjson_line(&NdjsonUpdate::Stream(StreamUpdate { id: stream_id }));
+    let lines = stream::once(async { Ok(stream_line) }).chain(lines);
+    Ok((
+        [(header::CONTENT_TYPE, NDJSON_CONTENT_TYPE)],
+        Body::from_stream(lines),
+    ))
+}
+
+#[derive(serde::Serialize)]
+struct LastModified {
+    /// Index of the first checkbox in the first chunk
+    start: u64,
+    chunk_bits: usize,
+    /// Unix time each chunk last changed, or null if it hasn't since the server started
+    chunks: Vec<Option<u64>>,
+}
+
+/// When each chunk overlapping the range was last changed
+#[tracing::instrument(skip(state, range), fields(start=range.start, end=range.end))]
+async fn last_modified(
+    State(state): State<SharedState>,
+    Query(range): Query<Range>,
+) -> axum::response::Result<Json<LastModified>> {
+    if range.start > range.end {
+        return Err((StatusCode::BAD_REQUEST, "start must be less than end").into());
+    }
+    if range.end > NUM_CHECKBOXES as u64 {
+        return Err((StatusCode::BAD_REQUEST, "end too large").into());
+    }
+    let std::ops::Range {
+        start: start_chunk,
+        end: end_chunk,
+    } = chunk::overlapping(range.start, range.end);
+    Ok(Json(LastModified {
+        start: (start_chunk * CHUNK_BITS) as u64,
+        chunk_bits: CHUNK_BITS,
+        chunks: (start_chunk..end_chunk)
+            .map(|i| state.bitmap.last_modified(i))
+            .collect(),
+    }))
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct DeltaParams {
+    start: u64,
+    end: u64,
+    /// Version the client is already up to date with, as of an earlier delta, update, or snapshot
+    since_seq: u64,
+}
+
+#[derive(serde::Serialize)]
+struct Delta {
+    /// Version to pass as `since_seq` next time
+    version: u64,
+    /// Chunks in the range with a newer version than `since_seq`
+    chunks: Vec<DeltaChunk>,
+}
+
+#[derive(serde::Serialize)]
+struct DeltaChunk {
+    /// Index of the chunk's first checkbox
+    offset: u64,
+    /// The chunk's bytes, base64 encoded
+    bits: String,
+    version: u64,
+}
+
+/// The chunks overlapping the range which changed since the given version, so a reconnecting
+/// client only has to fetch what it missed
+#[tracing::instrument(
+    skip(state, params),
+    fields(
+        start=params.start,
+        end=params.end,
+        since=params.since_seq,
+        chunks=Empty,
+        bytes=Empty,
+        encode_us=Empty,
+    )
+)]
+async fn delta(
+    State(state): State<SharedState>,
+    Query(params): Query<DeltaParams>,
+) -> axum::response::Result<Json<Delta>> {
+    if params.start > params.end {
+        return Err((StatusCode::BAD_REQUEST, "start must be less than end").into());
+    }
+    if params.end > NUM_CHECKBOXES as u64 {
+        return Err((StatusCode::BAD_REQUEST, "end too large").into());
+    }
+    // Read first, anything changing while we collect chunks gets a newer version than this
+    let version = state.bitmap.version();
+    let std::ops::Range {
+        start: start_chunk,
+        end: end_chunk,
+    } = chunk::overlapping(params.start, params.end);
+    let mut b64_chunk = [0; CHUNK_BYTES * 4 / 3 + 4];
+    let encode_start = Instant::now();
+    let chunks: Vec<_> = (start_chunk..end_chunk)
+        .filter_map(|i| {
+            let chunk = state.bitmap.current(i);
+            (chunk.version > params.since_seq).then(|| DeltaChunk {
+                offset: (i * CHUNK_BITS) as u64,
+                bits: encode_chunk(&chunk.bytes, &mut b64_chunk).to_owned(),
+                version: chunk.version,
+            })
+        })
+        .collect();
+    let span = Span::current();
+    span.record("chunks", chunks.len());
+    span.record(
+        "bytes",
+        chunks.iter().map(|chunk| chunk.bits.len()).sum::<usize>(),
+    );
+    span.record("encode_us", encode_start.elapsed().as_micros() as u64);
+    debug!(scanned = end_chunk - start_chunk, "served delta");
+    Ok(Json(Delta { version, chunks }))
+}
+
+#[derive(serde::Serialize)]
+struct Stats {
+    sse_connections: usize,
+    sse_clients: usize,
+    /// Bytes sent down streams
+    stream_bandwidth: BandwidthStats,
+    /// Totals since the server was first run, which carry over restarts
+    lifetime: LifetimeTotals,
+    abuse_detections: u64,
+    throttled_clients: usize,
+    /// Toggles waiting to be applied
+    queued_toggles: usize,
+    /// Longest time any chunk's watchers are currently made to wait between updates
+    max_notify_interval_ms: u64,
+    /// Chunks whose updates are currently slowed down by heavy activity or many watchers
+    stretched_notify_chunks: usize,
+    /// Time to response head by route
+    latency: BTreeMap<String, RouteLatency>,
+    allocator: AllocatorStats,
+    image_pool: ImagePoolStats,
+}
+
+async fn stats(State(state): State<SharedState>) -> Json<Stats> {
+    let (max_notify_interval, stretched_notify_chunks) = state.bitmap.notify_intervals();
+    Json(Stats {
+        sse_connections: state.subscriptions.open(),
+        sse_clients: state.subscriptions.clients(),
+        stream_bandwidth: state.bandwidth.stats(),
+        lifetime: state.lifetime.totals(),
+        abuse_detections: state.abuse.total_detections(),
+        throttled_clients: state.abuse.throttles().len(),
+        queued_toggles: state.toggles.len(),
+        max_notify_interval_ms: max_notify_interval.as_millis() as u64,
+        stretched_notify_chunks,
+        latency: state.latency.summaries(),
+        allocator: allocator::stats(),
+        image_pool: state.images.stats(),
+    })
+}
+
+/// The sum of all sliders, for clients which only want the one number
+async fn sum(State(state): State<SharedState>) -> Json<SumUpdate> {
+    Json(SumUpdate {
+        sum: state.bitmap.sum(),
+        seq: state.bitmap.sequence(),
+    })
+}
+
+/// The number of checked checkboxes, for clients which only want the one number
+async fn count(State(state): State<SharedState>) -> Json<CountUpdate> {
+    Json(CountUpdate {
+        count: state.bitmap.count(),
+        seq: state.bitmap.sequence(),
+    })
+}
+
+/// Request latencies and disk space in the Prometheus text format
+async fn metrics(State(state): State<SharedState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.latency.prometheus()
+            + &state.bitmap.propagation().prometheus()
+            + &state.disk.prometheus(),
+    )
+}
+
+fn throttled(remaining: Duration) -> Response {
+    let retry_after = remaining.as_secs().max(1).to_string();
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after)],
+        "Writes from this address are temporarily throttled",
+    )
+        .into_response()
+}
+
+#[tracing::instrument(skip(state))]
+async fn toggle(
+    State(state): State<SharedState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(idx): Path<u64>,
+) -> axum::response::Result<()> {
+    if idx >= NUM_CHECKBOXES as u64 {
+        return Err((StatusCode::BAD_REQUEST, "Index too large").into());
+    }
+    state.abuse.check(addr.ip(), idx).map_err(throttled)?;
+    let bit_index = idx as usize;
+    let owned = state
+        .cluster
+        .as_ref()
+        .is_none_or(|cluster| cluster.is_owned(bit_index / CHUNK_BITS));
+    if owned {
+        return state.toggles.toggle(bit_index).await.map_err(|QueueFull| {
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many toggles waiting, try again later",
+            )
+                .into()
+        });
+    }
+    let mut writes = Writes::new(&state);
+    writes.toggle(bit_index);
+    Ok(writes.finish().await?)
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct ToggleIfParams {
+    expected: u8,
+}
+
+#[derive(serde::Serialize)]
+struct ToggleIfResult {
+    toggled: bool,
+    value: u8,
+}
+
+/// Toggles the bit only if it currently holds `expected`, so clients racing on the same bit don't
+/// undo each other. Either way the bit ends up as the opposite of `expected`.
+#[tracing::instrument(skip(state))]
+async fn toggle_if(
+    State(state): State<SharedState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(idx): Path<u64>,
+    Query(params): Query<ToggleIfParams>,
+) -> axum::response::Result<Json<ToggleIfResult>> {
+    if idx >= NUM_CHECKBOXES as u64 {
+        return Err((StatusCode::BAD_REQUEST, "Index too large").into());
+    }
+    if params.expected > 1 {
+        return Err((StatusCode::BAD_REQUEST, "expected must be 0 or 1").into());
+    }
+    state.abuse.check(addr.ip(), idx).map_err(throttled)?;
+    let mut writes = Writes::new(&state);
+    let toggled = writes.toggle_if(idx as usize, params.expected == 1).await?;
+    writes.finish().await?;
+    Ok(Json(ToggleIfResult {
+        toggled,
+        value: params.expected ^ 1,
+    }))
+}
+
+#[tracing::instrument(skip(state))]
+async fn set_byte(
+    State(state): State<SharedState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((idx, value)): Path<(u64, u8)>,
+) -> axum::response::Result<()> {
+    if idx >= NUM_SLIDERS as u64 {
+        return Err((StatusCode::BAD_REQUEST, "Index too large").into());
+    }
+    state.abuse.check(addr.ip(), idx * 8).map_err(throttled)?;
+    let mut writes = Writes::new(&state);
+    writes.set_byte(idx as usize, value);
+    Ok(writes.finish().await?)
+}
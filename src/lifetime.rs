@@ -0,0 +1,89 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::MissedTickBehavior;
+
+use crate::reporting;
+use crate::shared_bitmap::SharedBitmap;
+use crate::subscriptions::SubscriptionLimits;
+
+/// Totals counted since the server was first run rather than since it last started
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct LifetimeTotals {
+    /// Writes to the board, with a bulk write counted once per chunk it touched
+    pub mutations: u64,
+    /// Subscriptions ever opened
+    pub connections: u64,
+    /// Most subscriptions open at once
+    pub peak_connections: usize,
+}
+
+/// Running totals which survive restarts, saved as JSON every flush interval and at exit. A crash
+/// loses whatever was counted since the last save.
+pub struct Lifetime {
+    path: PathBuf,
+    /// The totals from before this process started
+    previous: LifetimeTotals,
+    bitmap: Arc<SharedBitmap>,
+    subscriptions: Arc<SubscriptionLimits>,
+}
+
+impl Lifetime {
+    pub fn load_or_create(
+        path: impl Into<PathBuf>,
+        bitmap: Arc<SharedBitmap>,
+        subscriptions: Arc<SubscriptionLimits>,
+    ) -> io::Result<Self> {
+        let path = path.into();
+        let previous = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => LifetimeTotals::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            previous,
+            bitmap,
+            subscriptions,
+        })
+    }
+
+    pub fn totals(&self) -> LifetimeTotals {
+        LifetimeTotals {
+            mutations: self.previous.mutations + self.bitmap.total_mutations(),
+            connections: self.previous.connections + self.subscriptions.opened(),
+            peak_connections: self
+                .previous
+                .peak_connections
+                .max(self.subscriptions.peak()),
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        // Write then rename, so a crash mid-write can't leave truncated totals behind
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(&self.totals())?)?;
+        fs::rename(tmp_path, &self.path)
+    }
+
+    /// Saves the totals every `interval`
+    pub async fn run(self: Arc<Self>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick is immediate, and there's nothing new to save yet
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let lifetime = Arc::clone(&self);
+            match tokio::task::spawn_blocking(move || lifetime.save()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => reporting::report("Failed to save lifetime totals", e),
+                Err(e) => reporting::report("Failed to save lifetime totals", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{fmt, io};
+
+use tokio::time::Instant;
+
+use crate::http_client::{Connection, Target};
+use crate::rng::Rng;
+use crate::shared_bitmap::CHUNK_BITS;
+use crate::{NUM_CHECKBOXES, NUM_SLIDERS};
+
+pub struct LoadgenArgs {
+    target: Target,
+    duration: Duration,
+    concurrency: usize,
+    toggle_weight: u32,
+    set_byte_weight: u32,
+    subscriptions: usize,
+    subscription_bits: u64,
+}
+
+impl LoadgenArgs {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut result = Self {
+            target: Target::parse("http://localhost:8000")?,
+            duration: Duration::from_secs(10),
+            concurrency: 16,
+            toggle_weight: 1,
+            set_byte_weight: 1,
+            subscriptions: 0,
+            subscription_bits: 10 * CHUNK_BITS as u64,
+        };
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| format!("missing value for {name}"))
+            };
+            match arg.as_str() {
+                "--duration" => result.duration = Duration::from_secs(parse(&value(&arg)?)?),
+                "--concurrency" => result.concurrency = parse(&value(&arg)?)?,
+                "--toggles" => result.toggle_weight = parse(&value(&arg)?)?,
+                "--set-bytes" => result.set_byte_weight = parse(&value(&arg)?)?,
+                "--subscriptions" => result.subscriptions = parse(&value(&arg)?)?,
+                "--subscription-bits" => result.subscription_bits = parse(&value(&arg)?)?,
+                _ if !arg.starts_with("--") => result.target = Target::parse(&arg)?,
+                _ => return Err(format!("unknown argument {arg}")),
+            }
+        }
+        if result.toggle_weight == 0 && result.set_byte_weight == 0 && result.subscriptions == 0 {
+            return Err("nothing to do: all weights are zero".into());
+        }
+        if result.subscription_bits > NUM_CHECKBOXES as u64 {
+            return Err("subscription range too large".into());
+        }
+        Ok(result)
+    }
+}
+
+fn parse<T: std::str::FromStr>(s: &str) -> Result<T, String> {
+    s.parse().map_err(|_| format!("invalid number {s:?}"))
+}
+
+#[derive(Default)]
+struct OpStats {
+    latencies: Mutex<Vec<Duration>>,
+    errors: AtomicU64,
+}
+
+impl OpStats {
+    fn record(&self, result: Result<Duration, ()>) {
+        match result {
+            Ok(latency) => self.latencies.lock().unwrap().push(latency),
+            Err(()) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+struct Report<'a> {
+    name: &'a str,
+    stats: &'a OpStats,
+    elapsed: Duration,
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut latencies = self.stats.latencies.lock().unwrap();
+        latencies.sort_unstable();
+        let errors = self.stats.errors.load(Ordering::Relaxed);
+        let rate = latencies.len() as f64 / self.elapsed.as_secs_f64();
+        write!(
+            f,
+            "{:<14} ok={:<8} err={:<6} rate={rate:>9.1}/s",
+            self.name,
+            latencies.len(),
+            errors
+        )?;
+        if latencies.is_empty() {
+            return Ok(());
+        }
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+        write!(
+            f,
+            " p50={:?} p90={:?} p99={:?} max={:?}",
+            percentile(50),
+            percentile(90),
+            percentile(99),
+            latencies[latencies.len() - 1]
+        )
+    }
+}
+
+async fn request(
+    conn: &mut Option<Connection>,
+    target: &Target,
+    method: &str,
+    path: &str,
+) -> Result<Duration, ()> {
+    let start = Instant::now();
+    let result = async {
+        let c = match conn {
+            Some(c) => c,
+            None => conn.insert(Connection::connect(target).await?),
+        };
+        c.send(target, method, path, &[], &[]).await?;
+        let head = c.read_head().await?;
+        let len = head.content_length.ok_or(io::ErrorKind::InvalidData)?;
+        c.skip_body(len).await?;
+        Ok::<_, io::Error>(head.status)
+    }
+    .await;
+    match result {
+        Ok(status) if (200..300).contains(&status) => Ok(start.elapsed()),
+        Ok(_) => Err(()),
+        Err(_) => {
+            *conn = None;
+            Err(())
+        }
+    }
+}
+
+struct Totals {
+    toggles: OpStats,
+    set_bytes: OpStats,
+    first_update: OpStats,
+    sse_bytes: AtomicU64,
+}
+
+async fn writer(args: Arc<LoadgenArgs>, totals: Arc<Totals>, seed: u64) {
+    let mut rng = Rng::seeded(seed);
+    let mut conn = None;
+    let deadline = Instant::now() + args.duration;
+    let total_weight = u64::from(args.toggle_weight) + u64::from(args.set_byte_weight);
+    if total_weight == 0 {
+        return;
+    }
+    while Instant::now() < deadline {
+        if rng.below(total_weight) < u64::from(args.toggle_weight) {
+            let idx = rng.below(NUM_CHECKBOXES as u64);
+            let path = format!("/toggle/{idx}");
+            let result = request(&mut conn, &args.target, "POST", &path).await;
+            totals.toggles.record(result);
+        } else {
+            let idx = rng.below(NUM_SLIDERS as u64);
+            let value = rng.below(256);
+            let path = format!("/set_byte/{idx}/{value}");
+            let result = request(&mut conn, &args.target, "POST", &path).await;
+            totals.set_bytes.record(result);
+        }
+    }
+}
+
+async fn subscriber(args: Arc<LoadgenArgs>, totals: Arc<Totals>, seed: u64) {
+    let mut rng = Rng::seeded(seed);
+    let start = rng.below(NUM_CHECKBOXES as u64 - args.subscription_bits + 1);
+    let end = start + args.subscription_bits;
+    let path = format!("/updates?start={start}&end={end}");
+    let deadline = Instant::now() + args.duration;
+
+    let connect_start = Instant::now();
+    let subscribe = async {
+        let mut conn = Connection::connect(&args.target).await?;
+        conn.send(&args.target, "GET", &path, &[], &[]).await?;
+        let head = conn.read_head().await?;
+        Ok::<_, io::Error>((head.status, conn))
+    };
+    let mut conn = match subscribe.await {
+        Ok((200, conn)) => conn,
+        _ => {
+            totals.first_update.record(Err(()));
+            return;
+        }
+    };
+    let mut first = true;
+    loop {
+        if !conn.buf.is_empty() {
+            if first {
+                totals.first_update.record(Ok(connect_start.elapsed()));
+                first = false;
+            }
+            let received = conn.buf.len() as u64;
+            totals.sse_bytes.fetch_add(received, Ordering::Relaxed);
+            conn.buf.clear();
+        }
+        match tokio::time::timeout_at(deadline, conn.fill()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) | Err(_) => break,
+        }
+    }
+}
+
+pub async fn run(args: LoadgenArgs) {
+    let args = Arc::new(args);
+    let totals = Arc::new(Totals {
+        toggles: OpStats::default(),
+        set_bytes: OpStats::default(),
+        first_update: OpStats::default(),
+        sse_bytes: AtomicU64::new(0),
+    });
+
+    println!(
+        "driving {} for {:?}: {} writers (toggle:set_byte = {}:{}), {} subscriptions",
+        args.target,
+        args.duration,
+        args.concurrency,
+        args.toggle_weight,
+        args.set_byte_weight,
+        args.subscriptions
+    );
+
+    let start = Instant::now();
+    let mut tasks = tokio::task::JoinSet::new();
+    for i in 0..args.subscriptions {
+        let fut = subscriber(Arc::clone(&args), Arc::clone(&totals), i as u64);
+        tasks.spawn(fut);
+    }
+    for i in 0..args.concurrency {
+        let seed = (args.subscriptions + i) as u64;
+        let fut = writer(Arc::clone(&args), Arc::clone(&totals), seed);
+        tasks.spawn(fut);
+    }
+    while tasks.join_next().await.is_some() {}
+    let elapsed = start.elapsed();
+
+    let report = |name, stats| Report {
+        name,
+        stats,
+        elapsed,
+    };
+    println!("{}", report("toggle", &totals.toggles));
+    println!("{}", report("set_byte", &totals.set_bytes));
+    println!("{}", report("first update", &totals.first_update));
+    println!(
+        "{:<14} {} bytes received",
+        "sse",
+        totals.sse_bytes.load(Ordering::Relaxed)
+    );
+}
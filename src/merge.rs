@@ -0,0 +1,119 @@
+//! `POST /merge`, for clients which kept recording changes while offline. Toggles commute, so
+//! they're applied as they are. Byte sets are last-writer-wins: the server doesn't keep per-byte
+//! write times, so a set is only applied if nothing in its chunk has changed since it was made.
+
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::extract::{ConnectInfo, State};
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+
+use crate::cluster::Writes;
+use crate::rate_limit::{self, Charged, Client};
+use crate::shared_bitmap::CHUNK_BYTES;
+use crate::{throttled, SharedState, NUM_CHECKBOXES, NUM_SLIDERS};
+
+/// Most toggles and sets accepted in one merge
+pub const MAX_MERGE_OPS: usize = 10_000;
+
+#[derive(Deserialize, Debug)]
+pub struct MergeRequest {
+    /// Checkboxes toggled, each as many times as it appears
+    #[serde(default)]
+    toggles: Vec<u64>,
+    #[serde(default)]
+    sets: Vec<ByteSet>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ByteSet {
+    idx: u64,
+    value: u8,
+    /// Unix time the client made the change, in milliseconds
+    at: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MergeResult {
+    toggled: usize,
+    /// Sets which were newer than anything else written to their chunk
+    applied: usize,
+    /// Sets which lost to a later write
+    superseded: usize,
+}
+
+#[tracing::instrument(skip_all, fields(toggles = merge.toggles.len(), sets = merge.sets.len()))]
+pub async fn merge(
+    State(state): State<SharedState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(client): Extension<Client>,
+    Json(mut merge): Json<MergeRequest>,
+) -> axum::response::Result<(Charged, Json<MergeResult>)> {
+    if merge.toggles.len() + merge.sets.len() > MAX_MERGE_OPS {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Too many changes in one merge",
+        )
+            .into());
+    }
+    if merge
+        .toggles
+        .iter()
+        .any(|&idx| idx >= NUM_CHECKBOXES as u64)
+        || merge.sets.iter().any(|set| set.idx >= NUM_SLIDERS as u64)
+    {
+        return Err((StatusCode::BAD_REQUEST, "Index too large").into());
+    }
+    // Every change counts against the client like a separate write would, and if the client can't
+    // afford them all or gets throttled, none of them are applied
+    let charged =
+        rate_limit::charge(&state, client, merge.toggles.len() + merge.sets.len()).await?;
+    for &idx in &merge.toggles {
+        state.abuse.check(addr.ip(), idx).map_err(throttled)?;
+    }
+    for set in &merge.sets {
+        state
+            .abuse
+            .check(addr.ip(), set.idx * 8)
+            .map_err(throttled)?;
+    }
+
+    let mut writes = Writes::new(&state);
+    for &idx in &merge.toggles {
+        writes.toggle(idx as usize);
+    }
+    // A set claiming to be from the future would otherwise beat every write until then
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    for set in &mut merge.sets {
+        set.at = set.at.min(now_ms);
+    }
+    // Oldest first, so the latest of several sets to a byte wins
+    merge.sets.sort_by_key(|set| set.at);
+    let mut applied = 0;
+    for set in &merge.sets {
+        let idx = set.idx as usize;
+        // Chunk times are only kept to the second, a set in the same second as a newer write wins
+        let superseded = state
+            .bitmap
+            .last_modified(idx / CHUNK_BYTES)
+            .is_some_and(|modified| modified > set.at / 1000);
+        if !superseded {
+            writes.set_byte(idx, set.value);
+            applied += 1;
+        }
+    }
+    writes.finish().await?;
+    Ok((
+        charged,
+        Json(MergeResult {
+            toggled: merge.toggles.len(),
+            applied,
+            superseded: merge.sets.len() - applied,
+        }),
+    ))
+}
@@ -0,0 +1,106 @@
+//! Optional bridge to an MQTT broker, built with the `mqtt` feature. Each chunk's contents are
+//! published as raw bytes to `<prefix>/chunk/<n>` whenever it changes, retained so new
+//! subscribers get the current state, and mutations published to `<prefix>/commands` are applied
+//! to the board.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::stream;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use tokio_stream::StreamExt;
+use tracing::{debug, info, warn};
+
+use crate::config::MqttConfig;
+use crate::shared_bitmap::{SharedBitmap, NUM_CHUNKS};
+use crate::{NUM_CHECKBOXES, NUM_SLIDERS};
+
+// Requests which may be queued for the event loop before publishing waits
+const REQUEST_CAPACITY: usize = 1024;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// A message on the commands topic, like `{"op": "set_byte", "idx": 12, "value": 255}`
+#[derive(serde::Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Command {
+    Toggle { idx: u64 },
+    SetByte { idx: u64, value: u8 },
+}
+
+/// Runs the bridge forever, reconnecting whenever the connection to the broker drops
+pub async fn run(bitmap: Arc<SharedBitmap>, config: MqttConfig) {
+    let mut options = MqttOptions::new(
+        format!("sliders-{}", std::process::id()),
+        config.host.as_str(),
+        config.port,
+    );
+    options.set_keep_alive(Duration::from_secs(30));
+    let (client, mut event_loop) = AsyncClient::new(options, REQUEST_CAPACITY);
+    let commands_topic = format!("{}/commands", config.topic_prefix);
+
+    let publisher = tokio::spawn(publish_chunks(
+        Arc::clone(&bitmap),
+        client.clone(),
+        config.topic_prefix.clone(),
+    ));
+    info!(
+        host = config.host,
+        port = config.port,
+        "starting mqtt bridge"
+    );
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("connected to mqtt broker");
+                // Subscriptions don't survive reconnecting with a clean session, so (re)subscribe
+                // on every connect. Waiting here could deadlock with the queue we're draining.
+                if let Err(e) = client.try_subscribe(commands_topic.as_str(), QoS::AtLeastOnce) {
+                    warn!(error = %e, "failed to subscribe to mqtt commands");
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) if publish.topic == commands_topic => {
+                match serde_json::from_slice(&publish.payload) {
+                    Ok(command) => apply(&bitmap, command),
+                    Err(e) => debug!(error = %e, "ignoring invalid mqtt command"),
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!(error = %e, "mqtt connection failed, retrying");
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+        if publisher.is_finished() {
+            warn!("mqtt publisher stopped, shutting down the bridge");
+            return;
+        }
+    }
+}
+
+fn apply(bitmap: &SharedBitmap, command: Command) {
+    debug!(?command, "applying mqtt command");
+    match command {
+        Command::Toggle { idx } if idx < NUM_CHECKBOXES as u64 => bitmap.toggle(idx as usize),
+        Command::SetByte { idx, value } if idx < NUM_SLIDERS as u64 => {
+            bitmap.set_byte(idx as usize, value)
+        }
+        command => debug!(?command, "ignoring out of range mqtt command"),
+    }
+}
+
+async fn publish_chunks(bitmap: Arc<SharedBitmap>, client: AsyncClient, topic_prefix: String) {
+    let watches = (0..NUM_CHUNKS).map(|i| {
+        tokio_stream::wrappers::WatchStream::new(bitmap.watch(i)).map(move |chunk| (i, chunk))
+    });
+    let mut updates = stream::select_all(watches);
+    while let Some((i, chunk)) = updates.next().await {
+        let topic = format!("{topic_prefix}/chunk/{i}");
+        if let Err(e) = client
+            .publish(topic, QoS::AtMostOnce, true, chunk.bytes.to_vec())
+            .await
+        {
+            warn!(error = %e, "failed to publish chunk to mqtt");
+            return;
+        }
+    }
+}
@@ -0,0 +1,388 @@
+//! `/mux.ws`, a WebSocket carrying any number of labeled subscriptions ("topics"), each with its
+//! own range and throttle, for clients which watch several parts of the board at once and would
+//! otherwise need a stream for each.
+//!
+//! Every message is a JSON text frame. The client sends
+//! `{"op":"subscribe","topic":..,"start":..,"end":..,"throttle_ms":..}`, replacing any topic of the
+//! same name, and `{"op":"unsubscribe","topic":..}`. The server answers with `subscribed`,
+//! `unsubscribed`, and `error` events, and sends
+//! `{"event":"update","topic":..,"offset":..,"bits":..,"version":..,"seq":..}` for each chunk of
+//! a topic's range as soon as it's subscribed, then whenever it changes. With a `throttle_ms`, a
+//! chunk's changes are sent at most that often, only the latest contents being kept in between.
+//!
+//! When the server shuts down, every topic is sent a `server_shutdown` event, its chunks' last
+//! contents, and an `end` event, and then the socket is closed. A socket which goes over its
+//! bandwidth budget is closed with a policy violation and the reason `quota_exceeded`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::response::Response;
+use futures::{SinkExt, Stream, StreamExt};
+use tokio::sync::mpsc;
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::MissedTickBehavior;
+
+use crate::bandwidth::Meter;
+use crate::proxy::Origin;
+use crate::shared_bitmap::{SharedBitmap, VersionedChunk, CHUNK_BITS, CHUNK_BYTES};
+use crate::{
+    encode_chunk, pause, subscribe_updates, Base64ChunkBuffer, ChunkUpdate, Range, SharedState,
+    Update,
+};
+
+/// Topics one socket may have open at once
+const MAX_TOPICS: usize = 16;
+/// Longest topic name, in bytes
+const MAX_TOPIC_LEN: usize = 64;
+const MAX_THROTTLE: Duration = Duration::from_secs(60);
+/// Messages waiting to be written to the socket, beyond which topics wait their turn
+const OUTBOX: usize = 256;
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe {
+        topic: String,
+        start: u64,
+        end: u64,
+        /// Least time between updates of the same chunk, none if not given
+        throttle_ms: Option<u64>,
+    },
+    Unsubscribe {
+        topic: String,
+    },
+}
+
+#[derive(serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum ServerMessage<'a> {
+    Subscribed {
+        topic: &'a str,
+    },
+    Unsubscribed {
+        topic: &'a str,
+    },
+    Update {
+        topic: &'a str,
+        #[serde(flatten)]
+        chunk: ChunkUpdate<'a>,
+    },
+    ServerShutdown {
+        topic: &'a str,
+        reconnect_after_ms: u64,
+    },
+    End {
+        topic: &'a str,
+    },
+    Error {
+        /// The topic the error is about, if any
+        topic: Option<&'a str>,
+        message: &'a str,
+    },
+}
+
+impl ServerMessage<'_> {
+    fn to_message(&self) -> Message {
+        Message::Text(serde_json::to_string(self).expect("serializing a message can't fail"))
+    }
+}
+
+#[tracing::instrument(skip(state, ws))]
+pub async fn mux_ws(
+    State(state): State<SharedState>,
+    origin: Origin,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(move |socket| serve(socket, state, origin))
+}
+
+/// The topics open on one socket
+struct Topics {
+    open: HashMap<Arc<str>, Topic>,
+    /// Each finishes with its topic's name and id
+    tasks: JoinSet<(Arc<str>, u64)>,
+    /// Tells a topic apart from earlier ones of the same name
+    next_id: u64,
+}
+
+struct Topic {
+    id: u64,
+    task: AbortHandle,
+}
+
+impl Topics {
+    fn new() -> Self {
+        Self {
+            open: HashMap::new(),
+            tasks: JoinSet::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Starts the task sending `topic`'s updates to `outbox`, returning the reply to the request
+    /// for it
+    fn subscribe(
+        &mut self,
+        state: &SharedState,
+        origin: Origin,
+        outbox: &mpsc::Sender<Message>,
+        topic: String,
+        range: Range,
+        throttle: Option<Duration>,
+    ) -> Message {
+        let error = |message| {
+            ServerMessage::Error {
+                topic: Some(&topic),
+                message,
+            }
+            .to_message()
+        };
+        if topic.len() > MAX_TOPIC_LEN {
+            return error("Topic name is too long");
+        }
+        if throttle.is_some_and(|throttle| throttle > MAX_THROTTLE) {
+            return error("Throttle is too long");
+        }
+        // A topic being replaced doesn't count against the limit
+        if let Some(replaced) = self.open.remove(topic.as_str()) {
+            replaced.task.abort();
+        } else if self.open.len() >= MAX_TOPICS {
+            return error("Too many topics");
+        }
+        let updates = match subscribe_updates(state.clone(), origin, range, pause::never()) {
+            Ok(updates) => updates,
+            Err((_, message)) => return error(message),
+        };
+        let topic: Arc<str> = topic.into();
+        let id = self.next_id;
+        self.next_id += 1;
+        let task = self.tasks.spawn({
+            let topic = Arc::clone(&topic);
+            let bitmap = Arc::clone(&state.bitmap);
+            let outbox = outbox.clone();
+            let throttle = throttle.filter(|throttle| !throttle.is_zero());
+            async move {
+                run_topic(&topic, updates, throttle, bitmap, outbox).await;
+                (topic, id)
+            }
+        });
+        let reply = ServerMessage::Subscribed { topic: &topic }.to_message();
+        self.open.insert(topic, Topic { id, task });
+        reply
+    }
+
+    fn unsubscribe(&mut self, topic: &str) -> Message {
+        match self.open.remove(topic) {
+            Some(removed) => {
+                removed.task.abort();
+                ServerMessage::Unsubscribed { topic }.to_message()
+            }
+            None => ServerMessage::Error {
+                topic: Some(topic),
+                message: "No such topic",
+            }
+            .to_message(),
+        }
+    }
+
+    /// Forgets a topic whose updates have ended, unless it has since been replaced
+    fn finished(&mut self, topic: &str, id: u64) {
+        if self.open.get(topic).is_some_and(|open| open.id == id) {
+            self.open.remove(topic);
+        }
+    }
+}
+
+async fn serve(socket: WebSocket, state: SharedState, origin: Origin) {
+    let (mut tx, mut rx) = socket.split();
+    let mut meter = state.bandwidth.open(origin.client);
+    let (outbox, mut pending) = mpsc::channel(OUTBOX);
+    let mut topics = Topics::new();
+    let mut shutdown = pin!(state.shutdown.clone().wait());
+    let mut shutting_down = false;
+    loop {
+        let reply = tokio::select! {
+            Some(msg) = pending.recv() => msg,
+            Some(finished) = topics.tasks.join_next(), if !topics.tasks.is_empty() => {
+                // Unsubscribed and replaced topics were already forgotten when they were aborted
+                if let Ok((topic, id)) = finished {
+                    topics.finished(&topic, id);
+                }
+                if !(shutting_down && topics.tasks.is_empty()) {
+                    continue;
+                }
+                // Whatever the topics sent before finishing is still to go out
+                while let Ok(msg) = pending.try_recv() {
+                    if !send(&mut tx, &mut meter, msg).await {
+                        return;
+                    }
+                }
+                let _ = tx
+                    .send(Message::Close(Some(CloseFrame {
+                        code: close_code::RESTART,
+                        reason: "server_shutdown".into(),
+                    })))
+                    .await;
+                return;
+            }
+            () = &mut shutdown, if !shutting_down => {
+                shutting_down = true;
+                if !topics.tasks.is_empty() {
+                    continue;
+                }
+                let _ = tx.send(Message::Close(None)).await;
+                return;
+            }
+            msg = rx.next() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    handle(&text, &state, origin, &outbox, &mut topics, shutting_down)
+                }
+                // Pings are answered for us
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                Some(Ok(Message::Binary(_))) => ServerMessage::Error {
+                    topic: None,
+                    message: "Expected a JSON text message",
+                }
+                .to_message(),
+                Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+            },
+        };
+        if !send(&mut tx, &mut meter, reply).await {
+            return;
+        }
+    }
+}
+
+/// Carries out a message from the client, returning the reply
+fn handle(
+    text: &str,
+    state: &SharedState,
+    origin: Origin,
+    outbox: &mpsc::Sender<Message>,
+    topics: &mut Topics,
+    shutting_down: bool,
+) -> Message {
+    match serde_json::from_str(text) {
+        Ok(ClientMessage::Subscribe { topic, .. }) if shutting_down => ServerMessage::Error {
+            topic: Some(&topic),
+            message: "The server is shutting down",
+        }
+        .to_message(),
+        Ok(ClientMessage::Subscribe {
+            topic,
+            start,
+            end,
+            throttle_ms,
+        }) => {
+            let throttle = throttle_ms.map(Duration::from_millis);
+            topics.subscribe(state, origin, outbox, topic, Range { start, end }, throttle)
+        }
+        Ok(ClientMessage::Unsubscribe { topic }) => topics.unsubscribe(&topic),
+        Err(_) => ServerMessage::Error {
+            topic: None,
+            message: "Expected a subscribe or unsubscribe message",
+        }
+        .to_message(),
+    }
+}
+
+/// Forwards a topic's chunks to the socket, until its updates end or the topic is unsubscribed
+async fn run_topic(
+    topic: &str,
+    updates: impl Stream<Item = Update>,
+    throttle: Option<Duration>,
+    bitmap: Arc<SharedBitmap>,
+    outbox: mpsc::Sender<Message>,
+) {
+    let mut updates = pin!(updates);
+    let chunk_message = |i: usize, chunk: &VersionedChunk| {
+        let mut buf: Base64ChunkBuffer = [0; CHUNK_BYTES * 4 / 3 + 4];
+        ServerMessage::Update {
+            topic,
+            chunk: ChunkUpdate {
+                offset: (i * CHUNK_BITS) as u64,
+                bits: encode_chunk(&chunk.bytes, &mut buf),
+                version: chunk.version,
+                seq: bitmap.sequence(),
+            },
+        }
+        .to_message()
+    };
+    // Chunks changed since the last flush, when throttled
+    let mut held = BTreeMap::new();
+    let mut flush = throttle.map(|throttle| {
+        let mut interval = tokio::time::interval(throttle);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval
+    });
+    loop {
+        let update = tokio::select! {
+            update = updates.next() => update,
+            _ = async { flush.as_mut().unwrap().tick().await },
+                if flush.is_some() && !held.is_empty() =>
+            {
+                for (i, chunk) in std::mem::take(&mut held) {
+                    if outbox.send(chunk_message(i, &chunk)).await.is_err() {
+                        return;
+                    }
+                }
+                continue;
+            }
+        };
+        let msg = match update {
+            Some(Update::Chunk(i, chunk)) if flush.is_some() => {
+                held.insert(i, chunk);
+                continue;
+            }
+            Some(Update::Chunk(i, chunk)) => chunk_message(i, &chunk),
+            Some(Update::Shutdown(reconnect_after)) => ServerMessage::ServerShutdown {
+                topic,
+                reconnect_after_ms: reconnect_after.as_millis() as u64,
+            }
+            .to_message(),
+            Some(Update::End) | None => break,
+            // Totals and announcements go to streams meant for whole pages, not to topics
+            Some(
+                Update::Sum(_)
+                | Update::Count(_)
+                | Update::Milestone(_)
+                | Update::Viewers(_)
+                | Update::Announce(_),
+            ) => continue,
+        };
+        if outbox.send(msg).await.is_err() {
+            return;
+        }
+    }
+    // Anything still held goes out before the end
+    for (i, chunk) in held {
+        if outbox.send(chunk_message(i, &chunk)).await.is_err() {
+            return;
+        }
+    }
+    let _ = outbox.send(ServerMessage::End { topic }.to_message()).await;
+}
+
+/// Sends `msg` unless the socket would go over its budget, in which case it's closed. Returns
+/// whether the socket is still open.
+async fn send(tx: &mut (impl SinkExt<Message> + Unpin), meter: &mut Meter, msg: Message) -> bool {
+    let len = match &msg {
+        Message::Text(text) => text.len(),
+        _ => 0,
+    };
+    if !meter.send(len) {
+        let _ = tx
+            .send(Message::Close(Some(CloseFrame {
+                code: close_code::POLICY,
+                reason: "quota_exceeded".into(),
+            })))
+            .await;
+        return false;
+    }
+    tx.send(msg).await.is_ok()
+}
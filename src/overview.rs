@@ -0,0 +1,205 @@
+//! A downsampled view of the whole board for spectators, each cell the average of a square block
+//! of sliders, so watching everything doesn't take a subscription to every chunk
+
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{sse, IntoResponse, Response, Sse};
+use axum::Json;
+use base64::prelude::BASE64_STANDARD_NO_PAD;
+use base64::Engine;
+use futures::{stream, Stream, StreamExt};
+use serde::Serialize;
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+use tracing::warn;
+
+use crate::picture::{BOARD_HEIGHT, BOARD_WIDTH};
+use crate::proxy::Origin;
+use crate::shared_bitmap::SharedBitmap;
+use crate::{cdn, snapshot, SharedState, NUM_SLIDERS};
+
+/// Sliders along each side of the block averaged into one cell
+const BLOCK: u32 = 10;
+pub const OVERVIEW_WIDTH: u32 = BOARD_WIDTH / BLOCK;
+pub const OVERVIEW_HEIGHT: u32 = BOARD_HEIGHT / BLOCK;
+const _: () = assert!(BOARD_WIDTH.is_multiple_of(BLOCK) && BOARD_HEIGHT.is_multiple_of(BLOCK));
+
+const PRODUCE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// The board's sequence number the frame was taken at
+    pub seq: u64,
+    /// One byte per cell, row by row, `OVERVIEW_WIDTH` to a row
+    pub cells: Arc<[u8]>,
+}
+
+fn downsample(bitmap: &SharedBitmap) -> Frame {
+    let snapshot = bitmap.snapshot(0..NUM_SLIDERS);
+    let mut sums = vec![0u32; (OVERVIEW_WIDTH * OVERVIEW_HEIGHT) as usize];
+    for (y, row) in snapshot
+        .bytes
+        .chunks_exact(BOARD_WIDTH as usize)
+        .enumerate()
+    {
+        let cells =
+            &mut sums[y / BLOCK as usize * OVERVIEW_WIDTH as usize..][..OVERVIEW_WIDTH as usize];
+        for (cell, block) in cells.iter_mut().zip(row.chunks_exact(BLOCK as usize)) {
+            *cell += block.iter().map(|&byte| u32::from(byte)).sum::<u32>();
+        }
+    }
+    let area = BLOCK * BLOCK;
+    Frame {
+        seq: snapshot.sequence,
+        cells: sums
+            .iter()
+            .map(|&sum| ((sum + area / 2) / area) as u8)
+            .collect(),
+    }
+}
+
+/// The latest overview frame, kept current by [`Overview::run`]
+pub struct Overview {
+    frame: watch::Sender<Frame>,
+}
+
+impl Overview {
+    pub fn new(bitmap: &SharedBitmap) -> Self {
+        Self {
+            frame: watch::Sender::new(downsample(bitmap)),
+        }
+    }
+
+    /// Produces a new frame every `PRODUCE_INTERVAL` the board has changed, forever
+    pub async fn run(self: Arc<Self>, bitmap: Arc<SharedBitmap>) {
+        let mut interval = tokio::time::interval(PRODUCE_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if bitmap.sequence() == self.frame.borrow().seq {
+                continue;
+            }
+            let bitmap = Arc::clone(&bitmap);
+            match tokio::task::spawn_blocking(move || downsample(&bitmap)).await {
+                Ok(frame) => {
+                    self.frame.send_replace(frame);
+                }
+                Err(e) => warn!(error = %e, "producing an overview frame panicked"),
+            }
+        }
+    }
+
+    pub fn watch(&self) -> watch::Receiver<Frame> {
+        self.frame.subscribe()
+    }
+
+    pub fn latest(&self) -> Frame {
+        self.frame.borrow().clone()
+    }
+}
+
+/// Tags a frame the same way `/board.bin` is tagged, so a poller only downloads changed frames
+fn etag(state: &SharedState, frame: &Frame) -> HeaderValue {
+    HeaderValue::try_from(format!("\"{:x}-{}\"", state.started_at, frame.seq))
+        .expect("etag is always a valid header value")
+}
+
+fn not_modified(headers: &HeaderMap, etag: &HeaderValue) -> bool {
+    headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|value| snapshot::etag_matches(value, etag))
+}
+
+/// The latest frame's cells, one byte each, row by row
+#[tracing::instrument(skip(state, headers))]
+pub async fn overview_bin(State(state): State<SharedState>, headers: HeaderMap) -> Response {
+    let frame = state.overview.latest();
+    let etag = etag(&state, &frame);
+    if not_modified(&headers, &etag) {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    }
+    let mut response = (
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/octet-stream"),
+            ),
+            (header::CACHE_CONTROL, HeaderValue::from_static("no-cache")),
+            (header::ETAG, etag),
+        ],
+        Bytes::copy_from_slice(&frame.cells),
+    )
+        .into_response();
+    cdn::tag(&state, response.headers_mut(), &["overview"]);
+    response
+}
+
+#[derive(Serialize)]
+pub struct OverviewReport<'a> {
+    /// The board's sequence number the frame was taken at
+    seq: u64,
+    width: u32,
+    height: u32,
+    /// Sliders along each side of the block averaged into a cell
+    block: u32,
+    /// The average of each block, row by row
+    cells: &'a [u8],
+}
+
+/// The latest frame as JSON, for clients which would rather not parse bytes
+#[tracing::instrument(skip(state, headers))]
+pub async fn overview_json(State(state): State<SharedState>, headers: HeaderMap) -> Response {
+    let frame = state.overview.latest();
+    let etag = etag(&state, &frame);
+    if not_modified(&headers, &etag) {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    }
+    let mut response = (
+        [
+            (header::CACHE_CONTROL, HeaderValue::from_static("no-cache")),
+            (header::ETAG, etag),
+        ],
+        Json(OverviewReport {
+            seq: frame.seq,
+            width: OVERVIEW_WIDTH,
+            height: OVERVIEW_HEIGHT,
+            block: BLOCK,
+            cells: &frame.cells,
+        }),
+    )
+        .into_response();
+    cdn::tag(&state, response.headers_mut(), &["overview"]);
+    response
+}
+
+/// Server-sent `overview` events holding each new frame's cells, base64 encoded, with the frame's
+/// sequence number as the event id, ending with an `end` event when the server shuts down
+#[tracing::instrument(skip(state))]
+pub async fn overview_updates(
+    State(state): State<SharedState>,
+    origin: Origin,
+) -> axum::response::Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>> {
+    // The overview isn't made of chunks, so only takes a slot
+    let subscription = state
+        .subscriptions
+        .try_acquire(origin, 0)
+        .map_err(|message| (StatusCode::TOO_MANY_REQUESTS, message))?;
+    let frames =
+        tokio_stream::wrappers::WatchStream::new(state.overview.watch()).map(move |frame| {
+            // Held until the stream is dropped
+            let _subscription = &subscription;
+            let event = sse::Event::default()
+                .event("overview")
+                .id(frame.seq.to_string())
+                .data(BASE64_STANDARD_NO_PAD.encode(&frame.cells));
+            Ok(event)
+        });
+    let end = stream::once(async { Ok(sse::Event::default().event("end").data("")) });
+    let stream = frames.take_until(state.shutdown.wait()).chain(end);
+    Ok(Sse::new(stream).keep_alive(sse::KeepAlive::new()))
+}
@@ -0,0 +1,173 @@
+//! Pausing update streams without closing them, for clients like mobile apps going into the
+//! background. `/updates` and `/updates.ndjson` start with a `stream` event carrying the stream's
+//! id, `POST /updates/:id/pause` stops chunk updates and board-wide events while keeping the
+//! connection and its subscription slot, and `POST /updates/:id/resume` starts them again.
+//!
+//! Next to nothing is queued while paused. Chunks are watched, so on resume each chunk which
+//! changed is sent once, as it is now, and a stream paused long enough to miss board-wide events
+//! is sent their current values instead.
+
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use axum::extract::{ConnectInfo, Path, State};
+use axum::http::StatusCode;
+use futures::{stream, Stream, StreamExt};
+use tokio::sync::watch;
+
+use crate::SharedState;
+
+struct Pausable {
+    /// Only the address which opened the stream can pause it
+    ip: IpAddr,
+    paused: watch::Sender<bool>,
+}
+
+/// The open streams which can be paused, by id
+pub struct Pauses {
+    streams: Mutex<HashMap<u64, Pausable>>,
+    /// Ids are a count of streams hashed with this key, which comes from the OS's randomness, so
+    /// one stream's id gives away nothing about another's
+    id_key: RandomState,
+    registered: AtomicU64,
+}
+
+impl Pauses {
+    pub fn new() -> Self {
+        Self {
+            streams: Mutex::default(),
+            id_key: RandomState::new(),
+            registered: AtomicU64::new(0),
+        }
+    }
+
+    /// Registers a stream opened by `ip`, which can be paused until the returned handle is dropped
+    pub fn register(self: &Arc<Self>, ip: IpAddr) -> PauseHandle {
+        let ip = ip.to_canonical();
+        let (sender, paused) = watch::channel(false);
+        let mut streams = self.streams.lock().unwrap();
+        let id = loop {
+            let id = self
+                .id_key
+                .hash_one(self.registered.fetch_add(1, Ordering::Relaxed));
+            if !streams.contains_key(&id) {
+                break id;
+            }
+        };
+        streams.insert(id, Pausable { ip, paused: sender });
+        PauseHandle {
+            pauses: Arc::clone(self),
+            id,
+            paused,
+        }
+    }
+
+    /// Pauses or resumes the stream, returning false if `ip` has no open stream with that id
+    fn set(&self, id: u64, ip: IpAddr, paused: bool) -> bool {
+        let streams = self.streams.lock().unwrap();
+        match streams.get(&id) {
+            Some(stream) if stream.ip == ip.to_canonical() => {
+                stream.paused.send_replace(paused);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A registered stream, unregistered when dropped
+pub struct PauseHandle {
+    pauses: Arc<Pauses>,
+    id: u64,
+    paused: watch::Receiver<bool>,
+}
+
+impl PauseHandle {
+    /// The id clients pass to pause and resume, hex encoded
+    pub fn id(&self) -> String {
+        format!("{:016x}", self.id)
+    }
+
+    /// Follows the stream being paused and resumed
+    pub fn paused(&self) -> watch::Receiver<bool> {
+        self.paused.clone()
+    }
+}
+
+impl Drop for PauseHandle {
+    fn drop(&mut self) {
+        self.pauses.streams.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// For streams which can't be paused
+pub fn never() -> watch::Receiver<bool> {
+    watch::channel(false).1
+}
+
+/// Passes on `inner`'s items, except while `paused` is true, when `inner` isn't polled at all
+pub fn gate<S: Stream>(inner: S, paused: watch::Receiver<bool>) -> impl Stream<Item = S::Item> {
+    // Once nothing can pause the stream any more, items are just passed on
+    let controlled = paused.has_changed().is_ok();
+    stream::unfold(
+        (Box::pin(inner), paused, controlled),
+        |(mut inner, mut paused, mut controlled)| async move {
+            loop {
+                if !controlled {
+                    let item = inner.next().await?;
+                    return Some((item, (inner, paused, controlled)));
+                }
+                if *paused.borrow_and_update() {
+                    controlled = paused.changed().await.is_ok();
+                    continue;
+                }
+                tokio::select! {
+                    item = inner.next() => {
+                        return Some((item?, (inner, paused, controlled)));
+                    }
+                    changed = paused.changed() => controlled = changed.is_ok(),
+                }
+            }
+        },
+    )
+}
+
+fn parse_id(id: &str) -> Option<u64> {
+    (id.len() == 16)
+        .then(|| u64::from_str_radix(id, 16).ok())
+        .flatten()
+}
+
+fn set_paused(state: &SharedState, id: &str, addr: SocketAddr, paused: bool) -> StatusCode {
+    let Some(id) = parse_id(id) else {
+        return StatusCode::NOT_FOUND;
+    };
+    if state.pauses.set(id, addr.ip(), paused) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+/// Stops sending board updates down the stream, until it's resumed
+#[tracing::instrument(skip(state))]
+pub async fn pause(
+    State(state): State<SharedState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<String>,
+) -> StatusCode {
+    set_paused(&state, &id, addr, true)
+}
+
+/// Sends whatever changed while the stream was paused, then carries on as before
+#[tracing::instrument(skip(state))]
+pub async fn resume(
+    State(state): State<SharedState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<String>,
+) -> StatusCode {
+    set_paused(&state, &id, addr, false)
+}
@@ -0,0 +1,199 @@
+//! Decoding of uploaded pictures into slider values. The board is treated as a grayscale image
+//! `BOARD_WIDTH` sliders wide, with each slider holding the brightness of one pixel.
+
+use std::io::Cursor;
+use std::net::SocketAddr;
+
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, Path, Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use image::{GrayImage, ImageFormat, ImageReader, Limits};
+
+use crate::cluster::Writes;
+use crate::shared_bitmap::CHUNK_BYTES;
+use crate::{reporting, snapshot, throttled, SharedState, NUM_SLIDERS};
+
+pub const BOARD_WIDTH: u32 = 1000;
+pub const BOARD_HEIGHT: u32 = (NUM_SLIDERS / BOARD_WIDTH as usize) as u32;
+
+/// Largest upload accepted by endpoints taking a picture
+pub const MAX_PICTURE_BYTES: usize = 16 * 1024 * 1024;
+// Keeps a small, highly compressed upload from decoding into gigabytes of pixels
+pub const MAX_PICTURE_DIMENSION: u32 = 8192;
+
+/// Largest upload accepted by `/stamp`
+pub const MAX_STAMP_BYTES: usize = 256 * 1024;
+// Stamps are for drawing tools, not for repainting large parts of the board at once
+const MAX_STAMP_DIMENSION: u32 = 128;
+
+const _: () = assert!(BOARD_WIDTH as usize * BOARD_HEIGHT as usize == NUM_SLIDERS);
+
+/// Decodes a PNG or JPEG no more than `max_dimension` pixels on a side into 8-bit grayscale
+pub fn decode_gray(bytes: &[u8], max_dimension: u32) -> Result<GrayImage, (StatusCode, String)> {
+    let mut reader = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid image: {e}")))?;
+    if !matches!(reader.format(), Some(ImageFormat::Png | ImageFormat::Jpeg)) {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Expected a PNG or JPEG image".to_owned(),
+        ));
+    }
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(max_dimension);
+    limits.max_image_height = Some(max_dimension);
+    reader.limits(limits);
+    let image = reader.decode().map_err(|e| {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Invalid image: {e}"),
+        )
+    })?;
+    Ok(image.into_luma8())
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct StampParams {
+    /// Column of the stamp's top left corner
+    x: u32,
+    /// Row of the stamp's top left corner
+    y: u32,
+    /// How strongly the stamp replaces what's underneath, from 0 (not at all) to 255 (entirely)
+    #[serde(default = "opaque")]
+    opacity: u8,
+}
+
+fn opaque() -> u8 {
+    u8::MAX
+}
+
+/// Draws a small PNG or JPEG onto the board with its top left corner at `x`, `y`
+#[tracing::instrument(skip(state, body), fields(len = body.len()))]
+pub async fn stamp(
+    State(state): State<SharedState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<StampParams>,
+    body: Bytes,
+) -> axum::response::Result<()> {
+    let image = state
+        .images
+        .run(move || decode_gray(&body, MAX_STAMP_DIMENSION))
+        .await
+        .map_err(|e| e.response("Failed to decode image"))??;
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return Err((StatusCode::BAD_REQUEST, "Empty stamp").into());
+    }
+    if params.x.saturating_add(width) > BOARD_WIDTH
+        || params.y.saturating_add(height) > BOARD_HEIGHT
+    {
+        return Err((StatusCode::BAD_REQUEST, "Stamp doesn't fit on the board").into());
+    }
+    let start = (params.y * BOARD_WIDTH + params.x) as u64;
+    state.abuse.check(addr.ip(), start * 8).map_err(throttled)?;
+
+    let opacity = u32::from(params.opacity);
+    let mut writes = Writes::new(&state);
+    let mut row = vec![0; width as usize];
+    for (y, pixels) in (params.y..).zip(image.as_raw().chunks_exact(width as usize)) {
+        let offset = (y * BOARD_WIDTH + params.x) as usize;
+        state.bitmap.load_bytes(offset, &mut row);
+        for (under, &over) in row.iter_mut().zip(pixels) {
+            *under =
+                ((u32::from(*under) * (255 - opacity) + u32::from(over) * opacity) / 255) as u8;
+        }
+        writes.store_bytes(offset, &row);
+    }
+    Ok(writes.finish().await?)
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct ImageParams {
+    /// Index of the slider in the image's top left corner
+    #[serde(default)]
+    start: usize,
+}
+
+/// Renders the `width` × `height` sliders from `start` on as a grayscale PNG, one pixel per slider,
+/// `width` sliders to a row. Unlike the board's own rows, the image's rows can be any width, so any
+/// run of sliders can be shown at any shape.
+#[tracing::instrument(skip(state))]
+pub async fn render(
+    State(state): State<SharedState>,
+    Path((width, file)): Path<(u32, String)>,
+    Query(params): Query<ImageParams>,
+) -> axum::response::Result<Response> {
+    let (height, sliders) = image_sliders(width, &file, params.start)?;
+    let snapshot = state.bitmap.snapshot(sliders.clone());
+    let png = state
+        .images
+        .run(move || {
+            let image = GrayImage::from_raw(width, height, snapshot.bytes)
+                .expect("the snapshot is exactly one byte per pixel");
+            let mut png = Cursor::new(Vec::new());
+            image.write_to(&mut png, ImageFormat::Png)?;
+            Ok::<_, image::ImageError>(png.into_inner())
+        })
+        .await
+        .map_err(|e| e.response("Failed to render image"))?
+        .map_err(|e| reporting::internal_error("Failed to encode image", e))?;
+    let mut response = png.into_response();
+    insert_image_headers(response.headers_mut(), &state, sliders, snapshot.sequence);
+    Ok(response)
+}
+
+/// The headers `GET /image/:width/:height` would respond with, worked out without rendering the
+/// image
+#[tracing::instrument(skip(state))]
+pub async fn render_head(
+    State(state): State<SharedState>,
+    Path((width, file)): Path<(u32, String)>,
+    Query(params): Query<ImageParams>,
+) -> axum::response::Result<Response> {
+    let (_, sliders) = image_sliders(width, &file, params.start)?;
+    let mut response = snapshot::unsized_head_body().into_response();
+    let sequence = state.bitmap.sequence();
+    insert_image_headers(response.headers_mut(), &state, sliders, sequence);
+    Ok(response)
+}
+
+/// The height of the image `width` wide named by `file`, like `100.png`, and the sliders it shows
+/// from `start` on
+fn image_sliders(
+    width: u32,
+    file: &str,
+    start: usize,
+) -> Result<(u32, std::ops::Range<usize>), (StatusCode, &'static str)> {
+    let Some(height) = file
+        .strip_suffix(".png")
+        .and_then(|h| h.parse::<u32>().ok())
+    else {
+        return Err((StatusCode::NOT_FOUND, "Expected a height like 100.png"));
+    };
+    let dimensions = 1..=MAX_PICTURE_DIMENSION;
+    if !dimensions.contains(&width) || !dimensions.contains(&height) {
+        return Err((StatusCode::BAD_REQUEST, "Invalid image dimensions"));
+    }
+    let len = width as usize * height as usize;
+    if start.saturating_add(len) > NUM_SLIDERS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Image runs past the end of the board",
+        ));
+    }
+    Ok((height, start..start + len))
+}
+
+fn insert_image_headers(
+    headers: &mut HeaderMap,
+    state: &SharedState,
+    sliders: std::ops::Range<usize>,
+    sequence: u64,
+) {
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    headers.insert(header::ETAG, snapshot::board_etag(state, sequence));
+    let chunks = sliders.start / CHUNK_BYTES..sliders.end.div_ceil(CHUNK_BYTES);
+    snapshot::insert_range_headers(headers, state, chunks);
+}
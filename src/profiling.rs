@@ -0,0 +1,96 @@
+//! On-demand CPU profiling of the running server, built with the `pprof` feature
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use axum::extract::Query;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use pprof::protos::Message;
+use tracing::info;
+
+use crate::reporting;
+
+// Samples per second, a prime so sampling doesn't line up with periodic work
+const SAMPLE_FREQUENCY: i32 = 99;
+const MAX_PROFILE_SECS: u64 = 60;
+
+// Only one profiler can be attached to the process at a time
+static PROFILING: AtomicBool = AtomicBool::new(false);
+
+#[derive(serde::Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum ProfileFormat {
+    /// An SVG flamegraph, for looking at in a browser
+    #[default]
+    Flamegraph,
+    /// An uncompressed pprof protobuf, for `go tool pprof` and friends
+    Pprof,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct ProfileParams {
+    #[serde(default = "default_seconds")]
+    seconds: u64,
+    #[serde(default)]
+    format: ProfileFormat,
+}
+
+fn default_seconds() -> u64 {
+    10
+}
+
+struct ProfilingGuard;
+
+impl Drop for ProfilingGuard {
+    fn drop(&mut self) {
+        PROFILING.store(false, Ordering::Release);
+    }
+}
+
+/// Samples the whole process for `seconds`, then responds with the profile
+#[tracing::instrument]
+pub async fn pprof(
+    Query(params): Query<ProfileParams>,
+) -> axum::response::Result<impl IntoResponse> {
+    if params.seconds == 0 || params.seconds > MAX_PROFILE_SECS {
+        return Err((StatusCode::BAD_REQUEST, "seconds must be between 1 and 60").into());
+    }
+    if PROFILING.swap(true, Ordering::Acquire) {
+        return Err((StatusCode::CONFLICT, "A profile is already being taken").into());
+    }
+    let guard = ProfilingGuard;
+
+    info!("starting cpu profile");
+    // Waiting out the profile on a blocking thread keeps it from tying up a runtime worker
+    let profile = tokio::task::spawn_blocking(move || {
+        let _guard = guard;
+        let profiler = pprof::ProfilerGuardBuilder::default()
+            .frequency(SAMPLE_FREQUENCY)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()
+            .map_err(|e| e.to_string())?;
+        std::thread::sleep(Duration::from_secs(params.seconds));
+        let report = profiler.report().build().map_err(|e| e.to_string())?;
+        let mut body = Vec::new();
+        match params.format {
+            ProfileFormat::Flamegraph => report.flamegraph(&mut body).map_err(|e| e.to_string())?,
+            ProfileFormat::Pprof => report
+                .pprof()
+                .map_err(|e| e.to_string())?
+                .encode(&mut body)
+                .map_err(|e| e.to_string())?,
+        }
+        Ok::<_, String>(body)
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|result| result)
+    .map_err(|e| reporting::internal_error("Failed to take profile", e))?;
+
+    let content_type = match params.format {
+        ProfileFormat::Flamegraph => "image/svg+xml",
+        ProfileFormat::Pprof => "application/octet-stream",
+    };
+    Ok(([(header::CONTENT_TYPE, content_type)], profile))
+}
@@ -0,0 +1,285 @@
+//! Running behind a reverse proxy like nginx, possibly mounted under a path like `/sliders/` rather
+//! than at the root. The prefix is taken off each request before it's routed, for proxies which
+//! pass it on.
+//!
+//! Requests from a trusted proxy are taken to come from the client it names in `X-Forwarded-For`,
+//! so rate limits, bans, and subscription limits apply to each client rather than to the proxy as a
+//! whole. The client's address, and the scheme it connected with from `X-Forwarded-Proto`, are
+//! recorded on the request's span. The headers are ignored from anyone else, since any client can
+//! send them. Limits per connection rather than per client go by the connection the request
+//! actually arrived on, see [`Origin`], as a proxy carries many clients' requests over each of its
+//! connections.
+
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts, Request, State};
+use axum::http::request::Parts;
+use axum::http::uri::PathAndQuery;
+use axum::http::{HeaderMap, HeaderName, StatusCode, Uri};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Redirect, Response};
+use tracing::Span;
+
+static X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+static X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+
+#[derive(Debug, Clone, Default)]
+pub struct ProxyConfig {
+    /// Prefix every route is served under, like `/sliders`, empty when mounted at the root
+    pub base_path: String,
+    /// Peers whose forwarding headers are believed
+    pub trusted: Vec<Network>,
+}
+
+/// An address, or a whole network of them like `10.0.0.0/8`
+#[derive(Debug, Clone, Copy)]
+pub struct Network {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Network {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len));
+                let mask = mask.unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len));
+                let mask = mask.unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| format!("{s:?} isn't an address or network"))?
+            .to_canonical();
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|&len| len <= max_len)
+                .ok_or_else(|| format!("{s:?} has an invalid prefix length"))?,
+            None => max_len,
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// Normalizes a configured base path to `/prefix` form, without a trailing slash, or to an empty
+/// string for the root
+pub fn parse_base_path(path: &str) -> Result<String, String> {
+    let trimmed = path.trim_matches('/');
+    if trimmed.is_empty() {
+        return Ok(String::new());
+    }
+    let valid = trimmed.split('/').all(|segment| {
+        !segment.is_empty()
+            && segment
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"-_.~".contains(&b))
+    });
+    if !valid {
+        return Err(format!("{path:?} isn't a plain path like /sliders"));
+    }
+    Ok(format!("/{trimmed}"))
+}
+
+/// Where a request came from: the connection it arrived on, and the client which made it. The two
+/// only differ for requests through a trusted proxy, where `connection` is the proxy's.
+#[derive(Debug, Clone, Copy)]
+pub struct Origin {
+    pub connection: SocketAddr,
+    pub client: IpAddr,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Origin {
+    type Rejection = <ConnectInfo<SocketAddr> as FromRequestParts<S>>::Rejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(&origin) = parts.extensions.get::<Origin>() {
+            return Ok(origin);
+        }
+        // Not through `resolve_client`, so straight from the connection
+        let ConnectInfo(addr) = ConnectInfo::<SocketAddr>::from_request_parts(parts, state).await?;
+        Ok(Self {
+            connection: addr,
+            client: addr.ip().to_canonical(),
+        })
+    }
+}
+
+fn is_trusted(trusted: &[Network], ip: IpAddr) -> bool {
+    trusted.iter().any(|network| network.contains(ip))
+}
+
+/// The client a request from `peer` was made by: the peer itself, unless it's a trusted proxy, in
+/// which case the last address in `X-Forwarded-For` which isn't also a trusted proxy
+fn client_ip(trusted: &[Network], peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    let mut client = peer.to_canonical();
+    if !is_trusted(trusted, client) {
+        return client;
+    }
+    // Each proxy appends the address it heard from, so the nearest hops are at the end
+    let hops: Vec<&str> = headers
+        .get_all(&X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    for hop in hops.into_iter().rev() {
+        let Ok(ip) = hop.parse::<IpAddr>() else {
+            break;
+        };
+        client = ip.to_canonical();
+        if !is_trusted(trusted, client) {
+            break;
+        }
+    }
+    client
+}
+
+/// Middleware replacing the request's peer address with the client's, when it came through a
+/// trusted proxy, and recording the client and scheme on the request's span as `client` and
+/// `scheme`. The connection's own address stays available as [`Origin::connection`].
+pub async fn resolve_client(
+    State(trusted): State<Arc<[Network]>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let client = client_ip(&trusted, peer.ip(), req.headers());
+    let scheme = req
+        .headers()
+        .get(&X_FORWARDED_PROTO)
+        .and_then(|proto| proto.to_str().ok())
+        .filter(|_| is_trusted(&trusted, peer.ip().to_canonical()))
+        .unwrap_or("http")
+        .to_owned();
+    let span = Span::current();
+    span.record("client", tracing::field::display(client));
+    span.record("scheme", scheme.as_str());
+    req.extensions_mut().insert(Origin {
+        connection: peer,
+        client,
+    });
+    req.extensions_mut()
+        .insert(ConnectInfo(SocketAddr::new(client, peer.port())));
+    next.run(req).await
+}
+
+/// Middleware taking `base_path` off the front of the request's path, so it's routed as if the
+/// service were at the root. Paths outside it aren't found, and the prefix by itself is redirected
+/// to have a trailing slash, which the frontend's relative links need.
+pub async fn strip_base_path(
+    State(base_path): State<Arc<str>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let uri = req.uri();
+    let Some(rest) = uri.path().strip_prefix(&*base_path) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let query = uri
+        .query()
+        .map(|query| format!("?{query}"))
+        .unwrap_or_default();
+    if rest.is_empty() {
+        return Redirect::permanent(&format!("{base_path}/{query}")).into_response();
+    }
+    if !rest.starts_with('/') {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = PathAndQuery::try_from(format!("{rest}{query}")).ok();
+    let Ok(uri) = Uri::from_parts(parts) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    *req.uri_mut() = uri;
+    next.run(req).await
+}
+
+#[cfg(all(test, not(sliders_loom)))]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn networks(list: &[&str]) -> Vec<Network> {
+        list.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    fn forwarded(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(&X_FORWARDED_FOR, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn networks_contain_their_addresses() {
+        let trusted = networks(&["10.0.0.0/8", "::1", "fd00::/8"]);
+        assert!(is_trusted(&trusted, "10.1.2.3".parse().unwrap()));
+        assert!(!is_trusted(&trusted, "11.0.0.1".parse().unwrap()));
+        assert!(is_trusted(&trusted, "::1".parse().unwrap()));
+        assert!(is_trusted(&trusted, "fd12::1".parse().unwrap()));
+        assert!(!is_trusted(&trusted, "::2".parse().unwrap()));
+        assert!(networks(&["0.0.0.0/0"])[0].contains("1.2.3.4".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("localhost".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn forwarded_for_is_only_believed_from_trusted_proxies() {
+        let trusted = networks(&["127.0.0.1", "10.0.0.0/8"]);
+        let headers = forwarded(&["1.1.1.1, 2.2.2.2", "10.0.0.5"]);
+        // Through two of our proxies, the last untrusted hop is the client
+        let ip = client_ip(&trusted, "127.0.0.1".parse().unwrap(), &headers);
+        assert_eq!(ip, "2.2.2.2".parse::<IpAddr>().unwrap());
+        // Straight from a client, the header could say anything, so is ignored
+        let ip = client_ip(&trusted, "3.3.3.3".parse().unwrap(), &headers);
+        assert_eq!(ip, "3.3.3.3".parse::<IpAddr>().unwrap());
+        // A v4-mapped peer on the dual-stack socket is matched as plain v4
+        let ip = client_ip(
+            &trusted,
+            "::ffff:127.0.0.1".parse().unwrap(),
+            &forwarded(&[]),
+        );
+        assert_eq!(ip, "127.0.0.1".parse::<IpAddr>().unwrap());
+        // Garbage stops the walk at the last hop which could be read
+        let headers = forwarded(&["garbage, 10.0.0.9"]);
+        let ip = client_ip(&trusted, "127.0.0.1".parse().unwrap(), &headers);
+        assert_eq!(ip, "10.0.0.9".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn base_paths_are_normalized() {
+        assert_eq!(parse_base_path("").unwrap(), "");
+        assert_eq!(parse_base_path("/").unwrap(), "");
+        assert_eq!(parse_base_path("sliders/").unwrap(), "/sliders");
+        assert_eq!(parse_base_path("/a/b").unwrap(), "/a/b");
+        assert!(parse_base_path("/:id").is_err());
+        assert!(parse_base_path("/a//b").is_err());
+    }
+}
@@ -0,0 +1,720 @@
+//! Token bucket rate limiting of writes, in two tiers: anonymous clients get a bucket per
+//! address, and holders of an API key (sent as `X-Api-Key`) get a larger bucket per key. Every
+//! limited response carries `X-RateLimit-Limit`, `X-RateLimit-Remaining`, and `X-RateLimit-Reset`
+//! (seconds until the bucket is full again). A request making several writes at once takes a
+//! token for each, and is refused whole if the bucket can't cover them all.
+//!
+//! The limits can follow a daily schedule, scaled up or down during windows of the day (in UTC)
+//! like quiet hours, or paused entirely for maintenance, in which case writes are refused with a
+//! 503 until the window ends. The schedule is given as `HH:MM-HH:MM=FACTOR` windows, comma
+//! separated, the first listed winning where they overlap, and is advertised in `/config.json`.
+//!
+//! Buckets live in this process, unless the server is built with the `redis` feature and given
+//! a Redis server to keep them in, so that instances behind a load balancer share one limit.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, IntoResponseParts, Response, ResponseParts};
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::admin::constant_time_eq;
+use crate::{unix_now, SharedState};
+
+static X_API_KEY: HeaderName = HeaderName::from_static("x-api-key");
+static X_RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("x-ratelimit-limit");
+static X_RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("x-ratelimit-remaining");
+static X_RATELIMIT_RESET: HeaderName = HeaderName::from_static("x-ratelimit-reset");
+
+// Full buckets are forgotten after this long, since a new bucket would be full anyway
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Tier {
+    /// Writes allowed per second on average, 0 for no limit
+    pub per_sec: u32,
+    /// Writes allowed at once by a full bucket, its capacity. The bucket refills at `per_sec`.
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    pub anonymous: Tier,
+    pub keyed: Tier,
+    /// Keys which put a client in the `keyed` tier
+    pub api_keys: Vec<String>,
+    /// Redis server to keep buckets in, shared by every instance pointed at it
+    pub redis_url: Option<String>,
+    pub schedule: Schedule,
+}
+
+impl Tier {
+    /// The tier with its rate and burst multiplied by `factor`. An unlimited tier stays unlimited.
+    fn scaled(self, factor: f64) -> Self {
+        if self.per_sec == 0 {
+            return self;
+        }
+        // Never scaled down to 0, which would lift the limit instead
+        let scale = |n: u32| ((f64::from(n) * factor).round() as u32).max(1);
+        Self {
+            per_sec: scale(self.per_sec),
+            burst: scale(self.burst),
+        }
+    }
+
+    /// Tokens in a full bucket
+    fn capacity(self) -> f64 {
+        f64::from(self.burst.max(1))
+    }
+
+    /// A bucket with `tokens` as of `elapsed` seconds ago, refilled since then and with `cost`
+    /// tokens taken if there are that many, returning whether there were and how many are left.
+    /// The Redis script does the same.
+    fn take_tokens(self, tokens: f64, elapsed: f64, cost: f64) -> (bool, f64) {
+        let tokens = (tokens + elapsed.max(0.0) * f64::from(self.per_sec)).min(self.capacity());
+        if tokens >= cost {
+            (true, tokens - cost)
+        } else {
+            (false, tokens)
+        }
+    }
+}
+
+/// A time of day in UTC, to the minute, written as `HH:MM`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(into = "String")]
+pub struct TimeOfDay {
+    /// Since midnight
+    minutes: u16,
+}
+
+impl TimeOfDay {
+    fn secs(self) -> u64 {
+        u64::from(self.minutes) * 60
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid time of day {s:?}, expected HH:MM");
+        let (hours, minutes) = s.trim().split_once(':').ok_or_else(invalid)?;
+        let hours: u16 = hours.parse().map_err(|_| invalid())?;
+        let minutes: u16 = minutes.parse().map_err(|_| invalid())?;
+        if hours >= 24 || minutes >= 60 {
+            return Err(invalid());
+        }
+        Ok(Self {
+            minutes: hours * 60 + minutes,
+        })
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> Self {
+        time.to_string()
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.minutes / 60, self.minutes % 60)
+    }
+}
+
+/// Part of every day during which the limits are scaled
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Window {
+    pub start: TimeOfDay,
+    /// Wraps past midnight if before `start`, and covers the whole day if the same
+    pub end: TimeOfDay,
+    /// Multiplies every tier's rate and burst, 0 refusing writes altogether
+    pub factor: f64,
+}
+
+impl Window {
+    /// Seconds from `secs` into the day until the window ends, if it's open then
+    fn remaining(&self, secs: u64) -> Option<u64> {
+        let (start, end) = (self.start.secs(), self.end.secs());
+        let open = match start.cmp(&end) {
+            std::cmp::Ordering::Less => (start..end).contains(&secs),
+            std::cmp::Ordering::Greater => secs >= start || secs < end,
+            std::cmp::Ordering::Equal => true,
+        };
+        open.then(|| match (end + SECS_PER_DAY - secs) % SECS_PER_DAY {
+            0 => SECS_PER_DAY,
+            remaining => remaining,
+        })
+    }
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid window {s:?}, expected HH:MM-HH:MM=FACTOR");
+        let (times, factor) = s.split_once('=').ok_or_else(invalid)?;
+        let (start, end) = times.split_once('-').ok_or_else(invalid)?;
+        let factor: f64 = factor.trim().parse().map_err(|_| invalid())?;
+        if !factor.is_finite() || factor < 0.0 {
+            return Err(invalid());
+        }
+        Ok(Self {
+            start: start.parse()?,
+            end: end.parse()?,
+            factor,
+        })
+    }
+}
+
+/// Windows of the day with different limits, none by default
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(transparent)]
+pub struct Schedule(Vec<Window>);
+
+impl Schedule {
+    /// The window open at unix time `now`, the first listed if several are, and seconds until it
+    /// ends
+    fn current(&self, now: u64) -> Option<(&Window, u64)> {
+        let secs = now % SECS_PER_DAY;
+        self.0
+            .iter()
+            .find_map(|window| Some((window, window.remaining(secs)?)))
+    }
+
+    /// Until writes are allowed again, if the window open at unix time `now` pauses them
+    fn paused(&self, now: u64) -> Option<Duration> {
+        self.current(now)
+            .filter(|(window, _)| window.factor == 0.0)
+            .map(|(_, remaining)| Duration::from_secs(remaining))
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|window| !window.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Whose bucket a write is taken from. [`enforce`] adds it to the request's extensions, for
+/// handlers to [`charge`] more writes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Client {
+    Anonymous(IpAddr),
+    /// Index of the client's key in the configured keys
+    Keyed(usize),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Buckets {
+    buckets: HashMap<Client, Bucket>,
+    last_prune: Instant,
+}
+
+/// Where a client's bucket stands after a write
+struct Usage {
+    limit: u32,
+    remaining: u32,
+    /// Until the bucket is full again
+    reset: Duration,
+    /// Until the write can be retried, if it was refused
+    retry_after: Option<Duration>,
+}
+
+impl Usage {
+    fn new(tier: Tier, allowed: bool, tokens: f64, cost: f64) -> Self {
+        let rate = f64::from(tier.per_sec);
+        let capacity = tier.capacity();
+        Self {
+            limit: capacity as u32,
+            remaining: tokens as u32,
+            reset: Duration::from_secs_f64(((capacity - tokens) / rate).max(0.0)),
+            retry_after: (!allowed).then(|| Duration::from_secs_f64((cost - tokens) / rate)),
+        }
+    }
+
+    fn insert_headers(&self, headers: &mut HeaderMap) {
+        headers.insert(X_RATELIMIT_LIMIT.clone(), HeaderValue::from(self.limit));
+        headers.insert(
+            X_RATELIMIT_REMAINING.clone(),
+            HeaderValue::from(self.remaining),
+        );
+        headers.insert(
+            X_RATELIMIT_RESET.clone(),
+            HeaderValue::from(self.reset.as_secs_f64().ceil() as u64),
+        );
+    }
+}
+
+fn rate_limited(retry_after: Duration) -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after.as_secs().max(1))],
+        "Rate limit exceeded",
+    )
+        .into_response()
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<Buckets>,
+    #[cfg(feature = "redis")]
+    redis: Option<shared::RedisBuckets>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        #[cfg(feature = "redis")]
+        let redis = config
+            .redis_url
+            .as_deref()
+            .and_then(shared::RedisBuckets::new);
+        #[cfg(not(feature = "redis"))]
+        if config.redis_url.is_some() {
+            tracing::warn!(
+                "SLIDERS_REDIS_URL is set, but the server was built without the redis feature"
+            );
+        }
+        Self {
+            config,
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                last_prune: Instant::now(),
+            }),
+            #[cfg(feature = "redis")]
+            redis,
+        }
+    }
+
+    fn tier(&self, client: Client) -> Tier {
+        let tier = match client {
+            Client::Anonymous(_) => self.config.anonymous,
+            Client::Keyed(_) => self.config.keyed,
+        };
+        match self.config.schedule.current(unix_now()) {
+            Some((window, _)) => tier.scaled(window.factor),
+            None => tier,
+        }
+    }
+
+    /// Until writes are allowed again, if the schedule has paused them
+    fn paused(&self) -> Option<Duration> {
+        self.config.schedule.paused(unix_now())
+    }
+
+    /// Takes `cost` tokens from the client's bucket, or returns `None` if its tier isn't limited
+    async fn take(&self, client: Client, cost: u32) -> Option<Usage> {
+        let tier = self.tier(client);
+        if tier.per_sec == 0 {
+            return None;
+        }
+        let cost = f64::from(cost);
+        #[cfg(feature = "redis")]
+        if let Some(redis) = &self.redis {
+            // If Redis is unreachable, each instance limits on its own until it's back
+            if let Some((allowed, tokens)) = redis.take(client, tier, cost).await {
+                return Some(Usage::new(tier, allowed, tokens, cost));
+            }
+        }
+        let (allowed, tokens) = self.take_local(client, tier, cost);
+        Some(Usage::new(tier, allowed, tokens, cost))
+    }
+
+    /// Takes `cost` tokens from the client's bucket in this process, returning whether there
+    /// were that many and how many are left
+    fn take_local(&self, client: Client, tier: Tier, cost: f64) -> (bool, f64) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if now.duration_since(buckets.last_prune) > PRUNE_INTERVAL {
+            buckets.last_prune = now;
+            buckets.buckets.retain(|&client, bucket| {
+                let tier = self.tier(client);
+                let refilled =
+                    now.duration_since(bucket.updated).as_secs_f64() * f64::from(tier.per_sec);
+                bucket.tokens + refilled < tier.capacity()
+            });
+        }
+        let bucket = buckets.buckets.entry(client).or_insert(Bucket {
+            tokens: tier.capacity(),
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        let (allowed, tokens) = tier.take_tokens(bucket.tokens, elapsed, cost);
+        bucket.tokens = tokens;
+        bucket.updated = now;
+        (allowed, tokens)
+    }
+
+    fn key_index(&self, provided: &[u8]) -> Option<usize> {
+        self.config
+            .api_keys
+            .iter()
+            .position(|key| constant_time_eq(key.as_bytes(), provided))
+    }
+}
+
+/// Middleware enforcing the write rate limits
+pub async fn enforce(
+    State(state): State<SharedState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let limiter = &state.rate_limiter;
+    let client = match req.headers().get(&X_API_KEY) {
+        Some(key) => match limiter.key_index(key.as_bytes()) {
+            Some(index) => Client::Keyed(index),
+            None => return (StatusCode::UNAUTHORIZED, "Unknown API key").into_response(),
+        },
+        None => Client::Anonymous(addr.ip().to_canonical()),
+    };
+    if let Some(remaining) = limiter.paused() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, remaining.as_secs())],
+            "Writes are paused for maintenance",
+        )
+            .into_response();
+    }
+    req.extensions_mut().insert(client);
+    let Some(usage) = limiter.take(client, 1).await else {
+        return next.run(req).await;
+    };
+
+    let mut response = match usage.retry_after {
+        Some(retry_after) => rate_limited(retry_after),
+        None => next.run(req).await,
+    };
+    // A handler which charged more writes has already set the headers
+    if !response.headers().contains_key(&X_RATELIMIT_REMAINING) {
+        usage.insert_headers(response.headers_mut());
+    }
+    response
+}
+
+/// Rate limit headers for a response whose handler [`charge`]d the client for more writes
+pub struct Charged(Option<Usage>);
+
+impl IntoResponseParts for Charged {
+    type Error = Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        if let Some(usage) = self.0 {
+            usage.insert_headers(res.headers_mut());
+        }
+        Ok(res)
+    }
+}
+
+/// Charges a request which makes `writes` writes for all but the one [`enforce`] already took,
+/// refusing it with a 429 if the client's bucket can't cover them all
+pub async fn charge(
+    state: &SharedState,
+    client: Client,
+    writes: usize,
+) -> Result<Charged, Response> {
+    let limiter = &state.rate_limiter;
+    let extra = u32::try_from(writes.saturating_sub(1)).unwrap_or(u32::MAX);
+    if extra == 0 {
+        return Ok(Charged(None));
+    }
+    let tier = limiter.tier(client);
+    if tier.per_sec != 0 && f64::from(extra) + 1.0 > tier.capacity() {
+        // Waiting wouldn't help, a full bucket can't cover them
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            "More writes at once than the rate limit allows",
+        )
+            .into_response());
+    }
+    let Some(usage) = limiter.take(client, extra).await else {
+        return Ok(Charged(None));
+    };
+    match usage.retry_after {
+        Some(retry_after) => {
+            let mut response = rate_limited(retry_after);
+            usage.insert_headers(response.headers_mut());
+            Err(response)
+        }
+        None => Ok(Charged(Some(usage))),
+    }
+}
+
+#[cfg(feature = "redis")]
+mod shared {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use redis::aio::ConnectionManager;
+    use redis::{Client as RedisClient, Script};
+    use tokio::sync::OnceCell;
+    use tracing::{info, warn};
+
+    use super::{Client, Tier};
+
+    // The same refill as `Tier::take_tokens`, timed by the Redis server's clock so instances with
+    // skewed clocks agree. Idle buckets expire once they'd be full again.
+    const TOKEN_BUCKET: &str = r"
+        local rate = tonumber(ARGV[1])
+        local capacity = tonumber(ARGV[2])
+        local cost = tonumber(ARGV[3])
+        local time = redis.call('TIME')
+        local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+        local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+        local tokens = tonumber(bucket[1]) or capacity
+        local updated = tonumber(bucket[2]) or now
+        tokens = math.min(capacity, tokens + math.max(0, now - updated) * rate)
+        local allowed = 0
+        if tokens >= cost then
+            tokens = tokens - cost
+            allowed = 1
+        end
+        redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated', tostring(now))
+        redis.call('PEXPIRE', KEYS[1], math.ceil((capacity - tokens) / rate * 1000) + 1000)
+        return {allowed, tostring(tokens)}
+    ";
+
+    pub struct RedisBuckets {
+        client: RedisClient,
+        connection: OnceCell<ConnectionManager>,
+        script: Script,
+        /// Whether the last attempt to use Redis worked, so failures are logged once rather than
+        /// on every write
+        healthy: AtomicBool,
+    }
+
+    impl RedisBuckets {
+        pub fn new(url: &str) -> Option<Self> {
+            match RedisClient::open(url) {
+                Ok(client) => Some(Self {
+                    client,
+                    connection: OnceCell::new(),
+                    script: Script::new(TOKEN_BUCKET),
+                    healthy: AtomicBool::new(true),
+                }),
+                Err(e) => {
+                    warn!(error = %e, "invalid SLIDERS_REDIS_URL, rate limiting per instance");
+                    None
+                }
+            }
+        }
+
+        /// Takes `cost` tokens from the client's shared bucket, returning whether there were that
+        /// many and how many are left, or `None` if Redis couldn't be reached
+        pub async fn take(&self, client: Client, tier: Tier, cost: f64) -> Option<(bool, f64)> {
+            let key = match client {
+                Client::Anonymous(ip) => format!("sliders:ratelimit:ip:{ip}"),
+                // Instances are expected to share the same list of keys
+                Client::Keyed(index) => format!("sliders:ratelimit:key:{index}"),
+            };
+            let result = async {
+                let connection = self
+                    .connection
+                    .get_or_try_init(|| self.client.get_connection_manager())
+                    .await?;
+                self.script
+                    .key(key)
+                    .arg(tier.per_sec)
+                    .arg(tier.burst.max(1))
+                    .arg(cost)
+                    .invoke_async::<_, (i64, String)>(&mut connection.clone())
+                    .await
+            }
+            .await;
+            match result {
+                Ok((allowed, tokens)) => {
+                    if !self.healthy.swap(true, Ordering::Relaxed) {
+                        info!("rate limiting through redis again");
+                    }
+                    Some((allowed == 1, tokens.parse().unwrap_or(0.0)))
+                }
+                Err(e) => {
+                    if self.healthy.swap(false, Ordering::Relaxed) {
+                        warn!(error = %e, "redis unavailable, rate limiting per instance");
+                    }
+                    None
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(sliders_loom)))]
+mod tests {
+    use super::*;
+
+    const TIER: Tier = Tier {
+        per_sec: 2,
+        burst: 5,
+    };
+
+    const HOUR: u64 = 60 * 60;
+    // Some midnight UTC
+    const MIDNIGHT: u64 = 20_000 * SECS_PER_DAY;
+
+    fn at(hours: u64, minutes: u64) -> u64 {
+        MIDNIGHT + hours * HOUR + minutes * 60
+    }
+
+    fn factor_at(schedule: &Schedule, now: u64) -> Option<f64> {
+        schedule.current(now).map(|(window, _)| window.factor)
+    }
+
+    #[test]
+    fn parse_time_of_day() {
+        assert_eq!("00:00".parse::<TimeOfDay>().unwrap().minutes, 0);
+        assert_eq!(
+            " 23:59 ".parse::<TimeOfDay>().unwrap().minutes,
+            23 * 60 + 59
+        );
+        assert_eq!("7:05".parse::<TimeOfDay>().unwrap().to_string(), "07:05");
+        for invalid in ["24:00", "12:60", "12", "ab:cd", "-1:00", ""] {
+            assert!(invalid.parse::<TimeOfDay>().is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn parse_schedule() {
+        assert!("".parse::<Schedule>().unwrap().0.is_empty());
+        let schedule: Schedule = " 22:00-06:00=0.5 , 12:00-13:00=0,".parse().unwrap();
+        assert_eq!(schedule.0.len(), 2);
+        assert_eq!(schedule.0[0].start.to_string(), "22:00");
+        assert_eq!(schedule.0[0].end.to_string(), "06:00");
+        assert_eq!(schedule.0[0].factor, 0.5);
+        assert_eq!(schedule.0[1].factor, 0.0);
+        for invalid in [
+            "22:00-06:00",
+            "22:00=2",
+            "22:00-06:00=-1",
+            "22:00-06:00=NaN",
+            "22:00-06:00=inf",
+            "22:00-25:00=2",
+            "10:00-11:00=2,oops",
+        ] {
+            assert!(invalid.parse::<Schedule>().is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn window_wraps_past_midnight() {
+        let schedule: Schedule = "22:00-06:00=0.5".parse().unwrap();
+        assert_eq!(factor_at(&schedule, at(21, 59)), None);
+        assert_eq!(schedule.current(at(22, 0)).unwrap().1, 8 * HOUR);
+        assert_eq!(schedule.current(at(23, 30)).unwrap().1, 6 * HOUR + 30 * 60);
+        assert_eq!(schedule.current(at(0, 0)).unwrap().1, 6 * HOUR);
+        assert_eq!(schedule.current(at(5, 59)).unwrap().1, 60);
+        assert_eq!(factor_at(&schedule, at(6, 0)), None);
+        assert_eq!(factor_at(&schedule, at(12, 0)), None);
+    }
+
+    #[test]
+    fn window_with_same_start_and_end_covers_the_day() {
+        let schedule: Schedule = "09:00-09:00=3".parse().unwrap();
+        assert_eq!(schedule.current(at(9, 0)).unwrap().1, SECS_PER_DAY);
+        assert_eq!(schedule.current(at(8, 0)).unwrap().1, HOUR);
+        assert_eq!(schedule.current(at(10, 0)).unwrap().1, 23 * HOUR);
+    }
+
+    #[test]
+    fn first_listed_window_wins() {
+        let schedule: Schedule = "10:00-14:00=2,12:00-16:00=0".parse().unwrap();
+        assert_eq!(factor_at(&schedule, at(11, 0)), Some(2.0));
+        assert_eq!(factor_at(&schedule, at(13, 0)), Some(2.0));
+        assert_eq!(schedule.paused(at(13, 0)), None);
+        assert_eq!(factor_at(&schedule, at(15, 0)), Some(0.0));
+        assert_eq!(factor_at(&schedule, at(16, 0)), None);
+    }
+
+    #[test]
+    fn zero_factor_pauses_until_window_ends() {
+        let schedule: Schedule = "23:30-00:15=0,00:00-01:00=0.5".parse().unwrap();
+        assert_eq!(schedule.paused(at(23, 29)), None);
+        assert_eq!(
+            schedule.paused(at(23, 45)),
+            Some(Duration::from_secs(30 * 60))
+        );
+        assert_eq!(
+            schedule.paused(at(0, 10)),
+            Some(Duration::from_secs(5 * 60))
+        );
+        assert_eq!(schedule.paused(at(0, 15)), None);
+        assert_eq!(factor_at(&schedule, at(0, 15)), Some(0.5));
+    }
+
+    #[test]
+    fn scaling_never_lifts_the_limit() {
+        let scaled = TIER.scaled(0.01);
+        assert_eq!((scaled.per_sec, scaled.burst), (1, 1));
+        let scaled = TIER.scaled(2.5);
+        assert_eq!((scaled.per_sec, scaled.burst), (5, 13));
+        let unlimited = Tier {
+            per_sec: 0,
+            burst: 5,
+        };
+        assert_eq!(unlimited.scaled(0.5).per_sec, 0);
+    }
+
+    #[test]
+    fn full_bucket_allows_burst_then_refuses() {
+        let mut tokens = TIER.capacity();
+        for left in (0..5).rev() {
+            let (allowed, after) = TIER.take_tokens(tokens, 0.0, 1.0);
+            assert!(allowed);
+            assert_eq!(after, f64::from(left));
+            tokens = after;
+        }
+        assert_eq!(TIER.take_tokens(tokens, 0.0, 1.0), (false, 0.0));
+    }
+
+    #[test]
+    fn bucket_refills_at_rate_up_to_capacity() {
+        // Half a second at 2 per second is one token
+        assert_eq!(TIER.take_tokens(0.0, 0.5, 1.0), (true, 0.0));
+        assert_eq!(TIER.take_tokens(0.0, 0.25, 1.0), (false, 0.5));
+        assert_eq!(TIER.take_tokens(1.0, 60.0, 1.0), (true, 4.0));
+        // A clock going backwards doesn't drain the bucket
+        assert_eq!(TIER.take_tokens(3.0, -10.0, 1.0), (true, 2.0));
+    }
+
+    #[test]
+    fn costly_take_is_all_or_nothing() {
+        assert_eq!(TIER.take_tokens(TIER.capacity(), 0.0, 4.0), (true, 1.0));
+        // Not enough for all of them, so none are taken
+        assert_eq!(TIER.take_tokens(1.0, 0.5, 3.0), (false, 2.0));
+        assert_eq!(TIER.take_tokens(1.0, 1.0, 3.0), (true, 0.0));
+        let usage = Usage::new(TIER, false, 2.0, 3.0);
+        assert_eq!(usage.retry_after, Some(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn zero_burst_still_allows_one() {
+        let tier = Tier {
+            per_sec: 1,
+            burst: 0,
+        };
+        assert_eq!(tier.take_tokens(tier.capacity(), 0.0, 1.0), (true, 0.0));
+        assert_eq!(tier.take_tokens(0.0, 10.0, 1.0), (true, 0.0));
+    }
+}
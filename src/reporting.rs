@@ -0,0 +1,70 @@
+//! Reporting of internal errors: always to the log, and to Sentry when built with the `sentry`
+//! feature and given a DSN. Handlers turn failures into a 500 through here rather than panicking,
+//! and panics that slip through are caught and answered with a 500 as well, so the client gets
+//! an error it can quote (with its request id) instead of a dropped connection.
+
+use std::any::Any;
+use std::fmt::Display;
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use tracing::error;
+
+/// Keeps error reporting running, flushing outstanding reports when dropped
+pub struct Reporter {
+    #[cfg(feature = "sentry")]
+    _sentry: Option<sentry::ClientInitGuard>,
+}
+
+/// Starts reporting to Sentry if there's a DSN to report to
+pub fn init(sentry_dsn: Option<&str>) -> Reporter {
+    #[cfg(feature = "sentry")]
+    {
+        let guard = sentry_dsn.map(|dsn| {
+            // The default integrations include reporting panics, caught or not
+            sentry::init((
+                dsn,
+                sentry::ClientOptions {
+                    release: sentry::release_name!(),
+                    attach_stacktrace: true,
+                    ..Default::default()
+                },
+            ))
+        });
+        Reporter { _sentry: guard }
+    }
+    #[cfg(not(feature = "sentry"))]
+    {
+        if sentry_dsn.is_some() {
+            tracing::warn!(
+                "SLIDERS_SENTRY_DSN is set, but the server was built without the sentry feature"
+            );
+        }
+        Reporter {}
+    }
+}
+
+/// Records an unexpected failure
+pub fn report(what: &str, error: impl Display) {
+    error!(error = %error, "{what}");
+    #[cfg(feature = "sentry")]
+    sentry::capture_message(&format!("{what}: {error}"), sentry::Level::Error);
+}
+
+/// Records an unexpected failure in a handler, giving the 500 to respond with
+pub fn internal_error(what: &'static str, error: impl Display) -> (StatusCode, &'static str) {
+    report(what, error);
+    (StatusCode::INTERNAL_SERVER_ERROR, what)
+}
+
+/// Response to a request whose handler panicked, for `CatchPanicLayer`
+pub fn panic_response(panic: Box<dyn Any + Send + 'static>) -> Response {
+    let message = panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic");
+    // Sentry has already seen the panic through its panic hook
+    error!(panic = message, "handler panicked");
+    (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+}
@@ -0,0 +1,100 @@
+//! `X-Request-Id` correlation, so a request a user reports can be found in the server's traces.
+//! A valid id sent by the client (or a proxy in front of us) is kept, otherwise one is generated.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+use axum::body::{self, Body};
+use axum::extract::Request;
+use axum::http::{self, header, HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use tracing::Span;
+
+use crate::rng::Rng;
+
+pub static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// Longer ids sent by clients are replaced rather than logged
+const MAX_ID_LEN: usize = 64;
+// Plain text error messages are short, anything past this is left as it is
+const MAX_ERROR_BODY: usize = 16 * 1024;
+
+/// An id unique to this process, prefixed with a random tag so ids from before and after a
+/// restart don't collide
+fn generate() -> HeaderValue {
+    static PREFIX: OnceLock<u32> = OnceLock::new();
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let prefix = *PREFIX.get_or_init(|| Rng::seeded(0).next() as u32);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    HeaderValue::try_from(format!("{prefix:08x}-{n:x}")).unwrap()
+}
+
+fn is_valid(id: &HeaderValue) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LEN
+        && id
+            .as_bytes()
+            .iter()
+            .all(|&b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+    request_id: String,
+}
+
+/// Span for each request, like tower-http's default but with a place for the request id, and for
+/// the client and scheme, see [`crate::proxy`]
+pub fn make_span<B>(req: &http::Request<B>) -> Span {
+    tracing::debug_span!(
+        "request",
+        method = %req.method(),
+        uri = %req.uri(),
+        version = ?req.version(),
+        request_id = tracing::field::Empty,
+        client = tracing::field::Empty,
+        scheme = tracing::field::Empty,
+    )
+}
+
+/// Middleware attaching a request id to the request, its tracing span (as `request_id`), and
+/// its response. Plain text error responses are turned into JSON carrying the id, so it ends up
+/// wherever the error is shown or reported.
+pub async fn assign(mut req: Request, next: Next) -> Response {
+    let id = match req.headers().get(&X_REQUEST_ID) {
+        Some(id) if is_valid(id) => id.clone(),
+        _ => generate(),
+    };
+    let id_str = id.to_str().unwrap_or_default().to_owned();
+    Span::current().record("request_id", id_str.as_str());
+    req.headers_mut().insert(X_REQUEST_ID.clone(), id.clone());
+
+    let mut response = next.run(req).await;
+    let is_plain_text = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"text/plain"));
+    if (response.status().is_client_error() || response.status().is_server_error()) && is_plain_text
+    {
+        let (mut parts, response_body) = response.into_parts();
+        response = match body::to_bytes(response_body, MAX_ERROR_BODY).await {
+            Ok(bytes) => {
+                let error = ErrorBody {
+                    error: String::from_utf8_lossy(&bytes).into_owned(),
+                    request_id: id_str,
+                };
+                parts.headers.remove(header::CONTENT_TYPE);
+                parts.headers.remove(header::CONTENT_LENGTH);
+                (parts, Json(error)).into_response()
+            }
+            // Too long to be a message meant for the error field, and the body is gone
+            Err(_) => (parts, Body::empty()).into_response(),
+        };
+    }
+    response.headers_mut().insert(X_REQUEST_ID.clone(), id);
+    response
+}
@@ -0,0 +1,238 @@
+//! Export of the set bits as a serialized [roaring bitmap], in the portable format understood by
+//! the CRoaring, Java, and Go implementations (and everything built on them).
+//!
+//! [roaring bitmap]: https://github.com/RoaringBitmap/RoaringFormatSpec
+
+use std::time::Instant;
+
+use axum::extract::{Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use tracing::field::Empty;
+use tracing::{debug, Span};
+
+use crate::chunk;
+use crate::shared_bitmap::SharedBitmap;
+use crate::{Range, SharedState, NUM_CHECKBOXES};
+
+const SERIAL_COOKIE_NO_RUNCONTAINER: u32 = 12346;
+// Each container holds the low 16 bits of values sharing the same high 16 bits
+const CONTAINER_BITS: u64 = 1 << 16;
+const CONTAINER_BYTES: usize = (CONTAINER_BITS / 8) as usize;
+const CONTAINER_WORDS: usize = (CONTAINER_BITS / 64) as usize;
+// Past this many values, a bitmap container is smaller than an array of u16s
+const MAX_ARRAY_CARDINALITY: u32 = 4096;
+
+struct Container {
+    key: u16,
+    cardinality: u32,
+    words: Box<[u64; CONTAINER_WORDS]>,
+}
+
+impl Container {
+    fn serialized_len(&self) -> usize {
+        if self.cardinality > MAX_ARRAY_CARDINALITY {
+            CONTAINER_BYTES
+        } else {
+            self.cardinality as usize * 2
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        if self.cardinality > MAX_ARRAY_CARDINALITY {
+            for word in self.words.iter() {
+                out.extend_from_slice(&word.to_le_bytes());
+            }
+            return;
+        }
+        for (i, &word) in self.words.iter().enumerate() {
+            let mut word = word;
+            while word != 0 {
+                let value = i as u16 * 64 + word.trailing_zeros() as u16;
+                out.extend_from_slice(&value.to_le_bytes());
+                word &= word - 1;
+            }
+        }
+    }
+}
+
+/// Serializes the set bits with indexes in `start..end`
+fn serialize(bitmap: &SharedBitmap, start: u64, end: u64) -> Vec<u8> {
+    let mut containers = Vec::new();
+    let mut bytes = vec![0; CONTAINER_BYTES];
+    let mut key = start / CONTAINER_BITS;
+    // Only whole bytes inside the board can be copied, the partial bytes at either end of the
+    // range are masked off below
+    let copied_from = (key * CONTAINER_BITS / 8) as usize;
+    let copied_to = (end.div_ceil(8) as usize).min(NUM_CHECKBOXES / 8);
+    let snapshot = bitmap.snapshot(copied_from..copied_to);
+    while key * CONTAINER_BITS < end {
+        let base = key * CONTAINER_BITS;
+        let first_byte = (base / 8) as usize;
+        let last_byte =
+            (((base + CONTAINER_BITS).min(end)).div_ceil(8) as usize).min(NUM_CHECKBOXES / 8);
+        bytes.fill(0);
+        bytes[..last_byte - first_byte]
+            .copy_from_slice(&snapshot.bytes[first_byte - copied_from..last_byte - copied_from]);
+
+        let mut words = Box::new([0u64; CONTAINER_WORDS]);
+        let mut cardinality = 0;
+        for (i, (word, word_bytes)) in words.iter_mut().zip(bytes.chunks_exact(8)).enumerate() {
+            let word_start = base + i as u64 * 64;
+            let mut value = u64::from_le_bytes(word_bytes.try_into().unwrap());
+            if word_start < start {
+                value &= u64::MAX
+                    .checked_shl((start - word_start) as u32)
+                    .unwrap_or(0);
+            }
+            if word_start + 64 > end {
+                value &= !u64::MAX
+                    .checked_shl(end.saturating_sub(word_start) as u32)
+                    .unwrap_or(0);
+            }
+            *word = value;
+            cardinality += value.count_ones();
+        }
+        if cardinality > 0 {
+            containers.push(Container {
+                key: key as u16,
+                cardinality,
+                words,
+            });
+        }
+        key += 1;
+    }
+
+    let header_len = 8 + containers.len() * 8;
+    let body_len: usize = containers.iter().map(Container::serialized_len).sum();
+    let mut out = Vec::with_capacity(header_len + body_len);
+    out.extend_from_slice(&SERIAL_COOKIE_NO_RUNCONTAINER.to_le_bytes());
+    out.extend_from_slice(&(containers.len() as u32).to_le_bytes());
+    for container in &containers {
+        out.extend_from_slice(&container.key.to_le_bytes());
+        out.extend_from_slice(&((container.cardinality - 1) as u16).to_le_bytes());
+    }
+    let mut offset = header_len;
+    for container in &containers {
+        out.extend_from_slice(&(offset as u32).to_le_bytes());
+        offset += container.serialized_len();
+    }
+    for container in &containers {
+        container.write(&mut out);
+    }
+    out
+}
+
+/// The indexes of all set checkboxes in the range, as a serialized roaring bitmap
+#[tracing::instrument(
+    skip(state, range),
+    fields(start=range.start, end=range.end, chunks=Empty, bytes=Empty, encode_us=Empty)
+)]
+pub async fn bits_roaring(
+    State(state): State<SharedState>,
+    Query(range): Query<Range>,
+) -> axum::response::Result<impl IntoResponse> {
+    if range.start > range.end {
+        return Err((StatusCode::BAD_REQUEST, "start must be less than end").into());
+    }
+    if range.end > NUM_CHECKBOXES as u64 {
+        return Err((StatusCode::BAD_REQUEST, "end too large").into());
+    }
+    let encode_start = Instant::now();
+    let body = serialize(&state.bitmap, range.start, range.end);
+    let span = Span::current();
+    span.record("chunks", chunk::overlapping(range.start, range.end).len());
+    span.record("bytes", body.len());
+    span.record("encode_us", encode_start.elapsed().as_micros() as u64);
+    debug!("served roaring bitmap");
+    Ok(([(header::CONTENT_TYPE, "application/octet-stream")], body))
+}
+
+#[cfg(all(test, not(sliders_loom)))]
+mod tests {
+    use ::roaring::RoaringBitmap;
+
+    use super::*;
+
+    fn board(name: &str) -> (SharedBitmap, std::path::PathBuf) {
+        let path =
+            std::env::temp_dir().join(format!("sliders-roaring-{name}-{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        (SharedBitmap::load_or_create(&path).unwrap(), path)
+    }
+
+    /// Checks `start..end` serializes to what the reference implementation does for the same bits
+    fn assert_round_trip(bitmap: &SharedBitmap, set: &[u32], start: u64, end: u64) {
+        let serialized = serialize(bitmap, start, end);
+        let read = RoaringBitmap::deserialize_from(&serialized[..]).unwrap();
+        let expected: RoaringBitmap = set
+            .iter()
+            .copied()
+            .filter(|&bit| (start..end).contains(&u64::from(bit)))
+            .collect();
+        assert_eq!(read, expected, "{start}..{end}");
+        let mut reference = Vec::new();
+        expected.serialize_into(&mut reference).unwrap();
+        assert_eq!(serialized, reference, "{start}..{end}");
+    }
+
+    #[test]
+    fn unaligned_ranges() {
+        let (bitmap, path) = board("unaligned");
+        let set = [0, 1, 63, 64, 65, 127, 1000, 65_535, 65_536, 65_537, 200_000];
+        for &bit in &set {
+            bitmap.toggle(bit as usize);
+        }
+        for (start, end) in [
+            (0, NUM_CHECKBOXES as u64),
+            (1, 64),
+            (1, 65),
+            (63, 66),
+            (2, 65_537),
+            (65_535, 65_538),
+            (999, 200_001),
+            (65, 127),
+        ] {
+            assert_round_trip(&bitmap, &set, start, end);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn empty_range() {
+        let (bitmap, path) = board("empty");
+        bitmap.toggle(64);
+        for at in [0, 64, 65, NUM_CHECKBOXES as u64] {
+            assert_round_trip(&bitmap, &[64], at, at);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn array_and_bitmap_containers() {
+        let (bitmap, path) = board("containers");
+        let cardinality = MAX_ARRAY_CARDINALITY as usize;
+        // Exactly as many as an array container holds in the first container, one more in the
+        // second, and one more again at the end of the board
+        let last = NUM_CHECKBOXES - cardinality - 1;
+        let set: Vec<u32> = (0..cardinality)
+            .map(|i| i * 3)
+            .chain((0..=cardinality).map(|i| CONTAINER_BITS as usize + i * 5))
+            .chain(last..NUM_CHECKBOXES)
+            .map(|bit| bit as u32)
+            .collect();
+        for &bit in &set {
+            bitmap.toggle(bit as usize);
+        }
+        assert_round_trip(&bitmap, &set, 0, NUM_CHECKBOXES as u64);
+        // Cutting one off the end of each turns the bitmap containers into arrays
+        assert_round_trip(
+            &bitmap,
+            &set,
+            1,
+            (CONTAINER_BITS as usize + cardinality * 5) as u64,
+        );
+        assert_round_trip(&bitmap, &set, last as u64 + 1, NUM_CHECKBOXES as u64);
+        std::fs::remove_file(path).unwrap();
+    }
+}
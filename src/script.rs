@@ -0,0 +1,292 @@
+//! `POST /macro`, a short script of sets, toggles, and fills run in one request. Operations run in
+//! order, and all of a script's changes to a chunk are applied together, so subscribers see them
+//! as a single update rather than one per operation. Only the bytes a script sets are written,
+//! and its toggles are applied as toggles, so writes by others to the rest of the chunk aren't
+//! lost.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::ops::Range;
+
+use axum::extract::{ConnectInfo, State};
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+
+use crate::chunk::Chunk;
+use crate::cluster::Writes;
+use crate::rate_limit::{self, Charged, Client};
+use crate::shared_bitmap::{CHUNK_BITS, CHUNK_BYTES};
+use crate::{throttled, SharedState, NUM_CHECKBOXES, NUM_SLIDERS};
+
+/// Most operations accepted in one script
+pub const MAX_MACRO_OPS: usize = 1_000;
+/// Most sliders a script's fills may cover in total
+pub const MAX_MACRO_FILL: u64 = 64 * 1024;
+
+#[derive(Deserialize, Debug)]
+pub struct Script {
+    ops: Vec<Op>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Op {
+    /// Sets slider `idx` to `value`
+    Set { idx: u64, value: u8 },
+    /// Toggles checkbox `idx`
+    Toggle { idx: u64 },
+    /// Sets sliders `start` up to (not including) `end` to `value`
+    Fill { start: u64, end: u64, value: u8 },
+}
+
+impl Op {
+    /// The sliders the operation changes
+    fn bytes(&self) -> Range<u64> {
+        match *self {
+            Op::Set { idx, .. } => idx..idx + 1,
+            Op::Toggle { idx } => idx / 8..idx / 8 + 1,
+            Op::Fill { start, end, .. } => start..end,
+        }
+    }
+
+    fn in_bounds(&self) -> bool {
+        match *self {
+            Op::Set { idx, .. } => idx < NUM_SLIDERS as u64,
+            Op::Toggle { idx } => idx < NUM_CHECKBOXES as u64,
+            Op::Fill { start, end, .. } => start <= end && end <= NUM_SLIDERS as u64,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ScriptResult {
+    /// Operations run
+    ops: usize,
+    /// Chunks written to
+    chunks: usize,
+}
+
+/// A script's changes to one chunk. Toggles of a byte the script also sets are folded into the
+/// value it's set to, so the bits toggled and the bytes stored never overlap.
+struct ChunkWrite {
+    /// The value of each byte the script sets, where `set`
+    values: [u8; CHUNK_BYTES],
+    set: [bool; CHUNK_BYTES],
+    /// Bits to toggle in each byte the script doesn't set
+    flips: [u8; CHUNK_BYTES],
+}
+
+impl ChunkWrite {
+    fn new() -> Self {
+        Self {
+            values: [0; CHUNK_BYTES],
+            set: [false; CHUNK_BYTES],
+            flips: [0; CHUNK_BYTES],
+        }
+    }
+
+    fn fill(&mut self, span: Range<usize>, value: u8) {
+        self.values[span.clone()].fill(value);
+        self.set[span.clone()].fill(true);
+        // Anything toggled before is overwritten
+        self.flips[span].fill(0);
+    }
+
+    fn toggle(&mut self, bit: u16) {
+        let (byte_index, mask) = Chunk::index_mask(bit);
+        if self.set[byte_index] {
+            self.values[byte_index] ^= mask;
+        } else {
+            self.flips[byte_index] ^= mask;
+        }
+    }
+
+    /// The bits to toggle, indexes within the chunk
+    fn bits(&self) -> Vec<u16> {
+        (0..CHUNK_BITS as u16)
+            .filter(|&bit| {
+                let (byte_index, mask) = Chunk::index_mask(bit);
+                self.flips[byte_index] & mask != 0
+            })
+            .collect()
+    }
+
+    /// Each run of bytes the script sets, by its offset within the chunk
+    fn stores(&self) -> Vec<(usize, &[u8])> {
+        let mut start = 0;
+        self.set
+            .chunk_by(|a, b| a == b)
+            .filter_map(|run| {
+                let offset = start;
+                start += run.len();
+                run[0].then(|| (offset, &self.values[offset..start]))
+            })
+            .collect()
+    }
+}
+
+/// Each chunk's changes from running `ops` in order, by chunk
+fn plan(ops: &[Op]) -> BTreeMap<usize, ChunkWrite> {
+    let mut chunks: BTreeMap<usize, ChunkWrite> = BTreeMap::new();
+    for op in ops {
+        let bytes = op.bytes();
+        let mut index = bytes.start as usize;
+        while index < bytes.end as usize {
+            let chunk = index / CHUNK_BYTES;
+            let chunk_end = ((chunk + 1) * CHUNK_BYTES).min(bytes.end as usize);
+            let write = chunks.entry(chunk).or_insert_with(ChunkWrite::new);
+            match *op {
+                Op::Set { value, .. } | Op::Fill { value, .. } => write.fill(
+                    index % CHUNK_BYTES..(chunk_end - 1) % CHUNK_BYTES + 1,
+                    value,
+                ),
+                Op::Toggle { idx } => write.toggle((idx as usize % CHUNK_BITS) as u16),
+            }
+            index = chunk_end;
+        }
+    }
+    chunks
+}
+
+#[tracing::instrument(skip_all, fields(ops = script.ops.len()))]
+pub async fn run(
+    State(state): State<SharedState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(client): Extension<Client>,
+    Json(script): Json<Script>,
+) -> axum::response::Result<(Charged, Json<ScriptResult>)> {
+    if script.ops.len() > MAX_MACRO_OPS {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Too many operations in one macro",
+        )
+            .into());
+    }
+    if !script.ops.iter().all(Op::in_bounds) {
+        return Err((StatusCode::BAD_REQUEST, "Operation out of bounds").into());
+    }
+    let filled: u64 = script
+        .ops
+        .iter()
+        .filter(|op| matches!(op, Op::Fill { .. }))
+        .map(|op| op.bytes().end - op.bytes().start)
+        .sum();
+    if filled > MAX_MACRO_FILL {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Fills cover too much of the board",
+        )
+            .into());
+    }
+    // Every operation counts against the client like a separate write would, a fill once for each
+    // chunk it covers, and if the client can't afford them all or gets throttled, none of them are
+    // applied
+    let write_bits: Vec<u64> = script
+        .ops
+        .iter()
+        .flat_map(|op| {
+            let bytes = op.bytes();
+            let chunks = if bytes.is_empty() {
+                0..0
+            } else {
+                bytes.start / CHUNK_BYTES as u64..(bytes.end - 1) / CHUNK_BYTES as u64 + 1
+            };
+            chunks.map(move |chunk| match *op {
+                Op::Toggle { idx } => idx,
+                _ => (chunk * CHUNK_BYTES as u64).max(bytes.start) * 8,
+            })
+        })
+        .collect();
+    let charged = rate_limit::charge(&state, client, write_bits.len()).await?;
+    for &bit_index in &write_bits {
+        state.abuse.check(addr.ip(), bit_index).map_err(throttled)?;
+    }
+
+    let planned = plan(&script.ops);
+    let mut writes = Writes::new(&state);
+    for (&chunk, write) in &planned {
+        writes.write_chunk(chunk, &write.bits(), &write.stores());
+    }
+    writes.finish().await?;
+    Ok((
+        charged,
+        Json(ScriptResult {
+            ops: script.ops.len(),
+            chunks: planned.len(),
+        }),
+    ))
+}
+
+#[cfg(all(test, not(sliders_loom)))]
+mod tests {
+    use super::*;
+    use crate::shared_bitmap::SharedBitmap;
+
+    fn board(name: &str) -> (SharedBitmap, std::path::PathBuf) {
+        let path =
+            std::env::temp_dir().join(format!("sliders-script-{name}-{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        (SharedBitmap::load_or_create(&path).unwrap(), path)
+    }
+
+    fn apply(bitmap: &SharedBitmap, planned: &BTreeMap<usize, ChunkWrite>) {
+        for (&chunk, write) in planned {
+            bitmap.write_chunk(chunk, &write.bits(), &write.stores());
+        }
+    }
+
+    #[test]
+    fn toggles_folded_into_sets() {
+        let planned = plan(&[
+            Op::Toggle { idx: 3 * 8 },
+            Op::Set { idx: 3, value: 0 },
+            Op::Toggle { idx: 3 * 8 + 1 },
+            Op::Toggle { idx: 5 * 8 },
+            Op::Fill {
+                start: 5,
+                end: 7,
+                value: 2,
+            },
+            Op::Toggle { idx: 9 * 8 + 2 },
+            Op::Toggle { idx: 9 * 8 + 4 },
+            Op::Toggle { idx: 9 * 8 + 4 },
+        ]);
+        let write = &planned[&0];
+        assert_eq!(write.stores(), [(3, &[0b10][..]), (5, &[2, 2][..])]);
+        assert_eq!(write.bits(), [9 * 8 + 2]);
+    }
+
+    #[test]
+    fn fill_split_across_chunks() {
+        let planned = plan(&[Op::Fill {
+            start: CHUNK_BYTES as u64 - 2,
+            end: CHUNK_BYTES as u64 + 1,
+            value: 7,
+        }]);
+        assert_eq!(planned.len(), 2);
+        assert_eq!(planned[&0].stores(), [(CHUNK_BYTES - 2, &[7, 7][..])]);
+        assert_eq!(planned[&1].stores(), [(0, &[7][..])]);
+    }
+
+    #[test]
+    fn concurrent_writes_survive() {
+        let (bitmap, path) = board("concurrent");
+        bitmap.set_byte(127, 1);
+        let planned = plan(&[Op::Toggle { idx: 0 }, Op::Set { idx: 127, value: 9 }]);
+
+        // Made by others after the script is planned, before it's applied
+        bitmap.set_byte(64, 0x55);
+        bitmap.toggle(0);
+        apply(&bitmap, &planned);
+
+        let mut bytes = [0; CHUNK_BYTES];
+        bitmap.load_bytes(0, &mut bytes);
+        // Toggled once by each, so back to clear
+        assert_eq!(bytes[0], 0);
+        assert_eq!(bytes[64], 0x55);
+        assert_eq!(bytes[127], 9);
+        assert_eq!(bitmap.count(), 4 + 2);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -3,88 +3,345 @@ use std::convert::Infallible;
 use std::fs::File;
 use std::future::Future;
 use std::path::Path;
-use std::sync::atomic::{AtomicU64, AtomicU8};
 use std::sync::Arc;
 use std::{io, mem};
-use tokio::sync::{watch, Notify};
+use tokio::sync::{broadcast, watch, Notify};
 use tokio::task::JoinHandle;
-use tokio::time::Instant;
+use tokio::time::{Duration, Instant, MissedTickBehavior};
+use tracing::{debug, warn};
 
-pub const CHUNK_BYTES: usize = 128;
-pub const CHUNK_BITS: usize = CHUNK_BYTES * 8;
+use crate::chunk::Chunk;
+pub use crate::chunk::{CHUNK_BITS, CHUNK_BYTES};
+use crate::latency::{Propagation, Stage};
+
+#[cfg(sliders_loom)]
+use loom::sync::atomic::{fence, AtomicBool, AtomicU64};
+#[cfg(not(sliders_loom))]
+use std::sync::atomic::{fence, AtomicBool, AtomicU64};
 
 const TOTAL_BITS: usize = crate::NUM_CHECKBOXES;
-const NUM_CHUNKS: usize = (TOTAL_BITS + CHUNK_BITS - 1) / CHUNK_BITS;
+pub const NUM_CHUNKS: usize = TOTAL_BITS.div_ceil(CHUNK_BITS);
+const TOTAL_BYTES: usize = NUM_CHUNKS * CHUNK_BYTES;
 
-#[repr(transparent)]
-struct Chunk([AtomicU8; CHUNK_BYTES]);
+// Shortest time between updates sent to a chunk's watchers
+const MIN_NOTIFY_INTERVAL: Duration = Duration::from_millis(100);
+// Each this many watchers on a chunk stretch its notify interval by another `MIN_NOTIFY_INTERVAL`
+const WATCHERS_PER_NOTIFY_STEP: usize = 100;
 
-impl Default for Chunk {
-    fn default() -> Self {
-        Self::new()
-    }
+// Single byte writes held for slow byte write subscribers before they start missing some
+const BYTE_WRITES_CAPACITY: usize = 1024;
+
+// Granularity of dirty tracking for flushes, matches the usual page size
+const DIRTY_PAGE_BYTES: usize = 4096;
+const NUM_DIRTY_PAGES: usize = TOTAL_BYTES.div_ceil(DIRTY_PAGE_BYTES);
+
+// Times a read of many chunks goes back over the ones written meanwhile before holding off
+// writes to the ones still being written
+const ROUNDS_BEFORE_HOLD: usize = 4;
+// Tries at something another thread is in the way of before giving up the thread between them
+const SPINS_BEFORE_YIELD: u32 = 64;
+
+/// A single byte overwritten by [`SharedBitmap::set_byte`]
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct ByteWrite {
+    pub index: usize,
+    pub old: u8,
+    pub new: u8,
+}
+
+/// A chunk's contents as last sent to its watchers
+#[derive(Debug, Clone, Copy)]
+pub struct VersionedChunk {
+    /// Increases every time the chunk's watchers are sent new contents. Versions are drawn from
+    /// a single board-wide counter, see [`SharedBitmap::version`].
+    pub version: u64,
+    pub bytes: [u8; CHUNK_BYTES],
+    /// When the first write which went into these contents was made, `None` for contents which
+    /// weren't sent because of a write, like those at startup
+    pub changed_at: Option<Instant>,
 }
 
-impl Chunk {
-    pub const fn new() -> Self {
-        Self([const { AtomicU8::new(0) }; CHUNK_BYTES])
+/// Counts of the writes to a chunk begun and ended, which a reader compares before and after
+/// copying the chunk to know it saw no write half done. Like a sequence lock, but for any number
+/// of writers at once. Writers don't wait for readers, unless a reader found no moment between
+/// writes and is holding off new ones to the chunk for a moment.
+struct WriteSeq {
+    begun: AtomicU64,
+    ended: AtomicU64,
+    /// Readers holding off new writes
+    held: AtomicU64,
+}
+
+/// A write to a chunk in progress, ended when dropped
+struct WriteSeqGuard<'a>(&'a WriteSeq);
+
+/// Holds off new writes to a chunk until dropped
+struct HoldGuard<'a>(&'a WriteSeq);
+
+impl WriteSeq {
+    fn new() -> Self {
+        Self {
+            begun: AtomicU64::new(0),
+            ended: AtomicU64::new(0),
+            held: AtomicU64::new(0),
+        }
+    }
+
+    /// Waits for any reader holding off writes, then begins a write
+    fn begin(&self) -> WriteSeqGuard<'_> {
+        self.wait_for_readers();
+        self.begin_now()
     }
 
-    // Returns if the byte was added, otherwise, it was removed
-    pub fn toggle(&self, index: u16) -> bool {
-        let (byte_index, mask) = Self::index_mask(index);
-        let byte = &self.0[byte_index];
-        let orig = byte.fetch_xor(mask, std::sync::atomic::Ordering::Relaxed);
-        (orig & mask) != 0
+    /// Waits for any reader holding off writes. Never to be called with a write begun, which a
+    /// reader could be waiting on in turn.
+    fn wait_for_readers(&self) {
+        let mut spins = 0;
+        while self.held.load(std::sync::atomic::Ordering::Relaxed) != 0 {
+            backoff(&mut spins);
+        }
     }
 
-    pub fn set_byte(&self, index: usize, byte: u8) -> u8 {
-        self.0[index].swap(byte, std::sync::atomic::Ordering::Relaxed)
+    /// Begins a write, for a writer which has already waited for readers
+    fn begin_now(&self) -> WriteSeqGuard<'_> {
+        self.begun
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        // Pairs with the acquire fences of readers, so one which sees anything done under the
+        // guard also sees the write begun
+        fence(std::sync::atomic::Ordering::Release);
+        WriteSeqGuard(self)
     }
 
-    pub fn load(&self, dst: &mut [u8; CHUNK_BYTES]) {
-        for (out, byte) in dst.iter_mut().zip(&self.0) {
-            *out = byte.load(std::sync::atomic::Ordering::Relaxed);
+    fn hold(&self) -> HoldGuard<'_> {
+        self.held.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        HoldGuard(self)
+    }
+
+    /// Runs `read`, which should only do relaxed loads, until it ran with no write in progress or
+    /// begun meanwhile. Returns its result and the count of writes begun as of it, for
+    /// [`changed_since`](Self::changed_since).
+    fn read<T>(&self, mut read: impl FnMut() -> T) -> (T, u64) {
+        let mut spins = 0;
+        let mut _hold = None;
+        loop {
+            // Pairs with the release in `end`, so every ended write is seen, and counted as begun
+            let ended = self.ended.load(std::sync::atomic::Ordering::Acquire);
+            let begun = self.begun.load(std::sync::atomic::Ordering::Relaxed);
+            if begun == ended {
+                let value = read();
+                fence(std::sync::atomic::Ordering::Acquire);
+                if !self.changed_since(begun) {
+                    return (value, begun);
+                }
+            }
+            backoff(&mut spins);
+            // Writes keep coming back to back, so the writes in progress are let finish without
+            // new ones starting
+            if spins == SPINS_BEFORE_YIELD {
+                _hold = Some(self.hold());
+            }
         }
     }
 
-    #[inline]
-    const fn index_mask(index: u16) -> (usize, u8) {
-        debug_assert!(index < CHUNK_BITS as u16);
-        let index = index % CHUNK_BITS as u16;
-        let byte_index = index / 8;
-        let bit_index = index % 8;
-        (byte_index as usize, 1 << bit_index)
+    /// Whether a write began after `read` returned `begun`. Loads made before this of anything
+    /// such a write changes must be followed by an acquire fence for it to be seen here.
+    fn changed_since(&self, begun: u64) -> bool {
+        self.begun.load(std::sync::atomic::Ordering::Relaxed) != begun
+    }
+}
+
+impl Drop for WriteSeqGuard<'_> {
+    fn drop(&mut self) {
+        self.0
+            .ended
+            .fetch_add(1, std::sync::atomic::Ordering::Release);
+    }
+}
+
+impl Drop for HoldGuard<'_> {
+    fn drop(&mut self) {
+        self.0
+            .held
+            .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Waits a moment for another thread, spinning at first, then giving up the thread in case the
+/// other isn't running
+fn backoff(spins: &mut u32) {
+    *spins = spins.saturating_add(1);
+    #[cfg(sliders_loom)]
+    loom::thread::yield_now();
+    #[cfg(not(sliders_loom))]
+    if *spins < SPINS_BEFORE_YIELD {
+        std::hint::spin_loop();
+    } else {
+        std::thread::yield_now();
     }
 }
 
 struct Segment {
     notify_changed: Notify,
-    watch: watch::Sender<[u8; CHUNK_BYTES]>,
+    watch: watch::Sender<VersionedChunk>,
+    /// Unix time the chunk's watchers were last sent a change, 0 if not since startup
+    last_modified: AtomicU64,
+    /// Writes to the chunk since startup
+    mutations: AtomicU64,
+    /// Current minimum time between updates sent to watchers, in milliseconds
+    notify_interval_ms: AtomicU64,
+    /// Microseconds after [`SharedBitmap::started`] of the first write since the chunk was last
+    /// sent to its watchers, plus one so that 0 can mean no write since
+    changed_at: AtomicU64,
+    /// Set by writes, cleared when the chunk is sent to its watchers. Only the write which sets it
+    /// wakes the segment's task, the rest are picked up by the same send.
+    dirty: AtomicBool,
+    /// Held by every write to the chunk, for reads of it (and of the totals) which mustn't see a
+    /// write half done
+    writes: WriteSeq,
 }
 
 impl Default for Segment {
     fn default() -> Self {
         Self {
             notify_changed: Notify::new(),
-            watch: watch::Sender::new([0; CHUNK_BYTES]),
+            watch: watch::Sender::new(VersionedChunk {
+                version: 0,
+                bytes: [0; CHUNK_BYTES],
+                changed_at: None,
+            }),
+            last_modified: AtomicU64::new(0),
+            mutations: AtomicU64::new(0),
+            notify_interval_ms: AtomicU64::new(MIN_NOTIFY_INTERVAL.as_millis() as u64),
+            changed_at: AtomicU64::new(0),
+            dirty: AtomicBool::new(false),
+            writes: WriteSeq::new(),
         }
     }
 }
 impl Segment {
-    fn from_bytes(current_slice: &[u8; CHUNK_BYTES]) -> Self {
+    fn from_bytes(current_slice: &[u8; CHUNK_BYTES], version: u64) -> Self {
         Self {
             notify_changed: Notify::new(),
-            watch: watch::Sender::new(*current_slice),
+            watch: watch::Sender::new(VersionedChunk {
+                version,
+                bytes: *current_slice,
+                changed_at: None,
+            }),
+            last_modified: AtomicU64::new(0),
+            mutations: AtomicU64::new(0),
+            notify_interval_ms: AtomicU64::new(MIN_NOTIFY_INTERVAL.as_millis() as u64),
+            changed_at: AtomicU64::new(0),
+            dirty: AtomicBool::new(false),
+            writes: WriteSeq::new(),
         }
     }
 }
 
+/// Running totals over the whole bitmap, kept up to date incrementally by each mutation
+struct Counters {
+    bits_set: AtomicU64,
+    bytes_sum: AtomicU64,
+    mutations: AtomicU64,
+}
+
+impl Counters {
+    fn new(bits_set: u64, bytes_sum: u64) -> Self {
+        Self {
+            bits_set: AtomicU64::new(bits_set),
+            bytes_sum: AtomicU64::new(bytes_sum),
+            mutations: AtomicU64::new(0),
+        }
+    }
+
+    fn byte_changed(&self, prev: u8, byte: u8) {
+        let bit_diff = byte.count_ones() as i32 - prev.count_ones() as i32;
+        let diff = byte as i32 - prev as i32;
+        // use `as u64` which will sign extend, adding a sign extended negative value will act the
+        // same as subtracting
+        self.bits_set
+            .fetch_add(bit_diff as u64, std::sync::atomic::Ordering::Relaxed);
+        self.bytes_sum
+            .fetch_add(diff as u64, std::sync::atomic::Ordering::Relaxed);
+        self.mutations
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Applies the combined effect of many byte changes, counted as a single mutation
+    fn bytes_changed(&self, bit_diff: i64, diff: i64) {
+        self.adjust(bit_diff, diff);
+        self.mutations
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Changes the totals without counting a mutation
+    fn adjust(&self, bit_diff: i64, diff: i64) {
+        self.bits_set
+            .fetch_add(bit_diff as u64, std::sync::atomic::Ordering::Relaxed);
+        self.bytes_sum
+            .fetch_add(diff as u64, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Bit `bit`, an index within its chunk, was toggled from `prev_bit`
+    fn bit_toggled(&self, bit: u16, prev_bit: bool) {
+        let (_, mask) = Chunk::index_mask(bit);
+        let (bit_diff, diff) = if prev_bit {
+            (-1, -i64::from(mask))
+        } else {
+            (1, i64::from(mask))
+        };
+        self.bits_set
+            .fetch_add(bit_diff as u64, std::sync::atomic::Ordering::Relaxed);
+        // The slider holding the bit moves by the bit's value
+        self.bytes_sum
+            .fetch_add(diff as u64, std::sync::atomic::Ordering::Relaxed);
+        self.mutations
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn count(&self) -> u64 {
+        self.bits_set.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    fn sum(&self) -> u64 {
+        self.bytes_sum.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    fn mutations(&self) -> u64 {
+        self.mutations.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+/// Running totals which didn't match the board, and what they were corrected to
+#[derive(Debug)]
+pub struct Drift {
+    pub was_count: u64,
+    pub count: u64,
+    pub was_sum: u64,
+    pub sum: u64,
+}
+
 pub struct SharedBitmap {
     segments: Box<[Segment; NUM_CHUNKS]>,
     map: MmapRaw,
-    bits_set: AtomicU64,
-    bytes_sum: AtomicU64,
+    counters: Counters,
+    /// Latest chunk version handed out
+    version: AtomicU64,
+    dirty_pages: Box<[AtomicBool]>,
+    byte_writes: broadcast::Sender<ByteWrite>,
+    /// When the board was loaded, which write times are kept relative to
+    started: Instant,
+    /// Milliseconds after `started` that the flush task last finished a pass without an error
+    last_flush: AtomicU64,
+    propagation: Propagation,
+}
+
+/// A copy of part of the board as of a single point in the sequence of writes
+pub struct Snapshot {
+    pub bytes: Vec<u8>,
+    /// [`SharedBitmap::sequence`] as of the copy
+    pub sequence: u64,
+    /// [`SharedBitmap::version`] as of the copy
+    pub version: u64,
 }
 
 impl SharedBitmap {
@@ -100,100 +357,703 @@ impl SharedBitmap {
             .truncate(false)
             .open(path)?;
 
-        file.set_len(NUM_CHUNKS as u64 * CHUNK_BYTES as u64)?;
+        file.set_len(TOTAL_BYTES as u64)?;
 
         let map = unsafe { MmapOptions::new().map_mut(&file)? };
         let count = map.iter().map(|&byte| byte.count_ones() as u64).sum();
         let bytes_sum = map.iter().copied().map(u64::from).sum();
+        // Versions start from the time in microseconds, so they keep increasing across restarts.
+        // Each chunk takes a version at most once per `MIN_NOTIFY_INTERVAL`, far slower than that.
+        let initial_version = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
 
         let segment = |i| {
             let slice = &map[i * CHUNK_BYTES..][..CHUNK_BYTES];
             let slice: &[u8; CHUNK_BYTES] = slice.try_into().unwrap();
-            Segment::from_bytes(slice)
+            Segment::from_bytes(slice, initial_version)
         };
         let segments: Box<[Segment]> = (0..NUM_CHUNKS).map(segment).collect();
         let segments = segments.try_into().map_err(|_| ()).unwrap();
         Ok(Self {
             segments,
             map: MmapRaw::from(map),
-            bits_set: AtomicU64::new(count),
-            bytes_sum: AtomicU64::new(bytes_sum),
+            counters: Counters::new(count, bytes_sum),
+            version: AtomicU64::new(initial_version),
+            dirty_pages: (0..NUM_DIRTY_PAGES)
+                .map(|_| AtomicBool::new(false))
+                .collect(),
+            byte_writes: broadcast::Sender::new(BYTE_WRITES_CAPACITY),
+            started: Instant::now(),
+            last_flush: AtomicU64::new(0),
+            propagation: Propagation::new(),
         })
     }
 
+    /// Tasks sending each chunk's changes to its watchers. Changes are coalesced for at least
+    /// `MIN_NOTIFY_INTERVAL`, stretched up to `max_notify_interval` for chunks that keep changing
+    /// faster than that or have many watchers, to bound the bandwidth of a spam storm.
     pub fn run_tasks<'a>(
         self: &'a Arc<Self>,
+        max_notify_interval: Duration,
     ) -> impl Iterator<Item = impl Future<Output = Infallible>> + 'a {
-        (0..self.segments.len()).map(|i| {
+        (0..self.segments.len()).map(move |i| {
             let shared = Arc::clone(self);
             async move {
                 let segment = &shared.segments[i];
                 let mut next_possible_update = Instant::now();
+                let mut busy_interval = MIN_NOTIFY_INTERVAL;
                 loop {
-                    segment.notify_changed.notified().await;
+                    // A chunk which stays quiet for a whole interval is no longer busy
+                    let quiet = async {
+                        if busy_interval > MIN_NOTIFY_INTERVAL {
+                            tokio::time::sleep(busy_interval).await
+                        } else {
+                            std::future::pending().await
+                        }
+                    };
+                    tokio::select! {
+                        () = segment.notify_changed.notified() => {
+                            if let Some(changed_at) = shared.changed_at(segment) {
+                                shared.propagation.record(Stage::Woken, changed_at.elapsed());
+                            }
+                        }
+                        () = quiet => {
+                            busy_interval = MIN_NOTIFY_INTERVAL;
+                            segment.notify_interval_ms.store(
+                                MIN_NOTIFY_INTERVAL.as_millis() as u64,
+                                std::sync::atomic::Ordering::Relaxed,
+                            );
+                            continue;
+                        }
+                    }
+                    // Changing again before we were able to send the last change means the chunk
+                    // is busy, so back off further, otherwise relax back toward the minimum
+                    busy_interval = if Instant::now() < next_possible_update {
+                        (busy_interval * 2).min(max_notify_interval)
+                    } else {
+                        (busy_interval / 2).max(MIN_NOTIFY_INTERVAL)
+                    };
                     tokio::time::sleep_until(next_possible_update).await;
-                    next_possible_update = Instant::now() + std::time::Duration::from_millis(100);
+
+                    let watchers = segment.watch.receiver_count();
+                    let watched_interval =
+                        MIN_NOTIFY_INTERVAL * (1 + watchers / WATCHERS_PER_NOTIFY_STEP) as u32;
+                    let interval = busy_interval.max(watched_interval).min(max_notify_interval);
+                    next_possible_update = Instant::now() + interval;
+                    segment.notify_interval_ms.store(
+                        interval.as_millis() as u64,
+                        std::sync::atomic::Ordering::Relaxed,
+                    );
 
                     let chunk = &shared.chunks()[i];
-                    segment.watch.send_modify(|c| chunk.load(c));
+                    // Taken before loading, so a write made after the contents are loaded is
+                    // always noted for the next send
+                    let changed_at = shared.take_changed_at(segment);
+                    // Likewise, a write made after the contents are loaded wakes us again, and one
+                    // which found the flag still set is in the contents
+                    segment
+                        .dirty
+                        .swap(false, std::sync::atomic::Ordering::AcqRel);
+                    segment.watch.send_modify(|c| {
+                        chunk.load(&mut c.bytes);
+                        c.version = shared.next_version();
+                        c.changed_at = changed_at;
+                    });
+                    if let Some(changed_at) = changed_at {
+                        shared.propagation.record(Stage::Sent, changed_at.elapsed());
+                    }
+                    segment
+                        .last_modified
+                        .store(crate::unix_now(), std::sync::atomic::Ordering::Relaxed);
                 }
             }
         })
     }
 
-    pub fn spawn_tasks(self: &Arc<Self>) -> SharedBitmapRunningTasks {
-        let tasks = self.run_tasks().map(tokio::spawn).collect();
+    pub fn spawn_tasks(
+        self: &Arc<Self>,
+        flush_interval: Duration,
+        max_notify_interval: Duration,
+    ) -> SharedBitmapRunningTasks {
+        let mut tasks: Vec<_> = self
+            .run_tasks(max_notify_interval)
+            .map(tokio::spawn)
+            .collect();
+        tasks.push(tokio::spawn(Arc::clone(self).flush_task(flush_interval)));
         SharedBitmapRunningTasks { tasks }
     }
 
+    async fn flush_task(self: Arc<Self>, flush_interval: Duration) -> Infallible {
+        let mut interval = tokio::time::interval(flush_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            match self.flush_dirty() {
+                Ok(pages) => {
+                    if pages != 0 {
+                        debug!(pages, "flushed dirty pages");
+                    }
+                    self.last_flush.store(
+                        self.started.elapsed().as_millis() as u64,
+                        std::sync::atomic::Ordering::Relaxed,
+                    );
+                }
+                Err(e) => warn!(error = %e, "failed to flush dirty pages"),
+            }
+        }
+    }
+
+    /// How long since the flush task last got through a pass without an error, which keeps
+    /// growing if its flushes keep failing or it has died
+    pub fn since_flush(&self) -> Duration {
+        let last_flush = self.last_flush.load(std::sync::atomic::Ordering::Relaxed);
+        self.started
+            .elapsed()
+            .saturating_sub(Duration::from_millis(last_flush))
+    }
+
+    /// Starts asynchronous writeback of every page modified since the last flush, returning the
+    /// number of pages flushed
+    pub fn flush_dirty(&self) -> io::Result<usize> {
+        let mut flushed = 0;
+        let mut run_start = None;
+        for (i, dirty) in self.dirty_pages.iter().enumerate() {
+            let is_dirty = dirty.swap(false, std::sync::atomic::Ordering::Relaxed);
+            match (is_dirty, run_start) {
+                (true, None) => run_start = Some(i),
+                (false, Some(start)) => {
+                    flushed += i - start;
+                    self.flush_pages(start..i)?;
+                    run_start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(start) = run_start {
+            flushed += NUM_DIRTY_PAGES - start;
+            self.flush_pages(start..NUM_DIRTY_PAGES)?;
+        }
+        Ok(flushed)
+    }
+
+    fn flush_pages(&self, pages: std::ops::Range<usize>) -> io::Result<()> {
+        let offset = pages.start * DIRTY_PAGE_BYTES;
+        let end = (pages.end * DIRTY_PAGE_BYTES).min(TOTAL_BYTES);
+        self.map.flush_async_range(offset, end - offset)
+    }
+
+    /// Synchronously writes the whole map back to disk
+    pub fn flush(&self) -> io::Result<()> {
+        self.map.flush()
+    }
+
+    fn mark_dirty(&self, byte_index: usize) {
+        let dirty = &self.dirty_pages[byte_index / DIRTY_PAGE_BYTES];
+        // Avoid bouncing the cache line around with stores when the page is already dirty
+        if !dirty.load(std::sync::atomic::Ordering::Relaxed) {
+            dirty.store(true, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
     fn chunks(&self) -> &[Chunk] {
         debug_assert_eq!(self.map.len(), NUM_CHUNKS * mem::size_of::<Chunk>());
+        debug_assert!(self.map.as_ptr().cast::<Chunk>().is_aligned());
 
         unsafe { std::slice::from_raw_parts(self.map.as_ptr().cast::<Chunk>(), NUM_CHUNKS) }
     }
 
-    fn chunk_notify(&self, index: usize) -> (&Chunk, &Notify) {
-        let chunk = &self.chunks()[index];
-        let segment = &self.segments[index];
-        (chunk, &segment.notify_changed)
+    fn chunk_segment(&self, index: usize) -> (&Chunk, &Segment) {
+        (&self.chunks()[index], &self.segments[index])
+    }
+
+    /// Wakes the segment's task to send the chunk's new contents if this is the first change since
+    /// they were last sent, noting the time. Later changes only ride along with the same send, so
+    /// a write storm on one chunk doesn't wake its task on every write.
+    fn changed(&self, segment: &Segment) {
+        let ordering = std::sync::atomic::Ordering::Relaxed;
+        if segment.changed_at.load(ordering) == 0 {
+            let micros = self.started.elapsed().as_micros() as u64 + 1;
+            let _ = segment
+                .changed_at
+                .compare_exchange(0, micros, ordering, ordering);
+        }
+        // Swapped rather than just loaded, so the write is seen by the send that clears the flag
+        if !segment
+            .dirty
+            .swap(true, std::sync::atomic::Ordering::AcqRel)
+        {
+            segment.notify_changed.notify_one();
+        }
+    }
+
+    /// When the segment's chunk was first changed since it was last sent, if it has been
+    fn changed_at(&self, segment: &Segment) -> Option<Instant> {
+        let micros = segment
+            .changed_at
+            .load(std::sync::atomic::Ordering::Relaxed);
+        (micros != 0).then(|| self.started + Duration::from_micros(micros - 1))
+    }
+
+    /// Like [`Self::changed_at`], and clears it for the next change, as the contents are about to
+    /// be sent
+    fn take_changed_at(&self, segment: &Segment) -> Option<Instant> {
+        let micros = segment
+            .changed_at
+            .swap(0, std::sync::atomic::Ordering::Relaxed);
+        (micros != 0).then(|| self.started + Duration::from_micros(micros - 1))
+    }
+
+    fn count_mutations(&self, index: usize, mutations: u64) {
+        self.segments[index]
+            .mutations
+            .fetch_add(mutations, std::sync::atomic::Ordering::Relaxed);
     }
 
     pub fn set_byte(&self, index: usize, byte: u8) {
-        let (chunk, notify) = self.chunk_notify(index / CHUNK_BYTES);
+        let (chunk, segment) = self.chunk_segment(index / CHUNK_BYTES);
+        let _write = segment.writes.begin();
         let inner_idx = index % CHUNK_BYTES;
 
         let prev = chunk.set_byte(inner_idx, byte);
-        notify.notify_one();
+        self.changed(segment);
+        self.mark_dirty(index);
+        self.count_mutations(index / CHUNK_BYTES, 1);
 
-        let bit_diff = byte.count_ones() as i32 - prev.count_ones() as i32;
-        let diff = byte as i32 - prev as i32;
-        // use `as u64` which will sign extend, adding a sign extended negative value will act the
-        // same as subtracting
-        self.bits_set
-            .fetch_add(bit_diff as u64, std::sync::atomic::Ordering::Relaxed);
-        self.bytes_sum
-            .fetch_add(diff as u64, std::sync::atomic::Ordering::Relaxed);
+        self.counters.byte_changed(prev, byte);
+        // An error only means nobody is subscribed
+        let _ = self.byte_writes.send(ByteWrite {
+            index,
+            old: prev,
+            new: byte,
+        });
+    }
+
+    /// Every following write by [`Self::set_byte`], as it happens rather than coalesced like
+    /// chunk updates. Bulk writes aren't included.
+    pub fn subscribe_byte_writes(&self) -> broadcast::Receiver<ByteWrite> {
+        self.byte_writes.subscribe()
     }
 
     pub fn toggle(&self, bit_index: usize) {
-        let (chunk, notify) = self.chunk_notify(bit_index / CHUNK_BITS);
-        let prev_bit = chunk.toggle((bit_index % CHUNK_BITS) as u16);
-        notify.notify_one();
-        let diff = if prev_bit { -1 } else { 1 };
-        self.bits_set
-            .fetch_add(diff as u64, std::sync::atomic::Ordering::Relaxed);
+        let (chunk, segment) = self.chunk_segment(bit_index / CHUNK_BITS);
+        let _write = segment.writes.begin();
+        let bit = (bit_index % CHUNK_BITS) as u16;
+        let prev_bit = chunk.toggle(bit);
+        self.changed(segment);
+        self.mark_dirty(bit_index / 8);
+        self.count_mutations(bit_index / CHUNK_BITS, 1);
+        self.counters.bit_toggled(bit, prev_bit);
+    }
+
+    /// Toggles each of `bits`, indexes within chunk `chunk`, waking the chunk's watchers once for
+    /// all of them
+    pub fn toggle_many(&self, chunk: usize, bits: &[u16]) {
+        let (chunk_ref, segment) = self.chunk_segment(chunk);
+        let _write = segment.writes.begin();
+        for &bit in bits {
+            let prev_bit = chunk_ref.toggle(bit);
+            self.mark_dirty(chunk * CHUNK_BYTES + usize::from(bit) / 8);
+            self.counters.bit_toggled(bit, prev_bit);
+        }
+        self.changed(segment);
+        self.count_mutations(chunk, bits.len() as u64);
     }
 
-    pub fn watch(&self, segment_index: usize) -> watch::Receiver<[u8; CHUNK_BYTES]> {
+    /// Toggles each of `bits` and overwrites each run of bytes in `stores`, both indexes within
+    /// chunk `chunk`, as one write: a snapshot sees all of it or none of it, and the chunk's
+    /// watchers are woken once
+    pub fn write_chunk(&self, chunk: usize, bits: &[u16], stores: &[(usize, &[u8])]) {
+        let (chunk_ref, segment) = self.chunk_segment(chunk);
+        let _write = segment.writes.begin();
+        for &bit in bits {
+            let prev_bit = chunk_ref.toggle(bit);
+            self.mark_dirty(chunk * CHUNK_BYTES + usize::from(bit) / 8);
+            self.counters.bit_toggled(bit, prev_bit);
+        }
+        let mut bit_diff = 0;
+        let mut diff = 0;
+        for &(offset, src) in stores {
+            for (i, &byte) in (offset..).zip(src) {
+                let prev = chunk_ref.set_byte(i, byte);
+                bit_diff += i64::from(byte.count_ones()) - i64::from(prev.count_ones());
+                diff += i64::from(byte) - i64::from(prev);
+                self.mark_dirty(chunk * CHUNK_BYTES + i);
+            }
+        }
+        if !stores.is_empty() {
+            self.counters.bytes_changed(bit_diff, diff);
+        }
+        self.changed(segment);
+        self.count_mutations(chunk, (bits.len() + stores.len()) as u64);
+    }
+
+    /// Toggles bit `bit_index` only if it's currently `expected`, returning whether it did
+    pub fn toggle_if(&self, bit_index: usize, expected: bool) -> bool {
+        let (chunk, segment) = self.chunk_segment(bit_index / CHUNK_BITS);
+        let _write = segment.writes.begin();
+        let bit = (bit_index % CHUNK_BITS) as u16;
+        if !chunk.toggle_if(bit, expected) {
+            return false;
+        }
+        self.changed(segment);
+        self.mark_dirty(bit_index / 8);
+        self.count_mutations(bit_index / CHUNK_BITS, 1);
+        self.counters.bit_toggled(bit, expected);
+        true
+    }
+
+    /// Overwrites the bytes starting at byte `offset` with `src` as one bulk mutation, waking each
+    /// touched chunk's watchers once rather than once per byte
+    pub fn store_bytes(&self, offset: usize, src: &[u8]) {
+        let end = offset + src.len();
+        // Every chunk is written under the whole time, so a read of several sees all of it or
+        // none. Readers are waited for first, as one could be waiting for a chunk begun here.
+        let chunks = offset / CHUNK_BYTES..end.div_ceil(CHUNK_BYTES);
+        for i in chunks.clone() {
+            self.segments[i].writes.wait_for_readers();
+        }
+        let _writes: Vec<_> = chunks
+            .map(|i| self.segments[i].writes.begin_now())
+            .collect();
+        let mut bit_diff = 0;
+        let mut diff = 0;
+        let mut index = offset;
+        while index < end {
+            let chunk_end = ((index / CHUNK_BYTES + 1) * CHUNK_BYTES).min(end);
+            let (chunk, segment) = self.chunk_segment(index / CHUNK_BYTES);
+            for (i, &byte) in (index..chunk_end).zip(&src[index - offset..chunk_end - offset]) {
+                let prev = chunk.set_byte(i % CHUNK_BYTES, byte);
+                bit_diff += i64::from(byte.count_ones()) - i64::from(prev.count_ones());
+                diff += i64::from(byte) - i64::from(prev);
+                self.mark_dirty(i);
+            }
+            self.changed(segment);
+            self.count_mutations(index / CHUNK_BYTES, 1);
+            index = chunk_end;
+        }
+        self.counters.bytes_changed(bit_diff, diff);
+    }
+
+    /// Overwrites chunk `segment_index` with another instance's copy of it. That's not a write to
+    /// this instance, so it isn't counted in the chunk's mutations, but the board's sequence
+    /// still moves on if the contents changed, as it versions them.
+    pub fn mirror_chunk(&self, segment_index: usize, bytes: &[u8; CHUNK_BYTES]) {
+        let (chunk, segment) = self.chunk_segment(segment_index);
+        let _write = segment.writes.begin();
+        let mut changed = false;
+        let mut bit_diff = 0;
+        let mut diff = 0;
+        for (i, &byte) in bytes.iter().enumerate() {
+            let prev = chunk.set_byte(i, byte);
+            if prev != byte {
+                changed = true;
+                bit_diff += i64::from(byte.count_ones()) - i64::from(prev.count_ones());
+                diff += i64::from(byte) - i64::from(prev);
+                self.mark_dirty(segment_index * CHUNK_BYTES + i);
+            }
+        }
+        if changed {
+            self.changed(segment);
+            self.counters.bytes_changed(bit_diff, diff);
+        }
+    }
+
+    /// Lowers every nonzero byte by one, a chunk at a time, returning the number of bytes changed.
+    /// Concurrent writes aren't lost, each byte is decremented atomically.
+    pub fn decay(&self) -> u64 {
+        let mut changed = 0;
+        for (i, chunk) in self.chunks().iter().enumerate() {
+            // Only a chunk at a time, so a read of the chunk waits on this, not the whole pass
+            let _write = self.segments[i].writes.begin();
+            let mut chunk_changed = 0;
+            let mut bit_diff = 0;
+            for index in 0..CHUNK_BYTES {
+                let prev = chunk.decrement(index);
+                if prev == 0 {
+                    continue;
+                }
+                bit_diff += i64::from((prev - 1).count_ones()) - i64::from(prev.count_ones());
+                chunk_changed += 1;
+                self.mark_dirty(i * CHUNK_BYTES + index);
+            }
+            if chunk_changed != 0 {
+                // Counted chunk by chunk, under the chunk's write, so a snapshot taken partway
+                // through the pass has a sequence of its own
+                self.counters.bytes_changed(bit_diff, -chunk_changed);
+                self.changed(&self.segments[i]);
+                self.count_mutations(i, 1);
+                changed += chunk_changed as u64;
+            }
+        }
+        changed
+    }
+
+    /// Copies the bytes starting at byte `offset` into `dst`
+    pub fn load_bytes(&self, offset: usize, dst: &mut [u8]) {
+        let chunks = self.chunks();
+        let mut index = offset;
+        let mut dst = dst;
+        while !dst.is_empty() {
+            let chunk = &chunks[index / CHUNK_BYTES];
+            let inner_idx = index % CHUNK_BYTES;
+            let (part, rest) = dst.split_at_mut((CHUNK_BYTES - inner_idx).min(dst.len()));
+            match <&mut [u8; CHUNK_BYTES]>::try_from(&mut *part) {
+                Ok(whole) => chunk.load(whole),
+                Err(_) => {
+                    for (out, byte) in part.iter_mut().zip(&chunk.bytes()[inner_idx..]) {
+                        *out = byte.load(std::sync::atomic::Ordering::Relaxed);
+                    }
+                }
+            }
+            index += part.len();
+            dst = rest;
+        }
+    }
+
+    /// Chunk `segment_index`'s contents along with its count of writes, read together without
+    /// holding up writes
+    pub fn chunk_with_mutations(&self, segment_index: usize) -> ([u8; CHUNK_BYTES], u64) {
+        let mut bytes = [0; CHUNK_BYTES];
+        let (mutations, _) = self.segments[segment_index].writes.read(|| {
+            self.chunks()[segment_index].load(&mut bytes);
+            self.mutations(segment_index)
+        });
+        (bytes, mutations)
+    }
+
+    /// The checked checkboxes and sum of the sliders in chunk `segment_index`, and the count of
+    /// writes begun on it as of them
+    fn chunk_totals(&self, segment_index: usize) -> ((u64, u64), u64) {
+        let mut bytes = [0; CHUNK_BYTES];
+        let ((), begun) = self.segments[segment_index]
+            .writes
+            .read(|| self.chunks()[segment_index].load(&mut bytes));
+        let count = bytes.iter().map(|byte| u64::from(byte.count_ones())).sum();
+        let sum = bytes.iter().copied().map(u64::from).sum();
+        ((count, sum), begun)
+    }
+
+    /// Counts the checked checkboxes and sums the sliders from scratch, chunk by chunk without
+    /// holding up writes board-wide, and takes any drift found out of the running totals
+    pub fn recount(&self) -> Option<Drift> {
+        let mut chunks: Vec<_> = (0..NUM_CHUNKS).map(|i| self.chunk_totals(i)).collect();
+        let mut holds = Vec::new();
+        let mut round = 0;
+        loop {
+            round += 1;
+            let (was_count, was_sum) = (self.count(), self.sum());
+            // A write the totals include has begun, so if it isn't in the chunks read, it shows up
+            // as a chunk written since
+            fence(std::sync::atomic::Ordering::Acquire);
+            let mut changed = false;
+            for (i, (totals, begun)) in chunks.iter_mut().enumerate() {
+                if self.segments[i].writes.changed_since(*begun) {
+                    if round > ROUNDS_BEFORE_HOLD {
+                        holds.push(self.segments[i].writes.hold());
+                    }
+                    (*totals, *begun) = self.chunk_totals(i);
+                    changed = true;
+                }
+            }
+            if changed {
+                continue;
+            }
+            let count = chunks.iter().map(|((count, _), _)| count).sum();
+            let sum = chunks.iter().map(|((_, sum), _)| sum).sum();
+            if (count, sum) == (was_count, was_sum) {
+                return None;
+            }
+            // Only the drift is taken out, so writes made since the totals were read are kept
+            self.counters
+                .adjust(count as i64 - was_count as i64, sum as i64 - was_sum as i64);
+            return Some(Drift {
+                was_count,
+                count,
+                was_sum,
+                sum,
+            });
+        }
+    }
+
+    /// Brings the pages holding the bytes in `range` into memory, so a read of them all doesn't
+    /// stall on the disk a page at a time when they've been evicted
+    pub fn prefetch(&self, range: std::ops::Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        // One read-ahead of the whole range rather than a fault for each page. Not
+        // `Advice::Sequential`, which would have the kernel drop the pages again behind the read.
+        #[cfg(unix)]
+        if let Err(e) = self
+            .map
+            .advise_range(memmap2::Advice::WillNeed, range.start, range.len())
+        {
+            debug!(error = %e, "failed to advise read-ahead");
+        }
+        let chunks = self.chunks();
+        for index in range.step_by(DIRTY_PAGE_BYTES) {
+            let byte = &chunks[index / CHUNK_BYTES].bytes()[index % CHUNK_BYTES];
+            std::hint::black_box(byte.load(std::sync::atomic::Ordering::Relaxed));
+        }
+    }
+
+    /// Copies the bytes in `range` as of a single point in the sequence of writes, without holding
+    /// up writes board-wide: chunks written while being copied are copied again until none were,
+    /// with writes to any still being written after a few rounds held off briefly. Reads with
+    /// [`load_bytes`](Self::load_bytes) may instead see part of a bulk write, or a write to a later
+    /// byte but not an earlier one made before it.
+    pub fn snapshot(&self, range: std::ops::Range<usize>) -> Snapshot {
+        let mut bytes = vec![0; range.len()];
+        // Any waiting on the disk happens here, rather than while chunks are being written
+        self.prefetch(range.clone());
+        // Read before any of the contents, so they're at least as new as it
+        let version = self.version();
+        let chunks = range.start / CHUNK_BYTES..range.end.div_ceil(CHUNK_BYTES);
+        let mut copy_chunk = |i: usize| {
+            let start = (i * CHUNK_BYTES).max(range.start);
+            let end = ((i + 1) * CHUNK_BYTES).min(range.end);
+            let dst = &mut bytes[start - range.start..end - range.start];
+            let ((), begun) = self.segments[i].writes.read(|| self.load_bytes(start, dst));
+            begun
+        };
+        let mut begun: Vec<_> = chunks.clone().map(&mut copy_chunk).collect();
+        let mut holds = Vec::new();
+        let mut round = 0;
+        loop {
+            round += 1;
+            let sequence = self.sequence();
+            // A write counted in the sequence has begun, so if it isn't in the copy, it shows up
+            // as a chunk written since
+            fence(std::sync::atomic::Ordering::Acquire);
+            let mut changed = false;
+            for (i, begun) in chunks.clone().zip(&mut begun) {
+                if self.segments[i].writes.changed_since(*begun) {
+                    // Still being written after a few rounds, so it's held until the copy is done
+                    if round > ROUNDS_BEFORE_HOLD {
+                        holds.push(self.segments[i].writes.hold());
+                    }
+                    *begun = copy_chunk(i);
+                    changed = true;
+                }
+            }
+            if !changed {
+                return Snapshot {
+                    bytes,
+                    sequence,
+                    version,
+                };
+            }
+        }
+    }
+
+    /// How long changes take to reach watchers
+    pub fn propagation(&self) -> &Propagation {
+        &self.propagation
+    }
+
+    pub fn watch(&self, segment_index: usize) -> watch::Receiver<VersionedChunk> {
         self.segments[segment_index].watch.subscribe()
     }
 
+    /// Chunk `segment_index` as last sent to its watchers
+    pub fn current(&self, segment_index: usize) -> VersionedChunk {
+        *self.segments[segment_index].watch.borrow()
+    }
+
+    /// The current contents of chunk `segment_index`, sending them to its watchers right away
+    /// (under a new version) if they changed since they were last sent
+    pub fn refresh(&self, segment_index: usize) -> VersionedChunk {
+        let segment = &self.segments[segment_index];
+        let watch = &segment.watch;
+        let mut bytes = [0; CHUNK_BYTES];
+        self.chunks()[segment_index].load(&mut bytes);
+        watch.send_if_modified(|c| {
+            if c.bytes == bytes {
+                return false;
+            }
+            *c = VersionedChunk {
+                version: self.next_version(),
+                bytes,
+                changed_at: self.take_changed_at(segment),
+            };
+            true
+        });
+        *watch.borrow()
+    }
+
+    fn next_version(&self) -> u64 {
+        // Pairs with the acquire in `version`, so a reader seeing this version also sees the
+        // writes which led to it
+        self.version
+            .fetch_add(1, std::sync::atomic::Ordering::AcqRel)
+            + 1
+    }
+
+    /// The latest version of any chunk. Contents read after this are at least as new as every
+    /// chunk version up to it, so a client holding such a snapshot can skip chunk updates with a
+    /// version no greater than this.
+    pub fn version(&self) -> u64 {
+        self.version.load(std::sync::atomic::Ordering::Acquire)
+    }
+
+    /// Unix time chunk `segment_index` last changed, or `None` if it hasn't since startup. Only as
+    /// fine grained as updates to watchers, so may lag a change by a moment.
+    pub fn last_modified(&self, segment_index: usize) -> Option<u64> {
+        let at = self.segments[segment_index]
+            .last_modified
+            .load(std::sync::atomic::Ordering::Relaxed);
+        (at != 0).then_some(at)
+    }
+
+    /// Writes to chunk `segment_index` since startup, with a bulk write counted once per chunk it
+    /// touched
+    pub fn mutations(&self, segment_index: usize) -> u64 {
+        self.segments[segment_index]
+            .mutations
+            .load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Writes to the whole board since startup, counted as by [`mutations`](Self::mutations)
+    pub fn total_mutations(&self) -> u64 {
+        (0..self.segments.len()).map(|i| self.mutations(i)).sum()
+    }
+
+    /// Unix time any chunk last changed, or `None` if none has since startup, with the same lag as
+    /// [`last_modified`](Self::last_modified)
+    pub fn board_last_modified(&self) -> Option<u64> {
+        (0..NUM_CHUNKS).filter_map(|i| self.last_modified(i)).max()
+    }
+
+    /// The longest current notify interval of any chunk, and how many chunks have had theirs
+    /// stretched past the minimum
+    pub fn notify_intervals(&self) -> (Duration, usize) {
+        let min = MIN_NOTIFY_INTERVAL.as_millis() as u64;
+        let mut longest = min;
+        let mut stretched = 0;
+        for segment in self.segments.iter() {
+            let interval = segment
+                .notify_interval_ms
+                .load(std::sync::atomic::Ordering::Relaxed);
+            longest = longest.max(interval);
+            stretched += usize::from(interval > min);
+        }
+        (Duration::from_millis(longest), stretched)
+    }
+
     pub fn count(&self) -> u64 {
-        self.bits_set.load(std::sync::atomic::Ordering::Relaxed)
+        self.counters.count()
     }
 
     pub fn sum(&self) -> u64 {
-        self.bytes_sum.load(std::sync::atomic::Ordering::Relaxed)
+        self.counters.sum()
+    }
+
+    /// The number of mutations applied since the bitmap was loaded, usable as a cheap version of
+    /// the whole board
+    pub fn sequence(&self) -> u64 {
+        self.counters.mutations()
     }
 }
 
@@ -208,3 +1068,272 @@ impl Drop for SharedBitmapRunningTasks {
         }
     }
 }
+
+#[cfg(all(test, not(sliders_loom)))]
+mod tests {
+    use super::*;
+
+    fn board(name: &str) -> (SharedBitmap, std::path::PathBuf) {
+        let path =
+            std::env::temp_dir().join(format!("sliders-bitmap-{name}-{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        (SharedBitmap::load_or_create(&path).unwrap(), path)
+    }
+
+    #[test]
+    fn recount_matching_totals() {
+        let (bitmap, path) = board("recount-matching");
+        bitmap.set_byte(3, 0b111);
+        bitmap.toggle(CHUNK_BITS + 1);
+        assert!(bitmap.recount().is_none());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn recount_takes_out_drift() {
+        let (bitmap, path) = board("recount-drift");
+        bitmap.set_byte(3, 0b111);
+        bitmap.counters.adjust(5, -2);
+
+        let drift = bitmap.recount().unwrap();
+        assert_eq!((drift.was_count, drift.count), (8, 3));
+        assert_eq!((drift.was_sum, drift.sum), (5, 7));
+        assert_eq!((bitmap.count(), bitmap.sum()), (3, 7));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn snapshot_of_a_quiet_board_is_exact() {
+        let (bitmap, path) = board("snapshot-quiet");
+        bitmap.store_bytes(CHUNK_BYTES - 2, &[1, 2, 3, 4]);
+        let snapshot = bitmap.snapshot(CHUNK_BYTES - 3..CHUNK_BYTES + 3);
+        assert_eq!(snapshot.bytes, [0, 1, 2, 3, 4, 0]);
+        assert_eq!(snapshot.sequence, bitmap.sequence());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn snapshot_never_sees_bulk_write_half_done() {
+        let (bitmap, path) = board("snapshot-racing");
+        let bitmap = Arc::new(bitmap);
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = std::thread::spawn({
+            let bitmap = Arc::clone(&bitmap);
+            let stop = Arc::clone(&stop);
+            move || {
+                for value in (1..=u8::MAX).cycle() {
+                    if stop.load(std::sync::atomic::Ordering::Relaxed) {
+                        break;
+                    }
+                    // Across two chunks
+                    bitmap.store_bytes(CHUNK_BYTES - 4, &[value; 8]);
+                }
+            }
+        });
+        for _ in 0..1000 {
+            let snapshot = bitmap.snapshot(CHUNK_BYTES - 4..CHUNK_BYTES + 4);
+            assert!(snapshot.bytes.iter().all(|&byte| byte == snapshot.bytes[0]));
+        }
+        stop.store(true, std::sync::atomic::Ordering::Relaxed);
+        writer.join().unwrap();
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn decay_counts_each_chunk_it_changes() {
+        let (bitmap, path) = board("decay");
+        bitmap.set_byte(0, 1);
+        bitmap.set_byte(2 * CHUNK_BYTES, 0b100);
+        let sequence = bitmap.sequence();
+
+        assert_eq!(bitmap.decay(), 2);
+        assert_eq!(bitmap.sequence(), sequence + 2);
+        assert_eq!(bitmap.mutations(2), 2);
+        assert_eq!((bitmap.count(), bitmap.sum()), (2, 3));
+        assert!(bitmap.recount().is_none());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn mirrored_chunk_not_counted_as_a_write() {
+        let (bitmap, path) = board("mirror");
+        let mut bytes = [0; CHUNK_BYTES];
+        bytes[0] = 3;
+        bytes[5] = 0x80;
+        bitmap.mirror_chunk(2, &bytes);
+        assert_eq!((bitmap.count(), bitmap.sum()), (3, 0x83));
+        assert_eq!(bitmap.mutations(2), 0);
+        assert_eq!(bitmap.sequence(), 1);
+        // An unchanged copy isn't a new version of the board
+        bitmap.mirror_chunk(2, &bytes);
+        assert_eq!(bitmap.sequence(), 1);
+        bytes.swap(0, 1);
+        bitmap.mirror_chunk(2, &bytes);
+        assert_eq!(bitmap.sequence(), 2);
+        assert!(bitmap.recount().is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn chunk_read_with_its_mutations() {
+        let (bitmap, path) = board("chunk-mutations");
+        bitmap.store_bytes(CHUNK_BYTES - 1, &[1, 2]);
+        let (bytes, mutations) = bitmap.chunk_with_mutations(1);
+        assert_eq!(bytes[0], 2);
+        assert_eq!(mutations, 1);
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// Exhaustive interleaving tests for the lock-free pieces, on the same `Chunk` code as a normal
+/// build, run with
+/// `RUSTFLAGS="--cfg sliders_loom --cfg tokio_unstable" LOOM_MAX_PREEMPTIONS=3 cargo test --release loom`.
+/// The cfg is our own rather than `loom`, which would also switch tokio to its loom build.
+#[cfg(all(test, sliders_loom))]
+mod loom_tests {
+    use super::*;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    fn popcount(bytes: &[u8]) -> u64 {
+        bytes.iter().map(|b| u64::from(b.count_ones())).sum()
+    }
+
+    #[test]
+    fn concurrent_toggles_converge() {
+        loom::model(|| {
+            let chunk = Arc::new(Chunk::new());
+            let counters = Arc::new(Counters::new(0, 0));
+
+            let threads: Vec<_> = [3, 3, 4]
+                .into_iter()
+                .map(|index| {
+                    let chunk = Arc::clone(&chunk);
+                    let counters = Arc::clone(&counters);
+                    thread::spawn(move || {
+                        let prev = chunk.toggle(index);
+                        counters.bit_toggled(index, prev);
+                    })
+                })
+                .collect();
+            for t in threads {
+                t.join().unwrap();
+            }
+
+            let mut bytes = [0; CHUNK_BYTES];
+            chunk.load(&mut bytes);
+            // bit 3 toggled twice is back to clear, bit 4 toggled once is set
+            assert_eq!(bytes[0], 1 << 4);
+            assert_eq!(counters.count(), 1);
+            assert_eq!(counters.sum(), 1 << 4);
+        });
+    }
+
+    #[test]
+    fn concurrent_set_bytes_converge() {
+        loom::model(|| {
+            let chunk = Arc::new(Chunk::new());
+            chunk.set_byte(0, 0x0F);
+            let counters = Arc::new(Counters::new(4, 0x0F));
+
+            let threads: Vec<_> = [0xFF, 0x01]
+                .into_iter()
+                .map(|value| {
+                    let chunk = Arc::clone(&chunk);
+                    let counters = Arc::clone(&counters);
+                    thread::spawn(move || {
+                        let prev = chunk.set_byte(0, value);
+                        counters.byte_changed(prev, value);
+                    })
+                })
+                .collect();
+            for t in threads {
+                t.join().unwrap();
+            }
+
+            let mut bytes = [0; CHUNK_BYTES];
+            chunk.load(&mut bytes);
+            assert!(bytes[0] == 0xFF || bytes[0] == 0x01);
+            assert_eq!(counters.count(), popcount(&bytes));
+            assert_eq!(counters.sum(), u64::from(bytes[0]));
+        });
+    }
+
+    #[test]
+    fn toggle_racing_set_byte_keeps_count() {
+        loom::model(|| {
+            let chunk = Arc::new(Chunk::new());
+            let counters = Arc::new(Counters::new(0, 0));
+
+            let toggler = {
+                let chunk = Arc::clone(&chunk);
+                let counters = Arc::clone(&counters);
+                thread::spawn(move || {
+                    let prev = chunk.toggle(0);
+                    counters.bit_toggled(0, prev);
+                })
+            };
+            let setter = {
+                let chunk = Arc::clone(&chunk);
+                let counters = Arc::clone(&counters);
+                thread::spawn(move || {
+                    let prev = chunk.set_byte(0, 0b1010);
+                    counters.byte_changed(prev, 0b1010);
+                })
+            };
+            toggler.join().unwrap();
+            setter.join().unwrap();
+
+            let mut bytes = [0; CHUNK_BYTES];
+            chunk.load(&mut bytes);
+            assert!(bytes[0] == 0b1010 || bytes[0] == 0b1011);
+            assert_eq!(counters.count(), popcount(&bytes));
+            assert_eq!(counters.sum(), u64::from(bytes[0]));
+        });
+    }
+
+    #[test]
+    fn load_sees_whole_bytes() {
+        loom::model(|| {
+            let chunk = Arc::new(Chunk::new());
+
+            let writer = {
+                let chunk = Arc::clone(&chunk);
+                thread::spawn(move || {
+                    chunk.set_byte(0, 0xAA);
+                    chunk.set_byte(1, 0x55);
+                })
+            };
+            let mut bytes = [0; CHUNK_BYTES];
+            chunk.load(&mut bytes);
+            writer.join().unwrap();
+
+            assert!(bytes[0] == 0 || bytes[0] == 0xAA);
+            assert!(bytes[1] == 0 || bytes[1] == 0x55);
+            assert!(bytes[2..].iter().all(|&b| b == 0));
+        });
+    }
+
+    #[test]
+    fn read_never_sees_write_half_done() {
+        loom::model(|| {
+            let chunk = Arc::new(Chunk::new());
+            let writes = Arc::new(WriteSeq::new());
+
+            let writer = {
+                let chunk = Arc::clone(&chunk);
+                let writes = Arc::clone(&writes);
+                thread::spawn(move || {
+                    let _write = writes.begin();
+                    chunk.set_byte(0, 1);
+                    chunk.set_byte(1, 1);
+                })
+            };
+            let mut bytes = [0; CHUNK_BYTES];
+            writes.read(|| chunk.load(&mut bytes));
+            writer.join().unwrap();
+
+            assert_eq!(bytes[0], bytes[1]);
+        });
+    }
+}
@@ -0,0 +1,782 @@
+use std::convert::Infallible;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use axum::body::{Body, Bytes};
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use base64::prelude::BASE64_STANDARD_NO_PAD;
+use base64::Engine;
+use futures::{stream, StreamExt};
+use tracing::field::Empty;
+use tracing::{debug, Span};
+
+use crate::chunk;
+use crate::shared_bitmap::{SharedBitmap, CHUNK_BITS, CHUNK_BYTES, NUM_CHUNKS};
+use crate::{cdn, reporting, Range, SharedState, NUM_CHECKBOXES, NUM_SLIDERS};
+
+// Bytes read from the bitmap per streamed piece: a whole number of chunks, and a multiple of 3 so
+// that pieces base64 encode without padding in the middle of the stream
+const PIECE_BYTES: usize = CHUNK_BYTES * 96;
+const _: () = assert!(PIECE_BYTES.is_multiple_of(3));
+
+/// Header giving the board version the snapshot is at least as new as, see
+/// [`SharedBitmap::version`]
+static X_BOARD_VERSION: HeaderName = HeaderName::from_static("x-board-version");
+
+// However often the board changes, the pre-compressed copy is only redone this often
+const PRECOMPRESSED_MAX_AGE: Duration = Duration::from_secs(1);
+// Most chunks a range snapshot covers, the whole board is better fetched from `/snapshot/full`
+const MAX_RANGE_SNAPSHOT_CHUNKS: usize = 1024;
+const ZSTD_LEVEL: i32 = 9;
+const BROTLI_QUALITY: u32 = 9;
+const BROTLI_WINDOW_BITS: u32 = 22;
+
+#[derive(serde::Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotFormat {
+    /// Raw bytes, one per slider
+    #[default]
+    Binary,
+    /// The raw bytes, base64 encoded without padding, as in `update` events
+    Base64,
+    /// Run-length encoded bytes, see [`RleEncoder`]
+    Rle,
+}
+
+impl SnapshotFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Binary | Self::Rle => "application/octet-stream",
+            Self::Base64 => "text/plain; charset=utf-8",
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct SnapshotParams {
+    #[serde(default)]
+    format: SnapshotFormat,
+}
+
+/// How a range snapshot's `bits` are encoded, always base64 in the end to fit in JSON
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BitsFormat {
+    /// The raw bytes
+    #[default]
+    Base64,
+    /// The bytes run-length encoded first, see [`RleEncoder`]
+    Rle,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct RangeSnapshotParams {
+    #[serde(default)]
+    format: BitsFormat,
+}
+
+/// Streams `bytes` encoded a few chunks at a time, instead of building the whole encoded body in
+/// memory first
+fn stream_bytes(bytes: Vec<u8>, format: SnapshotFormat) -> Body {
+    let bytes = Bytes::from(bytes);
+    let end = bytes.len();
+    let mut rle = RleEncoder::default();
+    let pieces = stream::iter((0..end).step_by(PIECE_BYTES)).map(move |offset| {
+        let piece = bytes.slice(offset..PIECE_BYTES.min(end - offset) + offset);
+        let piece = match format {
+            SnapshotFormat::Binary => piece,
+            SnapshotFormat::Base64 => BASE64_STANDARD_NO_PAD.encode(&piece).into(),
+            SnapshotFormat::Rle => {
+                let mut encoded = Vec::new();
+                rle.push(&piece, &mut encoded);
+                if offset + piece.len() == end {
+                    rle.finish(&mut encoded);
+                }
+                encoded.into()
+            }
+        };
+        Ok::<_, Infallible>(piece)
+    });
+    Body::from_stream(pieces)
+}
+
+/// `bytes` in `format` all at once, for responses which need the whole body up front
+fn encode(bytes: Vec<u8>, format: SnapshotFormat) -> Vec<u8> {
+    match format {
+        SnapshotFormat::Binary => bytes,
+        SnapshotFormat::Base64 => BASE64_STANDARD_NO_PAD.encode(&bytes).into_bytes(),
+        SnapshotFormat::Rle => {
+            let mut encoded = Vec::new();
+            let mut rle = RleEncoder::default();
+            rle.push(&bytes, &mut encoded);
+            rle.finish(&mut encoded);
+            encoded
+        }
+    }
+}
+
+/// Encodes bytes as a sequence of runs, each written as the byte value followed by the length of
+/// the run as an unsigned LEB128 varint. An empty board is only a few bytes this way.
+#[derive(Default)]
+struct RleEncoder {
+    value: u8,
+    run: u64,
+}
+
+impl RleEncoder {
+    fn push(&mut self, bytes: &[u8], out: &mut Vec<u8>) {
+        for &byte in bytes {
+            if self.run > 0 && byte == self.value {
+                self.run += 1;
+            } else {
+                self.finish(out);
+                self.value = byte;
+                self.run = 1;
+            }
+        }
+    }
+
+    /// Writes out the current run, if any
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        if self.run == 0 {
+            return;
+        }
+        out.push(self.value);
+        let mut run = self.run;
+        while run >= 0x80 {
+            out.push((run as u8) | 0x80);
+            run >>= 7;
+        }
+        out.push(run as u8);
+        self.run = 0;
+    }
+}
+
+/// Encodings the whole board is kept pre-compressed in, most preferred first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Zstd,
+    Br,
+}
+
+impl Encoding {
+    const PREFERENCE: [Self; 2] = [Self::Zstd, Self::Br];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+            Self::Br => "br",
+        }
+    }
+}
+
+/// The most preferred of our encodings the client accepts, if any
+fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+    let accepted: Vec<&str> = headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let name = parts.next()?.trim();
+            let refused = parts.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            (!refused).then_some(name)
+        })
+        .collect();
+    Encoding::PREFERENCE.into_iter().find(|encoding| {
+        accepted
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(encoding.name()))
+    })
+}
+
+/// A snapshot of the whole board, compressed in each of our encodings
+struct Compressed {
+    sequence: u64,
+    version: u64,
+    taken_at: Instant,
+    zstd: Bytes,
+    br: Bytes,
+}
+
+impl Compressed {
+    fn new(bitmap: &SharedBitmap) -> io::Result<Self> {
+        let snapshot = bitmap.snapshot(0..NUM_SLIDERS);
+        let zstd = zstd::bulk::compress(&snapshot.bytes, ZSTD_LEVEL)?;
+        let mut br = Vec::new();
+        {
+            let mut writer =
+                brotli::CompressorWriter::new(&mut br, 4096, BROTLI_QUALITY, BROTLI_WINDOW_BITS);
+            writer.write_all(&snapshot.bytes)?;
+        }
+        Ok(Self {
+            sequence: snapshot.sequence,
+            version: snapshot.version,
+            taken_at: Instant::now(),
+            zstd: zstd.into(),
+            br: br.into(),
+        })
+    }
+
+    /// Whether this copy is still the one to send
+    fn is_current(&self, bitmap: &SharedBitmap) -> bool {
+        self.sequence == bitmap.sequence() || self.taken_at.elapsed() < PRECOMPRESSED_MAX_AGE
+    }
+
+    fn body(&self, encoding: Encoding) -> Bytes {
+        match encoding {
+            Encoding::Zstd => self.zstd.clone(),
+            Encoding::Br => self.br.clone(),
+        }
+    }
+}
+
+/// The whole board kept compressed for `/snapshot/full`, so the most requested snapshot isn't
+/// compressed again for every client. It's redone when a request finds the board changed since,
+/// at most every `PRECOMPRESSED_MAX_AGE`.
+#[derive(Default)]
+pub struct PrecompressedBoard {
+    latest: tokio::sync::Mutex<Option<Arc<Compressed>>>,
+}
+
+impl PrecompressedBoard {
+    async fn get(&self, bitmap: &Arc<SharedBitmap>) -> Result<Arc<Compressed>, String> {
+        // Held while compressing, so requests arriving meanwhile wait for this copy rather than
+        // all making their own
+        let mut latest = self.latest.lock().await;
+        if let Some(compressed) = latest.as_ref().filter(|c| c.is_current(bitmap)) {
+            return Ok(Arc::clone(compressed));
+        }
+        let bitmap = Arc::clone(bitmap);
+        let compressed = tokio::task::spawn_blocking(move || Compressed::new(&bitmap))
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.to_string())?;
+        Ok(Arc::clone(latest.insert(Arc::new(compressed))))
+    }
+
+    /// The copy which would be sent right now, if there is one and it isn't being redone
+    fn current(&self, bitmap: &SharedBitmap) -> Option<Arc<Compressed>> {
+        let latest = self.latest.try_lock().ok()?;
+        latest.as_ref().filter(|c| c.is_current(bitmap)).cloned()
+    }
+}
+
+/// Streams the whole board, one byte per slider. The binary format is sent from the
+/// pre-compressed copy to clients accepting one of its encodings.
+#[tracing::instrument(skip(state, headers))]
+pub async fn full_snapshot(
+    State(state): State<SharedState>,
+    Query(params): Query<SnapshotParams>,
+    headers: HeaderMap,
+) -> Response {
+    let format = params.format;
+    let encoding = negotiate(&headers).filter(|_| format == SnapshotFormat::Binary);
+    if let Some(encoding) = encoding {
+        match state.precompressed.get(&state.bitmap).await {
+            Ok(compressed) => {
+                let mut response = compressed.body(encoding).into_response();
+                insert_compressed_headers(response.headers_mut(), &state, encoding, &compressed);
+                insert_board_headers(response.headers_mut(), &state);
+                return response;
+            }
+            // Still worth answering, just without the cache
+            Err(e) => reporting::report("Failed to compress the board", e),
+        }
+    }
+    let snapshot = state.bitmap.snapshot(0..NUM_SLIDERS);
+    let mut response = stream_bytes(snapshot.bytes, format).into_response();
+    insert_format_headers(response.headers_mut(), format);
+    let headers = response.headers_mut();
+    headers.insert(&X_BOARD_VERSION, HeaderValue::from(snapshot.version));
+    headers.insert(header::ETAG, board_etag(&state, snapshot.sequence));
+    insert_board_headers(headers, &state);
+    response
+}
+
+/// The headers `GET /snapshot/full` would respond with, worked out without copying the board
+#[tracing::instrument(skip(state, headers))]
+pub async fn full_snapshot_head(
+    State(state): State<SharedState>,
+    Query(params): Query<SnapshotParams>,
+    headers: HeaderMap,
+) -> Response {
+    let mut response = ().into_response();
+    let response_headers = response.headers_mut();
+    let encoding = negotiate(&headers).filter(|_| params.format == SnapshotFormat::Binary);
+    // Only a copy which a GET would be sent right now says anything about its size
+    match encoding
+        .and_then(|encoding| Some((encoding, state.precompressed.current(&state.bitmap)?)))
+    {
+        Some((encoding, compressed)) => {
+            insert_compressed_headers(response_headers, &state, encoding, &compressed);
+        }
+        None => {
+            insert_format_headers(response_headers, params.format);
+            response_headers.insert(&X_BOARD_VERSION, HeaderValue::from(state.bitmap.version()));
+            response_headers.insert(header::ETAG, board_etag(&state, state.bitmap.sequence()));
+        }
+    }
+    insert_board_headers(response_headers, &state);
+    if !response.headers().contains_key(header::CONTENT_LENGTH) {
+        *response.body_mut() = unsized_head_body();
+    }
+    response
+}
+
+/// A body for a HEAD response which can't tell how long the GET's would be. An empty body would
+/// otherwise be taken to mean a length of zero.
+pub fn unsized_head_body() -> Body {
+    Body::from_stream(stream::empty::<Result<Bytes, Infallible>>())
+}
+
+fn insert_format_headers(headers: &mut HeaderMap, format: SnapshotFormat) {
+    let content_length = match format {
+        SnapshotFormat::Binary => Some(NUM_SLIDERS),
+        SnapshotFormat::Base64 => {
+            Some(base64::encoded_len(NUM_SLIDERS, false).expect("board size can't overflow"))
+        }
+        // Depends on the contents, which we don't know until we've streamed them
+        SnapshotFormat::Rle => None,
+    };
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(format.content_type()),
+    );
+    if let Some(content_length) = content_length {
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(content_length));
+    }
+}
+
+fn insert_compressed_headers(
+    headers: &mut HeaderMap,
+    state: &SharedState,
+    encoding: Encoding,
+    compressed: &Compressed,
+) {
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.name()),
+    );
+    headers.insert(
+        header::CONTENT_LENGTH,
+        HeaderValue::from(compressed.body(encoding).len()),
+    );
+    headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    headers.insert(&X_BOARD_VERSION, HeaderValue::from(compressed.version));
+    headers.insert(
+        header::ETAG,
+        variant_etag(state, compressed.sequence, encoding.name()),
+    );
+}
+
+/// Adds the CDN keys for the whole board, and `Last-Modified` as the last time any chunk changed
+/// if one has since startup
+fn insert_board_headers(headers: &mut HeaderMap, state: &SharedState) {
+    cdn::tag_chunks(state, headers, 0..NUM_CHUNKS);
+    insert_last_modified(headers, state.bitmap.board_last_modified());
+}
+
+/// Adds the CDN keys for `chunks`, and `Last-Modified` as the last time any of them changed if
+/// one has since startup
+pub fn insert_range_headers(
+    headers: &mut HeaderMap,
+    state: &SharedState,
+    chunks: std::ops::Range<usize>,
+) {
+    let last_modified = chunks
+        .clone()
+        .filter_map(|i| state.bitmap.last_modified(i))
+        .max();
+    cdn::tag_chunks(state, headers, chunks);
+    insert_last_modified(headers, last_modified);
+}
+
+fn insert_last_modified(headers: &mut HeaderMap, at: Option<u64>) {
+    if let Some(at) = at {
+        let at = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(at));
+        headers.insert(
+            header::LAST_MODIFIED,
+            HeaderValue::try_from(at).expect("an http date is a valid header value"),
+        );
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct RangeSnapshot {
+    /// Index of the first checkbox, the requested start rounded down to a whole chunk
+    start: u64,
+    /// Index after the last checkbox, the requested end rounded up to a whole chunk
+    end: u64,
+    chunk_bytes: usize,
+    /// The board's sequence number as of the snapshot
+    seq: u64,
+    /// How `bits` is encoded
+    format: BitsFormat,
+    /// The bytes from `start` to `end`, encoded as `format` says
+    bits: String,
+    /// The version of each chunk from `start` on. Updates to a chunk with a version no greater
+    /// than its entry here are already included.
+    chunk_versions: Vec<u64>,
+}
+
+/// The chunks overlapping the range as of a single point in time, along with what's needed to
+/// pick up their updates from there
+#[tracing::instrument(
+    skip(state, range),
+    fields(start=range.start, end=range.end, chunks=Empty, bytes=Empty, encode_us=Empty)
+)]
+pub async fn range_snapshot(
+    State(state): State<SharedState>,
+    Query(range): Query<Range>,
+    Query(params): Query<RangeSnapshotParams>,
+) -> axum::response::Result<(HeaderMap, Json<RangeSnapshot>)> {
+    let chunks = snapshot_chunks(&range)?;
+    // Read before the bytes, so no version here is newer than the contents sent
+    let chunk_versions = chunks
+        .clone()
+        .map(|i| state.bitmap.current(i).version)
+        .collect();
+    let snapshot = state
+        .bitmap
+        .snapshot(chunks.start * CHUNK_BYTES..chunks.end * CHUNK_BYTES);
+    let encode_start = Instant::now();
+    let bits = match params.format {
+        BitsFormat::Base64 => BASE64_STANDARD_NO_PAD.encode(&snapshot.bytes),
+        BitsFormat::Rle => {
+            BASE64_STANDARD_NO_PAD.encode(encode(snapshot.bytes, SnapshotFormat::Rle))
+        }
+    };
+    let span = Span::current();
+    span.record("chunks", chunks.len());
+    span.record("bytes", bits.len());
+    span.record("encode_us", encode_start.elapsed().as_micros() as u64);
+    debug!("served range snapshot");
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ETAG, board_etag(&state, snapshot.sequence));
+    insert_range_headers(&mut headers, &state, chunks.clone());
+    Ok((
+        headers,
+        Json(RangeSnapshot::new(
+            chunks,
+            snapshot.sequence,
+            params.format,
+            bits,
+            chunk_versions,
+        )),
+    ))
+}
+
+/// The headers `GET /snapshot` would respond with, worked out without copying the chunks
+#[tracing::instrument(skip(state))]
+pub async fn range_snapshot_head(
+    State(state): State<SharedState>,
+    Query(range): Query<Range>,
+    Query(params): Query<RangeSnapshotParams>,
+) -> axum::response::Result<Response> {
+    let chunks = snapshot_chunks(&range)?;
+    let sequence = state.bitmap.sequence();
+    let mut response = (
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            ),
+            (header::ETAG, board_etag(&state, sequence)),
+        ],
+        (),
+    )
+        .into_response();
+    match params.format {
+        BitsFormat::Base64 => {
+            let chunk_versions = chunks
+                .clone()
+                .map(|i| state.bitmap.current(i).version)
+                .collect();
+            // The bits are the only part too big to write out just to measure, and their length
+            // only depends on how many chunks they cover
+            let without_bits = serde_json::to_vec(&RangeSnapshot::new(
+                chunks.clone(),
+                sequence,
+                params.format,
+                String::new(),
+                chunk_versions,
+            ))
+            .expect("a range snapshot always serializes");
+            let bits_len = base64::encoded_len(chunks.len() * CHUNK_BYTES, false)
+                .expect("range snapshot size can't overflow");
+            response.headers_mut().insert(
+                header::CONTENT_LENGTH,
+                HeaderValue::from(without_bits.len() + bits_len),
+            );
+        }
+        // Depends on the contents, which we'd have to copy and encode to know
+        BitsFormat::Rle => *response.body_mut() = unsized_head_body(),
+    }
+    insert_range_headers(response.headers_mut(), &state, chunks);
+    Ok(response)
+}
+
+impl RangeSnapshot {
+    fn new(
+        chunks: std::ops::Range<usize>,
+        seq: u64,
+        format: BitsFormat,
+        bits: String,
+        chunk_versions: Vec<u64>,
+    ) -> Self {
+        Self {
+            start: (chunks.start * CHUNK_BITS) as u64,
+            end: (chunks.end * CHUNK_BITS) as u64,
+            chunk_bytes: CHUNK_BYTES,
+            seq,
+            format,
+            bits,
+            chunk_versions,
+        }
+    }
+}
+
+/// The chunks a range snapshot of `range` covers, if it's one that can be taken
+fn snapshot_chunks(range: &Range) -> Result<std::ops::Range<usize>, (StatusCode, &'static str)> {
+    if range.start > range.end {
+        return Err((StatusCode::BAD_REQUEST, "start must be less than end"));
+    }
+    if range.end > NUM_CHECKBOXES as u64 {
+        return Err((StatusCode::BAD_REQUEST, "end too large"));
+    }
+    let chunks = chunk::overlapping(range.start, range.end);
+    if chunks.len() > MAX_RANGE_SNAPSHOT_CHUNKS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "range too large, use /snapshot/full for the whole board",
+        ));
+    }
+    Ok(chunks)
+}
+
+/// Serves the board as a download, raw or in another snapshot `format`, with byte ranges (of the
+/// body as sent) and `ETag` revalidation so large downloads can be resumed
+#[tracing::instrument(skip(state, headers))]
+pub async fn board_bin(
+    State(state): State<SharedState>,
+    Query(params): Query<SnapshotParams>,
+    headers: HeaderMap,
+) -> Response {
+    let etag = board_etag(&state, state.bitmap.sequence());
+    if headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|value| etag_matches(value, &etag))
+    {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    }
+
+    // Whatever is sent, whole or in part, is from this one copy, which the ETag then describes
+    let snapshot = state.bitmap.snapshot(0..NUM_SLIDERS);
+    let etag = board_etag(&state, snapshot.sequence);
+    // Encoded whole, so ranges can be served from it
+    let body = encode(snapshot.bytes, params.format);
+    let len = body.len();
+
+    let mut status = StatusCode::OK;
+    let mut range = 0..len;
+    // A Range is only honored if the client's copy (if it told us which) is still current
+    let range_header = headers
+        .get(header::RANGE)
+        .filter(|_| headers.get(header::IF_RANGE).is_none_or(|v| *v == etag))
+        .and_then(|value| value.to_str().ok());
+    // Unparseable and multi-part ranges are ignored, and the whole board is sent instead
+    if let Some(parsed) = range_header.and_then(|r| http_range_header::parse_range_header(r).ok()) {
+        match parsed.validate(len as u64).as_deref() {
+            Ok([single]) => {
+                status = StatusCode::PARTIAL_CONTENT;
+                range = *single.start() as usize..*single.end() as usize + 1;
+            }
+            Ok(_) => {}
+            Err(_) => {
+                return (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(header::CONTENT_RANGE, format!("bytes */{len}"))],
+                )
+                    .into_response();
+            }
+        }
+    }
+
+    let mut response = (
+        status,
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(params.format.content_type()),
+            ),
+            (header::ACCEPT_RANGES, HeaderValue::from_static("bytes")),
+            (header::CACHE_CONTROL, HeaderValue::from_static("no-cache")),
+            (header::ETAG, etag),
+            (X_BOARD_VERSION.clone(), HeaderValue::from(snapshot.version)),
+        ],
+        [(header::CONTENT_LENGTH, range.len().to_string())],
+        stream_bytes(body[range.clone()].to_vec(), SnapshotFormat::Binary),
+    )
+        .into_response();
+    if status == StatusCode::PARTIAL_CONTENT {
+        let content_range = format!("bytes {}-{}/{len}", range.start, range.end - 1);
+        response.headers_mut().insert(
+            header::CONTENT_RANGE,
+            HeaderValue::try_from(content_range).expect("content range is a valid header value"),
+        );
+    }
+    insert_board_headers(response.headers_mut(), &state);
+    response
+}
+
+/// The headers `GET /board.bin` would respond with for the whole board, worked out without
+/// copying it
+#[tracing::instrument(skip(state, headers))]
+pub async fn board_bin_head(
+    State(state): State<SharedState>,
+    Query(params): Query<SnapshotParams>,
+    headers: HeaderMap,
+) -> Response {
+    let etag = board_etag(&state, state.bitmap.sequence());
+    if headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|value| etag_matches(value, &etag))
+    {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    }
+    let mut response = (
+        [
+            (header::ACCEPT_RANGES, HeaderValue::from_static("bytes")),
+            (header::CACHE_CONTROL, HeaderValue::from_static("no-cache")),
+            (header::ETAG, etag),
+            (
+                X_BOARD_VERSION.clone(),
+                HeaderValue::from(state.bitmap.version()),
+            ),
+        ],
+        (),
+    )
+        .into_response();
+    insert_format_headers(response.headers_mut(), params.format);
+    insert_board_headers(response.headers_mut(), &state);
+    if !response.headers().contains_key(header::CONTENT_LENGTH) {
+        *response.body_mut() = unsized_head_body();
+    }
+    response
+}
+
+pub fn board_etag(state: &SharedState, sequence: u64) -> HeaderValue {
+    HeaderValue::try_from(format!("\"{:x}-{sequence}\"", state.started_at))
+        .expect("etag is always a valid header value")
+}
+
+/// Tags the board as of `sequence` sent with the content encoding `variant`, a different body
+/// at the same URL, so it gets its own tag
+fn variant_etag(state: &SharedState, sequence: u64, variant: &str) -> HeaderValue {
+    HeaderValue::try_from(format!("\"{:x}-{sequence}-{variant}\"", state.started_at))
+        .expect("etag is always a valid header value")
+}
+
+pub fn etag_matches(if_none_match: &HeaderValue, etag: &HeaderValue) -> bool {
+    let Ok(if_none_match) = if_none_match.to_str() else {
+        return false;
+    };
+    let etag = etag.as_bytes();
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/").as_bytes() == etag)
+}
+
+#[cfg(all(test, not(sliders_loom)))]
+mod tests {
+    use super::*;
+
+    fn decode(mut encoded: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        while let [value, rest @ ..] = encoded {
+            let mut run = 0u64;
+            let mut shift = 0;
+            let mut rest = rest;
+            loop {
+                let [byte, tail @ ..] = rest else {
+                    panic!("run length cut off");
+                };
+                rest = tail;
+                run |= u64::from(byte & 0x7F) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            assert_ne!(run, 0, "empty run");
+            bytes.extend(std::iter::repeat_n(*value, run as usize));
+            encoded = rest;
+        }
+        bytes
+    }
+
+    async fn streamed_rle(bytes: &[u8]) -> Vec<u8> {
+        let body = stream_bytes(bytes.to_vec(), SnapshotFormat::Rle);
+        axum::body::to_bytes(body, usize::MAX)
+            .await
+            .unwrap()
+            .to_vec()
+    }
+
+    #[tokio::test]
+    async fn rle_round_trips_runs_across_boundaries() {
+        let mut bytes = vec![0; 3 * PIECE_BYTES + 10];
+        // Runs ending just past a chunk, spanning a whole piece boundary, and of single bytes on
+        // either side of one
+        bytes[CHUNK_BYTES - 3..CHUNK_BYTES + 3].fill(7);
+        bytes[PIECE_BYTES - 200..2 * PIECE_BYTES + 5].fill(0xFF);
+        bytes[3 * PIECE_BYTES - 1] = 1;
+        bytes[3 * PIECE_BYTES] = 2;
+        let encoded = streamed_rle(&bytes).await;
+        assert_eq!(decode(&encoded), bytes);
+        assert_eq!(encode(bytes.clone(), SnapshotFormat::Rle), encoded);
+
+        // The same runs, however the input is split, encode the same
+        for piece in [1, CHUNK_BYTES, PIECE_BYTES - 1] {
+            let mut rle = RleEncoder::default();
+            let mut split = Vec::new();
+            for part in bytes.chunks(piece) {
+                rle.push(part, &mut split);
+            }
+            rle.finish(&mut split);
+            assert_eq!(split, encoded, "split into {piece} byte pieces");
+        }
+    }
+
+    #[tokio::test]
+    async fn rle_whole_board_is_one_run() {
+        let bytes = vec![0x42; NUM_SLIDERS];
+        let encoded = streamed_rle(&bytes).await;
+        // 1,000,000 as a LEB128 varint
+        assert_eq!(NUM_SLIDERS, 1_000_000);
+        assert_eq!(encoded, [0x42, 0xC0, 0x84, 0x3D]);
+        assert_eq!(decode(&encoded), bytes);
+    }
+
+    #[tokio::test]
+    async fn rle_of_nothing_is_empty() {
+        assert!(streamed_rle(&[]).await.is_empty());
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use crate::proxy::Origin;
+
+/// Tracks open `/updates` subscriptions, globally and per client address, to keep a single client
+/// from holding thousands of chunk watchers open. Besides the number of subscriptions, the chunks
+/// they watch are counted per connection and per address, since a few maximal ranges cost as much
+/// as many small ones.
+pub struct SubscriptionLimits {
+    max_total: usize,
+    max_per_ip: usize,
+    max_chunks_per_connection: usize,
+    max_chunks_per_ip: usize,
+    open: Mutex<OpenSubscriptions>,
+}
+
+#[derive(Default)]
+struct OpenSubscriptions {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    /// Chunks watched by each connection's subscriptions, by the connection's peer address, which
+    /// is a proxy's for clients behind one
+    chunks_per_connection: HashMap<SocketAddr, usize>,
+    /// Chunks watched by each address's subscriptions, over all its connections
+    chunks_per_ip: HashMap<IpAddr, usize>,
+    /// Subscriptions opened since startup
+    opened: u64,
+    /// Most subscriptions open at once since startup
+    peak: usize,
+}
+
+impl SubscriptionLimits {
+    pub fn new(
+        max_total: usize,
+        max_per_ip: usize,
+        max_chunks_per_connection: usize,
+        max_chunks_per_ip: usize,
+    ) -> Self {
+        Self {
+            max_total,
+            max_per_ip,
+            max_chunks_per_connection,
+            max_chunks_per_ip,
+            open: Mutex::default(),
+        }
+    }
+
+    /// Reserves a subscription slot watching `chunks` chunks for a request from `origin`, or
+    /// returns why not if any limit has been reached. The slot is released when the returned guard
+    /// is dropped.
+    pub fn try_acquire(
+        self: &Arc<Self>,
+        origin: Origin,
+        chunks: usize,
+    ) -> Result<SubscriptionGuard, &'static str> {
+        // IPv4 clients connecting to our dual-stack socket show up as v4-mapped v6 addresses
+        let connection = origin.connection;
+        let connection = SocketAddr::new(connection.ip().to_canonical(), connection.port());
+        let ip = origin.client;
+        let mut open = self.open.lock().unwrap();
+        if open.total >= self.max_total
+            || open.per_ip.get(&ip).is_some_and(|&n| n >= self.max_per_ip)
+        {
+            return Err("Too many open subscriptions");
+        }
+        let connection_chunks = open
+            .chunks_per_connection
+            .get(&connection)
+            .copied()
+            .unwrap_or(0);
+        let ip_chunks = open.chunks_per_ip.get(&ip).copied().unwrap_or(0);
+        if connection_chunks + chunks > self.max_chunks_per_connection
+            || ip_chunks + chunks > self.max_chunks_per_ip
+        {
+            return Err("Too many subscribed chunks");
+        }
+        *open.per_ip.entry(ip).or_default() += 1;
+        if chunks > 0 {
+            *open.chunks_per_connection.entry(connection).or_default() += chunks;
+            *open.chunks_per_ip.entry(ip).or_default() += chunks;
+        }
+        open.total += 1;
+        open.opened += 1;
+        open.peak = open.peak.max(open.total);
+        Ok(SubscriptionGuard {
+            limits: Arc::clone(self),
+            connection,
+            ip,
+            chunks,
+        })
+    }
+
+    /// Number of currently open subscriptions
+    pub fn open(&self) -> usize {
+        self.open.lock().unwrap().total
+    }
+
+    /// Number of distinct addresses with at least one open subscription
+    pub fn clients(&self) -> usize {
+        self.open.lock().unwrap().per_ip.len()
+    }
+
+    /// Number of subscriptions opened since startup
+    pub fn opened(&self) -> u64 {
+        self.open.lock().unwrap().opened
+    }
+
+    /// Most subscriptions open at once since startup
+    pub fn peak(&self) -> usize {
+        self.open.lock().unwrap().peak
+    }
+}
+
+pub struct SubscriptionGuard {
+    limits: Arc<SubscriptionLimits>,
+    connection: SocketAddr,
+    ip: IpAddr,
+    chunks: usize,
+}
+
+/// Takes `n` from the count under `key`, forgetting it once it reaches zero
+fn release<K: Eq + std::hash::Hash>(counts: &mut HashMap<K, usize>, key: &K, n: usize) {
+    if let Some(count) = counts.get_mut(key) {
+        *count -= n;
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+impl Drop for SubscriptionGuard {
+    fn drop(&mut self) {
+        let mut open = self.limits.open.lock().unwrap();
+        open.total -= 1;
+        release(&mut open.per_ip, &self.ip, 1);
+        if self.chunks > 0 {
+            release(
+                &mut open.chunks_per_connection,
+                &self.connection,
+                self.chunks,
+            );
+            release(&mut open.chunks_per_ip, &self.ip, self.chunks);
+        }
+    }
+}
+
+#[cfg(all(test, not(sliders_loom)))]
+mod tests {
+    use super::*;
+
+    fn origin(connection: &str, client: &str) -> Origin {
+        Origin {
+            connection: connection.parse().unwrap(),
+            client: client.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn chunks_limited_by_connection_and_by_client() {
+        let limits = Arc::new(SubscriptionLimits::new(100, 100, 10, 15));
+        // Two clients sharing one of the proxy's connections share its chunk limit
+        let _a = limits
+            .try_acquire(origin("10.0.0.1:5000", "1.1.1.1"), 6)
+            .unwrap();
+        assert!(limits
+            .try_acquire(origin("10.0.0.1:5000", "2.2.2.2"), 5)
+            .is_err());
+        let _b = limits
+            .try_acquire(origin("10.0.0.1:5001", "2.2.2.2"), 5)
+            .unwrap();
+        // One client over several of the proxy's connections is held to its own limit
+        let _c = limits
+            .try_acquire(origin("10.0.0.1:5002", "1.1.1.1"), 9)
+            .unwrap();
+        assert!(limits
+            .try_acquire(origin("10.0.0.1:5003", "1.1.1.1"), 1)
+            .is_err());
+        assert_eq!(limits.clients(), 2);
+    }
+
+    #[test]
+    fn released_when_dropped() {
+        let limits = Arc::new(SubscriptionLimits::new(100, 100, 10, 10));
+        let subscription = limits
+            .try_acquire(origin("10.0.0.1:5000", "1.1.1.1"), 10)
+            .unwrap();
+        assert!(limits
+            .try_acquire(origin("10.0.0.1:5000", "2.2.2.2"), 1)
+            .is_err());
+        drop(subscription);
+        assert_eq!(limits.open(), 0);
+        let _again = limits
+            .try_acquire(origin("10.0.0.1:5000", "1.1.1.1"), 10)
+            .unwrap();
+    }
+}
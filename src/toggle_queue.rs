@@ -0,0 +1,75 @@
+//! Batching of `/toggle` requests. Rather than every request toggling its own bit and waking the
+//! chunk's watchers, toggles are queued and applied by a single task a chunk at a time. The queue
+//! is bounded, and a toggle which can't be queued in time is refused, so a burst sheds load with
+//! 503s instead of piling up requests.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::shared_bitmap::{SharedBitmap, CHUNK_BITS};
+
+/// Most toggles applied in one batch
+const MAX_BATCH: usize = 4096;
+
+struct Queued {
+    bit_index: usize,
+    applied: oneshot::Sender<()>,
+}
+
+/// The queue had no space for a toggle within the configured wait
+#[derive(Debug)]
+pub struct QueueFull;
+
+pub struct ToggleQueue {
+    tx: mpsc::Sender<Queued>,
+    /// Longest a toggle may wait for space in the queue
+    wait: Duration,
+}
+
+impl ToggleQueue {
+    /// Starts the task applying queued toggles to `bitmap`, which runs until the queue is dropped
+    pub fn new(bitmap: Arc<SharedBitmap>, capacity: usize, wait: Duration) -> Self {
+        let (tx, rx) = mpsc::channel(capacity.max(1));
+        tokio::spawn(run(bitmap, rx));
+        Self { tx, wait }
+    }
+
+    /// Toggles bit `bit_index`, returning once the toggle has been applied
+    pub async fn toggle(&self, bit_index: usize) -> Result<(), QueueFull> {
+        let (applied, done) = oneshot::channel();
+        self.tx
+            .send_timeout(Queued { bit_index, applied }, self.wait)
+            .await
+            .map_err(|_| QueueFull)?;
+        // The task only goes away with the queue, which outlives this borrow
+        done.await.map_err(|_| QueueFull)
+    }
+
+    /// Toggles waiting to be applied
+    pub fn len(&self) -> usize {
+        self.tx.max_capacity() - self.tx.capacity()
+    }
+}
+
+async fn run(bitmap: Arc<SharedBitmap>, mut rx: mpsc::Receiver<Queued>) {
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+    let mut bits = Vec::new();
+    while rx.recv_many(&mut batch, MAX_BATCH).await > 0 {
+        batch.sort_unstable_by_key(|queued| queued.bit_index);
+        for group in batch.chunk_by(|a, b| a.bit_index / CHUNK_BITS == b.bit_index / CHUNK_BITS) {
+            bits.clear();
+            bits.extend(
+                group
+                    .iter()
+                    .map(|queued| (queued.bit_index % CHUNK_BITS) as u16),
+            );
+            bitmap.toggle_many(group[0].bit_index / CHUNK_BITS, &bits);
+        }
+        for queued in batch.drain(..) {
+            // The request may have been dropped while waiting, which is fine
+            let _ = queued.applied.send(());
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! Slow background checking of the bookkeeping kept alongside the board. Every chunk is hashed
+//! in turn, and one whose contents changed without a write being counted for it points at a
+//! write path skipping its bookkeeping (and so likely its watchers too). After each pass over the
+//! board the running count and sum are recounted from scratch, and corrected if they've drifted.
+//!
+//! Either is a bug, so is logged loudly, but neither is fatal.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error};
+
+use crate::shared_bitmap::{SharedBitmap, NUM_CHUNKS};
+
+/// A chunk's contents as of the last pass, by hash, and the writes counted for it then
+#[derive(Clone, Copy, Default)]
+struct Seen {
+    hash: u64,
+    mutations: u64,
+}
+
+/// Checks every chunk once per `pass`, never if it's zero
+pub async fn run(bitmap: Arc<SharedBitmap>, pass: Duration) {
+    if pass.is_zero() {
+        return;
+    }
+    let mut interval = tokio::time::interval(pass / NUM_CHUNKS as u32);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut seen: Option<Vec<Seen>> = None;
+    loop {
+        let mut current = Vec::with_capacity(NUM_CHUNKS);
+        for i in 0..NUM_CHUNKS {
+            interval.tick().await;
+            let (bytes, mutations) = bitmap.chunk_with_mutations(i);
+            let mut hasher = DefaultHasher::new();
+            bytes.hash(&mut hasher);
+            let hash = hasher.finish();
+            // Nothing to compare against on the first pass
+            if let Some(prev) = seen.as_ref().map(|seen| seen[i]) {
+                if prev.mutations == mutations && prev.hash != hash {
+                    error!(chunk = i, "chunk changed without a write being counted");
+                }
+            }
+            current.push(Seen { hash, mutations });
+        }
+        seen = Some(current);
+
+        match tokio::task::spawn_blocking({
+            let bitmap = Arc::clone(&bitmap);
+            move || bitmap.recount()
+        })
+        .await
+        {
+            Ok(Some(drift)) => error!(
+                was_count = drift.was_count,
+                count = drift.count,
+                was_sum = drift.was_sum,
+                sum = drift.sum,
+                "running totals had drifted from the board, corrected"
+            ),
+            Ok(None) => debug!("running totals match the board"),
+            Err(e) => error!(error = %e, "recounting the board failed"),
+        }
+    }
+}
@@ -1,6 +1,6 @@
 "use strict";
 const PADDING_ROWS = 6;
-const NUM_VALUES = 1000000;
+let config = null;
 let visibleWidth = 0;
 let visibleHeight = 0;
 let fullHeight = 0;
@@ -13,7 +13,7 @@ let firstVisibleRow = 0;
 let renderedRows = [];
 const content = document.getElementById('content');
 const contentContainer = document.getElementById('content-container');
-const data = new Uint8Array(NUM_VALUES);
+let data = new Uint8Array(0);
 function setBit(n, value = true) {
     let changed = false;
     if (value) {
@@ -51,6 +51,9 @@ function getByte(n) {
     return data[n];
 }
 function onResize() {
+    if (config === null) {
+        return;
+    }
     const firstVisibleCb = firstVisibleRow * numCols;
     // Find the number of input elements that can fit in the container
     content.textContent = '';
@@ -68,7 +71,7 @@ function onResize() {
     if (numCols < 4) {
         numCols = 4;
     }
-    numRows = Math.ceil(1000000 / numCols);
+    numRows = Math.ceil(config.board.sliders / numCols);
     fullHeight = numRows * cbHeight;
     content.style.height = `${fullHeight}px`;
     visibleHeight = contentContainer.clientHeight;
@@ -108,8 +111,9 @@ function doScroll(force) {
             console.warn("force with non-empty renderedRows");
         }
     }
-    const roundedFirstCheckbox = (((newFirstRenderedRow * numCols) / 512) | 0) * 512;
-    const roundedLastCheckbox = (((newLastRenderedRow * numCols + 511) / 512) | 0) * 512;
+    const chunkBytes = config.chunk_bytes;
+    const roundedFirstCheckbox = (((newFirstRenderedRow * numCols) / chunkBytes) | 0) * chunkBytes;
+    const roundedLastCheckbox = (((newLastRenderedRow * numCols + chunkBytes - 1) / chunkBytes) | 0) * chunkBytes;
     if (roundedFirstCheckbox !== eventSourceStart || roundedLastCheckbox !== eventSourceEnd) {
         eventSourceStart = roundedFirstCheckbox;
         eventSourceEnd = roundedLastCheckbox;
@@ -144,7 +148,7 @@ function makeRow(n) {
     row.className = 'cb-row';
     for (let i = 0; i < numCols; i++) {
         let byteIdx = n * numCols + i;
-        if (byteIdx >= NUM_VALUES) {
+        if (byteIdx >= data.length) {
             break;
         }
         const slider = makeSlider(getByte(byteIdx));
@@ -153,7 +157,7 @@ function makeRow(n) {
             const inputElem = target.children[0];
             const value = parseInt(inputElem.value);
             setByte(byteIdx, value);
-            fetch(`set_byte/${byteIdx}/${value}`, {
+            fetch(endpoint(config.endpoints.set_byte, { index: byteIdx, value }), {
                 method: 'POST',
             });
         };
@@ -164,10 +168,19 @@ function makeRow(n) {
 }
 function updateSum(sum) {
     const countEl = document.getElementById('avg');
-    const percent = sum / 255 / NUM_VALUES * 100;
+    const percent = sum / 255 / data.length * 100;
     countEl.textContent = `${percent.toFixed(7)}%`;
 }
-function handleUpdate(offset, base64Data) {
+// Version of the last contents applied to each chunk, by offset
+const chunkVersions = new Map();
+function handleUpdate(id, base64Data) {
+    var _a;
+    // Ids are `offset:version`, older or repeated versions (say, after reconnecting) are skipped
+    const [offset, version] = id.split(':').map(Number);
+    if (version <= ((_a = chunkVersions.get(offset)) !== null && _a !== void 0 ? _a : 0)) {
+        return;
+    }
+    chunkVersions.set(offset, version);
     const data = atob(base64Data);
     // Offset is in bits, so divide by 8 to get bytes
     let i = offset / 8;
@@ -176,21 +189,33 @@ function handleUpdate(offset, base64Data) {
         setByte(i + j, byte);
     }
 }
+// Fills in an endpoint's `{placeholders}`, relative to the page so the site can live under a prefix
+function endpoint(path, params = {}) {
+    return path.replace(/^\//, '').replace(/\{(\w+)\}/g, (_, name) => params[name].toString());
+}
 let eventSourceStart = 0;
 let eventSourceEnd = 0;
 function createEventSource() {
     eventSource === null || eventSource === void 0 ? void 0 : eventSource.close();
     // Units are in bytes now
-    eventSource = new EventSource(`updates?start=${eventSourceStart * 8}&end=${eventSourceEnd * 8}`);
+    eventSource = new EventSource(`${endpoint(config.endpoints.updates)}?start=${eventSourceStart * 8}&end=${eventSourceEnd * 8}`);
     eventSource.addEventListener("error", () => {
         eventSource === null || eventSource === void 0 ? void 0 : eventSource.close();
         setTimeout(createEventSource, 500);
     });
     eventSource.addEventListener("sum", (ev) => updateSum(parseFloat(ev.data)));
-    eventSource.addEventListener("update", (ev) => handleUpdate(parseInt(ev.lastEventId), ev.data));
+    eventSource.addEventListener("update", (ev) => handleUpdate(ev.lastEventId, ev.data));
 }
 let eventSource = null;
 window.addEventListener('resize', onResize);
-window.addEventListener('load', onResize);
+window.addEventListener('load', () => {
+    fetch('config.json')
+        .then(response => response.json())
+        .then((loaded) => {
+            config = loaded;
+            data = new Uint8Array(loaded.board.sliders);
+            onResize();
+        });
+});
 contentContainer.addEventListener("scroll", () => doScroll(false));
 //# sourceMappingURL=main.js.map
\ No newline at end of file
@@ -1,5 +1,13 @@
 const PADDING_ROWS = 6;
-const NUM_VALUES = 1000000;
+
+// Served by the server at /config.json, only the parts used here
+interface ClientConfig {
+    board: { sliders: number }
+    chunk_bytes: number
+    endpoints: { updates: string, set_byte: string }
+}
+
+let config: ClientConfig | null = null
 
 let visibleWidth: number = 0;
 let visibleHeight: number = 0;
@@ -17,7 +25,7 @@ let renderedRows: Element[] = []
 const content = document.getElementById('content')!;
 const contentContainer = document.getElementById('content-container')!;
 
-const data = new Uint8Array(NUM_VALUES)
+let data = new Uint8Array(0)
 
 function setBit(n: number, value: boolean = true): void {
     let changed = false
@@ -59,6 +67,9 @@ function getByte(n: number): number {
 }
 
 function onResize(): void {
+    if (config === null) {
+        return
+    }
     const firstVisibleCb = firstVisibleRow * numCols;
     // Find the number of input elements that can fit in the container
     content.textContent = '';
@@ -78,7 +89,7 @@ function onResize(): void {
     if (numCols < 4) {
         numCols = 4
     }
-    numRows = Math.ceil(1000000 / numCols);
+    numRows = Math.ceil(config.board.sliders / numCols);
     fullHeight = numRows * cbHeight;
     content.style.height = `${fullHeight}px`
 
@@ -121,8 +132,9 @@ function doScroll(force: boolean): void {
         }
     }
 
-    const roundedFirstCheckbox = (((newFirstRenderedRow * numCols) / 512) | 0) * 512;
-    const roundedLastCheckbox = (((newLastRenderedRow * numCols + 511) / 512) | 0) * 512;
+    const chunkBytes = config!.chunk_bytes;
+    const roundedFirstCheckbox = (((newFirstRenderedRow * numCols) / chunkBytes) | 0) * chunkBytes;
+    const roundedLastCheckbox = (((newLastRenderedRow * numCols + chunkBytes - 1) / chunkBytes) | 0) * chunkBytes;
     if (roundedFirstCheckbox !== eventSourceStart || roundedLastCheckbox !== eventSourceEnd) {
         eventSourceStart = roundedFirstCheckbox;
         eventSourceEnd = roundedLastCheckbox;
@@ -162,7 +174,7 @@ function makeRow(n: number): HTMLDivElement {
     row.className = 'cb-row';
     for (let i = 0; i < numCols; i++) {
         let byteIdx = n * numCols + i;
-        if (byteIdx >= NUM_VALUES) {
+        if (byteIdx >= data.length) {
             break
         }
         const slider = makeSlider(getByte(byteIdx))
@@ -171,7 +183,7 @@ function makeRow(n: number): HTMLDivElement {
             const inputElem = target.children[0] as HTMLInputElement
             const value = parseInt(inputElem.value);
             setByte(byteIdx, value)
-            fetch(`set_byte/${byteIdx}/${value}`, {
+            fetch(endpoint(config!.endpoints.set_byte, {index: byteIdx, value}), {
                 method: 'POST',
             })
         }
@@ -183,11 +195,20 @@ function makeRow(n: number): HTMLDivElement {
 
 function updateSum(sum: number): void {
     const countEl = document.getElementById('avg')!;
-    const percent = sum / 255 / NUM_VALUES * 100;
+    const percent = sum / 255 / data.length * 100;
     countEl.textContent = `${percent.toFixed(7)}%`
 }
 
-function handleUpdate(offset: number, base64Data: string): void {
+// Version of the last contents applied to each chunk, by offset
+const chunkVersions = new Map<number, number>()
+
+function handleUpdate(id: string, base64Data: string): void {
+    // Ids are `offset:version`, older or repeated versions (say, after reconnecting) are skipped
+    const [offset, version] = id.split(':').map(Number);
+    if (version <= (chunkVersions.get(offset) ?? 0)) {
+        return
+    }
+    chunkVersions.set(offset, version)
     const data = atob(base64Data);
     // Offset is in bits, so divide by 8 to get bytes
     let i = offset / 8;
@@ -197,22 +218,35 @@ function handleUpdate(offset: number, base64Data: string): void {
     }
 }
 
+// Fills in an endpoint's `{placeholders}`, relative to the page so the site can live under a prefix
+function endpoint(path: string, params: Record<string, number> = {}): string {
+    return path.replace(/^\//, '').replace(/\{(\w+)\}/g, (_, name: string) => params[name].toString())
+}
+
 let eventSourceStart = 0
 let eventSourceEnd = 0
 function createEventSource(): void {
     eventSource?.close()
     // Units are in bytes now
-    eventSource = new EventSource(`updates?start=${eventSourceStart * 8}&end=${eventSourceEnd * 8}`);
+    eventSource = new EventSource(`${endpoint(config!.endpoints.updates)}?start=${eventSourceStart * 8}&end=${eventSourceEnd * 8}`);
     eventSource.addEventListener("error", () => {
         eventSource?.close()
         setTimeout(createEventSource, 500)
     })
     eventSource.addEventListener("sum", (ev) => updateSum(parseFloat(ev.data)))
-    eventSource.addEventListener("update", (ev) => handleUpdate(parseInt(ev.lastEventId), ev.data))
+    eventSource.addEventListener("update", (ev) => handleUpdate(ev.lastEventId, ev.data))
 }
 
 let eventSource: EventSource | null = null
 
 window.addEventListener('resize', onResize)
-window.addEventListener('load', onResize)
+window.addEventListener('load', () => {
+    fetch('config.json')
+        .then(response => response.json())
+        .then((loaded: ClientConfig) => {
+            config = loaded
+            data = new Uint8Array(loaded.board.sliders)
+            onResize()
+        })
+})
 contentContainer.addEventListener("scroll", () => doScroll(false))
\ No newline at end of file